@@ -9,7 +9,7 @@ use std::fs::File;
 use std::io::BufReader;
 use std::io::Read;
 
-use azchar_error::ma;
+use azchar_error::Error;
 
 #[derive(Debug, Deserialize)]
 /// The base configuration file for the app.
@@ -19,12 +19,12 @@ pub struct Config {
 
 impl Config {
     /// Creates a config.
-    pub fn from_path(path: &str) -> Result<Self, String> {
-        let mut file = BufReader::new(File::open(path).map_err(ma)?);
+    pub fn from_path(path: &str) -> Result<Self, Error> {
+        let mut file = BufReader::new(File::open(path)?);
         let mut input = String::new();
 
-        file.read_to_string(&mut input).map_err(ma)?;
-        toml::from_str(&input).map_err(ma)
+        file.read_to_string(&mut input)?;
+        Ok(toml::from_str(&input)?)
     }
 
     /// Get the path without modifying it.
@@ -0,0 +1,10 @@
+-- Every roll made for the character.
+create table rolls(
+  id INTEGER primary key AUTOINCREMENT,
+  date TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  label TEXT,
+  expression TEXT NOT NULL,
+  -- The whole roll, with every die, as JSON.
+  details TEXT NOT NULL,
+  total BIGINT NOT NULL
+);
@@ -0,0 +1,10 @@
+-- Rolls made up for a particular part. These go before the system's rolls of the same name.
+create table roll_macros(
+  id INTEGER primary key AUTOINCREMENT,
+  of BIGINT NOT NULL references characters(id),
+  name TEXT NOT NULL,
+  -- Dice expressions, which may refer to attributes, eg. "1d20+@str_mod".
+  attack TEXT,
+  damage TEXT,
+  UNIQUE(of, name)
+);
@@ -0,0 +1,7 @@
+-- Defaults, bounds and choices for permitted attributes.
+alter table permitted_attributes add column default_num BIGINT;
+alter table permitted_attributes add column default_text TEXT;
+alter table permitted_attributes add column min_value DOUBLE;
+alter table permitted_attributes add column max_value DOUBLE;
+-- A JSON list of the allowed values.
+alter table permitted_attributes add column choices TEXT;
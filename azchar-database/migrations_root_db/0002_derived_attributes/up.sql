@@ -0,0 +1,2 @@
+-- Derived attributes are worked out from a formula instead of being stored.
+alter table permitted_attributes add column formula TEXT;
@@ -0,0 +1,10 @@
+-- Rolls made in the system which are not for any one character.
+create table rolls(
+  id INTEGER primary key AUTOINCREMENT,
+  date TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  label TEXT,
+  expression TEXT NOT NULL,
+  -- The whole roll, with every die, as JSON.
+  details TEXT NOT NULL,
+  total BIGINT NOT NULL
+);
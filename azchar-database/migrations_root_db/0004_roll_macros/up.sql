@@ -0,0 +1,11 @@
+-- Rolls which every part of a kind can make, eg. the attack and damage of a weapon.
+create table roll_macros(
+  id INTEGER primary key AUTOINCREMENT,
+  name TEXT NOT NULL,
+  part_name TEXT NOT NULL,
+  part_type INTEGER NOT NULL,
+  -- Dice expressions, which may refer to attributes, eg. "1d20+@str_mod".
+  attack TEXT,
+  damage TEXT,
+  UNIQUE(name, part_name, part_type)
+);
@@ -0,0 +1,3 @@
+-- Sheets are referred to by paths relative to the root database from here on.
+-- SQL can't tell where the root database is, so older absolute paths are changed
+-- by `CharacterDbRef::make_paths_relative` whenever it is loaded. See `migrate_root`.
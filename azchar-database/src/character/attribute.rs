@@ -1,7 +1,8 @@
 //! This deals with the attributes table.
 use super::character::characters;
 use crate::root_db::system::PermittedAttribute;
-use azchar_error::ma;
+use crate::shared::AttributeType;
+use azchar_error::Error;
 
 use diesel::{Connection, SqliteConnection};
 use diesel::{ExpressionMethods, Insertable, QueryDsl, RunQueryDsl};
@@ -49,6 +50,8 @@ impl Attribute {
             value_num: self.value_num,
             value_text: self.value_text,
             description: self.description,
+            attribute_type: None,
+            read_only: false,
         };
         let k = AttributeKey {
             key: self.key,
@@ -82,8 +85,8 @@ impl NewAttribute {
     pub(crate) fn from_permitted(ch_id: i64, permitted: &PermittedAttribute) -> Self {
         NewAttribute {
             key: permitted.key.to_owned(),
-            value_num: None,
-            value_text: None,
+            value_num: permitted.default_num,
+            value_text: permitted.default_text.to_owned(),
             description: Some(permitted.attribute_description.to_owned()),
             of: ch_id,
         }
@@ -92,10 +95,10 @@ impl NewAttribute {
 
 impl NewAttribute {
     pub(crate) fn checked_insert(
-        self,
+        mut self,
         conn: &SqliteConnection,
         permitted_attrs: &[PermittedAttribute],
-    ) -> Result<usize, String> {
+    ) -> Result<usize, Error> {
         use self::attributes::dsl::*;
         use super::character::characters::dsl as c_dsl;
         use crate::diesel::NullableExpressionMethods;
@@ -108,35 +111,107 @@ impl NewAttribute {
                 .filter(c_dsl::part_type.nullable().eq(perm.part_type))
                 .select(c_dsl::id)
                 .first::<i64>(conn)
-                .optional()
-                .map_err(ma)?
+                .optional()?
                 .is_some()
             {
+                if let Some(perm) = find_permitted(conn, permitted_attrs, &self.key, self.of)? {
+                    if self.value_num.is_none() && self.value_text.is_none() {
+                        self.value_num = perm.default_num;
+                        self.value_text = perm.default_text.to_owned();
+                    }
+                    check_value(conn, perm, self.value_num, &self.value_text)?;
+                }
                 // Then try to insert. Maybe we'll be lucky.
                 diesel::insert_into(attributes)
                     .values(&self)
                     .execute(conn)
-                    .map_err(ma)
+                    .map_err(Error::from)
             } else {
-                Err(format!("Part does not exist for attribute {}.", self.key))
+                Err(Error::not_found("Part for attribute", &self.key))
             }
         } else {
-            Err(format!(
-                "Attribute {} not permitted in this system",
-                self.key
+            Err(Error::not_permitted(
+                format!("Attribute {}", self.key),
+                "it is not in this system",
             ))
         }
     }
 }
 
+/// Find the permitted attribute for a key on a part.
+/// The permitted attribute for the receiving part is preferred, but any with the key will do.
+fn find_permitted<'a>(
+    conn: &SqliteConnection,
+    permitted_attrs: &'a [PermittedAttribute],
+    key: &str,
+    of: i64,
+) -> Result<Option<&'a PermittedAttribute>, Error> {
+    use super::character::characters::dsl as c_dsl;
+    use crate::diesel::OptionalExtension;
+
+    let part = c_dsl::characters
+        .filter(c_dsl::id.eq(of))
+        .select((c_dsl::character_type, c_dsl::part_type))
+        .first::<(String, i32)>(conn)
+        .optional()?;
+    let perm = part
+        .and_then(|(name, t)| {
+            PermittedAttribute::find_for_part(permitted_attrs, key, t.into(), &name)
+        })
+        .or_else(|| permitted_attrs.iter().find(|a| a.key == key));
+    Ok(perm)
+}
+
+/// Check that a value is allowed by its permitted attribute,
+/// and that a part it refers to exists.
+/// NB: Derived attributes can't be written at all.
+pub(crate) fn check_value(
+    conn: &SqliteConnection,
+    perm: &PermittedAttribute,
+    value_num: Option<i64>,
+    value_text: &Option<String>,
+) -> Result<(), Error> {
+    use super::character::characters::dsl as c_dsl;
+    use crate::diesel::OptionalExtension;
+
+    if perm.formula.is_some() {
+        let what = format!("Attribute '{}'", perm.key);
+        return Err(Error::not_permitted(what, "it is derived from a formula"));
+    }
+    perm.check_value(value_num, value_text)?;
+    match (perm.value_type(), value_num) {
+        (AttributeType::PartReference, Some(n)) => {
+            let found = c_dsl::characters
+                .filter(c_dsl::id.eq(n))
+                .select(c_dsl::id)
+                .first::<i64>(conn)
+                .optional()?;
+            match found {
+                Some(_) => Ok(()),
+                None => Err(Error::not_found(
+                    "Part",
+                    format!("{} (referred to by '{}')", n, perm.key),
+                )),
+            }
+        }
+        _ => Ok(()),
+    }
+}
+
 pub type InputAttribute = NewAttribute;
 
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct AttributeValue {
-    id: Option<i64>,
-    value_num: Option<i64>,
-    value_text: Option<String>,
-    description: Option<String>,
+    pub(crate) id: Option<i64>,
+    pub(crate) value_num: Option<i64>,
+    pub(crate) value_text: Option<String>,
+    pub(crate) description: Option<String>,
+    /// Filled in from the system when a character is loaded. It is ignored when saving.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) attribute_type: Option<AttributeType>,
+    /// Set for derived attributes, which are worked out when a character is loaded.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub(crate) read_only: bool,
 }
 
 impl AttributeValue {
@@ -146,6 +221,8 @@ impl AttributeValue {
             value_num: None,
             value_text: Some("no".to_owned()),
             description: None,
+            attribute_type: None,
+            read_only: false,
         }
     }
 
@@ -171,6 +248,14 @@ impl AttributeValue {
         self.description = desc;
         self
     }
+
+    pub fn attribute_type(&self) -> Option<AttributeType> {
+        self.attribute_type
+    }
+
+    pub fn read_only(&self) -> bool {
+        self.read_only
+    }
 }
 
 #[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
@@ -223,9 +308,9 @@ pub struct Attributes(pub FnvHashMap<AttributeKey, AttributeValue>);
 
 impl Attributes {
     /// Get existing attributes for a character.
-    pub fn get_for_character(char_id: i64, conn: &SqliteConnection) -> Result<Self, String> {
+    pub fn get_for_character(char_id: i64, conn: &SqliteConnection) -> Result<Self, Error> {
         use self::attributes::dsl::*;
-        let attribute_vec: Vec<_> = attributes.filter(of.eq(char_id)).load(conn).map_err(ma)?;
+        let attribute_vec: Vec<_> = attributes.filter(of.eq(char_id)).load(conn)?;
         Ok(Attributes::from_vec(attribute_vec))
     }
 
@@ -234,12 +319,12 @@ impl Attributes {
     pub(crate) fn get_vec_for_characters(
         char_ids: &[i64],
         conn: &SqliteConnection,
-    ) -> Result<Vec<Attribute>, String> {
+    ) -> Result<Vec<Attribute>, Error> {
         use self::attributes::dsl::*;
 
         let mut attribute_vec: Vec<_> = Vec::new();
         for chunk in char_ids.chunks(999) {
-            let mut chunk = attributes.filter(of.eq_any(chunk)).load(conn).map_err(ma)?;
+            let mut chunk = attributes.filter(of.eq_any(chunk)).load(conn)?;
             attribute_vec.append(&mut chunk);
         }
         Ok(attribute_vec)
@@ -260,6 +345,8 @@ impl Attributes {
                         value_num: a.value_num,
                         value_text: a.value_text,
                         description: a.description,
+                        attribute_type: None,
+                        read_only: false,
                     };
                     (att_key, att_value)
                 })
@@ -280,6 +367,8 @@ impl Attributes {
                     value_num: a.value_num,
                     value_text: a.value_text,
                     description: a.description,
+                    attribute_type: None,
+                    read_only: false,
                 };
                 (att_key, att_value)
             })
@@ -293,7 +382,7 @@ impl Attributes {
     /// Get existing attributes for a list of characters.
     /// Intended to be used to get attributes for a defined subset of
     /// inner characters.
-    pub fn get_for_characters(char_ids: &[i64], conn: &SqliteConnection) -> Result<Self, String> {
+    pub fn get_for_characters(char_ids: &[i64], conn: &SqliteConnection) -> Result<Self, Error> {
         let attribute_vec = Self::get_vec_for_characters(char_ids, conn)?;
         Ok(Self::from_vec(attribute_vec))
     }
@@ -345,8 +434,12 @@ impl Attributes {
         k: &AttributeKey,
         v: &AttributeValue,
         conn: &SqliteConnection,
-    ) -> Result<(), String> {
+        permitted_attrs: &[PermittedAttribute],
+    ) -> Result<(), Error> {
         use self::attributes::dsl::*;
+        if let Some(perm) = find_permitted(conn, permitted_attrs, &k.key, k.of)? {
+            check_value(conn, perm, v.value_num, &v.value_text)?;
+        }
         match kv_into_attribute(k, v) {
             NewOrOldAttribute::Old(a) => diesel::replace_into(attributes)
                 .values(a)
@@ -357,6 +450,6 @@ impl Attributes {
                 .execute(conn)
                 .map(|_| ()),
         }
-        .map_err(ma)
+        .map_err(Error::from)
     }
 }
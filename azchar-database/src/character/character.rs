@@ -1,11 +1,12 @@
 //! This deals with the character columns.
 use crate::character::attribute::NewAttribute;
+use crate::character::formula::{self, Expr};
 use crate::character::image::{Image, NewImage};
 use crate::character::note::Note;
 use crate::root_db::system::{PermittedAttribute, PermittedPart};
-use crate::shared::Part;
+use crate::shared::{AttributeType, Part};
 
-use azchar_error::ma;
+use azchar_error::Error;
 
 use diesel::result::Error as DbError;
 use diesel::{ExpressionMethods, Insertable, QueryDsl, Queryable, RunQueryDsl};
@@ -93,6 +94,10 @@ impl Character {
         &self.name
     }
 
+    pub fn uuid(&self) -> &str {
+        &self.uuid
+    }
+
     pub fn character_type(&self) -> &str {
         &self.character_type
     }
@@ -124,6 +129,22 @@ impl Character {
     pub fn belongs_to(&self) -> &Option<i64> {
         &self.belongs_to
     }
+
+    /// Add the ids of every part beneath the given ones, however deep.
+    pub(crate) fn with_parts_beneath(parts: &[Self], mut ids: FnvHashSet<i64>) -> FnvHashSet<i64> {
+        loop {
+            let more = parts
+                .iter()
+                .filter(|p| !ids.contains(&p.id))
+                .filter(|p| p.belongs_to.map(|b| ids.contains(&b)) == Some(true))
+                .map(|p| p.id)
+                .collect::<Vec<_>>();
+            if more.is_empty() {
+                return ids;
+            }
+            ids.extend(more);
+        }
+    }
 }
 
 #[derive(Debug, Clone, Insertable, Default)]
@@ -142,7 +163,7 @@ pub struct NewCharacter {
 }
 
 impl NewCharacter {
-    fn from_part(part: &CharacterPart) -> Self {
+    pub(crate) fn from_part(part: &CharacterPart) -> Self {
         NewCharacter {
             name: part.name.clone(),
             uuid: part.uuid.clone(),
@@ -157,7 +178,7 @@ impl NewCharacter {
         }
     }
 
-    fn from_complete(main: &CompleteCharacter) -> Self {
+    pub(crate) fn from_complete(main: &CompleteCharacter) -> Self {
         NewCharacter {
             name: main.name.clone(),
             uuid: main.uuid.clone(),
@@ -194,7 +215,7 @@ impl NewCharacter {
         permitted_parts: &[PermittedPart],
         permitted_attrs: &[PermittedAttribute],
         image: &Option<Image>,
-    ) -> Result<(), String> {
+    ) -> Result<(), Error> {
         use self::characters::dsl::*;
         use crate::character::attribute::attributes::dsl as adsl;
         // First check if this is allowed.
@@ -202,14 +223,14 @@ impl NewCharacter {
             .iter()
             .any(|p| p.part_name == self.character_type && p.part_type == self.part_type)
         {
-            let m = format!(
-                "Part {}-({:?},{}) not permitted in this system",
+            let what = format!(
+                "Part {}-({:?},{})",
                 self.name, self.part_type, self.character_type
             );
-            return Err(m);
+            return Err(Error::not_permitted(what, "it is not in this system"));
         }
         // Next check if it chains with the character.
-        let parts: Vec<Character> = characters.load(conn).map_err(ma)?;
+        let parts: Vec<Character> = characters.load(conn)?;
         if self.belongs_to.is_none()
             || (matches!(self.part_type, Part::Main)
                 && parts.iter().any(|p| matches!(p.part_type, Part::Main)))
@@ -218,25 +239,20 @@ impl NewCharacter {
                 "Part {} has a \"Main\" typ, but one already exists on this sheet.",
                 self.name
             );
-            return Err(m);
+            return Err(Error::conflict(m));
         }
         if !parts.iter().any(|p| Some(p.id) == self.belongs_to) {
             let m = format!(
                 "Part {}-({:?},{}) doesn't belong.",
                 self.name, self.part_type, self.character_type
             );
-            return Err(m);
+            return Err(Error::validation(m));
         }
         diesel::insert_into(characters)
             .values(&self)
-            .execute(conn)
-            .map_err(ma)?;
-
-        let pid = characters
-            .order_by(id.desc())
-            .select(id)
-            .first(conn)
-            .map_err(ma)?;
+            .execute(conn)?;
+
+        let pid = characters.order_by(id.desc()).select(id).first(conn)?;
         let new_attributes = permitted_attrs
             .iter()
             .filter(|pa| pa.obligatory_for_part(self.part_type, &self.character_type))
@@ -245,8 +261,7 @@ impl NewCharacter {
 
         diesel::insert_into(adsl::attributes)
             .values(&new_attributes)
-            .execute(conn)
-            .map_err(ma)?;
+            .execute(conn)?;
         if let Some(image) = image {
             NewImage {
                 of: pid,
@@ -465,6 +480,10 @@ impl CompleteCharacter {
         &self.image
     }
 
+    pub fn notes(&self) -> &[Note] {
+        &self.notes
+    }
+
     /// Compare the main parts of two complete characters: NB: Attributes not compared.
     fn compare_main(&self, other: &CompleteCharacter) -> bool {
         self.id == other.id
@@ -498,16 +517,16 @@ impl CompleteCharacter {
         }
     }
 
-    pub fn load(conn: &SqliteConnection) -> Result<CompleteCharacter, String> {
+    pub fn load(conn: &SqliteConnection) -> Result<CompleteCharacter, Error> {
         use self::characters::dsl::*;
         use super::attribute::attributes::dsl as attr_dsl;
 
         let then = std::time::Instant::now();
 
-        let mut chars: Vec<Character> = characters.load(conn).map_err(ma)?;
-        let notes = Note::load_all(conn).map_err(ma)?;
-        let mut images = Image::load_all(conn).map_err(ma)?;
-        let attrs: Vec<Attribute> = attr_dsl::attributes.load(conn).map_err(ma)?;
+        let mut chars: Vec<Character> = characters.load(conn)?;
+        let notes = Note::load_all(conn)?;
+        let mut images = Image::load_all(conn)?;
+        let attrs: Vec<Attribute> = attr_dsl::attributes.load(conn)?;
 
         let a = then.elapsed().as_micros();
         let mut attrs = Attributes::key_val_vec(attrs);
@@ -515,7 +534,7 @@ impl CompleteCharacter {
         attrs.sort_unstable_by(|a, b| b.0.of.cmp(&a.0.of));
 
         if chars.is_empty() {
-            return Err("No parts found for character.".to_string());
+            return Err(Error::not_found("Parts", "for character"));
         }
         let core = chars.pop().expect("We have at least one.");
         let core_image = if images.last().map(|i| i.of == core.id).unwrap_or(false) {
@@ -581,17 +600,97 @@ impl CompleteCharacter {
         })
     }
 
+    /// Fill in the type of every attribute from the system, so that frontends know what it holds.
+    pub(crate) fn set_attribute_types(&mut self, permitted_attrs: &[PermittedAttribute]) {
+        let main = (Part::Main, self.character_type.as_ref());
+        let parts = self
+            .parts
+            .iter_mut()
+            .flat_map(|p| {
+                let part = (p.part_type, p.character_type.as_ref());
+                p.attributes.iter_mut().map(move |a| (part, a))
+            })
+            .chain(self.attributes.iter_mut().map(|a| (main, a)));
+        for ((part_type, part_name), (k, v)) in parts {
+            v.attribute_type =
+                PermittedAttribute::find_for_part(permitted_attrs, &k.key, part_type, part_name)
+                    .map(|a| a.value_type());
+        }
+    }
+
+    /// Load a character, with the type of each attribute filled in
+    /// and its derived attributes worked out.
+    pub fn load_for_system(
+        conn: &SqliteConnection,
+        permitted_attrs: &[PermittedAttribute],
+    ) -> Result<CompleteCharacter, Error> {
+        let mut character = CompleteCharacter::load(conn)?;
+        character.set_attribute_types(permitted_attrs);
+        character.derive_attributes(permitted_attrs);
+        Ok(character)
+    }
+
+    /// Work out the values of derived attributes and mark them as read only.
+    /// Those of the main part are worked out first, since the parts can refer to them.
+    /// NB: A value which can't be worked out (eg. one which refers to a missing value) is left empty.
+    pub(crate) fn derive_attributes(&mut self, permitted_attrs: &[PermittedAttribute]) {
+        let fields = [
+            Some(self.speed),
+            self.weight,
+            self.hp_total,
+            self.hp_current,
+        ];
+        let main_values = derive_for_part(
+            permitted_attrs,
+            (
+                Part::Main,
+                &self.character_type,
+                self.id.unwrap_or_default(),
+            ),
+            &mut self.attributes,
+            fields,
+            &FnvHashMap::default(),
+        );
+        for p in self.parts.iter_mut() {
+            let fields = [Some(p.speed), p.weight, p.hp_total, p.hp_current];
+            derive_for_part(
+                permitted_attrs,
+                (p.part_type, &p.character_type, p.id.unwrap_or_default()),
+                &mut p.attributes,
+                fields,
+                &main_values,
+            );
+        }
+    }
+
+    /// Drop derived attributes, since these are never stored.
+    fn strip_derived(&mut self, permitted_attrs: &[PermittedAttribute]) {
+        let is_stored = |part_type, part_name: &str, k: &AttributeKey| {
+            PermittedAttribute::find_for_part(permitted_attrs, &k.key, part_type, part_name)
+                .map(|a| a.formula.is_none())
+                .unwrap_or(true)
+        };
+        let main_type = self.character_type.clone();
+        self.attributes
+            .retain(|(k, _)| is_stored(Part::Main, &main_type, k));
+        for p in self.parts.iter_mut() {
+            let (part_type, part_name) = (p.part_type, p.character_type.clone());
+            p.attributes
+                .retain(|(k, _)| is_stored(part_type, &part_name, k));
+        }
+    }
+
     /// This function deletes a character part and all of its attributes.
-    pub fn delete_part(part_id: i64, conn: &SqliteConnection) -> Result<(), String> {
+    pub fn delete_part(part_id: i64, conn: &SqliteConnection) -> Result<(), Error> {
         use self::characters::dsl;
         use super::attribute::attributes::dsl as a_dsl;
 
-        let res = conn.immediate_transaction::<_, DbError, _>(|| {
+        crate::sheet_transaction(conn, || {
             diesel::dsl::delete(dsl::characters.filter(dsl::id.eq(part_id))).execute(conn)?;
             diesel::dsl::delete(a_dsl::attributes.filter(a_dsl::of.eq(part_id))).execute(conn)?;
+            super::roll_macro::RollMacro::delete_all_for(conn, part_id)?;
             Ok(())
-        });
-        res.map_err(|e| format!("Error deleting character part: {:?}", e))
+        })
     }
 
     /// Store a character in an existing sheet.
@@ -602,14 +701,13 @@ impl CompleteCharacter {
         mut self,
         conn: &SqliteConnection,
         (permitted_attrs, permitted_parts): (&[PermittedAttribute], &[PermittedPart]),
-    ) -> Result<(), String> {
+    ) -> Result<(), Error> {
         use self::characters::dsl::*;
         use super::image::images::dsl::images;
         use super::note::notes::dsl::notes;
         let then = std::time::Instant::now();
-        let mut error_string = "DbError::NotFound".to_string();
 
-        let res = conn.immediate_transaction::<_, DbError, _>(|| {
+        let res = crate::sheet_transaction(conn, || {
             // A check to see if the existing character already exists here.
             let existing: Option<(i64, String, String)> = characters
                 .filter(part_type.eq(Part::Main))
@@ -620,18 +718,18 @@ impl CompleteCharacter {
             // If the current sheet is already occupied by a different character, return early.
             if let Some(other) = &existing {
                 if self.id != Some(other.0) {
-                    error_string = format!(
+                    return Err(Error::conflict(format!(
                         "A character already exists on this sheet: name:{}, uuid:{}",
                         other.1, other.2,
-                    );
-                    return Err(DbError::NotFound);
+                    )));
                 }
             }
 
-            let old_complete = CompleteCharacter::load(conn).map_err(|e| {
-                error_string = format!("Save error: {:?}", e);
-                DbError::NotFound
-            })?;
+            let mut old_complete = CompleteCharacter::load(conn)?;
+            old_complete.set_attribute_types(permitted_attrs);
+            old_complete.strip_derived(permitted_attrs);
+            self.set_attribute_types(permitted_attrs);
+            self.strip_derived(permitted_attrs);
             if old_complete == self {
                 let b = then.elapsed().as_micros();
                 println!("same ret: {}", b);
@@ -644,13 +742,20 @@ impl CompleteCharacter {
                 .map(|p| ((p.part_name.as_ref(), p.part_type), p.obligatory))
                 .collect::<FnvHashMap<(&str, Part), bool>>();
 
-            let permitted_attrs_map = permitted_attrs
+            // The same key can be permitted on several parts.
+            let mut permitted_attrs_map = FnvHashMap::<&str, Vec<&PermittedAttribute>>::default();
+            for a in permitted_attrs.iter() {
+                permitted_attrs_map.entry(&a.key).or_default().push(a);
+            }
+            let part_ids = self
+                .parts
                 .iter()
-                .map(|a| (a.key.as_ref(), (a.obligatory, a.part_type, &a.part_name)))
-                .collect::<FnvHashMap<&str, (bool, Option<Part>, &Option<String>)>>();
+                .filter_map(|p| p.id)
+                .chain(self.id)
+                .collect::<FnvHashSet<_>>();
             let obligatory_attrs = permitted_attrs
                 .iter()
-                .filter(|a| a.obligatory)
+                .filter(|a| a.obligatory && a.formula.is_none())
                 .collect::<Vec<_>>();
 
             // Do the work.
@@ -658,8 +763,10 @@ impl CompleteCharacter {
                 .get(&(self.character_type.as_ref(), Part::Main))
                 .is_none()
             {
-                error_string = "Main part type is not permitted in the system".to_owned();
-                return Err(DbError::NotFound);
+                return Err(Error::not_permitted(
+                    format!("Main part type {}", self.character_type),
+                    "it is not in this system",
+                ));
             }
             let mut own_oblig_part_count = 1;
             for p in self.parts.iter() {
@@ -670,8 +777,10 @@ impl CompleteCharacter {
                         own_oblig_part_count += 1;
                     }
                 } else {
-                    error_string = format!("Forbidden part found: '{}'", p.character_type);
-                    return Err(DbError::NotFound);
+                    return Err(Error::not_permitted(
+                        format!("Part '{}'", p.character_type),
+                        "it is not in this system",
+                    ));
                 }
             }
 
@@ -680,19 +789,16 @@ impl CompleteCharacter {
                 .filter(|(_, ob)| *ob)
                 .count();
             if own_oblig_part_count < opc {
-                error_string = "Obligatory part missing.".to_string();
-                return Err(DbError::NotFound);
+                return Err(Error::validation("Obligatory part missing."));
             }
 
-            if let Err(e) = check_attributes_vs_db(
+            check_attributes_vs_db(
                 &self.attributes,
                 &permitted_attrs_map,
                 (&self.character_type, Part::Main),
                 &obligatory_attrs,
-            ) {
-                error_string = e;
-                return Err(DbError::NotFound);
-            };
+                &part_ids,
+            )?;
             let mut attribute_refs: Vec<_> = Vec::with_capacity(1000); // Why not.
             attribute_refs.extend(self.attributes.iter().map(|(k, v)| (k, v)));
             let mut new_chars = Vec::new();
@@ -714,15 +820,13 @@ impl CompleteCharacter {
 
             // Insert or update sub-characters.
             for sub_char in self.parts.iter_mut() {
-                if let Err(e) = check_attributes_vs_db(
+                check_attributes_vs_db(
                     &sub_char.attributes,
                     &permitted_attrs_map,
                     (&sub_char.character_type, sub_char.part_type),
                     &obligatory_attrs,
-                ) {
-                    error_string = e;
-                    return Err(DbError::NotFound);
-                };
+                    &part_ids,
+                )?;
                 attribute_refs.extend(sub_char.attributes.iter().map(|(k, v)| (k, v)));
 
                 // A mystery wrapped in an enigma wrapped in an onion.
@@ -745,12 +849,7 @@ impl CompleteCharacter {
             }
 
             for (image, new_char) in new_chars.into_iter() {
-                new_char
-                    .checked_insert(conn, permitted_parts, permitted_attrs, &image)
-                    .map_err(|e| {
-                        error_string = e;
-                        DbError::NotFound
-                    })?;
+                new_char.checked_insert(conn, permitted_parts, permitted_attrs, &image)?;
             }
             for chunk in upd_chars.chunks(999) {
                 diesel::replace_into(characters)
@@ -769,12 +868,7 @@ impl CompleteCharacter {
         });
         let d = then.elapsed().as_micros();
         println!("transaction: {}", d);
-
-        match res {
-            Ok(r) => Ok(r),
-            Err(DbError::NotFound) => Err(error_string),
-            Err(e) => Err(e.to_string()),
-        }
+        res
     }
 
     /// Insert a single key value.
@@ -783,11 +877,11 @@ impl CompleteCharacter {
         conn: &SqliteConnection,
         permitted_parts: &[PermittedPart],
         permitted_attrs: &[PermittedAttribute],
-    ) -> Result<(), String> {
+    ) -> Result<(), Error> {
         use self::characters::dsl::*;
         // Insert/update the image if it exists.
         if let Some(ref i) = chp.image {
-            i.update(conn).map_err(ma)?;
+            i.update(conn)?;
         }
         if let Some(ch_id) = chp.id {
             return diesel::update(characters.filter(id.eq(ch_id)))
@@ -805,7 +899,7 @@ impl CompleteCharacter {
                 ))
                 .execute(conn)
                 .map(|_| ())
-                .map_err(ma);
+                .map_err(Error::from);
         }
         NewCharacter::from_part(&chp).checked_insert(
             conn,
@@ -816,27 +910,153 @@ impl CompleteCharacter {
     }
 }
 
+/// Work out the derived attributes of one part and return all of its numeric values,
+/// so that the parts can use those of the main part.
+fn derive_for_part(
+    permitted_attrs: &[PermittedAttribute],
+    (part_type, part_name, part_id): (Part, &str, i64),
+    attributes: &mut Vec<(AttributeKey, AttributeValue)>,
+    fields: [Option<i32>; 4],
+    main_values: &FnvHashMap<String, f64>,
+) -> FnvHashMap<String, f64> {
+    let derived = permitted_attrs
+        .iter()
+        .filter(|a| a.permitted_for_part(part_type, part_name))
+        .filter_map(|a| a.formula.as_ref().map(|f| (a, formula::parse(f).ok())))
+        .collect::<Vec<_>>();
+    let mut derivation = Derivation {
+        formulas: derived
+            .iter()
+            .map(|(a, expr)| (a.key.as_ref(), expr.clone()))
+            .collect(),
+        values: attributes
+            .iter()
+            .filter_map(|(k, v)| numeric_value(v).map(|n| (k.key.clone(), n)))
+            .collect(),
+        fields,
+        main_values,
+        derived: FnvHashMap::default(),
+        visiting: FnvHashSet::default(),
+    };
+    for (perm, _) in derived.iter() {
+        let result = derivation.derived_value(&perm.key);
+        let (value_num, value_text) = match (perm.value_type(), result) {
+            (_, None) => (None, None),
+            (AttributeType::Decimal, Some(r)) => (None, Some(r.to_string())),
+            (_, Some(r)) => (Some(r.round() as i64), None),
+        };
+        match attributes.iter_mut().find(|(k, _)| k.key == perm.key) {
+            Some((_, v)) => {
+                v.value_num = value_num;
+                v.value_text = value_text;
+                v.read_only = true;
+            }
+            None => {
+                let k = AttributeKey {
+                    key: perm.key.clone(),
+                    of: part_id,
+                };
+                let v = AttributeValue {
+                    id: None,
+                    value_num,
+                    value_text,
+                    description: Some(perm.attribute_description.clone()),
+                    attribute_type: Some(perm.value_type()),
+                    read_only: true,
+                };
+                attributes.push((k, v));
+            }
+        }
+    }
+    let mut values = derivation.values;
+    for (k, v) in derivation.derived.into_iter() {
+        match v {
+            Some(v) => values.insert(k, v),
+            None => values.remove(&k),
+        };
+    }
+    values
+}
+
+/// A number from an attribute: its `value_num`, or its text if that is a number.
+fn numeric_value(v: &AttributeValue) -> Option<f64> {
+    v.value_num()
+        .map(|n| n as f64)
+        .or_else(|| v.value_text().as_ref().and_then(|t| t.trim().parse().ok()))
+}
+
+/// The state of working out the derived attributes of one part.
+struct Derivation<'a> {
+    formulas: FnvHashMap<&'a str, Option<Expr>>,
+    values: FnvHashMap<String, f64>,
+    fields: [Option<i32>; 4],
+    main_values: &'a FnvHashMap<String, f64>,
+    derived: FnvHashMap<String, Option<f64>>,
+    // Guards against cycles, which should have been caught when the system was made.
+    visiting: FnvHashSet<String>,
+}
+
+impl Derivation<'_> {
+    /// The part's own attribute, then its built in field, then the main part's attribute.
+    fn value(&mut self, key: &str) -> Option<f64> {
+        if self.formulas.contains_key(key) {
+            return self.derived_value(key);
+        }
+        if let Some(v) = self.values.get(key) {
+            return Some(*v);
+        }
+        if let Some(i) = formula::BUILT_IN_FIELDS.iter().position(|f| *f == key) {
+            return self.fields[i].map(f64::from);
+        }
+        self.main_values.get(key).copied()
+    }
+
+    fn derived_value(&mut self, key: &str) -> Option<f64> {
+        if let Some(v) = self.derived.get(key) {
+            return *v;
+        }
+        if !self.visiting.insert(key.to_owned()) {
+            return None;
+        }
+        let expr = self.formulas.get(key).cloned().flatten();
+        let result = expr.and_then(|e| e.evaluate(&mut |k| self.value(k)));
+        self.visiting.remove(key);
+        self.derived.insert(key.to_owned(), result);
+        result
+    }
+}
+
 fn check_attributes_vs_db(
     own_attributes: &[(AttributeKey, AttributeValue)],
-    permitted: &FnvHashMap<&str, (bool, Option<Part>, &Option<String>)>,
+    permitted: &FnvHashMap<&str, Vec<&PermittedAttribute>>,
     (part_name, part_type): (&str, Part),
     obligatory: &[&PermittedAttribute],
-) -> Result<(), String> {
+    part_ids: &FnvHashSet<i64>,
+) -> Result<(), Error> {
     // First attribute check.
-    for (ak, _) in own_attributes.iter() {
+    for (ak, av) in own_attributes.iter() {
         if let Some(v) = permitted.get(&ak.key.as_ref()) {
-            let part_type_ok = v.1.map(|x| x == part_type).unwrap_or(true);
-            let part_name_ok = v.2.as_ref().map(|x| x == part_name).unwrap_or(true);
-            if !part_type_ok || !part_name_ok {
-                let msg = format!(
-                    "Can't save sheet: Attribute '{}' not allowed for '{}'",
-                    ak.key, part_name
-                );
-                return Err(msg);
+            let perm = match v
+                .iter()
+                .find(|a| a.permitted_for_part(part_type, part_name))
+            {
+                Some(perm) => perm,
+                None => {
+                    let what = format!("Attribute '{}'", ak.key);
+                    let reason = format!("not allowed for '{}'", part_name);
+                    return Err(Error::not_permitted(what, reason));
+                }
+            };
+            perm.check_value(av.value_num(), av.value_text())?;
+            if let (AttributeType::PartReference, Some(n)) = (perm.value_type(), av.value_num()) {
+                if !part_ids.contains(&n) {
+                    let key = format!("{} (referred to by '{}')", n, ak.key);
+                    return Err(Error::not_found("Part", key));
+                }
             }
         } else {
-            let msg = format!("Can't save sheet: Illegal attribute '{}'.", ak.key);
-            return Err(msg);
+            let what = format!("Attribute '{}'", ak.key);
+            return Err(Error::not_permitted(what, "it is not in this system"));
         }
     }
     let attrs = own_attributes
@@ -849,7 +1069,7 @@ fn check_attributes_vs_db(
     {
         if !attrs.contains(&a.key) {
             let m = format!("Can't save sheet: Obligatory attribute missing '{}'", a.key);
-            return Err(m);
+            return Err(Error::validation(m));
         }
     }
     Ok(())
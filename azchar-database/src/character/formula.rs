@@ -0,0 +1,292 @@
+//! This deals with the formulas of derived attributes, eg. `floor((@str - 10) / 2)`.
+//! A formula has numbers, `+ - * /`, brackets, the functions `floor`, `ceil`, `round`,
+//! `abs`, `min` and `max`, and references to other values as `@key`, or `@{key}`
+//! when the key has spaces or other odd characters in it.
+use azchar_error::Error;
+
+/// Fields of a part which a formula can use as if they were attributes.
+pub(crate) const BUILT_IN_FIELDS: [&str; 4] = ["speed", "weight", "hp_total", "hp_current"];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Op {
+    Add,
+    Sub,
+    Mul,
+    Div,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Function {
+    Floor,
+    Ceil,
+    Round,
+    Abs,
+    Min,
+    Max,
+}
+
+/// A parsed formula.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Expr {
+    Number(f64),
+    Reference(String),
+    Negative(Box<Expr>),
+    Binary(Op, Box<Expr>, Box<Expr>),
+    Call(Function, Vec<Expr>),
+}
+
+impl Expr {
+    /// The keys this formula refers to.
+    pub(crate) fn references(&self) -> Vec<&str> {
+        let mut refs = Vec::new();
+        self.collect_references(&mut refs);
+        refs
+    }
+
+    fn collect_references<'a>(&'a self, refs: &mut Vec<&'a str>) {
+        match self {
+            Expr::Number(_) => {}
+            Expr::Reference(key) => refs.push(key),
+            Expr::Negative(e) => e.collect_references(refs),
+            Expr::Binary(_, a, b) => {
+                a.collect_references(refs);
+                b.collect_references(refs);
+            }
+            Expr::Call(_, args) => args.iter().for_each(|a| a.collect_references(refs)),
+        }
+    }
+
+    /// Work out the value. It is `None` if a value it needs is missing,
+    /// or if the result is not a number, eg. after dividing by zero.
+    pub(crate) fn evaluate(&self, lookup: &mut dyn FnMut(&str) -> Option<f64>) -> Option<f64> {
+        let result = match self {
+            Expr::Number(n) => *n,
+            Expr::Reference(key) => lookup(key)?,
+            Expr::Negative(e) => -e.evaluate(lookup)?,
+            Expr::Binary(op, a, b) => {
+                let (a, b) = (a.evaluate(lookup)?, b.evaluate(lookup)?);
+                match op {
+                    Op::Add => a + b,
+                    Op::Sub => a - b,
+                    Op::Mul => a * b,
+                    Op::Div => a / b,
+                }
+            }
+            Expr::Call(f, args) => {
+                let mut values = Vec::with_capacity(args.len());
+                for a in args.iter() {
+                    values.push(a.evaluate(lookup)?);
+                }
+                match f {
+                    Function::Floor => values[0].floor(),
+                    Function::Ceil => values[0].ceil(),
+                    Function::Round => values[0].round(),
+                    Function::Abs => values[0].abs(),
+                    Function::Min => values.into_iter().fold(f64::INFINITY, f64::min),
+                    Function::Max => values.into_iter().fold(f64::NEG_INFINITY, f64::max),
+                }
+            }
+        };
+        Some(result).filter(|r| r.is_finite())
+    }
+}
+
+/// Parse a formula.
+pub(crate) fn parse(text: &str) -> Result<Expr, Error> {
+    let mut parser = Parser {
+        chars: text.chars().collect(),
+        pos: 0,
+    };
+    let expr = parser.sum()?;
+    parser.skip_whitespace();
+    match parser.peek() {
+        None => Ok(expr),
+        Some(c) => Err(parser.error(&format!("unexpected '{}'", c))),
+    }
+}
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+}
+
+impl Parser {
+    fn error(&self, what: &str) -> Error {
+        let text = self.chars.iter().collect::<String>();
+        Error::parse(format!(
+            "Formula \"{}\": {} at position {}.",
+            text, what, self.pos
+        ))
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self.peek().map(char::is_whitespace).unwrap_or(false) {
+            self.pos += 1;
+        }
+    }
+
+    fn next_is(&mut self, c: char) -> bool {
+        self.skip_whitespace();
+        if self.peek() == Some(c) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn take_while<F: Fn(char) -> bool>(&mut self, f: F) -> String {
+        let start = self.pos;
+        while self.peek().map(&f).unwrap_or(false) {
+            self.pos += 1;
+        }
+        self.chars[start..self.pos].iter().collect()
+    }
+
+    fn sum(&mut self) -> Result<Expr, Error> {
+        let mut expr = self.product()?;
+        loop {
+            let op = if self.next_is('+') {
+                Op::Add
+            } else if self.next_is('-') {
+                Op::Sub
+            } else {
+                return Ok(expr);
+            };
+            expr = Expr::Binary(op, Box::new(expr), Box::new(self.product()?));
+        }
+    }
+
+    fn product(&mut self) -> Result<Expr, Error> {
+        let mut expr = self.unary()?;
+        loop {
+            let op = if self.next_is('*') {
+                Op::Mul
+            } else if self.next_is('/') {
+                Op::Div
+            } else {
+                return Ok(expr);
+            };
+            expr = Expr::Binary(op, Box::new(expr), Box::new(self.unary()?));
+        }
+    }
+
+    fn unary(&mut self) -> Result<Expr, Error> {
+        if self.next_is('-') {
+            Ok(Expr::Negative(Box::new(self.unary()?)))
+        } else {
+            self.atom()
+        }
+    }
+
+    fn atom(&mut self) -> Result<Expr, Error> {
+        self.skip_whitespace();
+        match self.peek() {
+            Some('(') => {
+                self.pos += 1;
+                let expr = self.sum()?;
+                if self.next_is(')') {
+                    Ok(expr)
+                } else {
+                    Err(self.error("missing ')'"))
+                }
+            }
+            Some('@') => {
+                self.pos += 1;
+                let key = if self.next_is('{') {
+                    let key = self.take_while(|c| c != '}');
+                    if !self.next_is('}') {
+                        return Err(self.error("missing '}'"));
+                    }
+                    key
+                } else {
+                    self.take_while(|c| c.is_alphanumeric() || c == '_')
+                };
+                if key.is_empty() {
+                    return Err(self.error("missing key after '@'"));
+                }
+                Ok(Expr::Reference(key))
+            }
+            Some(c) if c.is_ascii_digit() || c == '.' => {
+                let number = self.take_while(|c| c.is_ascii_digit() || c == '.');
+                number
+                    .parse()
+                    .map(Expr::Number)
+                    .map_err(|_| self.error(&format!("'{}' is not a number", number)))
+            }
+            Some(c) if c.is_alphabetic() => {
+                let name = self.take_while(char::is_alphanumeric);
+                let function = match name.as_ref() {
+                    "floor" => Function::Floor,
+                    "ceil" => Function::Ceil,
+                    "round" => Function::Round,
+                    "abs" => Function::Abs,
+                    "min" => Function::Min,
+                    "max" => Function::Max,
+                    _ => return Err(self.error(&format!("unknown function '{}'", name))),
+                };
+                if !self.next_is('(') {
+                    return Err(self.error(&format!("missing '(' after '{}'", name)));
+                }
+                let mut args = vec![self.sum()?];
+                while self.next_is(',') {
+                    args.push(self.sum()?);
+                }
+                if !self.next_is(')') {
+                    return Err(self.error("missing ')'"));
+                }
+                let arity_ok = match function {
+                    Function::Min | Function::Max => true,
+                    _ => args.len() == 1,
+                };
+                if arity_ok {
+                    Ok(Expr::Call(function, args))
+                } else {
+                    Err(self.error(&format!("'{}' takes one value", name)))
+                }
+            }
+            Some(c) => Err(self.error(&format!("unexpected '{}'", c))),
+            None => Err(self.error("unexpected end")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse;
+
+    fn evaluate(formula: &str) -> Option<f64> {
+        let values = [("str", 15.0), ("dex", 8.0), ("weapon_AP cost", 3.0)];
+        parse(formula).expect("Could not parse.").evaluate(&mut |key| {
+            values.iter().find(|(k, _)| *k == key).map(|(_, v)| *v)
+        })
+    }
+
+    #[test]
+    fn formulas_evaluate() {
+        assert_eq!(evaluate("floor((@str - 10) / 2)"), Some(2.0));
+        assert_eq!(evaluate("floor((@dex - 10) / 2)"), Some(-1.0));
+        assert_eq!(evaluate("2 + 3 * 4 - -1"), Some(15.0));
+        assert_eq!(evaluate("max(@str, @dex, 20) + min(1.5, 2)"), Some(21.5));
+        assert_eq!(evaluate("@{weapon_AP cost} * 2"), Some(6.0));
+        assert_eq!(evaluate("@str + @wis"), None);
+        assert_eq!(evaluate("@str / 0"), None);
+    }
+
+    #[test]
+    fn formulas_which_do_not_parse() {
+        for bad in ["", "(1 + 2", "@", "1 +", "sqrt(4)", "floor(1, 2)", "1 2"].iter() {
+            assert!(parse(bad).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn formula_references() {
+        let expr = parse("floor((@str + @{weapon_AP cost}) / @str)").expect("Parse.");
+        assert_eq!(expr.references(), vec!["str", "weapon_AP cost", "str"]);
+    }
+}
@@ -5,7 +5,7 @@ use diesel::{Insertable, Queryable, RunQueryDsl};
 use std::io::Read;
 
 use crate::character::character::characters;
-use azchar_error::ma;
+use azchar_error::Error;
 
 table! {
     images(id) {
@@ -28,18 +28,23 @@ pub struct InputImage {
 }
 
 impl InputImage {
-    pub(crate) fn convert_to_new(self) -> Result<NewImage, String> {
+    pub(crate) fn convert_to_new(self) -> Result<NewImage, Error> {
         let path = std::path::PathBuf::from(&self.link);
         let ext = match path.extension() {
             Some(e) => e,
-            _ => return Err(format!("Link '{:?}' not a valid image.", path)),
+            _ => {
+                return Err(Error::validation(format!(
+                    "Link '{:?}' not a valid image.",
+                    path
+                )))
+            }
         }
         .to_string_lossy()
         .to_owned();
         // Open and read new image.
-        let mut new_image = std::fs::File::open(&path).map_err(ma)?;
+        let mut new_image = std::fs::File::open(&path)?;
         let mut bytes = Vec::new();
-        new_image.read_to_end(&mut bytes).map_err(ma)?;
+        new_image.read_to_end(&mut bytes)?;
 
         Ok(NewImage {
             of: self.of,
@@ -60,9 +65,12 @@ pub struct NewImage {
 
 impl NewImage {
     /// A convenience function.
-    pub(crate) fn insert_new(self, conn: &SqliteConnection) -> Result<usize, String> {
+    pub(crate) fn insert_new(self, conn: &SqliteConnection) -> Result<usize, Error> {
         use self::images::dsl::*;
-        replace_into(images).values(&self).execute(conn).map_err(ma)
+        replace_into(images)
+            .values(&self)
+            .execute(conn)
+            .map_err(Error::from)
     }
 }
 
@@ -83,15 +91,18 @@ impl Image {
     }
 
     /// Get the latest after insertion.
-    pub fn get_latest(conn: &SqliteConnection) -> Result<Self, String> {
+    pub fn get_latest(conn: &SqliteConnection) -> Result<Self, Error> {
         use self::images::dsl::*;
-        images.order_by(id.desc()).first(conn).map_err(ma)
+        images.order_by(id.desc()).first(conn).map_err(Error::from)
     }
 
     /// A convenience function.
-    pub(crate) fn update(&self, conn: &SqliteConnection) -> Result<usize, String> {
+    pub(crate) fn update(&self, conn: &SqliteConnection) -> Result<usize, Error> {
         use self::images::dsl::*;
-        replace_into(images).values(self).execute(conn).map_err(ma)
+        replace_into(images)
+            .values(self)
+            .execute(conn)
+            .map_err(Error::from)
     }
 }
 
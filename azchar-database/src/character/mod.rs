@@ -1,8 +1,11 @@
 #![allow(clippy::module_inception)]
 pub mod attribute;
 pub mod character;
+pub(crate) mod formula;
 pub mod image;
 pub mod note;
+pub mod roll_log;
+pub mod roll_macro;
 #[cfg(test)]
 pub(crate) mod tests;
 
@@ -3,7 +3,7 @@ use diesel::SqliteConnection;
 use diesel::*;
 use diesel::{ExpressionMethods, Insertable, QueryDsl, Queryable, RunQueryDsl};
 
-use azchar_error::ma;
+use azchar_error::Error;
 
 table! {
     notes(id) {
@@ -61,9 +61,12 @@ impl InputNote {
     }
 
     /// A convenience function.
-    pub(crate) fn insert_new(self, conn: &SqliteConnection) -> Result<usize, String> {
+    pub(crate) fn insert_new(self, conn: &SqliteConnection) -> Result<usize, Error> {
         use self::notes::dsl::*;
-        insert_into(notes).values(&self).execute(conn).map_err(ma)
+        insert_into(notes)
+            .values(&self)
+            .execute(conn)
+            .map_err(Error::from)
     }
 }
 
@@ -75,15 +78,18 @@ impl Note {
     }
 
     /// Get the latest after insertion.
-    pub fn get_latest(conn: &SqliteConnection) -> Result<Self, String> {
+    pub fn get_latest(conn: &SqliteConnection) -> Result<Self, Error> {
         use self::notes::dsl::*;
-        notes.order_by(id.desc()).first(conn).map_err(ma)
+        notes.order_by(id.desc()).first(conn).map_err(Error::from)
     }
 
     /// A convenience function.
-    pub(crate) fn update(&self, conn: &SqliteConnection) -> Result<usize, String> {
+    pub(crate) fn update(&self, conn: &SqliteConnection) -> Result<usize, Error> {
         use self::notes::dsl::*;
-        replace_into(notes).values(self).execute(conn).map_err(ma)
+        replace_into(notes)
+            .values(self)
+            .execute(conn)
+            .map_err(Error::from)
     }
 }
 
@@ -0,0 +1,141 @@
+//! This deals with the log of rolls.
+//! A sheet keeps the rolls made for its character, and the root database keeps
+//! the rest of the rolls made in the system.
+use diesel::SqliteConnection;
+use diesel::*;
+use diesel::{ExpressionMethods, Insertable, QueryDsl, Queryable, RunQueryDsl};
+
+use azchar_error::Error;
+
+table! {
+    rolls(id) {
+        id -> BigInt,
+        date -> Text,
+        label -> Nullable<Text>,
+        expression -> Text,
+        details -> Text,
+        total -> BigInt,
+    }
+}
+
+/// A roll to be logged.
+#[derive(Clone, Debug, Insertable, Deserialize, Serialize)]
+#[table_name = "rolls"]
+pub struct InputRoll {
+    pub label: Option<String>,
+    /// The dice as they were rolled, eg. "1d20+5".
+    pub expression: String,
+    /// The whole roll as JSON. Its shape is up to whoever rolled.
+    pub details: String,
+    pub total: i64,
+}
+
+/// A roll from the log.
+#[derive(Debug, Clone, PartialEq, Identifiable, Queryable, Deserialize, Serialize)]
+#[table_name = "rolls"]
+pub struct LoggedRoll {
+    pub id: i64,
+    /// When the roll was made, eg. "2021-03-04 18:15:00" (UTC).
+    pub date: String,
+    pub label: Option<String>,
+    pub expression: String,
+    pub details: String,
+    pub total: i64,
+}
+
+impl InputRoll {
+    /// Log the roll, and give it back with its id and date.
+    pub(crate) fn insert_new(self, conn: &SqliteConnection) -> Result<LoggedRoll, Error> {
+        use self::rolls::dsl::*;
+        insert_into(rolls).values(&self).execute(conn)?;
+        rolls.order_by(id.desc()).first(conn).map_err(Error::from)
+    }
+}
+
+impl LoggedRoll {
+    /// The latest rolls first, at most `limit` of them, made no earlier than `since`.
+    pub fn load(
+        conn: &SqliteConnection,
+        limit: Option<i64>,
+        since: Option<&str>,
+    ) -> Result<Vec<Self>, Error> {
+        use self::rolls::dsl::*;
+        let mut query = rolls.order_by(id.desc()).into_boxed();
+        if let Some(s) = since {
+            query = query.filter(date.ge(s.to_owned()));
+        }
+        if let Some(l) = limit {
+            query = query.limit(l);
+        }
+        query.load(conn).map_err(Error::from)
+    }
+}
+
+#[cfg(test)]
+mod roll_log_tests {
+    use super::*;
+    use crate::root_db::characters::character_tests::*;
+    use crate::root_db::tests;
+
+    fn input(total: i64) -> InputRoll {
+        InputRoll {
+            label: Some("Attack".to_owned()),
+            expression: "1d20+5".to_owned(),
+            details: "{}".to_owned(),
+            total,
+        }
+    }
+
+    #[test]
+    fn log_and_load_rolls() {
+        let mut test_setup = tests::setup(tests::TestSystem::DnD5);
+        let conn = create_char_with_name_and_connect(&mut test_setup, "Euridice");
+        let inner_conn = conn.connect().expect("Connect.");
+
+        let first = input(7).insert_new(inner_conn).expect("Could not log.");
+        assert_eq!(first.total, 7);
+        assert_eq!(first.label.as_deref(), Some("Attack"));
+        input(25).insert_new(inner_conn).expect("Could not log.");
+
+        let all = LoggedRoll::load(inner_conn, None, None).expect("Could not load.");
+        assert_eq!(all.iter().map(|r| r.total).collect::<Vec<_>>(), vec![25, 7]);
+        let latest = LoggedRoll::load(inner_conn, Some(1), None).expect("Could not load.");
+        assert_eq!(latest.len(), 1);
+        assert_eq!(latest[0].total, 25);
+        let future = LoggedRoll::load(inner_conn, None, Some("9999-01-01")).expect("Load.");
+        assert!(future.is_empty());
+        let past = LoggedRoll::load(inner_conn, None, Some(&first.date)).expect("Load.");
+        assert_eq!(past.len(), 2);
+    }
+
+    #[test]
+    fn an_older_sheet_is_brought_up_to_date_when_loaded() {
+        use crate::LoadedDbs;
+        use diesel::Connection;
+
+        let mut test_setup = tests::setup(tests::TestSystem::MemorySphere);
+        let key = create_char_with_name(&mut test_setup, "Euridice");
+        let sheet_path = test_setup.loaded_dbs.character_connections()[&key]
+            .path()
+            .to_owned();
+        let root_path = test_setup.loaded_dbs.root_path().to_owned();
+        let tests::TestSetup {
+            root_dir: _root_dir,
+            loaded_dbs,
+        } = test_setup;
+        drop(loaded_dbs);
+
+        // Sheets made before rolls were logged have no table for them.
+        let old = diesel::SqliteConnection::establish(&sheet_path).expect("Connect.");
+        old.execute("drop table rolls;").expect("Drop.");
+        old.execute("drop table roll_macros;").expect("Drop.");
+        old.execute("delete from __diesel_schema_migrations where version >= '0001';")
+            .expect("Forget.");
+        drop(old);
+
+        let mut dbs = LoadedDbs::custom(&root_path).expect("Load.");
+        dbs.log_roll(Some(key.to_owned()), input(12)).expect("Log.");
+        let log = dbs.get_roll_log(Some(key), None, None).expect("Load.");
+        assert_eq!(log[0].total, 12);
+    }
+}
@@ -0,0 +1,178 @@
+//! This deals with roll macros made up for a particular part of a character.
+//! The system can give a roll macro to every part of a kind (see `root_db::system`),
+//! and a macro with the same name on the part itself goes before it.
+use diesel::SqliteConnection;
+use diesel::*;
+use diesel::{BoolExpressionMethods, ExpressionMethods, QueryDsl, RunQueryDsl};
+
+use azchar_error::Error;
+
+table! {
+    roll_macros(id) {
+        id -> BigInt,
+        of -> BigInt,
+        name -> Text,
+        attack -> Nullable<Text>,
+        damage -> Nullable<Text>,
+    }
+}
+
+/// A named roll for a part: an attack, the damage it does, or both.
+/// The rolls are dice expressions, which may refer to attributes, eg. "1d20+@str_mod".
+#[derive(Clone, Debug, PartialEq, Insertable, Queryable, Deserialize, Serialize)]
+#[table_name = "roll_macros"]
+pub struct RollMacro {
+    /// The id of the part it is for.
+    pub of: i64,
+    pub name: String,
+    pub attack: Option<String>,
+    pub damage: Option<String>,
+}
+
+impl RollMacro {
+    /// Check that the macro rolls something.
+    pub(crate) fn check(&self) -> Result<(), Error> {
+        if self.name.trim().is_empty() {
+            return Err(Error::validation("A roll macro needs a name."));
+        }
+        if self.attack.is_none() && self.damage.is_none() {
+            let m = format!(
+                "Roll macro '{}' has neither an attack nor damage.",
+                self.name
+            );
+            return Err(Error::validation(m));
+        }
+        Ok(())
+    }
+
+    /// Store the macro, replacing the part's macro of the same name if there is one.
+    pub(crate) fn save(&self, conn: &SqliteConnection) -> Result<(), Error> {
+        use self::roll_macros::dsl::*;
+        self.check()?;
+        replace_into(roll_macros).values(self).execute(conn)?;
+        Ok(())
+    }
+
+    /// All the macros made up for a part.
+    pub(crate) fn load_for(conn: &SqliteConnection, part_id: i64) -> Result<Vec<Self>, Error> {
+        use self::roll_macros::dsl::*;
+        roll_macros
+            .filter(of.eq(part_id))
+            .select((of, name, attack, damage))
+            .order_by(name.asc())
+            .load(conn)
+            .map_err(Error::from)
+    }
+
+    /// All the macros made up for the parts of a sheet.
+    pub(crate) fn load_all(conn: &SqliteConnection) -> Result<Vec<Self>, Error> {
+        use self::roll_macros::dsl::*;
+        roll_macros
+            .select((of, name, attack, damage))
+            .order_by((of.asc(), name.asc()))
+            .load(conn)
+            .map_err(Error::from)
+    }
+
+    /// Delete a part's macro.
+    pub(crate) fn delete(
+        conn: &SqliteConnection,
+        part_id: i64,
+        macro_name: &str,
+    ) -> Result<(), Error> {
+        use self::roll_macros::dsl::*;
+        let deleted =
+            delete(roll_macros.filter(of.eq(part_id).and(name.eq(macro_name)))).execute(conn)?;
+        if deleted == 0 {
+            let key = format!("{} (part = {})", macro_name, part_id);
+            return Err(Error::not_found("Roll macro", key));
+        }
+        Ok(())
+    }
+
+    /// Delete all the macros of a part, which is itself being deleted.
+    pub(crate) fn delete_all_for(conn: &SqliteConnection, part_id: i64) -> Result<(), Error> {
+        use self::roll_macros::dsl::*;
+        delete(roll_macros.filter(of.eq(part_id))).execute(conn)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod roll_macro_tests {
+    use super::RollMacro;
+    use crate::character::character::InputCharacter;
+    use crate::root_db::characters::character_tests::*;
+    use crate::root_db::tests;
+    use crate::shared::Part;
+    use azchar_error::Error;
+
+    fn scimitar() -> InputCharacter {
+        InputCharacter {
+            name: "Scimitar".to_string(),
+            character_type: "weapon".to_string(),
+            speed: 0,
+            weight: Some(3),
+            size: None,
+            hp_total: None,
+            hp_current: None,
+            belongs_to: Some(1),
+            part_type: Part::InventoryItem,
+        }
+    }
+
+    #[test]
+    fn own_roll_macros_go_before_the_systems() {
+        let mut setup = tests::setup(tests::TestSystem::DnD5);
+        let key = create_char_with_name(&mut setup, "Euridice");
+        let dbs = &mut setup.loaded_dbs;
+        let c = dbs.create_part(scimitar(), key.to_owned()).expect("Part.");
+        let part_id = c.parts().iter().find(|p| p.name() == "Scimitar").unwrap();
+        let part_id = part_id.id().expect("Saved.");
+
+        // Every weapon gets a strike from the system.
+        let macros = dbs.get_roll_macros(key.to_owned(), part_id).expect("Load.");
+        assert_eq!(macros.len(), 1);
+        assert_eq!(
+            macros[0].attack.as_deref(),
+            Some("1d20+@weapon_attack_bonus")
+        );
+
+        let strike = RollMacro {
+            of: part_id,
+            name: "strike".to_owned(),
+            attack: Some("1d20+5".to_owned()),
+            damage: None,
+        };
+        let lunge = RollMacro {
+            name: "lunge".to_owned(),
+            damage: Some("2d6".to_owned()),
+            ..strike.clone()
+        };
+        dbs.set_roll_macro(key.to_owned(), strike.clone())
+            .expect("Set.");
+        let macros = dbs
+            .set_roll_macro(key.to_owned(), lunge.clone())
+            .expect("Set.");
+        assert_eq!(macros, vec![lunge.clone(), strike]);
+
+        // Without its own strike, the part has the system's again.
+        let macros = dbs
+            .delete_roll_macro(key.to_owned(), part_id, "strike")
+            .expect("Delete.");
+        assert_eq!(macros.len(), 2);
+        assert_eq!(macros[1].damage.as_deref(), Some("@weapon_damage1"));
+
+        let res = dbs.delete_roll_macro(key.to_owned(), part_id, "strike");
+        assert!(matches!(res, Err(Error::NotFound { .. })));
+        let empty = RollMacro {
+            attack: None,
+            damage: None,
+            ..lunge
+        };
+        let res = dbs.set_roll_macro(key.to_owned(), empty);
+        assert!(matches!(res, Err(Error::ValidationFailed { .. })));
+        let res = dbs.get_roll_macros(key, part_id + 100);
+        assert!(matches!(res, Err(Error::NotFound { .. })));
+    }
+}
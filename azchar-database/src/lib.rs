@@ -19,7 +19,8 @@ extern crate azchar_config;
 extern crate azchar_error;
 
 use azchar_config::Config;
-use azchar_error::ma;
+use azchar_error::Error;
+use diesel::connection::TransactionManager;
 use diesel::{Connection, SqliteConnection};
 
 pub mod character;
@@ -38,19 +39,39 @@ pub struct BasicConnection {
 }
 
 /// To do when a sheet is created.
-pub fn set_pragma(c: &SqliteConnection) -> Result<(), String> {
-    c.execute("pragma analysis_limit=1000;").map_err(ma)?;
-    c.execute("pragma foreign_keys=off;").map_err(ma)?;
-    c.execute("pragma journal_mode = OFF;").map_err(ma)?;
-    c.execute("pragma synchronous = off;").map_err(ma)?;
-    c.execute("pragma temp_store = memory;").map_err(ma)?;
-    c.execute("pragma wal_checkpoint(TRUNCATE);").map_err(ma)?;
-    c.execute("pragma locking_mode=EXCLUSIVE;").map_err(ma)?;
-    c.execute("pragma wal_autocheckpoint = 2000;").map_err(ma)?;
-    c.execute("pragma optimize;").map_err(ma)?;
+pub fn set_pragma(c: &SqliteConnection) -> Result<(), Error> {
+    c.execute("pragma analysis_limit=1000;")?;
+    c.execute("pragma foreign_keys=off;")?;
+    c.execute("pragma journal_mode = OFF;")?;
+    c.execute("pragma synchronous = off;")?;
+    c.execute("pragma temp_store = memory;")?;
+    c.execute("pragma wal_checkpoint(TRUNCATE);")?;
+    c.execute("pragma locking_mode=EXCLUSIVE;")?;
+    c.execute("pragma wal_autocheckpoint = 2000;")?;
+    c.execute("pragma optimize;")?;
     Ok(())
 }
 
+/// Run `f` in an immediate transaction on a sheet.
+/// Sheets run without a journal, which makes a rollback impossible,
+/// so the journal is kept in memory for the length of the transaction.
+/// If a transaction is already open, eg. for a batch, `f` runs in a savepoint of it instead,
+/// because SQLite can't begin one transaction inside another.
+pub(crate) fn sheet_transaction<T, F>(conn: &SqliteConnection, f: F) -> Result<T, Error>
+where
+    F: FnOnce() -> Result<T, Error>,
+{
+    let depth =
+        TransactionManager::<SqliteConnection>::get_transaction_depth(conn.transaction_manager());
+    if depth > 0 {
+        return conn.transaction(f);
+    }
+    conn.execute("pragma journal_mode = MEMORY;")?;
+    let res = conn.immediate_transaction(f);
+    conn.execute("pragma journal_mode = OFF;")?;
+    res
+}
+
 impl std::fmt::Debug for BasicConnection {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), core::fmt::Error> {
         f.debug_struct("BasicConnection")
@@ -73,18 +94,25 @@ impl BasicConnection {
     }
 
     /// Try to connect to an Sqlite Database.
-    pub fn connect(&mut self) -> Result<&SqliteConnection, String> {
+    pub fn connect(&mut self) -> Result<&SqliteConnection, Error> {
         if let Some(ref con) = self.connection {
             return Ok(con);
         }
 
-        let c = SqliteConnection::establish(&self.db_path).map_err(ma)?;
+        // Sqlite would make an empty database, which is no use to anyone.
+        if !std::path::Path::new(&self.db_path).exists() {
+            return Err(Error::not_found("Database file", &self.db_path));
+        }
+        let c = SqliteConnection::establish(&self.db_path)?;
         set_pragma(&c)?;
         self.connection = Some(c);
         Ok(self.connection.as_ref().expect("Is there."))
     }
 
     pub fn drop_connection(&mut self) {
+        if self.in_transaction() {
+            return;
+        }
         if let Err(e) = Self::tidy_up(&self.connection) {
             println!(
                 "Error {:?} when tidying database for {:?} on close.",
@@ -100,23 +128,38 @@ impl BasicConnection {
     }
 
     /// Drops the inner connection.
+    /// A connection with an open transaction is kept, or the transaction would be lost.
     pub fn drop_inner(&mut self) {
-        self.connection = None;
+        if !self.in_transaction() {
+            self.connection = None;
+        }
+    }
+
+    /// Whether a transaction is open on this connection.
+    pub fn in_transaction(&self) -> bool {
+        match self.connection {
+            Some(ref c) => {
+                TransactionManager::<SqliteConnection>::get_transaction_depth(
+                    c.transaction_manager(),
+                ) > 0
+            }
+            None => false,
+        }
     }
 
     /// Used to get all db_refs.
-    pub fn get_all_char_refs(&self) -> Result<Vec<CharacterDbRef>, String> {
+    pub fn get_all_char_refs(&self) -> Result<Vec<CharacterDbRef>, Error> {
         match self.connection {
             Some(ref conn) => CharacterDbRef::get_all(conn),
-            None => Err("Not connected!".to_string()),
+            None => Err(Error::database("Not connected!")),
         }
     }
 
     /// Do the thing where you tidy up before closing.
-    fn tidy_up(conn: &Option<SqliteConnection>) -> Result<(), String> {
+    fn tidy_up(conn: &Option<SqliteConnection>) -> Result<(), Error> {
         if let Some(c) = conn {
-            c.execute("pragma optimize;").map_err(ma)?;
-            c.execute("vacuum;").map_err(ma)?;
+            c.execute("pragma optimize;")?;
+            c.execute("vacuum;")?;
         }
         Ok(())
     }
@@ -0,0 +1,276 @@
+//! This deals with backing up a whole system, its root database and every sheet,
+//! as a single archive which can be restored elsewhere, eg. on another machine.
+//! The archive starts with `BACKUP_KIND` on a line of its own, then the length of the
+//! manifest (eight bytes, little endian), the manifest as JSON, and then the files
+//! one after another, in the order of the manifest.
+use super::characters::character_dbs::dsl as db_dsl;
+use super::{BasicConnection, CharacterDbRef, LoadedDbs};
+
+use azchar_error::Error;
+use diesel::{ExpressionMethods, QueryDsl, RunQueryDsl, SqliteConnection};
+
+use std::io::{Read, Write};
+use std::path::Path;
+
+/// What every backup says it is.
+pub const BACKUP_KIND: &str = "azchar-backup";
+/// The version of the format written here. Backups of this version or older can be restored.
+pub const BACKUP_VERSION: u32 = 1;
+
+/// A file in a backup.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BackupFile {
+    /// The name of the file, relative to the directory of the root database.
+    pub path: String,
+    pub size: u64,
+    /// The SHA-1 of the file, in hex.
+    pub sha1: String,
+}
+
+/// A character sheet in a backup.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BackupSheet {
+    pub name: String,
+    pub uuid: String,
+    pub file: BackupFile,
+}
+
+/// What is in a backup.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BackupManifest {
+    /// Always `BACKUP_KIND`.
+    pub kind: String,
+    pub version: u32,
+    /// The root database, in which the sheets are referred to by their relative paths.
+    pub root: BackupFile,
+    pub sheets: Vec<BackupSheet>,
+}
+
+impl BackupFile {
+    fn new(path: String, content: &[u8]) -> Self {
+        Self {
+            path,
+            size: content.len() as u64,
+            sha1: checksum(content),
+        }
+    }
+
+    /// Read the file from the archive and check that it is what the manifest says.
+    fn read_from(&self, archive: &mut impl Read) -> Result<Vec<u8>, Error> {
+        let name = Path::new(&self.path);
+        if name.file_name() != Some(name.as_os_str()) {
+            let m = format!("{:?} is not the name of a file in the backup.", self.path);
+            return Err(Error::validation(m));
+        }
+        let mut content = Vec::new();
+        archive.take(self.size).read_to_end(&mut content)?;
+        if content.len() as u64 != self.size || checksum(&content) != self.sha1 {
+            let m = format!("{} in the backup is damaged.", self.path);
+            return Err(Error::validation(m));
+        }
+        Ok(content)
+    }
+}
+
+impl BackupManifest {
+    /// Read the manifest at the start of an archive.
+    pub fn read(archive: &mut impl Read) -> Result<Self, Error> {
+        let mut kind = vec![0; BACKUP_KIND.len() + 1];
+        archive.read_exact(&mut kind)?;
+        if kind != format!("{}\n", BACKUP_KIND).as_bytes() {
+            return Err(Error::validation("This is not a backup."));
+        }
+        let mut len = [0; 8];
+        archive.read_exact(&mut len)?;
+        let mut manifest = Vec::new();
+        archive
+            .take(u64::from_le_bytes(len))
+            .read_to_end(&mut manifest)?;
+        let manifest: Self = serde_json::from_slice(&manifest)?;
+        if manifest.kind != BACKUP_KIND {
+            return Err(Error::validation("This is not a backup."));
+        }
+        if manifest.version > BACKUP_VERSION {
+            let m = format!(
+                "Backup version {} is newer than this one ({}).",
+                manifest.version, BACKUP_VERSION
+            );
+            return Err(Error::validation(m));
+        }
+        Ok(manifest)
+    }
+
+    /// The name of the system which was backed up.
+    pub fn system_name(&self) -> String {
+        Path::new(&self.root.path)
+            .file_stem()
+            .map(|s| s.to_string_lossy().to_string())
+            .unwrap_or_default()
+    }
+}
+
+fn checksum(content: &[u8]) -> String {
+    sha1_smol::Sha1::from(content).digest().to_string()
+}
+
+/// The name of a file, which is where it goes in a backup.
+fn file_name(path: &str) -> Result<String, Error> {
+    Path::new(path)
+        .file_name()
+        .map(|n| n.to_string_lossy().to_string())
+        .ok_or_else(|| Error::validation(format!("{:?} is not a file.", path)))
+}
+
+/// Refer to the sheets of a root database by their file names alone.
+fn set_sheet_paths(
+    conn: &SqliteConnection,
+    sheets: &[(CharacterDbRef, String)],
+) -> Result<(), Error> {
+    for (sheet, file) in sheets.iter() {
+        let row = db_dsl::character_dbs
+            .filter(db_dsl::name.eq(&sheet.name))
+            .filter(db_dsl::uuid.eq(&sheet.uuid));
+        diesel::update(row)
+            .set(db_dsl::db_path.eq(file))
+            .execute(conn)?;
+    }
+    Ok(())
+}
+
+impl LoadedDbs {
+    /// Write the root database and every sheet to a single archive at `path`.
+    /// The sheets are referred to by relative paths in the archived root database,
+    /// so that they can be restored anywhere. Gives the manifest of the archive.
+    pub fn backup(&mut self, path: &Path) -> Result<BackupManifest, Error> {
+        let sheets = CharacterDbRef::get_all(self.get_inner_root()?)?
+            .into_iter()
+            .map(|r| file_name(&r.db_path).map(|f| (r, f)))
+            .collect::<Result<Vec<_>, Error>>()?;
+
+        // The root database is changed in a copy, so the system itself is left alone.
+        let copy_dir = tempfile::tempdir()?;
+        let root_name = file_name(&self.root_path)?;
+        let copy_path = copy_dir.path().join(&root_name);
+        std::fs::copy(&self.root_path, &copy_path)?;
+        let mut copy = BasicConnection::new(&copy_path.to_string_lossy());
+        set_sheet_paths(copy.connect()?, &sheets)?;
+        copy.drop_connection();
+        let root = std::fs::read(&copy_path)?;
+
+        let mut contents = vec![root];
+        let mut manifest = BackupManifest {
+            kind: BACKUP_KIND.to_owned(),
+            version: BACKUP_VERSION,
+            root: BackupFile::new(root_name, &contents[0]),
+            sheets: Vec::with_capacity(sheets.len()),
+        };
+        for (sheet, file) in sheets.into_iter() {
+            let sheet_path = self.sheet_path(&sheet.db_path);
+            let content = std::fs::read(&sheet_path).map_err(|e| match e.kind() {
+                std::io::ErrorKind::NotFound => Error::not_found("Sheet file", &sheet_path),
+                _ => Error::from(e),
+            })?;
+            manifest.sheets.push(BackupSheet {
+                name: sheet.name,
+                uuid: sheet.uuid,
+                file: BackupFile::new(file, &content),
+            });
+            contents.push(content);
+        }
+
+        let json = serde_json::to_vec(&manifest)?;
+        let mut archive = std::io::BufWriter::new(std::fs::File::create(path)?);
+        archive.write_all(format!("{}\n", BACKUP_KIND).as_bytes())?;
+        archive.write_all(&(json.len() as u64).to_le_bytes())?;
+        archive.write_all(&json)?;
+        for content in contents.iter() {
+            archive.write_all(content)?;
+        }
+        archive.flush()?;
+        Ok(manifest)
+    }
+
+    /// Unpack a backup into `dir`, which is created if need be, and load the system.
+    /// Nothing is written unless every file in the backup is whole,
+    /// and no file in `dir` is replaced.
+    pub fn restore(archive: &Path, dir: &Path) -> Result<Self, Error> {
+        let mut archive = std::io::BufReader::new(std::fs::File::open(archive)?);
+        let manifest = BackupManifest::read(&mut archive)?;
+        let files = std::iter::once(&manifest.root).chain(manifest.sheets.iter().map(|s| &s.file));
+        let mut contents = Vec::with_capacity(manifest.sheets.len() + 1);
+        for file in files {
+            contents.push((file.path.to_owned(), file.read_from(&mut archive)?));
+        }
+
+        std::fs::create_dir_all(dir)?;
+        let dir = dir.canonicalize()?;
+        if let Some((path, _)) = contents.iter().find(|(p, _)| dir.join(p).exists()) {
+            let m = format!("{:?} already exists as a file!", dir.join(path));
+            return Err(Error::conflict(m));
+        }
+        for (path, content) in contents.iter() {
+            std::fs::write(dir.join(path), content)?;
+        }
+
+        // The sheets are next to the root database, which refers to them by name.
+        Self::custom(&dir.join(&manifest.root.path).to_string_lossy())
+    }
+}
+
+#[cfg(test)]
+mod backup_tests {
+    use super::*;
+    use crate::root_db::characters::character_tests::*;
+    use crate::root_db::tests;
+
+    #[test]
+    fn backup_and_restore_elsewhere() {
+        let mut setup = tests::setup(tests::TestSystem::DnD5);
+        let euridice = create_char_with_name(&mut setup, "Euridice");
+        let saloth = create_char_with_name(&mut setup, "Saloth");
+        let archive = setup.root_dir.path().join("campaign.azbak");
+        let manifest = setup.loaded_dbs.backup(&archive).expect("Backup.");
+        assert_eq!(manifest.root.path, "Memory Sphere.db");
+        assert_eq!(manifest.system_name(), "Memory Sphere");
+        assert_eq!(manifest.sheets.len(), 2);
+        assert!(manifest.sheets.iter().all(|s| !s.file.path.contains('/')));
+
+        let elsewhere = tempfile::tempdir().unwrap();
+        let target = elsewhere.path().join("restored");
+        let mut restored = LoadedDbs::restore(&archive, &target).expect("Restore.");
+        assert!(restored.missing_sheets().is_empty());
+        let target = target.canonicalize().unwrap();
+        for c in restored.character_connections().values() {
+            assert!(Path::new(c.path()).starts_with(&target));
+        }
+        for key in [euridice, saloth] {
+            let original = setup.loaded_dbs.load_character(key.to_owned()).unwrap();
+            let copy = restored.load_character(key).expect("Load.");
+            assert_eq!(original, copy);
+        }
+
+        // Restoring over the restored system is refused.
+        let res = LoadedDbs::restore(&archive, &target);
+        assert!(matches!(res, Err(Error::Conflict { .. })));
+    }
+
+    #[test]
+    fn damaged_backups_are_not_restored() {
+        let mut setup = tests::setup(tests::TestSystem::MemorySphere);
+        create_char_with_name(&mut setup, "Euridice");
+        let archive = setup.root_dir.path().join("campaign.azbak");
+        setup.loaded_dbs.backup(&archive).expect("Backup.");
+        let mut bytes = std::fs::read(&archive).unwrap();
+        let last = bytes.len() - 1;
+        bytes[last] = bytes[last].wrapping_add(1);
+        std::fs::write(&archive, bytes).unwrap();
+
+        let target = tempfile::tempdir().unwrap();
+        let res = LoadedDbs::restore(&archive, target.path());
+        assert!(matches!(res, Err(Error::ValidationFailed { .. })));
+        assert_eq!(std::fs::read_dir(target.path()).unwrap().count(), 0);
+
+        let res = LoadedDbs::restore(Path::new("../examples/dnd5e0.toml"), target.path());
+        assert!(matches!(res, Err(Error::ValidationFailed { .. })));
+    }
+}
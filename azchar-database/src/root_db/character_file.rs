@@ -0,0 +1,671 @@
+//! This deals with a character as a file of its own, so that it can be kept or moved
+//! to another system. The file holds everything on the sheet, images, notes and roll
+//! macros included, and says what it is and which version of the format it is in.
+//! The ids in the file are those of the sheet it came from. They only link the parts,
+//! attributes and macros together, and every part gets a new id when it is imported.
+use super::characters::character_dbs::dsl as db_dsl;
+use super::system::{PermittedAttribute, PermittedPart};
+use super::{CharacterDbRef, LoadedDbs};
+use crate::character::attribute::attributes::dsl as at_dsl;
+use crate::character::attribute::{AttributeKey, AttributeValue, NewAttribute};
+use crate::character::character::characters::dsl as ch_dsl;
+use crate::character::character::{Character, CharacterPart, CompleteCharacter, NewCharacter};
+use crate::character::image::{Image, NewImage};
+use crate::character::note::notes::dsl as note_dsl;
+use crate::character::roll_macro::RollMacro;
+use crate::shared::{AttributeType, Part};
+use crate::BasicConnection;
+
+use azchar_error::Error;
+use diesel::{ExpressionMethods, QueryDsl, RunQueryDsl, SqliteConnection};
+use fnv::{FnvHashMap, FnvHashSet};
+use uuid_rs::v4;
+
+use std::path::Path;
+
+/// What every character file says it is.
+pub const CHARACTER_FILE_KIND: &str = "azchar-character";
+/// The version of the format written here. Files of this version or older can be imported.
+pub const CHARACTER_FILE_VERSION: u32 = 1;
+
+/// How a character file is written.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum CharacterFileFormat {
+    Json,
+    Toml,
+}
+
+/// What to do when an imported character has the uuid of a character in the system.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum UuidClashPolicy {
+    /// Keep the uuid anyway. This fails if the name is the same too,
+    /// since the two would need the same sheet.
+    Keep,
+    /// Give the imported character a new uuid.
+    Regenerate,
+    /// Delete the character in the system and put the imported one in its place.
+    Overwrite,
+}
+
+/// A character, with everything needed to put it in another system.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CharacterFile {
+    /// Always `CHARACTER_FILE_KIND`.
+    pub kind: String,
+    pub version: u32,
+    /// The root database it was exported from, for reference.
+    pub system: String,
+    /// The character as it is stored, without derived attributes.
+    pub character: CompleteCharacter,
+    #[serde(default)]
+    pub roll_macros: Vec<RollMacro>,
+}
+
+impl CharacterFile {
+    /// Read a character file, which may be JSON or TOML.
+    pub fn read(path: &Path) -> Result<Self, Error> {
+        let text = std::fs::read_to_string(path)?;
+        let file: Self = if text.trim_start().starts_with('{') {
+            serde_json::from_str(&text)?
+        } else {
+            tables_to_pairs(toml::from_str(&text)?).try_into()?
+        };
+        if file.kind != CHARACTER_FILE_KIND {
+            let m = format!("{:?} is not a character file.", path);
+            return Err(Error::validation(m));
+        }
+        if file.version > CHARACTER_FILE_VERSION {
+            let m = format!(
+                "Character file version {} is newer than this one ({}).",
+                file.version, CHARACTER_FILE_VERSION
+            );
+            return Err(Error::validation(m));
+        }
+        Ok(file)
+    }
+
+    /// Write the file, replacing whatever is at `path`.
+    pub fn write(&self, path: &Path, format: CharacterFileFormat) -> Result<(), Error> {
+        let text = match format {
+            CharacterFileFormat::Json => serde_json::to_string_pretty(self)?,
+            // TOML needs the tables after the plain values, which a `Value` sorts out.
+            CharacterFileFormat::Toml => {
+                toml::to_string(&pairs_to_tables(toml::Value::try_from(self)?))?
+            }
+        };
+        std::fs::write(path, text)?;
+        Ok(())
+    }
+
+    /// Check that the character fits the system, and give its parts in the order
+    /// in which they can be inserted: each after the part it belongs to.
+    fn check<'a>(
+        &'a self,
+        permitted_parts: &[PermittedPart],
+        permitted_attrs: &[PermittedAttribute],
+    ) -> Result<Vec<&'a CharacterPart>, Error> {
+        let character = &self.character;
+        let is_permitted = |name: &str, part_type: Part| {
+            permitted_parts
+                .iter()
+                .any(|p| p.part_name == name && p.part_type == part_type)
+        };
+        let main = (Part::Main, character.character_type.as_str());
+        let parts = character
+            .parts()
+            .iter()
+            .map(|p| (p.part_type(), p.character_type()));
+        for (part_type, name) in std::iter::once(main).chain(parts.clone()) {
+            if !is_permitted(name, part_type) {
+                let what = format!("Part '{}' ({:?})", name, part_type);
+                return Err(Error::not_permitted(what, "it is not in this system"));
+            }
+        }
+        for p in permitted_parts.iter().filter(|p| p.obligatory) {
+            if !std::iter::once(main)
+                .chain(parts.clone())
+                .any(|(t, n)| (t, n) == (p.part_type, p.part_name.as_str()))
+            {
+                let m = format!("Obligatory part '{}' is missing.", p.part_name);
+                return Err(Error::validation(m));
+            }
+        }
+
+        let main_attrs = (main, character.attributes());
+        let part_attrs = character
+            .parts()
+            .iter()
+            .map(|p| ((p.part_type(), p.character_type()), p.attributes.as_slice()));
+        for ((part_type, name), attrs) in std::iter::once(main_attrs).chain(part_attrs) {
+            for (k, v) in attrs.iter() {
+                match PermittedAttribute::find_for_part(permitted_attrs, k.key(), part_type, name) {
+                    None => {
+                        let what = format!("Attribute '{}' of '{}'", k.key(), name);
+                        return Err(Error::not_permitted(what, "it is not in this system"));
+                    }
+                    Some(perm) if perm.formula.is_none() => {
+                        perm.check_value(v.value_num(), v.value_text())?;
+                    }
+                    Some(_) => {}
+                }
+            }
+        }
+
+        // Parts which belong to no part belong to the main part.
+        let mut placed = character.id().into_iter().collect::<FnvHashSet<_>>();
+        let mut left = character.parts().iter().collect::<Vec<_>>();
+        let mut ordered = Vec::with_capacity(left.len());
+        while !left.is_empty() {
+            let (ready, rest): (Vec<_>, Vec<_>) = left
+                .into_iter()
+                .partition(|p| p.belongs_to.map(|b| placed.contains(&b)).unwrap_or(true));
+            if ready.is_empty() {
+                let m = format!(
+                    "Part '{}' belongs to a part which is not in the file.",
+                    rest[0].name()
+                );
+                return Err(Error::validation(m));
+            }
+            placed.extend(ready.iter().filter_map(|p| p.id()));
+            ordered.extend(ready);
+            left = rest;
+        }
+        if let Some(m) = self.roll_macros.iter().find(|m| !placed.contains(&m.of)) {
+            let m = format!(
+                "Roll macro '{}' is for a part which is not in the file.",
+                m.name
+            );
+            return Err(Error::validation(m));
+        }
+        Ok(ordered)
+    }
+
+    /// Put the character on an empty sheet, with `uuid` for its own.
+    /// Attributes the system requires and the file does not have are added.
+    fn insert(
+        &self,
+        conn: &SqliteConnection,
+        uuid: &str,
+        parts: &[&CharacterPart],
+        permitted_attrs: &[PermittedAttribute],
+    ) -> Result<(), Error> {
+        let character = &self.character;
+        let mut main = NewCharacter::from_complete(character);
+        main.uuid = uuid.to_owned();
+        diesel::insert_into(ch_dsl::characters)
+            .values(&main)
+            .execute(conn)?;
+        let main_id = Character::get_latest_id(conn)?;
+        // The ids in the file, and the ids they have now.
+        let mut ids = FnvHashMap::default();
+        ids.extend(character.id().map(|id| (id, main_id)));
+
+        let mut inserted = vec![(
+            main_id,
+            (Part::Main, character.character_type.as_str()),
+            character.attributes(),
+            character.image(),
+        )];
+        for p in parts.iter() {
+            let mut new = NewCharacter::from_part(p);
+            new.belongs_to = Some(p.belongs_to.map(|b| ids[&b]).unwrap_or(main_id));
+            diesel::insert_into(ch_dsl::characters)
+                .values(&new)
+                .execute(conn)?;
+            let id = Character::get_latest_id(conn)?;
+            ids.extend(p.id().map(|old| (old, id)));
+            let part = (p.part_type(), p.character_type());
+            inserted.push((id, part, p.attributes.as_slice(), &p.image));
+        }
+
+        let mut new_attributes = Vec::new();
+        for (id, (part_type, name), attrs, image) in inserted {
+            new_attributes.extend(
+                attrs
+                    .iter()
+                    .filter_map(|(k, v)| stored_attribute(permitted_attrs, (part_type, name), k, v))
+                    .map(|(k, v, value_type)| NewAttribute {
+                        key: k.key().to_owned(),
+                        value_num: match value_type {
+                            AttributeType::PartReference => {
+                                v.value_num().and_then(|n| ids.get(&n).copied())
+                            }
+                            _ => v.value_num(),
+                        },
+                        value_text: v.value_text().to_owned(),
+                        description: v.description.to_owned(),
+                        of: id,
+                    }),
+            );
+            let missing = permitted_attrs
+                .iter()
+                .filter(|a| a.obligatory_for_part(part_type, name))
+                .filter(|a| !attrs.iter().any(|(k, _)| k.key() == a.key))
+                .map(|a| NewAttribute::from_permitted(id, a));
+            new_attributes.extend(missing);
+            if let Some(Image {
+                format, content, ..
+            }) = image
+            {
+                NewImage {
+                    of: id,
+                    format: format.to_owned(),
+                    content: content.to_owned(),
+                }
+                .insert_new(conn)?;
+            }
+        }
+        for chunk in new_attributes.chunks(999) {
+            diesel::insert_into(at_dsl::attributes)
+                .values(chunk)
+                .execute(conn)?;
+        }
+        for chunk in character.notes().chunks(999) {
+            diesel::insert_into(note_dsl::notes)
+                .values(chunk)
+                .execute(conn)?;
+        }
+        for m in self.roll_macros.iter() {
+            let of = ids[&m.of];
+            RollMacro { of, ..m.clone() }.save(conn)?;
+        }
+        Ok(())
+    }
+}
+
+/// TOML can't have a table in an array in an array, so in a TOML file
+/// each attribute is a table of its `key` and `value` rather than a pair.
+fn pairs_to_tables(value: toml::Value) -> toml::Value {
+    use toml::Value;
+    match value {
+        Value::Array(a) => Value::Array(
+            a.into_iter()
+                .map(|v| match v {
+                    Value::Array(pair) if pair.len() == 2 && pair.iter().all(Value::is_table) => {
+                        let mut pair = pair.into_iter();
+                        let mut t = toml::value::Table::new();
+                        t.insert("key".to_owned(), pair.next().expect("Two."));
+                        t.insert("value".to_owned(), pair.next().expect("Two."));
+                        Value::Table(t)
+                    }
+                    v => pairs_to_tables(v),
+                })
+                .collect(),
+        ),
+        Value::Table(t) => Value::Table(
+            t.into_iter()
+                .map(|(k, v)| (k, pairs_to_tables(v)))
+                .collect(),
+        ),
+        v => v,
+    }
+}
+
+/// The reverse of `pairs_to_tables`.
+fn tables_to_pairs(value: toml::Value) -> toml::Value {
+    use toml::Value;
+    let is_pair = |t: &toml::value::Table| {
+        t.len() == 2
+            && t.get("key").map(Value::is_table).unwrap_or(false)
+            && t.get("value").map(Value::is_table).unwrap_or(false)
+    };
+    match value {
+        Value::Array(a) => Value::Array(
+            a.into_iter()
+                .map(|v| match v {
+                    Value::Table(mut t) if is_pair(&t) => {
+                        let key = t.remove("key").expect("Checked.");
+                        let value = t.remove("value").expect("Checked.");
+                        Value::Array(vec![key, value])
+                    }
+                    v => tables_to_pairs(v),
+                })
+                .collect(),
+        ),
+        Value::Table(t) => Value::Table(
+            t.into_iter()
+                .map(|(k, v)| (k, tables_to_pairs(v)))
+                .collect(),
+        ),
+        v => v,
+    }
+}
+
+/// An attribute with its type, unless it is derived, in which case it isn't stored.
+fn stored_attribute<'a>(
+    permitted_attrs: &[PermittedAttribute],
+    (part_type, name): (Part, &str),
+    k: &'a AttributeKey,
+    v: &'a AttributeValue,
+) -> Option<(&'a AttributeKey, &'a AttributeValue, AttributeType)> {
+    PermittedAttribute::find_for_part(permitted_attrs, k.key(), part_type, name)
+        .filter(|a| a.formula.is_none())
+        .map(|a| (k, v, a.value_type()))
+}
+
+/// The name and uuid of an imported character go into the name of its sheet,
+/// so the name can't lead out of the system's directory, and the uuid has to be one.
+fn check_sheet_name(name: &str, uuid: &str) -> Result<(), Error> {
+    if name.trim().is_empty() || name.contains(['/', '\\']) || name.contains("..") {
+        let m = format!("'{}' can't be the name of a character's sheet.", name);
+        return Err(Error::validation(m));
+    }
+    let groups = uuid.split('-').map(str::len).collect::<Vec<_>>();
+    if groups != [8, 4, 4, 4, 12] || !uuid.chars().all(|c| c == '-' || c.is_ascii_hexdigit()) {
+        let m = format!("The uuid of {} ({}) is not a uuid.", name, uuid);
+        return Err(Error::validation(m));
+    }
+    Ok(())
+}
+
+impl LoadedDbs {
+    /// Write a character to a file of its own.
+    pub fn export_character(
+        &mut self,
+        key: (String, String),
+        path: &Path,
+        format: CharacterFileFormat,
+    ) -> Result<(), Error> {
+        let system = Path::new(&self.root_path)
+            .file_stem()
+            .map(|s| s.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let conn = self.connection_for(Some(key))?;
+        let file = CharacterFile {
+            kind: CHARACTER_FILE_KIND.to_owned(),
+            version: CHARACTER_FILE_VERSION,
+            system,
+            character: CompleteCharacter::load(conn)?,
+            roll_macros: RollMacro::load_all(conn)?,
+        };
+        file.write(path, format)
+    }
+
+    /// Put the character in a character file on a new sheet in this system.
+    /// Returns the character name and uuid, which is new if it had to be regenerated.
+    /// A character which is overwritten is only removed once the new sheet is filled in.
+    pub fn import_character(
+        &mut self,
+        path: &Path,
+        policy: UuidClashPolicy,
+    ) -> Result<(String, String), Error> {
+        let file = CharacterFile::read(path)?;
+        // Nothing is changed until the whole file is known to fit.
+        let parts = file.check(&self.permitted_parts, &self.permitted_attrs)?;
+        let name = file.character.name().to_owned();
+        let mut uuid = file.character.uuid().to_owned();
+        check_sheet_name(&name, &uuid)?;
+        let clash = self.connections.keys().find(|(_, u)| u == &uuid).cloned();
+        let mut replaced = None;
+        match (clash, policy) {
+            (None, _) => {}
+            (Some(old), UuidClashPolicy::Keep) if old.0 == name => {
+                let m = format!("{} (uuid = {}) is already in the system.", name, uuid);
+                return Err(Error::conflict(m));
+            }
+            (Some(_), UuidClashPolicy::Keep) => {}
+            (Some(_), UuidClashPolicy::Regenerate) => uuid = v4!(),
+            (Some(old), UuidClashPolicy::Overwrite) => {
+                let db_path = CharacterDbRef::get_all(self.get_inner_root()?)?
+                    .into_iter()
+                    .find(|r| (r.name(), r.uuid()) == (old.0.as_str(), old.1.as_str()))
+                    .map(|r| r.db_path().to_owned())
+                    .ok_or_else(|| super::character_not_found(&old))?;
+                replaced = Some((old, db_path));
+            }
+        }
+
+        // The character being overwritten keeps its sheet until the new one is filled in,
+        // so the new sheet can't have the same file name.
+        let file_name = match replaced {
+            Some(_) => format!("{}_{}_{}.db", name, uuid, v4!()),
+            None => format!("{}_{}.db", name, uuid),
+        };
+        let mut sheet = self.new_sheet_file(&name, &uuid, &file_name)?;
+        let res = sheet.connect().and_then(|conn| {
+            crate::sheet_transaction(conn, || {
+                file.insert(conn, &uuid, &parts, &self.permitted_attrs)
+            })
+        });
+        if let Err(e) = res {
+            // Leave no empty sheet behind.
+            self.remove_sheet(&file_name, sheet)?;
+            return Err(e);
+        }
+        let key = (name, uuid);
+        if let Some((old, db_path)) = replaced {
+            let old_sheet = self
+                .connections
+                .remove(&old)
+                .expect("The clash is in the map.");
+            self.remove_sheet(&db_path, old_sheet)?;
+        }
+        self.connections.insert(key.to_owned(), sheet);
+        Ok(key)
+    }
+
+    /// Remove a sheet file and the root database's reference to it.
+    /// The sheet is found by its path in the root database, since a character
+    /// and the one overwriting it can have the same name and uuid.
+    fn remove_sheet(&mut self, db_path: &str, sheet: BasicConnection) -> Result<(), Error> {
+        diesel::delete(db_dsl::character_dbs.filter(db_dsl::db_path.eq(db_path)))
+            .execute(self.get_inner_root()?)?;
+        let path = sheet.path().to_owned();
+        drop(sheet);
+        std::fs::remove_file(path).map_err(Error::from)
+    }
+}
+
+#[cfg(test)]
+mod character_file_tests {
+    use super::*;
+    use crate::character::character::InputCharacter;
+    use crate::character::image::InputImage;
+    use crate::character::note::InputNote;
+    use crate::root_db::characters::character_tests::*;
+    use crate::root_db::tests;
+
+    fn part(name: &str, belongs_to: i64) -> InputCharacter {
+        InputCharacter {
+            name: name.to_string(),
+            character_type: "weapon".to_string(),
+            speed: 0,
+            weight: Some(3),
+            size: None,
+            hp_total: None,
+            hp_current: None,
+            belongs_to: Some(belongs_to),
+            part_type: Part::InventoryItem,
+        }
+    }
+
+    /// Euridice with a sword, a gem set in the sword, a picture of the sword, a note and a macro.
+    fn euridice(setup: &mut tests::TestSetup) -> (String, String) {
+        let key = create_char_with_name(setup, "Euridice");
+        let dbs = &mut setup.loaded_dbs;
+        let c = dbs.create_part(part("Sword", 1), key.to_owned()).unwrap();
+        let sword = c.parts().iter().find(|p| p.name() == "Sword").unwrap();
+        let sword = sword.id().unwrap();
+        dbs.create_part(part("Gem", sword), key.to_owned()).unwrap();
+        let image = InputImage {
+            of: sword,
+            link: "../examples/c-euri-2021b.png".to_string(),
+        };
+        dbs.create_update_image(key.0.to_owned(), key.1.to_owned(), image)
+            .unwrap();
+        let note = InputNote::new_note("Diary".to_string(), Some("Found a gem.".to_string()));
+        dbs.add_note(key.0.to_owned(), key.1.to_owned(), note)
+            .unwrap();
+        let lunge = RollMacro {
+            of: sword,
+            name: "lunge".to_owned(),
+            attack: Some("1d20+2".to_owned()),
+            damage: None,
+        };
+        dbs.set_roll_macro(key.to_owned(), lunge).unwrap();
+        key
+    }
+
+    /// Check that `imported` is `original` under other ids.
+    fn assert_same(dbs: &mut LoadedDbs, original: &(String, String), imported: &(String, String)) {
+        let a = dbs.load_character(original.to_owned()).unwrap();
+        let b = dbs.load_character(imported.to_owned()).unwrap();
+        assert_eq!(a.name(), b.name());
+        assert_eq!(a.attributes().len(), b.attributes().len());
+        assert_eq!(a.notes()[0].title, b.notes()[0].title);
+        assert_eq!(a.notes()[0].date, b.notes()[0].date);
+        let find = |c: &CompleteCharacter, name: &str| {
+            c.parts()
+                .iter()
+                .find(|p| p.name() == name)
+                .cloned()
+                .unwrap()
+        };
+        let (sword, gem) = (find(&b, "Sword"), find(&b, "Gem"));
+        assert_eq!(sword.belongs_to, b.id());
+        assert_eq!(gem.belongs_to, sword.id());
+        let content = |p: &CharacterPart| p.image.as_ref().map(|i| i.content.to_owned());
+        assert_eq!(content(&sword), content(&find(&a, "Sword")));
+        assert!(content(&sword).is_some());
+        let macros = dbs
+            .get_roll_macros(imported.to_owned(), sword.id().unwrap())
+            .unwrap();
+        assert!(macros.iter().any(|m| m.name == "lunge"));
+    }
+
+    #[test]
+    fn export_and_import_in_both_formats() {
+        let mut setup = tests::setup(tests::TestSystem::DnD5);
+        let key = euridice(&mut setup);
+        let dir = setup.root_dir.path().to_owned();
+        let dbs = &mut setup.loaded_dbs;
+        for (file, format) in [
+            ("euridice.json", CharacterFileFormat::Json),
+            ("euridice.toml", CharacterFileFormat::Toml),
+        ] {
+            let path = dir.join(file);
+            dbs.export_character(key.to_owned(), &path, format)
+                .expect("Export.");
+            let res = dbs.import_character(&path, UuidClashPolicy::Keep);
+            assert!(matches!(res, Err(Error::Conflict { .. })), "{:?}", res);
+            let imported = dbs
+                .import_character(&path, UuidClashPolicy::Regenerate)
+                .expect("Import.");
+            assert_ne!(imported.1, key.1);
+            assert_same(dbs, &key, &imported);
+        }
+        assert_eq!(dbs.list_characters().unwrap().len(), 3);
+
+        // Overwriting replaces the character, and keeps its uuid.
+        let path = dir.join("euridice.json");
+        let imported = dbs
+            .import_character(&path, UuidClashPolicy::Overwrite)
+            .expect("Import.");
+        assert_eq!(imported, key);
+        assert_eq!(dbs.list_characters().unwrap().len(), 3);
+        let copy = dbs
+            .list_characters()
+            .unwrap()
+            .into_iter()
+            .find(|c| c.uuid() != key.1)
+            .map(|c| (c.name().to_owned(), c.uuid().to_owned()));
+        assert_same(dbs, &copy.unwrap(), &imported);
+    }
+
+    #[test]
+    fn import_checks_the_file() {
+        let mut setup = tests::setup(tests::TestSystem::DnD5);
+        let key = euridice(&mut setup);
+        let path = setup.root_dir.path().join("euridice.json");
+        let dbs = &mut setup.loaded_dbs;
+        dbs.export_character(key.to_owned(), &path, CharacterFileFormat::Json)
+            .expect("Export.");
+        let file = CharacterFile::read(&path).expect("Read.");
+
+        let mut newer = file.clone();
+        newer.version = CHARACTER_FILE_VERSION + 1;
+        newer.write(&path, CharacterFileFormat::Json).unwrap();
+        let res = dbs.import_character(&path, UuidClashPolicy::Regenerate);
+        assert!(
+            matches!(res, Err(Error::ValidationFailed { .. })),
+            "{:?}",
+            res
+        );
+
+        let mut lost = file.clone();
+        lost.roll_macros[0].of = 1000;
+        lost.write(&path, CharacterFileFormat::Json).unwrap();
+        let res = dbs.import_character(&path, UuidClashPolicy::Regenerate);
+        assert!(
+            matches!(res, Err(Error::ValidationFailed { .. })),
+            "{:?}",
+            res
+        );
+
+        let mut alien = file.clone();
+        alien.character.parts[0].character_type = "spaceship".to_owned();
+        alien.write(&path, CharacterFileFormat::Json).unwrap();
+        let res = dbs.import_character(&path, UuidClashPolicy::Regenerate);
+        assert!(matches!(res, Err(Error::NotPermitted { .. })), "{:?}", res);
+
+        // The name and uuid go into the sheet's file name.
+        for (name, uuid) in [("../../Euridice", key.1.as_str()), ("Euridice", "../x")] {
+            let mut escaped = serde_json::to_value(&file).unwrap();
+            escaped["character"]["name"] = name.into();
+            escaped["character"]["uuid"] = uuid.into();
+            std::fs::write(&path, escaped.to_string()).unwrap();
+            let res = dbs.import_character(&path, UuidClashPolicy::Keep);
+            assert!(
+                matches!(res, Err(Error::ValidationFailed { .. })),
+                "{:?}",
+                res
+            );
+        }
+        // Nothing was left behind by the failures.
+        assert_eq!(dbs.list_characters().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn failed_overwrite_keeps_the_old_character() {
+        let mut setup = tests::setup(tests::TestSystem::DnD5);
+        let key = euridice(&mut setup);
+        let dir = setup.root_dir.path().to_owned();
+        let path = dir.join("euridice.json");
+        let dbs = &mut setup.loaded_dbs;
+        dbs.export_character(key.to_owned(), &path, CharacterFileFormat::Json)
+            .expect("Export.");
+        let files = || std::fs::read_dir(&dir).unwrap().count();
+        let before = files();
+
+        // The file fits the system, but can't go on a sheet twice over.
+        let mut twice = CharacterFile::read(&path).expect("Read.");
+        let attribute = twice.character.attributes[0].clone();
+        twice.character.attributes.push(attribute);
+        twice.write(&path, CharacterFileFormat::Json).unwrap();
+        let res = dbs.import_character(&path, UuidClashPolicy::Overwrite);
+        assert!(matches!(res, Err(Error::Conflict { .. })), "{:?}", res);
+
+        assert_eq!(dbs.list_characters().unwrap().len(), 1);
+        assert_eq!(files(), before);
+        let kept = dbs.load_character(key).expect("Load.");
+        assert_eq!(kept.notes()[0].title, "Diary");
+    }
+
+    #[test]
+    fn obligatory_parts_can_come_in_any_order() {
+        let mut setup = tests::setup(tests::TestSystem::DnD5);
+        let key = euridice(&mut setup);
+        let path = setup.root_dir.path().join("euridice.json");
+        let dbs = &mut setup.loaded_dbs;
+        dbs.export_character(key.to_owned(), &path, CharacterFileFormat::Json)
+            .expect("Export.");
+        let mut file = CharacterFile::read(&path).expect("Read.");
+        file.character.parts.reverse();
+        file.write(&path, CharacterFileFormat::Json).unwrap();
+
+        let imported = dbs
+            .import_character(&path, UuidClashPolicy::Regenerate)
+            .expect("Import.");
+        assert_same(dbs, &key, &imported);
+    }
+}
@@ -1,8 +1,10 @@
 //! This deals with the base connections for the root db and outer dbs.
-use azchar_error::ma;
+use azchar_error::Error;
 
-use diesel::RunQueryDsl;
 use diesel::SqliteConnection;
+use diesel::{ExpressionMethods, QueryDsl, RunQueryDsl};
+
+use std::path::Path;
 
 table! {
     character_dbs(id) {
@@ -25,9 +27,9 @@ pub struct CharacterDbRef {
 
 impl CharacterDbRef {
     /// Get all in a db.
-    pub fn get_all(conn: &SqliteConnection) -> Result<Vec<CharacterDbRef>, String> {
+    pub fn get_all(conn: &SqliteConnection) -> Result<Vec<CharacterDbRef>, Error> {
         use self::character_dbs::dsl::*;
-        character_dbs.load(conn).map_err(ma)
+        character_dbs.load(conn).map_err(Error::from)
     }
 
     pub fn name(&self) -> &str {
@@ -37,6 +39,40 @@ impl CharacterDbRef {
     pub fn uuid(&self) -> &str {
         &self.uuid
     }
+
+    /// The path of the sheet, relative to the directory of the root database.
+    pub fn db_path(&self) -> &str {
+        &self.db_path
+    }
+
+    /// Older root databases refer to their sheets by absolute paths, which break
+    /// when the system is moved. Refer to those in `root_dir` by relative paths instead,
+    /// as well as those which are not where they were, but are in `root_dir`.
+    /// Relative paths are left alone, so this is run whenever the root database is loaded.
+    pub(crate) fn make_paths_relative(
+        conn: &SqliteConnection,
+        root_dir: &Path,
+    ) -> Result<(), Error> {
+        use self::character_dbs::dsl::*;
+        let root_dir = root_dir
+            .canonicalize()
+            .unwrap_or_else(|_| root_dir.to_owned());
+        for r in Self::get_all(conn)?.into_iter() {
+            let old = Path::new(&r.db_path);
+            if !old.is_absolute() {
+                continue;
+            }
+            let new = match (old.strip_prefix(&root_dir), old.file_name()) {
+                (Ok(rel), _) => rel.to_owned(),
+                (_, Some(f)) if !old.exists() && root_dir.join(f).exists() => f.into(),
+                _ => continue,
+            };
+            diesel::update(character_dbs.filter(id.eq(r.id)))
+                .set(db_path.eq(new.to_string_lossy().as_ref()))
+                .execute(conn)?;
+        }
+        Ok(())
+    }
 }
 
 #[derive(Debug, Clone, Insertable)]
@@ -373,4 +409,125 @@ pub(crate) mod character_tests {
         let char_conns = setup.loaded_dbs.character_connections();
         assert_eq!(char_conns.len(), 3);
     }
+
+    #[test]
+    fn sheet_transaction_rollback_and_commit() {
+        use crate::character::note::{InputNote, Note};
+
+        let mut setup = setup(TestSystem::MemorySphere);
+        let (name, uuid) = create_char_with_name(&mut setup, NAME1);
+        let key = (name.clone(), uuid.clone());
+        let note = || InputNote::new_note("A note".to_string(), None);
+        let dbs = &mut setup.loaded_dbs;
+
+        dbs.begin_sheet_transaction(&key).expect("Could not begin.");
+        dbs.add_note(name.clone(), uuid.clone(), note())
+            .expect("Could not add note.");
+        // Listing characters must not lose the transaction.
+        dbs.list_characters().expect("Could not list.");
+        dbs.rollback_sheet_transaction(&key)
+            .expect("Could not roll back.");
+        let conn = dbs.connections.get_mut(&key).unwrap().connect().unwrap();
+        assert!(Note::load_all(conn).expect("Could not load.").is_empty());
+
+        dbs.begin_sheet_transaction(&key).expect("Could not begin.");
+        dbs.add_note(name, uuid, note())
+            .expect("Could not add note.");
+        dbs.commit_sheet_transaction(&key)
+            .expect("Could not commit.");
+        let conn = dbs.connections.get_mut(&key).unwrap().connect().unwrap();
+        assert_eq!(Note::load_all(conn).expect("Could not load.").len(), 1);
+    }
+
+    #[test]
+    fn save_reports_what_went_wrong() {
+        use crate::character::attribute::{AttributeKey, AttributeValue};
+        use azchar_error::Error;
+
+        let mut setup = setup(TestSystem::MemorySphere);
+        let key = create_char_with_name(&mut setup, NAME1);
+        let mut character = setup
+            .loaded_dbs
+            .load_character(key.clone())
+            .expect("Could not load.");
+        // "attack_power" is not a memory sphere attribute.
+        character
+            .attributes
+            .push((AttributeKey::test(), AttributeValue::test()));
+        match setup.loaded_dbs.create_or_update_character(character) {
+            Err(Error::NotPermitted { what, .. }) => assert!(what.contains("attack_power")),
+            r => panic!("Expected `Error::NotPermitted`, got {:?}", r),
+        }
+
+        let other = (key.0, "no-such-uuid".to_owned());
+        match setup.loaded_dbs.load_character(other) {
+            Err(Error::NotFound { what, .. }) => assert_eq!(what, "Character"),
+            r => panic!("Expected `Error::NotFound`, got {:?}", r),
+        }
+    }
+
+    #[test]
+    fn move_the_system_and_load_it_again() {
+        use crate::root_db::characters::character_dbs::dsl;
+        use crate::LoadedDbs;
+        use azchar_error::Error;
+        use diesel::{ExpressionMethods, QueryDsl, RunQueryDsl};
+        use std::path::Path;
+
+        let mut setup = setup(TestSystem::MemorySphere);
+        let moved = create_char_with_name(&mut setup, NAME1);
+        let legacy = create_char_with_name(&mut setup, NAME2);
+        let lost = create_char_with_name(&mut setup, NAME3);
+        // Older root databases refer to their sheets by absolute paths,
+        // and have not had the migration which makes them relative.
+        let sheets = setup.loaded_dbs.character_connections();
+        let legacy_path = sheets[&legacy].path().to_owned();
+        let lost_path = sheets[&lost].path().to_owned();
+        assert!(Path::new(&legacy_path).is_absolute());
+        let root = setup.loaded_dbs.get_inner_root().unwrap();
+        diesel::update(dsl::character_dbs.filter(dsl::uuid.eq(&legacy.1)))
+            .set(dsl::db_path.eq(&legacy_path))
+            .execute(root)
+            .unwrap();
+        diesel::sql_query("delete from __diesel_schema_migrations where version = '0005';")
+            .execute(root)
+            .unwrap();
+        std::fs::remove_file(&lost_path).unwrap();
+
+        let TestSetup {
+            root_dir,
+            loaded_dbs,
+        } = setup;
+        drop(loaded_dbs);
+        let elsewhere = tempfile::tempdir().unwrap();
+        let new_dir = elsewhere.path().join("moved");
+        std::fs::rename(root_dir.path(), &new_dir).unwrap();
+        let root_path = new_dir.join("Memory Sphere.db");
+        let mut dbs = LoadedDbs::custom(&root_path.to_string_lossy()).expect("Load.");
+
+        let refs = dbs.list_characters().unwrap();
+        assert!(refs.iter().all(|r| !Path::new(r.db_path()).is_absolute()));
+        for key in [moved.to_owned(), legacy] {
+            dbs.load_character(key).expect("Load.");
+        }
+        let missing = dbs.missing_sheets();
+        assert_eq!(missing.len(), 1);
+        assert_eq!(missing[0].0, lost);
+        match dbs.load_character(lost) {
+            Err(Error::NotFound { what, .. }) => assert_eq!(what, "Database file"),
+            r => panic!("Expected `Error::NotFound`, got {:?}", r),
+        }
+
+        // Absolute paths are made relative whenever the system is loaded.
+        let moved_path = dbs.character_connections()[&moved].path().to_owned();
+        diesel::update(dsl::character_dbs.filter(dsl::uuid.eq(&moved.1)))
+            .set(dsl::db_path.eq(&moved_path))
+            .execute(dbs.get_inner_root().unwrap())
+            .unwrap();
+        drop(dbs);
+        let mut dbs = LoadedDbs::custom(&root_path.to_string_lossy()).expect("Load.");
+        let refs = dbs.list_characters().unwrap();
+        let r = refs.iter().find(|r| r.uuid() == moved.1).unwrap();
+        assert_eq!(r.db_path(), format!("{}_{}.db", moved.0, moved.1));
+    }
 }
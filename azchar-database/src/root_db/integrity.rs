@@ -0,0 +1,488 @@
+//! This checks that a root database and its sheets still agree, and that each sheet
+//! holds together, eg. after a crash or a deletion which was only half done.
+//! Every problem is reported, and most of them can be fixed.
+use super::characters::character_dbs::dsl as db_dsl;
+use super::system::PermittedAttribute;
+use super::{open_sheet, BasicConnection, CharacterDbRef, LoadedDbs, NewCharacterDbRef};
+use crate::character::attribute::attributes::dsl as at_dsl;
+use crate::character::character::characters::dsl as ch_dsl;
+use crate::character::character::Character;
+use crate::character::image::images::dsl as im_dsl;
+use crate::character::roll_macro::roll_macros::dsl as rm_dsl;
+use crate::shared::Part;
+
+use azchar_error::Error;
+use diesel::{ExpressionMethods, QueryDsl, RunQueryDsl, SqliteConnection};
+use fnv::FnvHashSet;
+
+use std::path::{Path, PathBuf};
+
+/// Something which is wrong with a system or one of its sheets.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Problem {
+    /// The root database refers to a sheet file which is not there.
+    /// Only reported, since the file may only have been moved and can be put back.
+    MissingSheet {
+        name: String,
+        uuid: String,
+        path: String,
+    },
+    /// A sheet next to the root database which neither it nor any other root database
+    /// there refers to. Fixed by adding its character to the system, unless the uuid is taken.
+    OrphanSheet {
+        path: String,
+        name: String,
+        uuid: String,
+    },
+    /// The main part on a sheet has another uuid than the root database has for it.
+    /// Fixed by giving the main part the uuid in the root database.
+    UuidMismatch {
+        name: String,
+        uuid: String,
+        sheet_uuid: String,
+    },
+    /// A part belongs to a part which is not on the sheet.
+    /// Fixed by deleting it, along with the parts which belong to it.
+    DanglingPart {
+        name: String,
+        uuid: String,
+        part_id: i64,
+        belongs_to: i64,
+    },
+    /// An attribute, image or roll macro of a part which is not on the sheet.
+    /// Fixed by deleting it.
+    DanglingRow {
+        name: String,
+        uuid: String,
+        /// "attributes", "images" or "roll_macros".
+        table: String,
+        id: i64,
+        of: i64,
+    },
+    /// An attribute which the system does not permit for its part, eg. after the system
+    /// was updated with `RemovalPolicy::Keep`. Fixed by deleting it.
+    UnpermittedAttribute {
+        name: String,
+        uuid: String,
+        part_id: i64,
+        key: String,
+    },
+}
+
+/// A problem, and whether it has been fixed.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct IntegrityProblem {
+    pub problem: Problem,
+    pub fixed: bool,
+}
+
+impl IntegrityProblem {
+    fn new(problem: Problem) -> Self {
+        Self {
+            problem,
+            fixed: false,
+        }
+    }
+}
+
+/// A database file next to the root database which is not one of its sheets.
+enum OtherFile {
+    /// Another root database, with the sheets it refers to.
+    Root(Vec<PathBuf>),
+    /// A sheet, with the name and uuid of its main part.
+    Sheet(String, String),
+    /// Something else, eg. a sheet which was never filled in.
+    Other,
+    /// Something which could not be read, eg. because another system has it open.
+    Unknown,
+}
+
+impl OtherFile {
+    fn read(path: &Path) -> Self {
+        let mut conn = BasicConnection::new(&path.to_string_lossy());
+        let conn = match conn.connect() {
+            Ok(c) => c,
+            Err(_) => return Self::Unknown,
+        };
+        if let Ok(refs) = CharacterDbRef::get_all(conn) {
+            let dir = super::root_dir(&path.to_string_lossy());
+            let sheets = refs
+                .iter()
+                .map(|r| canonical(&dir.join(r.db_path())))
+                .collect();
+            return Self::Root(sheets);
+        }
+        let main = ch_dsl::characters
+            .filter(ch_dsl::part_type.eq(Part::Main))
+            .select((ch_dsl::name, ch_dsl::uuid))
+            .first::<(String, String)>(conn);
+        match main.map_err(Error::from) {
+            Ok((name, uuid)) => Self::Sheet(name, uuid),
+            Err(Error::Database { reason }) if reason.contains("locked") => Self::Unknown,
+            Err(_) => Self::Other,
+        }
+    }
+}
+
+fn canonical(path: &Path) -> PathBuf {
+    path.canonicalize().unwrap_or_else(|_| path.to_owned())
+}
+
+impl LoadedDbs {
+    /// Look for problems with the system and its sheets, and fix them if `repair` is set.
+    /// Each sheet is fixed in a transaction of its own.
+    pub fn check_integrity(&mut self, repair: bool) -> Result<Vec<IntegrityProblem>, Error> {
+        let mut problems = self.check_missing_sheets();
+        problems.extend(self.check_orphan_sheets(repair)?);
+
+        // A missing sheet has nothing to check.
+        let mut keys = self
+            .connections
+            .iter()
+            .filter(|(_, c)| Path::new(c.path()).exists())
+            .map(|(k, _)| k.to_owned())
+            .collect::<Vec<_>>();
+        keys.sort();
+        for key in keys {
+            let conn = self.connections.get_mut(&key).expect("Key from the map.");
+            let c = conn.connect()?;
+            let permitted_attrs = &self.permitted_attrs;
+            let found =
+                crate::sheet_transaction(c, || check_sheet(c, &key, permitted_attrs, repair));
+            conn.drop_inner();
+            problems.extend(found?);
+        }
+        Ok(problems)
+    }
+
+    fn check_missing_sheets(&self) -> Vec<IntegrityProblem> {
+        self.missing_sheets()
+            .into_iter()
+            .map(|((name, uuid), path)| {
+                IntegrityProblem::new(Problem::MissingSheet { name, uuid, path })
+            })
+            .collect()
+    }
+
+    fn check_orphan_sheets(&mut self, repair: bool) -> Result<Vec<IntegrityProblem>, Error> {
+        let dir = super::root_dir(&self.root_path);
+        let root = canonical(Path::new(&self.root_path));
+        let mut claimed = self
+            .connections
+            .values()
+            .map(|c| canonical(Path::new(c.path())))
+            .collect::<FnvHashSet<_>>();
+        let mut sheets = Vec::new();
+        // Other systems may keep their sheets in the same directory, and if one of them
+        // can't be read, none of the sheets can be said to be orphans.
+        let mut unknown = false;
+        let mut files = std::fs::read_dir(if dir.as_os_str().is_empty() {
+            Path::new(".")
+        } else {
+            &dir
+        })?
+        .filter_map(|e| e.ok().map(|e| canonical(&e.path())))
+        .filter(|p| p.extension().map(|e| e == "db").unwrap_or(false))
+        .filter(|p| p != &root && p.is_file())
+        .collect::<Vec<_>>();
+        files.retain(|p| !claimed.contains(p));
+        files.sort();
+        for path in files.into_iter() {
+            match OtherFile::read(&path) {
+                OtherFile::Root(refs) => claimed.extend(refs),
+                OtherFile::Sheet(name, uuid) => sheets.push((path, name, uuid)),
+                OtherFile::Other => {}
+                OtherFile::Unknown => unknown = true,
+            }
+        }
+        if unknown {
+            return Ok(Vec::new());
+        }
+
+        let mut problems = Vec::new();
+        for (path, name, uuid) in sheets.into_iter().filter(|(p, _, _)| !claimed.contains(p)) {
+            let file_name = path
+                .file_name()
+                .map(|f| f.to_string_lossy().to_string())
+                .unwrap_or_default();
+            let mut problem = IntegrityProblem::new(Problem::OrphanSheet {
+                path: file_name.to_owned(),
+                name: name.to_owned(),
+                uuid: uuid.to_owned(),
+            });
+            let taken = self.connections.keys().any(|(_, u)| u == &uuid);
+            if repair && !taken {
+                let reference = NewCharacterDbRef::new(name.to_owned(), file_name, uuid.to_owned());
+                diesel::insert_into(db_dsl::character_dbs)
+                    .values(&reference)
+                    .execute(self.get_inner_root()?)?;
+                let conn = open_sheet(&path.to_string_lossy())?;
+                self.connections.insert((name, uuid), conn);
+                problem.fixed = true;
+            }
+            problems.push(problem);
+        }
+        Ok(problems)
+    }
+}
+
+/// Check a sheet which is there, and fix it if `repair` is set.
+fn check_sheet(
+    conn: &SqliteConnection,
+    (name, uuid): &(String, String),
+    permitted_attrs: &[PermittedAttribute],
+    repair: bool,
+) -> Result<Vec<IntegrityProblem>, Error> {
+    let mut problems = Vec::new();
+    let mut parts = ch_dsl::characters.load::<Character>(conn)?;
+
+    if let Some(main) = parts.iter().find(|p| p.part_type == Part::Main) {
+        if main.uuid() != uuid {
+            let mut problem = IntegrityProblem::new(Problem::UuidMismatch {
+                name: name.to_owned(),
+                uuid: uuid.to_owned(),
+                sheet_uuid: main.uuid().to_owned(),
+            });
+            if repair {
+                diesel::update(ch_dsl::characters.filter(ch_dsl::id.eq(main.id)))
+                    .set(ch_dsl::uuid.eq(uuid))
+                    .execute(conn)?;
+                problem.fixed = true;
+            }
+            problems.push(problem);
+        }
+    }
+
+    let ids = parts.iter().map(|p| p.id).collect::<FnvHashSet<_>>();
+    let dangling = parts
+        .iter()
+        .filter_map(|p| p.belongs_to().map(|b| (p.id, b)))
+        .filter(|(_, b)| !ids.contains(b))
+        .collect::<Vec<_>>();
+    for (part_id, belongs_to) in dangling.iter() {
+        problems.push(IntegrityProblem {
+            problem: Problem::DanglingPart {
+                name: name.to_owned(),
+                uuid: uuid.to_owned(),
+                part_id: *part_id,
+                belongs_to: *belongs_to,
+            },
+            fixed: repair,
+        });
+    }
+    if repair && !dangling.is_empty() {
+        // What belongs to a deleted part goes with it.
+        let deleted = dangling.iter().map(|(id, _)| *id).collect();
+        let deleted = Character::with_parts_beneath(&parts, deleted)
+            .into_iter()
+            .collect::<Vec<_>>();
+        for chunk in deleted.chunks(999) {
+            diesel::delete(ch_dsl::characters.filter(ch_dsl::id.eq_any(chunk))).execute(conn)?;
+            diesel::delete(at_dsl::attributes.filter(at_dsl::of.eq_any(chunk))).execute(conn)?;
+            diesel::delete(im_dsl::images.filter(im_dsl::of.eq_any(chunk))).execute(conn)?;
+            diesel::delete(rm_dsl::roll_macros.filter(rm_dsl::of.eq_any(chunk))).execute(conn)?;
+        }
+        parts = ch_dsl::characters.load::<Character>(conn)?;
+    }
+
+    let ids = parts.iter().map(|p| p.id).collect::<FnvHashSet<_>>();
+    let rows = [
+        (
+            "attributes",
+            at_dsl::attributes
+                .select((at_dsl::id, at_dsl::of))
+                .load::<(i64, i64)>(conn)?,
+        ),
+        (
+            "images",
+            im_dsl::images
+                .select((im_dsl::id, im_dsl::of))
+                .load::<(i64, i64)>(conn)?,
+        ),
+        (
+            "roll_macros",
+            rm_dsl::roll_macros
+                .select((rm_dsl::id, rm_dsl::of))
+                .load::<(i64, i64)>(conn)?,
+        ),
+    ];
+    for (table, rows) in rows.iter() {
+        for (id, of) in rows.iter().filter(|(_, of)| !ids.contains(of)) {
+            if repair {
+                match *table {
+                    "attributes" => diesel::delete(at_dsl::attributes.filter(at_dsl::id.eq(id)))
+                        .execute(conn)?,
+                    "images" => {
+                        diesel::delete(im_dsl::images.filter(im_dsl::id.eq(id))).execute(conn)?
+                    }
+                    _ => diesel::delete(rm_dsl::roll_macros.filter(rm_dsl::id.eq(id)))
+                        .execute(conn)?,
+                };
+            }
+            problems.push(IntegrityProblem {
+                problem: Problem::DanglingRow {
+                    name: name.to_owned(),
+                    uuid: uuid.to_owned(),
+                    table: table.to_string(),
+                    id: *id,
+                    of: *of,
+                },
+                fixed: repair,
+            });
+        }
+    }
+
+    let attributes = at_dsl::attributes
+        .select((at_dsl::id, at_dsl::key, at_dsl::of))
+        .load::<(i64, String, i64)>(conn)?;
+    for (id, key, of) in attributes.into_iter() {
+        let part = match parts.iter().find(|p| p.id == of) {
+            Some(p) => p,
+            None => continue,
+        };
+        let permitted = permitted_attrs
+            .iter()
+            .any(|a| a.key == key && a.permitted_for_part(part.part_type, part.character_type()));
+        if permitted {
+            continue;
+        }
+        if repair {
+            diesel::delete(at_dsl::attributes.filter(at_dsl::id.eq(id))).execute(conn)?;
+        }
+        problems.push(IntegrityProblem {
+            problem: Problem::UnpermittedAttribute {
+                name: name.to_owned(),
+                uuid: uuid.to_owned(),
+                part_id: of,
+                key,
+            },
+            fixed: repair,
+        });
+    }
+    Ok(problems)
+}
+
+#[cfg(test)]
+mod integrity_tests {
+    use super::*;
+    use crate::character::attribute::NewAttribute;
+    use crate::character::character::InputCharacter;
+    use crate::character::image::InputImage;
+    use crate::root_db::characters::character_tests::*;
+    use crate::root_db::tests;
+
+    fn part(name: &str, belongs_to: i64) -> InputCharacter {
+        InputCharacter {
+            name: name.to_string(),
+            character_type: "weapon".to_string(),
+            speed: 0,
+            weight: Some(3),
+            size: None,
+            hp_total: None,
+            hp_current: None,
+            belongs_to: Some(belongs_to),
+            part_type: Part::InventoryItem,
+        }
+    }
+
+    fn kinds(problems: &[IntegrityProblem]) -> Vec<String> {
+        let mut kinds = problems
+            .iter()
+            .map(|p| match &p.problem {
+                Problem::MissingSheet { name, .. } => format!("missing {}", name),
+                Problem::OrphanSheet { name, .. } => format!("orphan {}", name),
+                Problem::UuidMismatch { name, .. } => format!("uuid {}", name),
+                Problem::DanglingPart { name, .. } => format!("part {}", name),
+                Problem::DanglingRow { name, table, .. } => format!("{} {}", table, name),
+                Problem::UnpermittedAttribute { name, key, .. } => format!("{} {}", key, name),
+            })
+            .collect::<Vec<_>>();
+        kinds.sort();
+        kinds
+    }
+
+    #[test]
+    fn find_and_fix_everything() {
+        let mut setup = tests::setup(tests::TestSystem::DnD5);
+        let euridice = create_char_with_name(&mut setup, "Euridice");
+        let saloth = create_char_with_name(&mut setup, "Saloth");
+        let lost = create_char_with_name(&mut setup, "Lost");
+        let stray = create_char_with_name(&mut setup, "Stray");
+        let dbs = &mut setup.loaded_dbs;
+
+        // A sword with a gem in it. Deleting the sword leaves the gem and the sword's picture.
+        let c = dbs
+            .create_part(part("Sword", 1), euridice.to_owned())
+            .unwrap();
+        let sword = c.parts().iter().find(|p| p.name() == "Sword").unwrap();
+        let sword = sword.id().unwrap();
+        dbs.create_part(part("Gem", sword), euridice.to_owned())
+            .unwrap();
+        let image = InputImage {
+            of: sword,
+            link: "../examples/c-euri-2021b.png".to_string(),
+        };
+        dbs.create_update_image(euridice.0.to_owned(), euridice.1.to_owned(), image)
+            .unwrap();
+        dbs.delete_part(sword, euridice.to_owned()).unwrap();
+        let conn = dbs
+            .connections
+            .get_mut(&euridice)
+            .unwrap()
+            .connect()
+            .unwrap();
+        diesel::insert_into(at_dsl::attributes)
+            .values(&NewAttribute::test())
+            .execute(conn)
+            .unwrap();
+
+        let conn = dbs.connections.get_mut(&saloth).unwrap().connect().unwrap();
+        diesel::update(ch_dsl::characters.filter(ch_dsl::part_type.eq(Part::Main)))
+            .set(ch_dsl::uuid.eq("not-saloths-uuid"))
+            .execute(conn)
+            .unwrap();
+
+        let lost_path = dbs.connections[&lost].path().to_owned();
+        std::fs::remove_file(lost_path).unwrap();
+        let row = db_dsl::character_dbs.filter(db_dsl::uuid.eq(&stray.1));
+        diesel::delete(row)
+            .execute(dbs.get_inner_root().unwrap())
+            .unwrap();
+        dbs.list_characters().unwrap();
+
+        let expected = vec![
+            "images Euridice",
+            "memory_capacity Euridice",
+            "missing Lost",
+            "orphan Stray",
+            "part Euridice",
+            "uuid Saloth",
+        ];
+        let found = dbs.check_integrity(false).expect("Check.");
+        assert_eq!(kinds(&found), expected);
+        assert!(found.iter().all(|p| !p.fixed));
+        let fixed = dbs.check_integrity(true).expect("Repair.");
+        assert_eq!(kinds(&fixed), expected);
+        // A missing sheet is left for the user to put back.
+        assert!(fixed
+            .iter()
+            .all(|p| p.fixed != matches!(p.problem, Problem::MissingSheet { .. })));
+        assert_eq!(
+            kinds(&dbs.check_integrity(false).unwrap()),
+            vec!["missing Lost"]
+        );
+
+        let names = dbs
+            .list_characters()
+            .unwrap()
+            .iter()
+            .map(|c| c.name().to_owned())
+            .collect::<Vec<_>>();
+        assert_eq!(names.len(), 4);
+        assert!(names.contains(&"Lost".to_owned()));
+        dbs.load_character(stray).expect("Adopted.");
+        dbs.load_character(saloth).expect("Same uuid again.");
+        let c = dbs.load_character(euridice).expect("Load.");
+        assert!(c.parts().iter().all(|p| p.name() != "Gem"));
+    }
+}
@@ -5,19 +5,27 @@ use crate::character::character::InputCharacter;
 use crate::character::character::{Character, CharacterPart, CompleteCharacter, NewCharacter};
 use crate::character::image::{Image, InputImage};
 use crate::character::note::{InputNote, Note};
-use crate::root_db::system::{PermittedAttribute, PermittedPart};
+use crate::character::roll_log::{InputRoll, LoggedRoll};
+use crate::character::roll_macro::RollMacro;
+use crate::root_db::system::{PermittedAttribute, PermittedPart, SystemRollMacro};
 use crate::shared::*;
 use crate::Config;
 
-use azchar_error::ma;
+use azchar_error::Error;
+use diesel::connection::TransactionManager;
 use diesel::result::Error as DsError;
 use diesel::Connection;
 use fnv::FnvHashMap;
 
 pub mod attributes;
+pub mod backup;
+pub mod character_file;
 pub mod characters;
+pub mod integrity;
 pub mod system;
 pub mod system_config;
+pub mod system_lint;
+pub mod system_update;
 #[cfg(test)]
 pub(crate) mod tests;
 
@@ -27,7 +35,7 @@ use diesel::{RunQueryDsl, SqliteConnection};
 use uuid_rs::v4;
 
 use std::fs::File;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 embed_migrations!("migrations_main");
 
@@ -47,17 +55,24 @@ pub struct LoadedDbs {
 
 impl LoadedDbs {
     /// Load databases from standard configuration.
-    pub fn from_config(cfg: Config) -> Result<Self, String> {
+    pub fn from_config(cfg: Config) -> Result<Self, Error> {
         Self::custom(cfg.get_root_db_path())
     }
 
     /// Load databases from a custom path.
-    pub fn custom(path: &str) -> Result<Self, String> {
+    /// Sheets are found relative to the root database and brought up to date.
+    /// Those which are missing are given by `missing_sheets`.
+    pub fn custom(path: &str) -> Result<Self, Error> {
         let mut root_db = BasicConnection::new(path);
+        let dir = root_dir(path);
+        system_config::migrate_root(root_db.connect()?, &dir)?;
         let connections = CharacterDbRef::get_all(root_db.connect()?)?
             .into_iter()
-            .map(|refs| ((refs.name, refs.uuid), BasicConnection::new(&refs.db_path)))
-            .collect::<FnvHashMap<(String, String), BasicConnection>>();
+            .map(|refs| {
+                let conn = open_sheet(&sheet_path(&dir, &refs.db_path))?;
+                Ok(((refs.name, refs.uuid), conn))
+            })
+            .collect::<Result<FnvHashMap<(String, String), BasicConnection>, Error>>()?;
         let permitted_attrs = PermittedAttribute::load_all(root_db.connect()?)?;
         let permitted_parts = PermittedPart::load_all(root_db.connect()?)?;
         Ok(LoadedDbs {
@@ -70,21 +85,32 @@ impl LoadedDbs {
     }
 
     /// This function is used to refresh one's own status.
-    pub fn refresh_and_list(&mut self) -> Result<Vec<CharacterDbRef>, String> {
+    pub fn refresh_and_list(&mut self) -> Result<Vec<CharacterDbRef>, Error> {
         self.root_db.connect()?;
         let connections = CharacterDbRef::get_all(self.root_db.connect()?)?;
 
+        // Connections which are still there are kept, along with any open transaction.
+        let mut old = std::mem::take(&mut self.connections);
+        let dir = root_dir(&self.root_path);
         self.connections = connections
             .iter()
             .cloned()
-            .map(|refs| ((refs.name, refs.uuid), BasicConnection::new(&refs.db_path)))
-            .collect::<FnvHashMap<(String, String), BasicConnection>>();
+            .map(|refs| {
+                let key = (refs.name, refs.uuid);
+                let path = sheet_path(&dir, &refs.db_path);
+                let conn = match old.remove(&key) {
+                    Some(c) if c.path() == path => c,
+                    _ => open_sheet(&path)?,
+                };
+                Ok((key, conn))
+            })
+            .collect::<Result<FnvHashMap<(String, String), BasicConnection>, Error>>()?;
         Ok(connections)
     }
 
     /// A special case for creating a new system.
     /// NB, we do not load parts till later, because they do not exist yet!
-    pub fn new_system(path: &str) -> Result<Self, String> {
+    pub fn new_system(path: &str) -> Result<Self, Error> {
         let root_db = BasicConnection::new(path);
         Ok(LoadedDbs {
             root_db,
@@ -95,13 +121,18 @@ impl LoadedDbs {
         })
     }
 
+    /// The path of the root database.
+    pub fn root_path(&self) -> &str {
+        &self.root_path
+    }
+
     /// Reference to basic connection.
     pub fn root_connection(&self) -> &BasicConnection {
         &self.root_db
     }
 
     /// Needs to be connected.
-    pub fn get_inner_root(&mut self) -> Result<&SqliteConnection, String> {
+    pub fn get_inner_root(&mut self) -> Result<&SqliteConnection, Error> {
         self.root_db.connect()?;
         Ok(self.root_db.get_inner().expect("We just created it."))
     }
@@ -116,12 +147,29 @@ impl LoadedDbs {
         &self.connections
     }
 
+    /// The characters whose sheet files are not where the root database says they are,
+    /// with where that is.
+    pub fn missing_sheets(&self) -> Vec<((String, String), String)> {
+        let mut missing = self
+            .connections
+            .iter()
+            .filter(|(_, c)| !Path::new(c.path()).exists())
+            .map(|(k, c)| (k.to_owned(), c.path().to_owned()))
+            .collect::<Vec<_>>();
+        missing.sort();
+        missing
+    }
+
+    /// Where a sheet is, given its path in the root database.
+    pub(crate) fn sheet_path(&self, db_path: &str) -> String {
+        sheet_path(&root_dir(&self.root_path), db_path)
+    }
+
     /// Create a new character sheet database.
     /// Returns the character name and uuid.
-    pub fn create_sheet(&mut self, name: &str) -> Result<(String, String), String> {
+    pub fn create_sheet(&mut self, name: &str) -> Result<(String, String), Error> {
         use crate::character::attribute::attributes::dsl as at_dsl;
         use crate::character::character::characters::dsl as ch_dsl;
-        use crate::root_db::characters::character_dbs::dsl::character_dbs;
 
         let uuid = v4!();
         // Sanity check.
@@ -130,47 +178,15 @@ impl LoadedDbs {
             .get(&(uuid.clone(), name.to_owned()))
             .is_some()
         {
-            return Err(format!("{} already exists as a file! Try again.", name));
+            return Err(Error::conflict(format!(
+                "{} already exists as a file! Try again.",
+                name
+            )));
         }
+        let then = std::time::Instant::now();
         let file_name = format!("{}_{}.db", name, uuid);
-        let file_path = PathBuf::from(&self.root_path)
-            .parent()
-            .expect("Root path is file. Has parent.")
-            .join(&file_name);
-        if file_path.exists() {
-            return Err(format!(
-                "{:?} already exists as a file! Try again.",
-                file_path
-            ));
-        }
-
-        // Create the file.
-        let _sheet_db = File::create(file_path.clone()).map_err(ma)?;
-        let file_path = file_path.to_string_lossy().to_owned().to_string();
-        let reference = NewCharacterDbRef::new(name.to_owned(), file_path.clone(), uuid.clone());
-
-        // Clean up if we can't create the character sheet.
-        let root_conn = self.get_inner_root()?;
-        match diesel::insert_into(character_dbs)
-            .values(&vec![reference])
-            .execute(root_conn)
-            .map_err(ma)
-        {
-            Ok(_) => {}
-            Err(e) => {
-                std::fs::remove_file(file_path).map_err(ma)?;
-                return Err(e);
-            }
-        }
-
-        // Connect to the new character sheet.
-        let mut sheet_conn_outer = BasicConnection::new(&file_path);
+        let mut sheet_conn_outer = self.new_sheet_file(name, &uuid, &file_name)?;
         let sheet_conn = sheet_conn_outer.connect()?;
-        crate::set_pragma(sheet_conn)?;
-
-        // Create all needed tables
-        let then = std::time::Instant::now();
-        embedded_migrations::run(sheet_conn).map_err(ma)?;
         let t1 = then.elapsed().as_micros();
         // Create and place a new part.
         let mut main_new_part: NewCharacter = self
@@ -182,53 +198,51 @@ impl LoadedDbs {
         main_new_part.name = name.to_owned();
         main_new_part.uuid = uuid.to_owned();
 
-        sheet_conn
-            .transaction::<_, DsError, _>(|| {
+        sheet_conn.transaction::<_, DsError, _>(|| {
+            diesel::insert_into(ch_dsl::characters)
+                .values(main_new_part)
+                .execute(sheet_conn)?;
+            let char_id = Character::get_latest_id(sheet_conn)?;
+
+            let new_subparts: Vec<NewCharacter> = self
+                .permitted_parts
+                .iter()
+                .filter(|p| p.obligatory && !matches!(p.part_type, Part::Main))
+                .map(|p| {
+                    let mut p: NewCharacter = p.into();
+                    p.belongs_to = Some(char_id);
+                    p
+                })
+                .collect();
+
+            // Da chunking!
+            for chunk in new_subparts.chunks(999) {
                 diesel::insert_into(ch_dsl::characters)
-                    .values(main_new_part)
+                    .values(chunk)
                     .execute(sheet_conn)?;
-                let char_id = Character::get_latest_id(sheet_conn)?;
+            }
+
+            // All character parts created here are obligatory.
+            let identifiers: Vec<Character> = ch_dsl::characters.load(sheet_conn)?;
 
-                let new_subparts: Vec<NewCharacter> = self
-                    .permitted_parts
+            let mut new_attributes = Vec::new();
+            for p in identifiers {
+                let attr_iter = self
+                    .permitted_attrs
                     .iter()
-                    .filter(|p| p.obligatory && !matches!(p.part_type, Part::Main))
-                    .map(|p| {
-                        let mut p: NewCharacter = p.into();
-                        p.belongs_to = Some(char_id);
-                        p
-                    })
-                    .collect();
-
-                // Da chunking!
-                for chunk in new_subparts.chunks(999) {
-                    diesel::insert_into(ch_dsl::characters)
-                        .values(chunk)
-                        .execute(sheet_conn)?;
-                }
-
-                // All character parts created here are obligatory.
-                let identifiers: Vec<Character> = ch_dsl::characters.load(sheet_conn)?;
-
-                let mut new_attributes = Vec::new();
-                for p in identifiers {
-                    let attr_iter = self
-                        .permitted_attrs
-                        .iter()
-                        .filter(|a| a.obligatory_for_part(p.part_type, &p.character_type))
-                        .map(|a| NewAttribute::from_permitted(p.id, a));
-                    new_attributes.extend(attr_iter);
-                }
-
-                // Da chunking!
-                for chunk in new_attributes.chunks(999) {
-                    diesel::insert_into(at_dsl::attributes)
-                        .values(chunk)
-                        .execute(sheet_conn)?;
-                }
-                Ok(())
-            })
-            .map_err(ma)?;
+                    .filter(|a| a.obligatory_for_part(p.part_type, &p.character_type))
+                    .map(|a| NewAttribute::from_permitted(p.id, a));
+                new_attributes.extend(attr_iter);
+            }
+
+            // Da chunking!
+            for chunk in new_attributes.chunks(999) {
+                diesel::insert_into(at_dsl::attributes)
+                    .values(chunk)
+                    .execute(sheet_conn)?;
+            }
+            Ok(())
+        })?;
 
         let t2 = then.elapsed().as_micros();
         println!("migrations:{}us", t1);
@@ -240,13 +254,62 @@ impl LoadedDbs {
         Ok((name.to_string(), uuid))
     }
 
+    /// Create an empty sheet file for a character, with all its tables, and refer to it
+    /// in the root database. Gives the connection to it.
+    fn new_sheet_file(
+        &mut self,
+        name: &str,
+        uuid: &str,
+        file_name: &str,
+    ) -> Result<BasicConnection, Error> {
+        use crate::root_db::characters::character_dbs::dsl::character_dbs;
+
+        let file_path = PathBuf::from(&self.root_path)
+            .parent()
+            .expect("Root path is file. Has parent.")
+            .join(file_name);
+        if file_path.exists() {
+            return Err(Error::conflict(format!(
+                "{:?} already exists as a file! Try again.",
+                file_path
+            )));
+        }
+
+        // Create the file. It is referred to relative to the root database.
+        let _sheet_db = File::create(file_path.clone())?;
+        let file_path = file_path.to_string_lossy().to_owned().to_string();
+        let reference =
+            NewCharacterDbRef::new(name.to_owned(), file_name.to_owned(), uuid.to_owned());
+
+        // Clean up if we can't create the character sheet.
+        let root_conn = self.get_inner_root()?;
+        match diesel::insert_into(character_dbs)
+            .values(&vec![reference])
+            .execute(root_conn)
+            .map_err(Error::from)
+        {
+            Ok(_) => {}
+            Err(e) => {
+                std::fs::remove_file(file_path)?;
+                return Err(e);
+            }
+        }
+
+        // Connect to the new character sheet and create all needed tables.
+        let mut sheet_conn = BasicConnection::new(&file_path);
+        let c = sheet_conn.connect()?;
+        crate::set_pragma(c)?;
+        embedded_migrations::run(c)?;
+        Ok(sheet_conn)
+    }
+
     /// Create or update character.
     /// Take a JSON and either a) create a character or b) update a character
     /// Depending on whether the character exists in the current instance.
     pub fn create_or_update_character(
         &mut self,
         character: CompleteCharacter,
-    ) -> Result<(), String> {
+    ) -> Result<(), Error> {
         let then = std::time::Instant::now();
         let key = (character.name.to_owned(), character.uuid().to_owned());
         println!("{:?}", key);
@@ -272,48 +335,84 @@ impl LoadedDbs {
         Ok(())
     }
 
+    /// Open a transaction on a character sheet.
+    /// Everything done to the sheet until it is committed or rolled back is done in it.
+    pub fn begin_sheet_transaction(&mut self, key: &(String, String)) -> Result<(), Error> {
+        let conn = self.sheet_connection(key)?;
+        // Sheets run without a journal, which makes a rollback impossible.
+        conn.execute("pragma journal_mode = MEMORY;")?;
+        conn.transaction_manager()
+            .begin_transaction(conn)
+            .map_err(Error::from)
+    }
+
+    /// Commit the transaction opened by `begin_sheet_transaction`.
+    /// If the commit fails, the transaction is rolled back.
+    pub fn commit_sheet_transaction(&mut self, key: &(String, String)) -> Result<(), Error> {
+        let conn = self.sheet_connection(key)?;
+        let manager = conn.transaction_manager();
+        let res = match manager.commit_transaction(conn) {
+            Ok(()) => Ok(()),
+            Err(e) => {
+                manager.rollback_transaction(conn)?;
+                Err(Error::from(e))
+            }
+        };
+        conn.execute("pragma journal_mode = OFF;")?;
+        res
+    }
+
+    /// Undo everything done since `begin_sheet_transaction`.
+    pub fn rollback_sheet_transaction(&mut self, key: &(String, String)) -> Result<(), Error> {
+        let conn = self.sheet_connection(key)?;
+        conn.transaction_manager().rollback_transaction(conn)?;
+        conn.execute("pragma journal_mode = OFF;")?;
+        Ok(())
+    }
+
+    fn sheet_connection(&mut self, key: &(String, String)) -> Result<&SqliteConnection, Error> {
+        match self.connections.get_mut(key) {
+            Some(conn) => conn.connect(),
+            None => Err(character_not_found(key)),
+        }
+    }
+
     /// This is used to get a list of characters.
     /// These are the keys to the database.
-    pub fn list_characters(&mut self) -> Result<Vec<CharacterDbRef>, String> {
+    pub fn list_characters(&mut self) -> Result<Vec<CharacterDbRef>, Error> {
         self.refresh_and_list()
     }
 
     /// This is used to get the character list as a JSON string.
-    pub fn list_characters_json(&mut self) -> Result<String, String> {
-        serde_json::to_string(&self.refresh_and_list()?).map_err(ma)
+    pub fn list_characters_json(&mut self) -> Result<String, Error> {
+        serde_json::to_string(&self.refresh_and_list()?).map_err(Error::from)
     }
 
     /// A function to load a character.
-    pub fn load_character(&mut self, key: (String, String)) -> Result<CompleteCharacter, String> {
+    pub fn load_character(&mut self, key: (String, String)) -> Result<CompleteCharacter, Error> {
         if let Some(ref mut conn) = self.connections.get_mut(&key) {
-            CompleteCharacter::load(conn.connect()?)
+            CompleteCharacter::load_for_system(conn.connect()?, &self.permitted_attrs)
         } else {
-            Err(format!(
-                "Character ({}, uuid = {}) not found in this database",
-                key.0, key.1
-            ))
+            Err(character_not_found(&key))
         }
     }
 
     /// Load a character as a string. Ready for consumption.
-    pub fn load_character_as_json(&mut self, key: (String, String)) -> Result<String, String> {
-        serde_json::to_string(&self.load_character(key)?).map_err(ma)
+    pub fn load_character_as_json(&mut self, key: (String, String)) -> Result<String, Error> {
+        serde_json::to_string(&self.load_character(key)?).map_err(Error::from)
     }
 
     pub fn create_attribute(
         &mut self,
         new_attr: NewAttribute,
         key: (String, String),
-    ) -> Result<CompleteCharacter, String> {
+    ) -> Result<CompleteCharacter, Error> {
         if let Some(ref mut conn) = self.connections.get_mut(&key) {
             let c = conn.connect()?;
             new_attr.checked_insert(c, &self.permitted_attrs)?;
-            CompleteCharacter::load(c)
+            CompleteCharacter::load_for_system(c, &self.permitted_attrs)
         } else {
-            Err(format!(
-                "Character with identifier {}-{} not found.",
-                key.0, key.1
-            ))
+            Err(character_not_found(&key))
         }
     }
 
@@ -322,14 +421,16 @@ impl LoadedDbs {
         attr_key: AttributeKey,
         attr_value: AttributeValue,
         key: (String, String),
-    ) -> Result<(), String> {
+    ) -> Result<(), Error> {
         if let Some(ref mut conn) = self.connections.get_mut(&key) {
-            Attributes::insert_update_key_value(&attr_key, &attr_value, conn.connect()?)
+            Attributes::insert_update_key_value(
+                &attr_key,
+                &attr_value,
+                conn.connect()?,
+                &self.permitted_attrs,
+            )
         } else {
-            Err(format!(
-                "Character with identifier {}-{} not found.",
-                key.0, key.1
-            ))
+            Err(character_not_found(&key))
         }
     }
 
@@ -337,7 +438,7 @@ impl LoadedDbs {
         &mut self,
         part: CharacterPart,
         key: (String, String),
-    ) -> Result<(), String> {
+    ) -> Result<(), Error> {
         if let Some(ref mut conn) = self.connections.get_mut(&key) {
             CompleteCharacter::insert_update_character_part(
                 part,
@@ -346,10 +447,7 @@ impl LoadedDbs {
                 &self.permitted_attrs,
             )
         } else {
-            Err(format!(
-                "Character with identifier {}-{} not found.",
-                key.0, key.1
-            ))
+            Err(character_not_found(&key))
         }
     }
 
@@ -357,7 +455,7 @@ impl LoadedDbs {
         &mut self,
         new_part: InputCharacter,
         key: (String, String),
-    ) -> Result<CompleteCharacter, String> {
+    ) -> Result<CompleteCharacter, Error> {
         if let Some(ref mut conn) = self.connections.get_mut(&key) {
             let c = conn.connect()?;
             NewCharacter::from_input(new_part).checked_insert(
@@ -366,12 +464,9 @@ impl LoadedDbs {
                 &self.permitted_attrs,
                 &None,
             )?;
-            CompleteCharacter::load(c)
+            CompleteCharacter::load_for_system(c, &self.permitted_attrs)
         } else {
-            Err(format!(
-                "Character with identifier {}-{} not found.",
-                key.0, key.1
-            ))
+            Err(character_not_found(&key))
         }
     }
 
@@ -379,40 +474,33 @@ impl LoadedDbs {
         &mut self,
         part_id: i64,
         key: (String, String),
-    ) -> Result<CompleteCharacter, String> {
+    ) -> Result<CompleteCharacter, Error> {
         if let Some(ref mut conn) = self.connections.get_mut(&key) {
             let c = conn.connect()?;
             CompleteCharacter::delete_part(part_id, c)?;
-            CompleteCharacter::load(c)
+            CompleteCharacter::load_for_system(c, &self.permitted_attrs)
         } else {
-            Err(format!(
-                "Character with identifier {}-{} not found.",
-                key.0, key.1
-            ))
+            Err(character_not_found(&key))
         }
     }
 
-    pub fn delete_character(&mut self, char_name: String, char_uuid: String) -> Result<(), String> {
+    pub fn delete_character(&mut self, char_name: String, char_uuid: String) -> Result<(), Error> {
         use crate::diesel::{BoolExpressionMethods, ExpressionMethods, QueryDsl};
         use crate::root_db::characters::character_dbs::dsl::*;
 
         let key = (char_name.clone(), char_uuid.clone());
         if let Some(ref mut conn) = self.connections.get_mut(&key) {
             ::diesel::delete(character_dbs.filter(name.eq(&char_name).and(uuid.eq(&char_uuid))))
-                .execute(self.root_db.connect()?)
-                .map_err(ma)?;
+                .execute(self.root_db.connect()?)?;
             match std::fs::remove_file(&conn.db_path) {
                 Ok(()) => Ok(()),
                 Err(_) => match std::fs::remove_file(&conn.db_path) {
                     Ok(()) => Ok(()),
-                    Err(e) => Err(format!("{}", e)),
+                    Err(e) => Err(Error::from(e)),
                 },
             }
         } else {
-            Err(format!(
-                "Character with identifier {}-{} not found.",
-                key.0, key.1
-            ))
+            Err(character_not_found(&key))
         }
     }
 
@@ -422,14 +510,14 @@ impl LoadedDbs {
         char_name: String,
         char_uuid: String,
         image: InputImage,
-    ) -> Result<Image, String> {
+    ) -> Result<Image, Error> {
         let key = (char_name, char_uuid);
         if let Some(ref mut conn) = self.connections.get_mut(&key) {
             let conn = conn.connect()?;
             image.convert_to_new()?.insert_new(conn)?;
             Image::get_latest(conn)
         } else {
-            Err(format!("Character with identifier {:?} not found.", key,))
+            Err(character_not_found(&key))
         }
     }
 
@@ -438,14 +526,14 @@ impl LoadedDbs {
         char_name: String,
         char_uuid: String,
         new_note: InputNote,
-    ) -> Result<Note, String> {
+    ) -> Result<Note, Error> {
         let key = (char_name, char_uuid);
         if let Some(ref mut conn) = self.connections.get_mut(&key) {
             let conn = conn.connect()?;
             new_note.insert_new(conn)?;
             Note::get_latest(conn)
         } else {
-            Err(format!("Character with identifier {:?} not found.", key,))
+            Err(character_not_found(&key))
         }
     }
 
@@ -454,13 +542,128 @@ impl LoadedDbs {
         char_name: String,
         char_uuid: String,
         note: Note,
-    ) -> Result<(), String> {
+    ) -> Result<(), Error> {
         let key = (char_name, char_uuid);
         if let Some(ref mut conn) = self.connections.get_mut(&key) {
             let conn = conn.connect()?;
             note.update(conn).map(|_| ())
         } else {
-            Err(format!("Character with identifier {:?} not found.", key,))
+            Err(character_not_found(&key))
+        }
+    }
+
+    /// Log a roll for a character, or in the system's own log if it is for no one.
+    pub fn log_roll(
+        &mut self,
+        key: Option<(String, String)>,
+        roll: InputRoll,
+    ) -> Result<LoggedRoll, Error> {
+        roll.insert_new(self.connection_for(key)?)
+    }
+
+    /// The latest rolls for a character, or the rolls made for no one.
+    pub fn get_roll_log(
+        &mut self,
+        key: Option<(String, String)>,
+        limit: Option<i64>,
+        since: Option<&str>,
+    ) -> Result<Vec<LoggedRoll>, Error> {
+        LoggedRoll::load(self.connection_for(key)?, limit, since)
+    }
+
+    /// The roll macros a part has: its own, then those the system gives its kind of part.
+    /// A part's own macro goes before the system's macro of the same name.
+    pub fn get_roll_macros(
+        &mut self,
+        key: (String, String),
+        part_id: i64,
+    ) -> Result<Vec<RollMacro>, Error> {
+        use crate::character::character::characters::dsl::*;
+        use crate::diesel::{ExpressionMethods, OptionalExtension, QueryDsl};
+
+        let conn = self.connection_for(Some(key))?;
+        let part = characters
+            .filter(id.eq(part_id))
+            .first::<Character>(conn)
+            .optional()?
+            .ok_or_else(|| Error::not_found("Part", part_id.to_string()))?;
+        let mut macros = RollMacro::load_for(conn, part_id)?;
+        let root = self.get_inner_root()?;
+        for m in SystemRollMacro::load_for_part(root, &part.character_type, part.part_type)? {
+            if !macros.iter().any(|own| own.name == m.name) {
+                macros.push(RollMacro {
+                    of: part_id,
+                    name: m.name,
+                    attack: m.attack,
+                    damage: m.damage,
+                });
+            }
+        }
+        Ok(macros)
+    }
+
+    /// Give a part its own roll macro, and return all the macros it has.
+    pub fn set_roll_macro(
+        &mut self,
+        key: (String, String),
+        roll_macro: RollMacro,
+    ) -> Result<Vec<RollMacro>, Error> {
+        let part_id = roll_macro.of;
+        // This checks that the part exists.
+        self.get_roll_macros(key.to_owned(), part_id)?;
+        roll_macro.save(self.connection_for(Some(key.to_owned()))?)?;
+        self.get_roll_macros(key, part_id)
+    }
+
+    /// Delete a part's own roll macro, and return the macros it has left.
+    /// NB: The system's macros can only be removed by updating the system.
+    pub fn delete_roll_macro(
+        &mut self,
+        key: (String, String),
+        part_id: i64,
+        name: &str,
+    ) -> Result<Vec<RollMacro>, Error> {
+        RollMacro::delete(self.connection_for(Some(key.to_owned()))?, part_id, name)?;
+        self.get_roll_macros(key, part_id)
+    }
+
+    /// A sheet's connection, or the root database's if there is no key.
+    fn connection_for(
+        &mut self,
+        key: Option<(String, String)>,
+    ) -> Result<&SqliteConnection, Error> {
+        match key {
+            Some(key) => self.sheet_connection(&key),
+            None => self.get_inner_root(),
         }
     }
 }
+
+/// A connection to a sheet which has been brought up to date, if it is there.
+/// Sheets made by an older version are migrated here, once, rather than on each request.
+pub(crate) fn open_sheet(path: &str) -> Result<BasicConnection, Error> {
+    let mut sheet = BasicConnection::new(path);
+    if Path::new(path).exists() {
+        embedded_migrations::run(sheet.connect()?)?;
+        sheet.drop_inner();
+    }
+    Ok(sheet)
+}
+
+/// The directory of a root database, which the paths of its sheets are relative to.
+fn root_dir(root_path: &str) -> PathBuf {
+    Path::new(root_path)
+        .parent()
+        .map(Path::to_path_buf)
+        .unwrap_or_default()
+}
+
+/// Where a sheet is. Older root databases may still have an absolute path for it.
+fn sheet_path(root_dir: &Path, db_path: &str) -> String {
+    root_dir.join(db_path).to_string_lossy().to_string()
+}
+
+/// The error for a character which is not in the system.
+pub(crate) fn character_not_found(key: &(String, String)) -> Error {
+    Error::not_found("Character", format!("{} (uuid = {})", key.0, key.1))
+}
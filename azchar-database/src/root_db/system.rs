@@ -1,7 +1,7 @@
 //! This deals with tables on the root database that deal with
 //! permitted character parts and permitted attributes.
 use crate::shared::*;
-use azchar_error::ma;
+use azchar_error::Error;
 
 use crate::character::NewCharacter;
 
@@ -20,6 +20,12 @@ table!(
         part_name -> Nullable<Text>,
         part_type -> Nullable<Integer>,
         obligatory -> Bool,
+        default_num -> Nullable<BigInt>,
+        default_text -> Nullable<Text>,
+        min_value -> Nullable<Double>,
+        max_value -> Nullable<Double>,
+        choices -> Nullable<Text>,
+        formula -> Nullable<Text>,
     }
 );
 
@@ -32,6 +38,17 @@ table!(
     }
 );
 
+table!(
+    roll_macros(id) {
+        id -> BigInt,
+        name -> Text,
+        part_name -> Text,
+        part_type -> Integer,
+        attack -> Nullable<Text>,
+        damage -> Nullable<Text>,
+    }
+);
+
 /// This represents a part that is permitted and that will be created on a new sheet.
 #[derive(Debug, Clone, PartialEq, Identifiable, Queryable)]
 #[table_name = "permitted_parts"]
@@ -52,14 +69,52 @@ pub struct PermittedAttribute {
     pub(crate) part_name: Option<String>,
     pub(crate) part_type: Option<Part>,
     pub(crate) obligatory: bool,
+    // What a new attribute starts with.
+    pub(crate) default_num: Option<i64>,
+    pub(crate) default_text: Option<String>,
+    // Bounds for numbers, inclusive.
+    pub(crate) min_value: Option<f64>,
+    pub(crate) max_value: Option<f64>,
+    // If not empty, the only values the text may have.
+    pub(crate) choices: Vec<String>,
+    // Derived attributes are worked out from this, and can't be set.
+    pub(crate) formula: Option<String>,
+}
+
+/// A roll macro which every part of a kind has, eg. the attack and damage of a weapon.
+#[derive(Debug, Clone, PartialEq, Identifiable, Queryable)]
+#[table_name = "roll_macros"]
+pub struct SystemRollMacro {
+    id: i64,
+    pub(crate) name: String,
+    pub(crate) part_name: String,
+    #[diesel(deserialize_as = "i32")]
+    pub(crate) part_type: Part,
+    pub(crate) attack: Option<String>,
+    pub(crate) damage: Option<String>,
 }
 
+type PermittedAttributeRow = (
+    String,
+    i32,
+    String,
+    Option<String>,
+    Option<i32>,
+    bool,
+    Option<i64>,
+    Option<String>,
+    Option<f64>,
+    Option<f64>,
+    Option<String>,
+    Option<String>,
+);
+
 impl<DB, ST> Queryable<ST, DB> for PermittedAttribute
 where
     DB: Backend,
-    (String, i32, String, Option<String>, Option<i32>, bool): FromSqlRow<ST, DB>,
+    PermittedAttributeRow: FromSqlRow<ST, DB>,
 {
-    type Row = (String, i32, String, Option<String>, Option<i32>, bool);
+    type Row = PermittedAttributeRow;
 
     fn build(row: Self::Row) -> Self {
         PermittedAttribute {
@@ -69,6 +124,15 @@ where
             part_name: row.3,
             part_type: row.4.map(Into::into),
             obligatory: row.5,
+            default_num: row.6,
+            default_text: row.7,
+            min_value: row.8,
+            max_value: row.9,
+            choices: row
+                .10
+                .and_then(|c| serde_json::from_str(&c).ok())
+                .unwrap_or_default(),
+            formula: row.11,
         }
     }
 }
@@ -90,17 +154,17 @@ impl PermittedPart {
         self.id
     }
     /// Get all permitted parts.
-    pub fn load_all(root_conn: &SqliteConnection) -> Result<Vec<Self>, String> {
+    pub fn load_all(root_conn: &SqliteConnection) -> Result<Vec<Self>, Error> {
         use self::permitted_parts::dsl::*;
-        permitted_parts.load(root_conn).map_err(ma)
+        permitted_parts.load(root_conn).map_err(Error::from)
     }
     /// Get all permitted parts.
-    pub fn load_obligatory(root_conn: &SqliteConnection) -> Result<Vec<Self>, String> {
+    pub fn load_obligatory(root_conn: &SqliteConnection) -> Result<Vec<Self>, Error> {
         use self::permitted_parts::dsl::*;
         permitted_parts
             .filter(obligatory.eq(true))
             .load(root_conn)
-            .map_err(ma)
+            .map_err(Error::from)
     }
 }
 
@@ -111,8 +175,67 @@ impl PermittedAttribute {
             && self.part_name.as_ref().map(|x| x == c_type).unwrap_or(true)
     }
     /// A DRY function to check if a part is permitted for a character.
+    /// NB: Derived attributes are never obligatory, since they are not stored.
     pub(crate) fn obligatory_for_part(&self, p_type: Part, c_type: &str) -> bool {
-        self.obligatory && self.permitted_for_part(p_type, c_type)
+        self.obligatory && self.formula.is_none() && self.permitted_for_part(p_type, c_type)
+    }
+    /// What values of this attribute may hold.
+    pub(crate) fn value_type(&self) -> AttributeType {
+        self.attribute_type.into()
+    }
+    /// Check that a value fits the type, the bounds and the choices of this attribute.
+    pub(crate) fn check_value(
+        &self,
+        value_num: Option<i64>,
+        value_text: &Option<String>,
+    ) -> Result<(), Error> {
+        let value_type = self.value_type();
+        value_type.check_value(&self.key, value_num, value_text)?;
+        let number = match value_type {
+            AttributeType::Decimal => value_text.as_ref().and_then(|t| t.trim().parse().ok()),
+            _ => value_num.map(|n| n as f64),
+        };
+        if let (Some(n), Some(min)) = (number, self.min_value) {
+            if n < min {
+                let m = format!(
+                    "Attribute '{}' can't be less than {}, not {}.",
+                    self.key, min, n
+                );
+                return Err(Error::validation(m));
+            }
+        }
+        if let (Some(n), Some(max)) = (number, self.max_value) {
+            if n > max {
+                let m = format!(
+                    "Attribute '{}' can't be more than {}, not {}.",
+                    self.key, max, n
+                );
+                return Err(Error::validation(m));
+            }
+        }
+        match value_text {
+            Some(t) if !self.choices.is_empty() && !self.choices.contains(t) => {
+                let m = format!(
+                    "Attribute '{}' must be one of {}, not '{}'.",
+                    self.key,
+                    self.choices.join(", "),
+                    t
+                );
+                Err(Error::validation(m))
+            }
+            _ => Ok(()),
+        }
+    }
+    /// Find the permitted attribute for a key on a part.
+    pub(crate) fn find_for_part<'a>(
+        permitted: &'a [Self],
+        key: &str,
+        p_type: Part,
+        c_type: &str,
+    ) -> Option<&'a Self> {
+        permitted
+            .iter()
+            .find(|a| a.key == key && a.permitted_for_part(p_type, c_type))
     }
 
     /// Load permitted attributes for the part from the root database.
@@ -126,7 +249,7 @@ impl PermittedAttribute {
     pub(crate) fn load_for_part(
         part: &PermittedPart,
         root_conn: &SqliteConnection,
-    ) -> Result<Vec<Self>, String> {
+    ) -> Result<Vec<Self>, Error> {
         use self::permitted_attributes::dsl::*;
         let filter = part_type
             .is_null()
@@ -138,13 +261,13 @@ impl PermittedAttribute {
             .filter(filter)
             .order_by(part_type.asc())
             .load(root_conn)
-            .map_err(ma)
+            .map_err(Error::from)
     }
     // Load permitted attributes for the part from the root database.
     pub(crate) fn load_obligatory_for_part(
         part: &PermittedPart,
         root_conn: &SqliteConnection,
-    ) -> Result<Vec<Self>, String> {
+    ) -> Result<Vec<Self>, Error> {
         use self::permitted_attributes::dsl::*;
         let filter = part_type
             .is_null()
@@ -156,24 +279,24 @@ impl PermittedAttribute {
             .filter(filter.and(obligatory.eq(true)))
             .order_by(part_type.asc())
             .load(root_conn)
-            .map_err(ma)
+            .map_err(Error::from)
     }
 
-    pub(crate) fn load_all(root_conn: &SqliteConnection) -> Result<Vec<Self>, String> {
+    pub(crate) fn load_all(root_conn: &SqliteConnection) -> Result<Vec<Self>, Error> {
         use self::permitted_attributes::dsl::*;
         permitted_attributes
             .order_by(part_type.asc())
             .load(root_conn)
-            .map_err(ma)
+            .map_err(Error::from)
     }
 
-    pub(crate) fn load_all_obligatory(root_conn: &SqliteConnection) -> Result<Vec<Self>, String> {
+    pub(crate) fn load_all_obligatory(root_conn: &SqliteConnection) -> Result<Vec<Self>, Error> {
         use self::permitted_attributes::dsl::*;
         permitted_attributes
             .filter(obligatory.eq(true))
             .order_by(part_type.asc())
             .load(root_conn)
-            .map_err(ma)
+            .map_err(Error::from)
     }
 }
 
@@ -196,13 +319,80 @@ pub(crate) struct NewPermittedAttribute {
     pub(crate) part_name: Option<String>,
     pub(crate) part_type: Option<Part>,
     pub(crate) obligatory: bool,
+    pub(crate) default_num: Option<i64>,
+    pub(crate) default_text: Option<String>,
+    pub(crate) min_value: Option<f64>,
+    pub(crate) max_value: Option<f64>,
+    /// A JSON list.
+    pub(crate) choices: Option<String>,
+    pub(crate) formula: Option<String>,
+}
+
+impl From<&PermittedAttribute> for NewPermittedAttribute {
+    fn from(a: &PermittedAttribute) -> Self {
+        let choices = if a.choices.is_empty() {
+            None
+        } else {
+            serde_json::to_string(&a.choices).ok()
+        };
+        Self {
+            key: a.key.to_owned(),
+            attribute_type: a.attribute_type,
+            attribute_description: a.attribute_description.to_owned(),
+            part_name: a.part_name.to_owned(),
+            part_type: a.part_type,
+            obligatory: a.obligatory,
+            default_num: a.default_num,
+            default_text: a.default_text.to_owned(),
+            min_value: a.min_value,
+            max_value: a.max_value,
+            choices,
+            formula: a.formula.to_owned(),
+        }
+    }
+}
+
+impl SystemRollMacro {
+    /// Get all the roll macros of the system.
+    pub(crate) fn load_all(root_conn: &SqliteConnection) -> Result<Vec<Self>, Error> {
+        use self::roll_macros::dsl::*;
+        roll_macros
+            .order_by(id.asc())
+            .load(root_conn)
+            .map_err(Error::from)
+    }
+
+    /// Get the roll macros of a kind of part.
+    pub(crate) fn load_for_part(
+        root_conn: &SqliteConnection,
+        p_name: &str,
+        p_type: Part,
+    ) -> Result<Vec<Self>, Error> {
+        use self::roll_macros::dsl::*;
+        roll_macros
+            .filter(part_name.eq(p_name).and(part_type.eq(p_type)))
+            .order_by(name.asc())
+            .load(root_conn)
+            .map_err(Error::from)
+    }
+}
+
+/// A roll macro, to be inserted in a root database.
+#[derive(Debug, Clone, PartialEq, Insertable)]
+#[table_name = "roll_macros"]
+pub(crate) struct NewSystemRollMacro {
+    pub(crate) name: String,
+    pub(crate) part_name: String,
+    pub(crate) part_type: Part,
+    pub(crate) attack: Option<String>,
+    pub(crate) damage: Option<String>,
 }
 
 #[cfg(test)]
 mod system_tests {
     use super::{PermittedAttribute, PermittedPart};
     use crate::root_db::tests;
-    use crate::shared::Part;
+    use crate::shared::{AttributeType, Part};
 
     use diesel::SqliteConnection;
 
@@ -302,6 +492,12 @@ mod system_tests {
                     part_name: Some(String::from("main")),
                     part_type: Some(Part::Main),
                     obligatory: true,
+                    default_num: None,
+                    default_text: None,
+                    min_value: None,
+                    max_value: None,
+                    choices: Vec::new(),
+                    formula: None,
                 },
                 PermittedAttribute {
                     key: String::from("class"),
@@ -310,6 +506,12 @@ mod system_tests {
                     part_name: Some(String::from("main")),
                     part_type: Some(Part::Main),
                     obligatory: true,
+                    default_num: None,
+                    default_text: None,
+                    min_value: None,
+                    max_value: None,
+                    choices: Vec::new(),
+                    formula: None,
                 },
                 PermittedAttribute {
                     key: String::from("character_alignment"),
@@ -318,6 +520,12 @@ mod system_tests {
                     part_name: Some(String::from("main")),
                     part_type: Some(Part::Main),
                     obligatory: true,
+                    default_num: None,
+                    default_text: None,
+                    min_value: None,
+                    max_value: None,
+                    choices: Vec::new(),
+                    formula: None,
                 },
             ]
         );
@@ -335,29 +543,47 @@ mod system_tests {
             &[
                 PermittedAttribute {
                     key: String::from("cha"),
-                    attribute_type: 0,
+                    attribute_type: AttributeType::Integer as i32,
                     attribute_description: String::from(
                         "Persuading someone to go to bed with you."
                     ),
                     part_name: Some(String::from("main")),
                     part_type: Some(Part::Main),
                     obligatory: true,
+                    default_num: None,
+                    default_text: None,
+                    min_value: None,
+                    max_value: None,
+                    choices: Vec::new(),
+                    formula: None,
                 },
                 PermittedAttribute {
                     key: String::from("ac"),
-                    attribute_type: 0,
+                    attribute_type: AttributeType::Integer as i32,
                     attribute_description: String::from("But can you hit me?"),
                     part_name: Some(String::from("main")),
                     part_type: Some(Part::Main),
                     obligatory: true,
+                    default_num: None,
+                    default_text: None,
+                    min_value: None,
+                    max_value: None,
+                    choices: Vec::new(),
+                    formula: None,
                 },
                 PermittedAttribute {
                     key: String::from("level"),
-                    attribute_type: 0,
+                    attribute_type: AttributeType::Integer as i32,
                     attribute_description: String::from("The character's race."),
                     part_name: Some(String::from("main")),
                     part_type: Some(Part::Main),
                     obligatory: true,
+                    default_num: None,
+                    default_text: None,
+                    min_value: None,
+                    max_value: None,
+                    choices: Vec::new(),
+                    formula: None,
                 },
             ]
         );
@@ -379,6 +605,12 @@ mod system_tests {
                 part_name: Some(String::from("Memory Sphere")),
                 part_type: Some(Part::InventoryItem),
                 obligatory: true,
+                default_num: None,
+                default_text: None,
+                min_value: None,
+                max_value: None,
+                choices: Vec::new(),
+                formula: None,
             },
             PermittedAttribute {
                 key: String::from("mana_consumption"),
@@ -387,6 +619,12 @@ mod system_tests {
                 part_name: Some(String::from("Memory Sphere")),
                 part_type: Some(Part::InventoryItem),
                 obligatory: true,
+                default_num: None,
+                default_text: None,
+                min_value: None,
+                max_value: None,
+                choices: Vec::new(),
+                formula: None,
             },
             PermittedAttribute {
                 key: String::from("memory_capacity"),
@@ -395,6 +633,12 @@ mod system_tests {
                 part_name: Some(String::from("Memory Sphere")),
                 part_type: Some(Part::InventoryItem),
                 obligatory: true,
+                default_num: None,
+                default_text: None,
+                min_value: None,
+                max_value: None,
+                choices: Vec::new(),
+                formula: None,
             },
             PermittedAttribute {
                 key: String::from("memory_sphere_alignment"),
@@ -403,6 +647,12 @@ mod system_tests {
                 part_name: Some(String::from("Memory Sphere")),
                 part_type: Some(Part::InventoryItem),
                 obligatory: true,
+                default_num: None,
+                default_text: None,
+                min_value: None,
+                max_value: None,
+                choices: Vec::new(),
+                formula: None,
             },]
         );
     }
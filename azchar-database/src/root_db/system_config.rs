@@ -3,26 +3,47 @@
 use crate::root_db::system::PermittedAttribute as DbPermittedAttribute;
 use crate::root_db::system::PermittedPart as DbPermittedPart;
 use crate::root_db::system::{NewPermittedAttribute, NewPermittedPart};
+use crate::root_db::system::{NewSystemRollMacro, SystemRollMacro};
+use crate::root_db::CharacterDbRef;
 use crate::shared::*;
 use crate::LoadedDbs;
-use azchar_error::ma;
+use azchar_error::Error;
 
 use diesel::result::Error as DsError;
-use diesel::RunQueryDsl;
+use diesel::{RunQueryDsl, SqliteConnection};
+use serde::{Deserialize, Deserializer};
 
 use std::fs::File;
-use std::io::Read;
-use std::path::PathBuf;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
 
 // Create all needed tables
 embed_migrations!("migrations_root_db");
 
+/// Bring a root database made by an older version up to date.
+/// The paths of its sheets are made relative to `root_dir` every time, since a path
+/// left absolute by a failed load, or written by an older version, would otherwise stay so.
+pub(crate) fn migrate_root(root_conn: &SqliteConnection, root_dir: &Path) -> Result<(), Error> {
+    embedded_migrations::run(root_conn)?;
+    CharacterDbRef::make_paths_relative(root_conn, root_dir)
+}
+
 /// This represents a part that is permitted and that will be created on a new sheet.
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub(super) struct PermittedPart {
-    part_name: String,
-    part_type: Part,
-    obligatory: bool,
+    pub(super) part_name: String,
+    pub(super) part_type: Part,
+    pub(super) obligatory: bool,
+}
+
+impl From<DbPermittedPart> for PermittedPart {
+    fn from(p: DbPermittedPart) -> Self {
+        Self {
+            part_name: p.part_name,
+            part_type: p.part_type,
+            obligatory: p.obligatory,
+        }
+    }
 }
 
 impl From<PermittedPart> for NewPermittedPart {
@@ -38,16 +59,80 @@ impl From<PermittedPart> for NewPermittedPart {
 /// This represents a permitted attribute, to be created on a new sheet.
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub(super) struct PermittedAttribute {
-    key: String,
-    attribute_type: i32,
-    attribute_description: String,
-    part_name: Option<String>,
-    part_type: Option<Part>,
-    obligatory: bool,
+    pub(super) key: String,
+    /// Either the number or the name of an `AttributeType`.
+    #[serde(deserialize_with = "attribute_type_from_toml")]
+    pub(super) attribute_type: i32,
+    pub(super) attribute_description: String,
+    pub(super) part_name: Option<String>,
+    pub(super) part_type: Option<Part>,
+    pub(super) obligatory: bool,
+    /// What the attribute is set to on a new sheet or part.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(super) default: Option<AttributeDefault>,
+    /// The lowest number the attribute may hold.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(super) min: Option<f64>,
+    /// The highest number the attribute may hold.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(super) max: Option<f64>,
+    /// If not empty, the only text the attribute may hold.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(super) choices: Vec<String>,
+    /// Makes this a derived attribute, eg. "floor((@str - 10) / 2)".
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(super) formula: Option<String>,
 }
 
-impl From<PermittedAttribute> for NewPermittedAttribute {
-    fn from(a: PermittedAttribute) -> Self {
+/// A default value as it is written in the TOML.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub(super) enum AttributeDefault {
+    Boolean(bool),
+    Integer(i64),
+    Decimal(f64),
+    Text(String),
+}
+
+impl PermittedAttribute {
+    /// The default as `value_num` and `value_text`. Which one is used depends on the type.
+    pub(super) fn default_values(&self) -> (Option<i64>, Option<String>) {
+        let uses_text = AttributeType::from(self.attribute_type).uses_text();
+        match self.default {
+            None => (None, None),
+            Some(AttributeDefault::Boolean(b)) => (Some(b as i64), None),
+            Some(AttributeDefault::Integer(n)) if uses_text => (None, Some(n.to_string())),
+            Some(AttributeDefault::Integer(n)) => (Some(n), None),
+            Some(AttributeDefault::Decimal(n)) => (None, Some(n.to_string())),
+            Some(AttributeDefault::Text(ref t)) => (None, Some(t.to_owned())),
+        }
+    }
+}
+
+fn attribute_type_from_toml<'de, D: Deserializer<'de>>(d: D) -> Result<i32, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum NumberOrName {
+        Number(i32),
+        Name(AttributeType),
+    }
+    match NumberOrName::deserialize(d)? {
+        NumberOrName::Number(n) => Ok(n),
+        NumberOrName::Name(t) => Ok(t as i32),
+    }
+}
+
+impl From<DbPermittedAttribute> for PermittedAttribute {
+    fn from(a: DbPermittedAttribute) -> Self {
+        let value_type = a.value_type();
+        let default = match (a.default_num, a.default_text) {
+            (Some(n), _) if value_type == AttributeType::Boolean => {
+                Some(AttributeDefault::Boolean(n != 0))
+            }
+            (Some(n), _) => Some(AttributeDefault::Integer(n)),
+            (None, Some(t)) => Some(AttributeDefault::Text(t)),
+            (None, None) => None,
+        };
         Self {
             key: a.key,
             attribute_type: a.attribute_type,
@@ -55,6 +140,75 @@ impl From<PermittedAttribute> for NewPermittedAttribute {
             part_name: a.part_name,
             part_type: a.part_type,
             obligatory: a.obligatory,
+            default,
+            min: a.min_value,
+            max: a.max_value,
+            choices: a.choices,
+            formula: a.formula,
+        }
+    }
+}
+
+impl From<PermittedAttribute> for NewPermittedAttribute {
+    fn from(a: PermittedAttribute) -> Self {
+        (&DbPermittedAttribute::from(&a)).into()
+    }
+}
+
+impl From<&PermittedAttribute> for DbPermittedAttribute {
+    fn from(a: &PermittedAttribute) -> Self {
+        let (default_num, default_text) = a.default_values();
+        Self {
+            key: a.key.to_owned(),
+            attribute_type: a.attribute_type,
+            attribute_description: a.attribute_description.to_owned(),
+            part_name: a.part_name.to_owned(),
+            part_type: a.part_type,
+            obligatory: a.obligatory,
+            default_num,
+            default_text,
+            min_value: a.min,
+            max_value: a.max,
+            choices: a.choices.to_owned(),
+            formula: a.formula.to_owned(),
+        }
+    }
+}
+
+/// This represents a roll macro which every part of a kind has.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(super) struct RollMacro {
+    pub(super) name: String,
+    pub(super) part_name: String,
+    pub(super) part_type: Part,
+    /// The roll to hit, eg. "1d20+@str_mod+@weapon_attack_bonus".
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(super) attack: Option<String>,
+    /// The roll for damage, eg. "@weapon_damage1+@str_mod".
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(super) damage: Option<String>,
+}
+
+impl From<SystemRollMacro> for RollMacro {
+    fn from(m: SystemRollMacro) -> Self {
+        Self {
+            name: m.name,
+            part_name: m.part_name,
+            part_type: m.part_type,
+            attack: m.attack,
+            damage: m.damage,
+        }
+    }
+}
+
+impl From<RollMacro> for NewSystemRollMacro {
+    fn from(m: RollMacro) -> Self {
+        Self {
+            name: m.name,
+            part_name: m.part_name,
+            part_type: m.part_type,
+            attack: m.attack,
+            damage: m.damage,
         }
     }
 }
@@ -62,71 +216,116 @@ impl From<PermittedAttribute> for NewPermittedAttribute {
 /// This structure can recreate the system configuration for a game system.
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct SystemConfig {
-    permitted_parts: Vec<PermittedPart>,
-    permitted_attributes: Vec<PermittedAttribute>,
+    pub(super) permitted_parts: Vec<PermittedPart>,
+    pub(super) permitted_attributes: Vec<PermittedAttribute>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(super) roll_macros: Vec<RollMacro>,
 }
 
 impl SystemConfig {
     // Create an instance of `SystemConfig` from a config toml.
-    pub fn from_config(system_config_path: &std::path::Path) -> Result<Self, String> {
-        let mut config_file = std::fs::File::open(system_config_path).map_err(ma)?;
+    pub fn from_config(system_config_path: &std::path::Path) -> Result<Self, Error> {
+        let mut config_file = std::fs::File::open(system_config_path)?;
         let mut config_string = String::new();
-        config_file.read_to_string(&mut config_string).map_err(ma)?;
-        toml::from_str(&config_string).map_err(ma)
+        config_file.read_to_string(&mut config_string)?;
+        toml::from_str(&config_string).map_err(Error::from)
+    }
+
+    /// Recreate the configuration of an existing system from its root database.
+    pub fn from_root_db(root_conn: &SqliteConnection) -> Result<Self, Error> {
+        let permitted_parts = DbPermittedPart::load_all(root_conn)?
+            .into_iter()
+            .map(Into::into)
+            .collect();
+        let permitted_attributes = DbPermittedAttribute::load_all(root_conn)?
+            .into_iter()
+            .map(Into::into)
+            .collect();
+        let roll_macros = SystemRollMacro::load_all(root_conn)?
+            .into_iter()
+            .map(Into::into)
+            .collect();
+        Ok(Self {
+            permitted_parts,
+            permitted_attributes,
+            roll_macros,
+        })
+    }
+
+    /// Write the configuration as a toml, which `from_config` can read back.
+    pub fn to_config(&self, system_config_path: &std::path::Path) -> Result<(), Error> {
+        let config_string = toml::to_string(self)?;
+        let mut config_file = File::create(system_config_path)?;
+        config_file.write_all(config_string.as_bytes())?;
+        Ok(())
+    }
+
+    /// The permitted parts, attributes and roll macros, ready to be inserted in a root database.
+    pub(crate) fn into_new_permitted(
+        self,
+    ) -> (
+        Vec<NewPermittedPart>,
+        Vec<NewPermittedAttribute>,
+        Vec<NewSystemRollMacro>,
+    ) {
+        let permitted_parts = self.permitted_parts.into_iter().map(Into::into).collect();
+        let permitted_attributes = self
+            .permitted_attributes
+            .into_iter()
+            .map(Into::into)
+            .collect();
+        let roll_macros = self.roll_macros.into_iter().map(Into::into).collect();
+        (permitted_parts, permitted_attributes, roll_macros)
     }
 
     /// This function exists to:
-    // a) Create the root database with all three tables.
-    // b) Insert permitted attributes and parts into it.
+    // a) Create the root database with all its tables.
+    // b) Insert permitted attributes, parts and roll macros into it.
     pub fn into_system(
         self,
         path: &std::path::Path,
         system_name: &str,
-    ) -> Result<LoadedDbs, String> {
+    ) -> Result<LoadedDbs, Error> {
         // The required tables.
         use crate::root_db::system::permitted_attributes::dsl as pa_dsl;
         use crate::root_db::system::permitted_parts::dsl as pp_dsl;
+        use crate::root_db::system::roll_macros::dsl as rm_dsl;
 
+        self.check()?;
         let file_name = format!("{}.db", system_name);
         let file_path = PathBuf::from(path).join(&file_name);
         if file_path.exists() {
-            return Err(format!(
+            return Err(Error::conflict(format!(
                 "{:?} already exists as a file! Try again.",
                 file_path
-            ));
+            )));
         }
 
-        let _sheet_db = File::create(file_path.clone()).map_err(ma)?;
-        let file_path = file_path.canonicalize().map_err(ma)?;
+        let _sheet_db = File::create(file_path.clone())?;
+        let file_path = file_path.canonicalize()?;
         let file_path_string = file_path.to_string_lossy();
 
         let mut loaded_dbs = LoadedDbs::new_system(&file_path_string)?;
         let new_root = loaded_dbs.get_inner_root()?;
         crate::set_pragma(new_root)?;
 
-        let Self {
-            permitted_parts,
-            permitted_attributes,
-        } = self;
-        let permitted_attributes: Vec<NewPermittedAttribute> =
-            permitted_attributes.into_iter().map(Into::into).collect();
-        let permitted_parts: Vec<NewPermittedPart> =
-            permitted_parts.into_iter().map(Into::into).collect();
-
-        embedded_migrations::run(new_root).map_err(ma)?;
-
-        new_root
-            .immediate_transaction::<_, DsError, _>(|| {
-                // Insert values as needed.
-                diesel::insert_into(pp_dsl::permitted_parts)
-                    .values(&permitted_parts)
-                    .execute(new_root)?;
-                diesel::insert_into(pa_dsl::permitted_attributes)
-                    .values(&permitted_attributes)
-                    .execute(new_root)?;
-                Ok(())
-            })
-            .map_err(ma)?;
+        let (permitted_parts, permitted_attributes, roll_macros) = self.into_new_permitted();
+
+        embedded_migrations::run(new_root)?;
+
+        new_root.immediate_transaction::<_, DsError, _>(|| {
+            // Insert values as needed.
+            diesel::insert_into(pp_dsl::permitted_parts)
+                .values(&permitted_parts)
+                .execute(new_root)?;
+            diesel::insert_into(pa_dsl::permitted_attributes)
+                .values(&permitted_attributes)
+                .execute(new_root)?;
+            diesel::insert_into(rm_dsl::roll_macros)
+                .values(&roll_macros)
+                .execute(new_root)?;
+            Ok(())
+        })?;
 
         let pp = DbPermittedPart::load_all(new_root)?;
         let pa = DbPermittedAttribute::load_all(new_root)?;
@@ -141,7 +340,7 @@ impl SystemConfig {
 #[cfg(test)]
 mod system_config_tests {
     use super::{PermittedAttribute, PermittedPart, SystemConfig};
-    use crate::root_db::tests::MEMORY_SPHERE;
+    use crate::root_db::tests::{self, MEMORY_SPHERE};
     use crate::shared::*;
 
     #[test]
@@ -206,6 +405,11 @@ mod system_config_tests {
             part_name: Some(String::from("spell")),
             part_type: Some(Part::Ability),
             obligatory: true,
+            default: None,
+            min: None,
+            max: None,
+            choices: Vec::new(),
+            formula: None,
         };
         assert_eq!(expected, toml::from_str(&a).expect("Could not toml"));
     }
@@ -227,6 +431,11 @@ mod system_config_tests {
             part_name: Some(String::from("Giant Cupcake")),
             part_type: Some(Part::Summon),
             obligatory: true,
+            default: None,
+            min: None,
+            max: None,
+            choices: Vec::new(),
+            formula: None,
         };
         assert_eq!(expected, toml::from_str(&a).expect("Could not toml"));
     }
@@ -248,6 +457,11 @@ mod system_config_tests {
             part_name: Some(String::from("ranged_weapon")),
             part_type: Some(Part::InventoryItem),
             obligatory: true,
+            default: None,
+            min: None,
+            max: None,
+            choices: Vec::new(),
+            formula: None,
         };
         assert_eq!(expected, toml::from_str(&a).expect("Could not toml"));
     }
@@ -269,10 +483,29 @@ mod system_config_tests {
             part_name: Some(String::from("base")),
             part_type: Some(Part::Asset),
             obligatory: true,
+            default: None,
+            min: None,
+            max: None,
+            choices: Vec::new(),
+            formula: None,
         };
         assert_eq!(expected, toml::from_str(&a).expect("Could not toml"));
     }
 
+    #[test]
+    fn permitted_attributes_from_toml_with_type_name() {
+        let a = "\
+    key = \"spell_damage\"
+    attribute_type = \"Dice\"
+    attribute_description = \"You roll HOW MANY d6s!?\"
+    part_name = \"spell\"
+    part_type = \"Ability\"
+    obligatory = true
+    ";
+        let attribute: PermittedAttribute = toml::from_str(a).expect("Could not toml");
+        assert_eq!(attribute.attribute_type, AttributeType::Dice as i32);
+    }
+
     #[test]
     fn system_config_from_toml1() {
         let a = MEMORY_SPHERE;
@@ -302,6 +535,11 @@ mod system_config_tests {
                     part_name: Some(String::from("main")),
                     part_type: Some(Part::Main),
                     obligatory: true,
+                    default: None,
+                    min: None,
+                    max: None,
+                    choices: Vec::new(),
+                    formula: None,
                 },
                 PermittedAttribute {
                     key: String::from("class"),
@@ -310,6 +548,11 @@ mod system_config_tests {
                     part_name: Some(String::from("main")),
                     part_type: Some(Part::Main),
                     obligatory: true,
+                    default: None,
+                    min: None,
+                    max: None,
+                    choices: Vec::new(),
+                    formula: None,
                 },
                 PermittedAttribute {
                     key: String::from("character_alignment"),
@@ -318,6 +561,11 @@ mod system_config_tests {
                     part_name: Some(String::from("main")),
                     part_type: Some(Part::Main),
                     obligatory: true,
+                    default: None,
+                    min: None,
+                    max: None,
+                    choices: Vec::new(),
+                    formula: None,
                 },
                 PermittedAttribute {
                     key: String::from("mana_type"),
@@ -326,6 +574,11 @@ mod system_config_tests {
                     part_name: Some(String::from("Memory Sphere")),
                     part_type: Some(Part::InventoryItem),
                     obligatory: true,
+                    default: None,
+                    min: None,
+                    max: None,
+                    choices: Vec::new(),
+                    formula: None,
                 },
                 PermittedAttribute {
                     key: String::from("mana_consumption"),
@@ -334,6 +587,11 @@ mod system_config_tests {
                     part_name: Some(String::from("Memory Sphere")),
                     part_type: Some(Part::InventoryItem),
                     obligatory: true,
+                    default: None,
+                    min: None,
+                    max: None,
+                    choices: Vec::new(),
+                    formula: None,
                 },
                 PermittedAttribute {
                     key: String::from("memory_capacity"),
@@ -342,6 +600,11 @@ mod system_config_tests {
                     part_name: Some(String::from("Memory Sphere")),
                     part_type: Some(Part::InventoryItem),
                     obligatory: true,
+                    default: None,
+                    min: None,
+                    max: None,
+                    choices: Vec::new(),
+                    formula: None,
                 },
                 PermittedAttribute {
                     key: String::from("memory_sphere_alignment"),
@@ -350,8 +613,14 @@ mod system_config_tests {
                     part_name: Some(String::from("Memory Sphere")),
                     part_type: Some(Part::InventoryItem),
                     obligatory: true,
+                    default: None,
+                    min: None,
+                    max: None,
+                    choices: Vec::new(),
+                    formula: None,
                 },
             ],
+            roll_macros: Vec::new(),
         };
         assert_eq!(expected, toml::from_str(&a).expect("Could not toml"));
     }
@@ -362,6 +631,37 @@ mod system_config_tests {
         let dnd5toml: SystemConfig = toml::from_str(&text).expect("ho ho ho");
 
         assert_eq!(dnd5toml.permitted_parts.len(), 10);
-        assert_eq!(dnd5toml.permitted_attributes.len(), 126);
+        assert_eq!(dnd5toml.permitted_attributes.len(), 133);
+        assert_eq!(dnd5toml.roll_macros.len(), 1);
+        assert_eq!(dnd5toml.roll_macros[0].part_name, "weapon");
+    }
+
+    #[test]
+    fn export_system_round_trip() {
+        let mut setup = tests::setup(tests::TestSystem::DnD5);
+        let root = setup.loaded_dbs.get_inner_root().expect("Root is there.");
+        let exported = SystemConfig::from_root_db(root).expect("Could not export.");
+
+        let path = setup.root_dir.path().join("exported.toml");
+        exported.to_config(&path).expect("Could not write.");
+        let reread = SystemConfig::from_config(&path).expect("Could not read back.");
+        assert_eq!(exported, reread);
+
+        // Nothing is lost on the way, even if the order can change.
+        let text = std::fs::read_to_string("../examples/dnd5e0.toml").expect("Yes.");
+        let original: SystemConfig = toml::from_str(&text).expect("ho ho ho");
+        assert_eq!(original.permitted_parts, reread.permitted_parts);
+        assert_eq!(
+            original.permitted_attributes.len(),
+            reread.permitted_attributes.len()
+        );
+        for a in original.permitted_attributes.iter() {
+            assert!(
+                reread.permitted_attributes.contains(a),
+                "{:?} is missing.",
+                a
+            );
+        }
+        assert_eq!(original.roll_macros, reread.roll_macros);
     }
 }
@@ -0,0 +1,722 @@
+//! This checks a system config for mistakes before it is turned into a system.
+//! Warnings are things which are allowed, but are probably not what was meant.
+//! Errors are mistakes, but only those a system can't be made with stop it from being
+//! created (see `SystemConfig::check`), because configs which loaded before they were
+//! checked should still load.
+use super::system::PermittedAttribute as DbPermittedAttribute;
+use super::system_config::{PermittedAttribute, PermittedPart, SystemConfig};
+use crate::character::formula;
+use crate::shared::{AttributeType, Part};
+use azchar_error::Error;
+
+use fnv::{FnvHashMap, FnvHashSet};
+use std::fmt;
+
+/// How bad a problem is.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+/// A single problem with a system config.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    /// Where in the config, eg. "permitted_attributes[34]".
+    pub location: String,
+    /// The line in the config file, if it is known.
+    pub line: Option<usize>,
+    pub message: String,
+}
+
+impl Diagnostic {
+    fn error(location: String, message: String) -> Self {
+        Self {
+            severity: Severity::Error,
+            location,
+            line: None,
+            message,
+        }
+    }
+
+    fn warning(location: String, message: String) -> Self {
+        Self {
+            severity: Severity::Warning,
+            location,
+            line: None,
+            message,
+        }
+    }
+
+    pub fn is_error(&self) -> bool {
+        self.severity == Severity::Error
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let severity = match self.severity {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        };
+        match self.line {
+            Some(l) => write!(
+                f,
+                "{}: {} (line {}): {}",
+                severity, self.location, l, self.message
+            ),
+            None => write!(f, "{}: {}: {}", severity, self.location, self.message),
+        }
+    }
+}
+
+impl SystemConfig {
+    /// Check a config file, giving the line of every problem found.
+    pub fn lint_file(system_config_path: &std::path::Path) -> Result<Vec<Diagnostic>, Error> {
+        let text = std::fs::read_to_string(system_config_path)?;
+        Self::lint_text(&text)
+    }
+
+    /// Check the text of a config, giving the line of every problem found.
+    fn lint_text(text: &str) -> Result<Vec<Diagnostic>, Error> {
+        let config: Self = toml::from_str(text)?;
+        let mut diagnostics = config.lint();
+        config.find_lines(text, &mut diagnostics);
+        Ok(diagnostics)
+    }
+
+    /// Find everything that looks wrong with the config.
+    pub fn lint(&self) -> Vec<Diagnostic> {
+        let mut diagnostics = Vec::new();
+        self.lint_parts(&mut diagnostics);
+        self.lint_main_part(&mut diagnostics);
+        self.lint_attributes(&mut diagnostics);
+        self.lint_formulas(&mut diagnostics);
+        self.lint_roll_macros(&mut diagnostics);
+        diagnostics
+    }
+
+    /// Fail with the errors a system can't be made with, if there are any.
+    /// These are a missing main part, and mistakes in defaults, bounds, choices,
+    /// formulas and roll macros. Duplicated parts and keys, and keys of parts which
+    /// don't exist, are errors for `lint` but are let through, as they always were.
+    pub(crate) fn check(&self) -> Result<(), Error> {
+        let mut diagnostics = Vec::new();
+        self.lint_main_part(&mut diagnostics);
+        for (i, a) in self.permitted_attributes.iter().enumerate() {
+            let location = format!("permitted_attributes[{}]", i);
+            lint_attribute_values(a, &location, &mut diagnostics);
+        }
+        self.lint_formulas(&mut diagnostics);
+        self.lint_roll_macros(&mut diagnostics);
+        let errors = diagnostics
+            .into_iter()
+            .filter(Diagnostic::is_error)
+            .map(|d| d.to_string())
+            .collect::<Vec<_>>();
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(Error::validation(errors.join("; ")))
+        }
+    }
+
+    fn lint_parts(&self, diagnostics: &mut Vec<Diagnostic>) {
+        let mut seen = FnvHashMap::default();
+        for (i, p) in self.permitted_parts.iter().enumerate() {
+            if let Some(first) = seen.insert((&p.part_name, p.part_type), i) {
+                let m = format!(
+                    "Part '{}' ({:?}) is already defined at permitted_parts[{}].",
+                    p.part_name, p.part_type, first
+                );
+                diagnostics.push(Diagnostic::error(format!("permitted_parts[{}]", i), m));
+            }
+        }
+    }
+
+    /// Check that there is exactly one main part.
+    fn lint_main_part(&self, diagnostics: &mut Vec<Diagnostic>) {
+        let mut main = None;
+        for (i, p) in self.permitted_parts.iter().enumerate() {
+            let location = format!("permitted_parts[{}]", i);
+            if p.part_type == Part::Main {
+                match main {
+                    Some(first) => {
+                        let m = format!(
+                            "There can only be one main part, and permitted_parts[{}] is one already.",
+                            first
+                        );
+                        diagnostics.push(Diagnostic::error(location, m));
+                    }
+                    None => main = Some(i),
+                }
+            }
+        }
+        if main.is_none() {
+            let m = "There is no part with part_type = \"Main\".".to_owned();
+            diagnostics.push(Diagnostic::error("permitted_parts".to_owned(), m));
+        }
+    }
+
+    fn lint_attributes(&self, diagnostics: &mut Vec<Diagnostic>) {
+        let mut seen = FnvHashMap::default();
+        let mut seen_lowercase = FnvHashMap::default();
+        for (i, a) in self.permitted_attributes.iter().enumerate() {
+            let location = format!("permitted_attributes[{}]", i);
+            // A key can be used by several parts, but only once by each.
+            if let Some(first) = seen.insert((&a.key, &a.part_name, a.part_type), i) {
+                let m = format!(
+                    "Key '{}' is already defined for this part at permitted_attributes[{}].",
+                    a.key, first
+                );
+                diagnostics.push(Diagnostic::error(location.to_owned(), m));
+            } else if let Some(first) =
+                seen_lowercase.insert((a.key.to_lowercase(), &a.part_name, a.part_type), i)
+            {
+                let m = format!(
+                    "Key '{}' only differs by case from '{}' at permitted_attributes[{}].",
+                    a.key, self.permitted_attributes[first].key, first
+                );
+                diagnostics.push(Diagnostic::error(location.to_owned(), m));
+            }
+            if !AttributeType::is_defined(a.attribute_type) {
+                let m = format!(
+                    "Key '{}' has attribute_type = {}, which is not a known type, so its values are not checked.",
+                    a.key, a.attribute_type
+                );
+                diagnostics.push(Diagnostic::warning(location.to_owned(), m));
+            }
+            lint_attribute_values(a, &location, diagnostics);
+            self.lint_attribute_part(a, &location, diagnostics);
+            self.lint_key_prefix(a, &location, diagnostics);
+        }
+    }
+
+    /// Check that formulas parse, only refer to values which exist, and don't depend on themselves.
+    /// NB: Keys are not told apart by part here, so this is a little stricter than it needs to be.
+    fn lint_formulas(&self, diagnostics: &mut Vec<Diagnostic>) {
+        let keys = self
+            .permitted_attributes
+            .iter()
+            .map(|a| a.key.as_str())
+            .collect::<FnvHashSet<_>>();
+        let mut depends_on = FnvHashMap::<&str, Vec<String>>::default();
+        let mut locations = Vec::new();
+        for (i, a) in self.permitted_attributes.iter().enumerate() {
+            let location = format!("permitted_attributes[{}]", i);
+            let expr = match a.formula.as_ref().map(|f| formula::parse(f)) {
+                None => continue,
+                Some(Ok(expr)) => expr,
+                Some(Err(e)) => {
+                    let m = format!("The formula of key '{}' does not parse: {}", a.key, e);
+                    diagnostics.push(Diagnostic::error(location, m));
+                    continue;
+                }
+            };
+            let mut references = expr.references();
+            references.sort_unstable();
+            references.dedup();
+            for r in references.iter() {
+                if !keys.contains(r) && !formula::BUILT_IN_FIELDS.contains(r) {
+                    let m = format!(
+                        "The formula of key '{}' refers to '{}', which is neither a key nor a built in field.",
+                        a.key, r
+                    );
+                    diagnostics.push(Diagnostic::error(location.to_owned(), m));
+                }
+            }
+            depends_on
+                .entry(&a.key)
+                .or_default()
+                .extend(references.into_iter().map(String::from));
+            locations.push((a.key.as_str(), location));
+        }
+
+        let mut done = FnvHashSet::default();
+        for (key, location) in locations.into_iter() {
+            let mut path = Vec::new();
+            if let Some(cycle) = find_cycle(key, &depends_on, &mut path, &mut done) {
+                done.extend(path);
+                let m = format!(
+                    "The formula of key '{}' depends on itself: {}.",
+                    cycle[0],
+                    cycle.join(" -> ")
+                );
+                diagnostics.push(Diagnostic::error(location, m));
+            }
+        }
+    }
+
+    /// Check that the part an attribute belongs to exists.
+    fn lint_attribute_part(
+        &self,
+        a: &PermittedAttribute,
+        location: &str,
+        diagnostics: &mut Vec<Diagnostic>,
+    ) {
+        let parts = self
+            .permitted_parts
+            .iter()
+            .filter(|p| {
+                a.part_name
+                    .as_ref()
+                    .map(|n| n == &p.part_name)
+                    .unwrap_or(true)
+            })
+            .filter(|p| a.part_type.map(|t| t == p.part_type).unwrap_or(true))
+            .collect::<Vec<&PermittedPart>>();
+        if parts.is_empty() {
+            let m = match (&a.part_name, a.part_type) {
+                (Some(n), _) if !self.permitted_parts.iter().any(|p| &p.part_name == n) => {
+                    format!(
+                        "Key '{}' belongs to part '{}', which does not exist.",
+                        a.key, n
+                    )
+                }
+                (Some(n), Some(t)) => {
+                    format!(
+                        "Key '{}' belongs to part '{}' ({:?}), which does not exist.",
+                        a.key, n, t
+                    )
+                }
+                (_, t) => format!(
+                    "Key '{}' belongs to parts of type {:?}, and there are none.",
+                    a.key, t
+                ),
+            };
+            diagnostics.push(Diagnostic::error(location.to_owned(), m));
+        } else if a.obligatory && parts.iter().all(|p| !p.obligatory) {
+            let m = format!(
+                "Key '{}' is obligatory, but only for parts which are not.",
+                a.key
+            );
+            diagnostics.push(Diagnostic::warning(location.to_owned(), m));
+        }
+    }
+
+    /// Most keys start with the name of their part, eg. "armour_ac".
+    /// A key which starts with another part's name, or with something very close
+    /// to a part's name, was probably put in the wrong place or misspelled.
+    fn lint_key_prefix(
+        &self,
+        a: &PermittedAttribute,
+        location: &str,
+        diagnostics: &mut Vec<Diagnostic>,
+    ) {
+        let own = match a.part_name {
+            Some(ref n) => n,
+            None => return,
+        };
+        let prefix = match a.key.split_once('_') {
+            Some((prefix, _)) => prefix,
+            None => return,
+        };
+        if prefix == own {
+            return;
+        }
+        if let Some(other) = self.permitted_parts.iter().find(|p| p.part_name == prefix) {
+            let m = format!(
+                "Key '{}' looks like it is meant for part '{}', but belongs to '{}'.",
+                a.key, other.part_name, own
+            );
+            diagnostics.push(Diagnostic::warning(location.to_owned(), m));
+        } else if let Some(close) = self
+            .permitted_parts
+            .iter()
+            .find(|p| prefix.len() > 3 && edit_distance(prefix, &p.part_name) == 1)
+        {
+            let m = format!(
+                "Key '{}' starts with '{}', did you mean '{}'?",
+                a.key, prefix, close.part_name
+            );
+            diagnostics.push(Diagnostic::warning(location.to_owned(), m));
+        }
+    }
+
+    /// Check that every roll macro rolls something, for a part which exists.
+    fn lint_roll_macros(&self, diagnostics: &mut Vec<Diagnostic>) {
+        let mut seen = FnvHashMap::default();
+        for (i, m) in self.roll_macros.iter().enumerate() {
+            let location = format!("roll_macros[{}]", i);
+            if let Some(first) = seen.insert((&m.name, &m.part_name, m.part_type), i) {
+                let msg = format!(
+                    "Roll macro '{}' is already defined for this part at roll_macros[{}].",
+                    m.name, first
+                );
+                diagnostics.push(Diagnostic::error(location.to_owned(), msg));
+            }
+            if m.attack.is_none() && m.damage.is_none() {
+                let msg = format!("Roll macro '{}' has neither an attack nor damage.", m.name);
+                diagnostics.push(Diagnostic::error(location.to_owned(), msg));
+            }
+            if !self
+                .permitted_parts
+                .iter()
+                .any(|p| p.part_name == m.part_name && p.part_type == m.part_type)
+            {
+                let msg = format!(
+                    "Roll macro '{}' is for part '{}' ({:?}), which does not exist.",
+                    m.name, m.part_name, m.part_type
+                );
+                diagnostics.push(Diagnostic::error(location, msg));
+            }
+        }
+    }
+
+    /// Look up the line of every diagnostic in the text the config was read from.
+    fn find_lines(&self, text: &str, diagnostics: &mut [Diagnostic]) {
+        // Whitespace is ignored, so that `key="x"` and `key = "x"` are found alike.
+        let lines = text
+            .lines()
+            .map(squeeze)
+            .enumerate()
+            .filter(|(_, l)| !l.starts_with('#'))
+            .collect::<Vec<_>>();
+        let nth_line = |needle: &str, skip: &str, n: usize| {
+            lines
+                .iter()
+                .filter(|(_, l)| l.contains(needle) && (skip.is_empty() || !l.contains(skip)))
+                .nth(n)
+                .map(|(i, _)| i + 1)
+        };
+        for d in diagnostics.iter_mut() {
+            d.line = if let Some(i) = index_of(&d.location, "permitted_attributes[") {
+                let key = &self.permitted_attributes[i].key;
+                let n = self.permitted_attributes[..i]
+                    .iter()
+                    .filter(|a| &a.key == key)
+                    .count();
+                nth_line(&squeeze(&format!("key=\"{}\"", key)), "", n)
+            } else if let Some(i) = index_of(&d.location, "permitted_parts[") {
+                let name = &self.permitted_parts[i].part_name;
+                let n = self.permitted_parts[..i]
+                    .iter()
+                    .filter(|p| &p.part_name == name)
+                    .count();
+                nth_line(&squeeze(&format!("part_name=\"{}\"", name)), "key=", n)
+            } else if let Some(i) = index_of(&d.location, "roll_macros[") {
+                let name = &self.roll_macros[i].name;
+                let n = self.roll_macros[..i]
+                    .iter()
+                    .filter(|m| &m.name == name)
+                    .count();
+                nth_line(&squeeze(&format!("{{name=\"{}\"", name)), "", n)
+            } else {
+                None
+            };
+        }
+    }
+}
+
+/// Check that the default, bounds and choices of an attribute agree with each other.
+fn lint_attribute_values(
+    a: &PermittedAttribute,
+    location: &str,
+    diagnostics: &mut Vec<Diagnostic>,
+) {
+    if let (Some(min), Some(max)) = (a.min, a.max) {
+        if min > max {
+            let m = format!(
+                "Key '{}' has min = {}, which is more than max = {}.",
+                a.key, min, max
+            );
+            diagnostics.push(Diagnostic::error(location.to_owned(), m));
+        }
+    }
+    if a.default.is_some() {
+        let (num, text) = a.default_values();
+        if let Err(e) = DbPermittedAttribute::from(a).check_value(num, &text) {
+            let m = format!("The default of key '{}' is not allowed: {}", a.key, e);
+            diagnostics.push(Diagnostic::error(location.to_owned(), m));
+        }
+    }
+    let value_type = AttributeType::from(a.attribute_type);
+    if !a.choices.is_empty() && !value_type.uses_text() && value_type != AttributeType::Untyped {
+        let m = format!(
+            "Key '{}' has choices, but holds a number, so they are not checked.",
+            a.key
+        );
+        diagnostics.push(Diagnostic::warning(location.to_owned(), m));
+    } else if a.choices.is_empty() && value_type == AttributeType::Enumeration {
+        let m = format!("Key '{}' is an enumeration without choices.", a.key);
+        diagnostics.push(Diagnostic::warning(location.to_owned(), m));
+    }
+}
+
+/// Follow the references of formulas from `key`, and return the first loop found.
+fn find_cycle<'a>(
+    key: &'a str,
+    depends_on: &'a FnvHashMap<&'a str, Vec<String>>,
+    path: &mut Vec<&'a str>,
+    done: &mut FnvHashSet<&'a str>,
+) -> Option<Vec<&'a str>> {
+    if let Some(start) = path.iter().position(|k| *k == key) {
+        let mut cycle = path[start..].to_vec();
+        cycle.push(key);
+        return Some(cycle);
+    }
+    if done.contains(key) {
+        return None;
+    }
+    path.push(key);
+    for r in depends_on.get(key).into_iter().flatten() {
+        if let Some(cycle) = find_cycle(r, depends_on, path, done) {
+            return Some(cycle);
+        }
+    }
+    path.pop();
+    done.insert(key);
+    None
+}
+
+fn squeeze(s: &str) -> String {
+    s.chars().filter(|c| !c.is_whitespace()).collect()
+}
+
+fn index_of(location: &str, list: &str) -> Option<usize> {
+    location
+        .strip_prefix(list)
+        .and_then(|rest| rest.strip_suffix(']'))
+        .and_then(|i| i.parse().ok())
+}
+
+/// The number of single character changes needed to turn one string into another.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b = b.chars().collect::<Vec<_>>();
+    let mut row = (0..=b.len()).collect::<Vec<_>>();
+    for (i, ca) in a.chars().enumerate() {
+        let mut previous = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = previous + if ca == *cb { 0 } else { 1 };
+            previous = row[j + 1];
+            row[j + 1] = substitution.min(row[j] + 1).min(previous + 1);
+        }
+    }
+    row[b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{edit_distance, Severity};
+    use crate::root_db::system_config::SystemConfig;
+    use crate::root_db::tests::MEMORY_SPHERE;
+
+    fn lint(toml: &str) -> Vec<(Severity, String, String)> {
+        let config: SystemConfig = toml::from_str(toml).expect("Could not toml");
+        config
+            .lint()
+            .into_iter()
+            .map(|d| (d.severity, d.location, d.message))
+            .collect()
+    }
+
+    #[test]
+    fn edit_distances() {
+        assert_eq!(edit_distance("armour", "armour"), 0);
+        assert_eq!(edit_distance("amour", "armour"), 1);
+        assert_eq!(edit_distance("char", "cha"), 1);
+        assert_eq!(edit_distance("spell", "feat"), 4);
+    }
+
+    #[test]
+    fn memory_sphere_is_clean() {
+        assert!(lint(MEMORY_SPHERE).is_empty());
+    }
+
+    #[test]
+    fn lint_finds_broken_parts_and_keys() {
+        let toml = "\
+permitted_parts = [
+  { part_name = \"main\", part_type = \"Main\", obligatory = true },
+  { part_name = \"other main\", part_type = \"Main\", obligatory = true },
+  { part_name = \"spell\", part_type = \"Ability\", obligatory = false },
+]
+permitted_attributes = [
+  { key = \"race\", obligatory = true, attribute_type = 0, attribute_description = \"\", part_name = \"main\", part_type = \"Main\" },
+  { key = \"race\", obligatory = true, attribute_type = 0, attribute_description = \"\", part_name = \"main\", part_type = \"Main\" },
+  { key = \"Race\", obligatory = true, attribute_type = 0, attribute_description = \"\", part_name = \"main\", part_type = \"Main\" },
+  { key = \"sword_length\", obligatory = true, attribute_type = 0, attribute_description = \"\", part_name = \"sword\", part_type = \"InventoryItem\" },
+  { key = \"spell_level\", obligatory = true, attribute_type = 0, attribute_description = \"\", part_name = \"spell\", part_type = \"Ability\" },
+]";
+        let found = lint(toml);
+        let errors = found
+            .iter()
+            .filter(|d| d.0 == Severity::Error)
+            .map(|d| d.1.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            errors,
+            vec![
+                "permitted_parts[1]",
+                "permitted_attributes[1]",
+                "permitted_attributes[2]",
+                "permitted_attributes[3]",
+            ]
+        );
+        assert!(found[1]
+            .2
+            .contains("already defined for this part at permitted_attributes[0]"));
+        assert!(found[2].2.contains("only differs by case"));
+        assert!(found[3].2.contains("'sword', which does not exist"));
+        // Obligatory for a part which is not.
+        assert_eq!(found[4].0, Severity::Warning);
+        assert_eq!(found[4].1, "permitted_attributes[4]");
+    }
+
+    #[test]
+    fn lint_checks_defaults_bounds_and_choices() {
+        let toml = "\
+permitted_parts = [
+  { part_name = \"main\", part_type = \"Main\", obligatory = true },
+]
+permitted_attributes = [
+  { key = \"str\", obligatory = true, attribute_type = \"Integer\", attribute_description = \"\", part_name = \"main\", part_type = \"Main\", default = 10, min = 1, max = 30 },
+  { key = \"dex\", obligatory = true, attribute_type = \"Integer\", attribute_description = \"\", part_name = \"main\", part_type = \"Main\", min = 30, max = 1 },
+  { key = \"con\", obligatory = true, attribute_type = \"Integer\", attribute_description = \"\", part_name = \"main\", part_type = \"Main\", default = 40, max = 30 },
+  { key = \"alignment\", obligatory = true, attribute_type = \"Enumeration\", attribute_description = \"\", part_name = \"main\", part_type = \"Main\", choices = [\"Good\", \"Evil\"], default = \"Meh\" },
+  { key = \"mood\", obligatory = true, attribute_type = \"Enumeration\", attribute_description = \"\", part_name = \"main\", part_type = \"Main\" },
+  { key = \"level\", obligatory = true, attribute_type = \"Integer\", attribute_description = \"\", part_name = \"main\", part_type = \"Main\", choices = [\"1\"] },
+]";
+        let found = lint(toml);
+        let summary = found
+            .iter()
+            .map(|d| (d.0, d.1.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            summary,
+            vec![
+                (Severity::Error, "permitted_attributes[1]"),
+                (Severity::Error, "permitted_attributes[2]"),
+                (Severity::Error, "permitted_attributes[3]"),
+                (Severity::Warning, "permitted_attributes[4]"),
+                (Severity::Warning, "permitted_attributes[5]"),
+            ]
+        );
+        assert!(found[0].2.contains("more than max"));
+        assert!(found[1].2.contains("can't be more than 30"));
+        assert!(found[2].2.contains("must be one of Good, Evil"));
+    }
+
+    #[test]
+    fn lint_checks_formulas() {
+        let toml = "\
+permitted_parts = [
+  { part_name = \"main\", part_type = \"Main\", obligatory = true },
+]
+permitted_attributes = [
+  { key = \"str\", obligatory = true, attribute_type = \"Integer\", attribute_description = \"\", part_name = \"main\", part_type = \"Main\" },
+  { key = \"str_mod\", obligatory = false, attribute_type = \"Integer\", attribute_description = \"\", part_name = \"main\", part_type = \"Main\", formula = \"floor((@str - 10) / 2) + @hp_total\" },
+  { key = \"broken\", obligatory = false, attribute_type = \"Integer\", attribute_description = \"\", part_name = \"main\", part_type = \"Main\", formula = \"floor(@str\" },
+  { key = \"wis_mod\", obligatory = false, attribute_type = \"Integer\", attribute_description = \"\", part_name = \"main\", part_type = \"Main\", formula = \"@wis - 10\" },
+  { key = \"ouroboros\", obligatory = false, attribute_type = \"Integer\", attribute_description = \"\", part_name = \"main\", part_type = \"Main\", formula = \"@ouroboros + 1\" },
+]";
+        let found = lint(toml);
+        let summary = found
+            .iter()
+            .map(|d| (d.0, d.1.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            summary,
+            vec![
+                (Severity::Error, "permitted_attributes[2]"),
+                (Severity::Error, "permitted_attributes[3]"),
+                (Severity::Error, "permitted_attributes[4]"),
+            ]
+        );
+        assert!(found[0].2.contains("does not parse"));
+        assert!(found[1].2.contains("refers to 'wis'"));
+        assert!(found[2]
+            .2
+            .contains("depends on itself: ouroboros -> ouroboros"));
+    }
+
+    #[test]
+    fn lint_checks_roll_macros() {
+        let toml = "\
+permitted_parts = [
+  { part_name = \"main\", part_type = \"Main\", obligatory = true },
+  { part_name = \"weapon\", part_type = \"InventoryItem\", obligatory = false },
+]
+permitted_attributes = []
+roll_macros = [
+  { name = \"strike\", part_name = \"weapon\", part_type = \"InventoryItem\", attack = \"1d20\", damage = \"1d6\" },
+  { name = \"strike\", part_name = \"weapon\", part_type = \"InventoryItem\", attack = \"1d20+1\" },
+  { name = \"shrug\", part_name = \"weapon\", part_type = \"InventoryItem\" },
+  { name = \"cast\", part_name = \"spell\", part_type = \"Ability\", damage = \"8d6\" },
+]";
+        let found = lint(toml);
+        let summary = found
+            .iter()
+            .map(|d| (d.0, d.1.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            summary,
+            vec![
+                (Severity::Error, "roll_macros[1]"),
+                (Severity::Error, "roll_macros[2]"),
+                (Severity::Error, "roll_macros[3]"),
+            ]
+        );
+        assert!(found[0]
+            .2
+            .contains("already defined for this part at roll_macros[0]"));
+        assert!(found[1].2.contains("neither an attack nor damage"));
+        assert!(found[2]
+            .2
+            .contains("'spell' (Ability), which does not exist"));
+    }
+
+    #[test]
+    fn lint_finds_lines() {
+        let toml = "\
+# A sword, with its value on the armour.
+permitted_parts = [
+  { part_name = \"main\", part_type = \"Main\", obligatory = true },
+  { part_name = \"weapon\", part_type = \"InventoryItem\", obligatory = false },
+  { part_name = \"armour\", part_type = \"InventoryItem\", obligatory = false },
+]
+permitted_attributes = [
+  { key = \"race\", obligatory = true, attribute_type = 0, attribute_description = \"\", part_name = \"main\", part_type = \"Main\" },
+  # { key = \"weapon_value\", part_name = \"weapon\" },
+  { key = \"weapon_value\", obligatory = false, attribute_type = 0, attribute_description = \"\", part_name = \"armour\", part_type = \"InventoryItem\" },
+  { key = \"amour_ac_bonus\", obligatory = false, attribute_type = 0, attribute_description = \"\", part_name = \"armour\", part_type = \"InventoryItem\" },
+  {key=\"race\",obligatory=true,attribute_type=0,attribute_description=\"\",part_name=\"main\",part_type=\"Main\"},
+]";
+        let found = SystemConfig::lint_text(toml).expect("Could not lint.");
+        let lines = found.iter().map(|d| d.line).collect::<Vec<_>>();
+        assert_eq!(lines, vec![Some(10), Some(11), Some(12)]);
+        assert!(found[0].message.contains("'weapon_value'"));
+        assert!(found[1].message.contains("'amour_ac_bonus'"));
+        assert!(found[1]
+            .to_string()
+            .starts_with("warning: permitted_attributes[2] (line 11): "));
+        assert!(found[2].is_error());
+    }
+
+    #[test]
+    fn check_lets_old_mistakes_through() {
+        let toml = "\
+permitted_parts = [
+  { part_name = \"main\", part_type = \"Main\", obligatory = true },
+  { part_name = \"main\", part_type = \"Main\", obligatory = true },
+]
+permitted_attributes = [
+  { key = \"race\", obligatory = true, attribute_type = 0, attribute_description = \"\", part_name = \"main\", part_type = \"Main\" },
+  { key = \"race\", obligatory = true, attribute_type = 0, attribute_description = \"\", part_name = \"main\", part_type = \"Main\" },
+  { key = \"special_range\", obligatory = true, attribute_type = 0, attribute_description = \"\", part_name = \"special\", part_type = \"Ability\" },
+]";
+        let config: SystemConfig = toml::from_str(toml).expect("Could not toml");
+        assert_eq!(config.lint().iter().filter(|d| d.is_error()).count(), 4);
+        assert!(config.check().is_err(), "There are two main parts.");
+        let one_main = toml.replacen(
+            "  { part_name = \"main\", part_type = \"Main\", obligatory = true },\n",
+            "",
+            1,
+        );
+        let config: SystemConfig = toml::from_str(&one_main).expect("Could not toml");
+        assert_eq!(config.lint().iter().filter(|d| d.is_error()).count(), 2);
+        assert_eq!(config.check(), Ok(()));
+    }
+}
@@ -0,0 +1,790 @@
+//! This deals with changing the system of a root database which already has sheets.
+//! The new permitted parts and attributes are compared with the stored ones,
+//! the root database is changed to match, and every sheet is brought up to date.
+use super::system::{NewPermittedAttribute, NewPermittedPart, PermittedAttribute, PermittedPart};
+use super::system_config::SystemConfig;
+use super::LoadedDbs;
+use crate::character::attribute::{check_value, Attribute, NewAttribute};
+use crate::character::character::{Character, NewCharacter};
+use crate::shared::Part;
+use crate::BasicConnection;
+
+use azchar_error::Error;
+use diesel::{ExpressionMethods, QueryDsl, RunQueryDsl, SqliteConnection};
+use fnv::FnvHashSet;
+
+/// What to do with parts and attributes which are no longer in the system.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum RemovalPolicy {
+    /// Keep them in the system as optional, so that sheets which use them can still be saved.
+    Keep,
+    /// Remove them from the system and from every sheet.
+    Delete,
+}
+
+/// What an update did to a single sheet.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct SheetReport {
+    pub name: String,
+    pub uuid: String,
+    pub parts_added: usize,
+    pub attributes_added: usize,
+    pub parts_deleted: usize,
+    pub attributes_deleted: usize,
+    /// Values of changed attributes which the new system no longer allows, as "part: reason".
+    /// They are left as they were, so the sheet can't be saved until they are fixed.
+    pub invalid_values: Vec<String>,
+    /// Set if the sheet could not be updated, in which case it is left as it was.
+    /// NB: Parts deleted include those beneath a part type which was removed.
+    pub error: Option<Error>,
+}
+
+/// What an update changed. Parts are given as "name (type)".
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SystemUpdateReport {
+    pub policy: RemovalPolicy,
+    pub added_parts: Vec<String>,
+    pub changed_parts: Vec<String>,
+    pub removed_parts: Vec<String>,
+    pub added_attributes: Vec<String>,
+    pub changed_attributes: Vec<String>,
+    pub removed_attributes: Vec<String>,
+    pub sheets: Vec<SheetReport>,
+}
+
+impl SystemUpdateReport {
+    fn new(policy: RemovalPolicy) -> Self {
+        Self {
+            policy,
+            added_parts: Vec::new(),
+            changed_parts: Vec::new(),
+            removed_parts: Vec::new(),
+            added_attributes: Vec::new(),
+            changed_attributes: Vec::new(),
+            removed_attributes: Vec::new(),
+            sheets: Vec::new(),
+        }
+    }
+}
+
+fn part_label(name: &str, part_type: Part) -> String {
+    format!("{} ({:?})", name, part_type)
+}
+
+/// Attributes are told apart by their key and the part they belong to.
+fn same_place(old: &PermittedAttribute, new: &NewPermittedAttribute) -> bool {
+    old.key == new.key && old.part_name == new.part_name && old.part_type == new.part_type
+}
+
+fn same_attribute(old: &PermittedAttribute, new: &NewPermittedAttribute) -> bool {
+    &NewPermittedAttribute::from(old) == new
+}
+
+impl LoadedDbs {
+    /// Change the system to `config` and bring every sheet up to date.
+    /// New obligatory parts and attributes are added to sheets which lack them, with their defaults.
+    /// Stored values of changed attributes are checked against the new system and reported if invalid.
+    /// With `RemovalPolicy::Delete` the parts beneath a removed part go with it.
+    /// The root database is changed in one transaction and each sheet in its own,
+    /// so a sheet which fails is reported and can be fixed by running the update again.
+    pub fn update_system(
+        &mut self,
+        config: SystemConfig,
+        policy: RemovalPolicy,
+    ) -> Result<SystemUpdateReport, Error> {
+        use super::system::roll_macros::dsl as rm_dsl;
+
+        config.check()?;
+        let mut report = SystemUpdateReport::new(policy);
+        let (new_parts, new_attrs, new_macros) = config.into_new_permitted();
+        if !new_parts.iter().any(|p| p.part_type == Part::Main) {
+            return Err(Error::validation("A system needs a main part."));
+        }
+
+        let root = self.get_inner_root()?;
+        let old_parts = PermittedPart::load_all(root)?;
+        let old_attrs = PermittedAttribute::load_all(root)?;
+        root.immediate_transaction::<_, Error, _>(|| {
+            update_permitted_parts(root, &old_parts, &new_parts, policy, &mut report)?;
+            update_permitted_attributes(root, &old_attrs, &new_attrs, policy, &mut report)?;
+            // Roll macros are not stored on sheets, so they are simply replaced.
+            diesel::delete(rm_dsl::roll_macros).execute(root)?;
+            diesel::insert_into(rm_dsl::roll_macros)
+                .values(&new_macros)
+                .execute(root)?;
+            Ok(())
+        })?;
+        // With `Keep` the sheets only gain things.
+        let (removed_parts, removed_attrs) = if policy == RemovalPolicy::Delete {
+            let parts = old_parts
+                .iter()
+                .filter(|o| {
+                    !new_parts
+                        .iter()
+                        .any(|n| n.part_name == o.part_name && n.part_type == o.part_type)
+                })
+                .map(|o| (o.part_name.to_owned(), o.part_type))
+                .collect();
+            let attrs = old_attrs
+                .iter()
+                .filter(|o| !new_attrs.iter().any(|n| same_place(o, n)))
+                .map(|o| o.key.to_owned())
+                .collect::<FnvHashSet<_>>()
+                .into_iter()
+                .collect();
+            (parts, attrs)
+        } else {
+            (Vec::new(), Vec::new())
+        };
+        let permitted_parts = PermittedPart::load_all(root)?;
+        let permitted_attrs = PermittedAttribute::load_all(root)?;
+        self.permitted_parts = permitted_parts;
+        self.permitted_attrs = permitted_attrs;
+        let changed_attrs = report.changed_attributes.clone();
+
+        let mut keys = self.connections.keys().cloned().collect::<Vec<_>>();
+        keys.sort();
+        for key in keys {
+            let conn = self.connections.get_mut(&key).expect("Key from the map.");
+            let mut sheet = SheetReport {
+                name: key.0,
+                uuid: key.1,
+                ..SheetReport::default()
+            };
+            let res = update_sheet(
+                conn,
+                (&self.permitted_parts, &self.permitted_attrs),
+                (&removed_parts, &removed_attrs),
+                &changed_attrs,
+                &mut sheet,
+            );
+            conn.drop_inner();
+            if let Err(e) = res {
+                sheet.parts_added = 0;
+                sheet.attributes_added = 0;
+                sheet.parts_deleted = 0;
+                sheet.attributes_deleted = 0;
+                sheet.invalid_values.clear();
+                sheet.error = Some(e);
+            }
+            report.sheets.push(sheet);
+        }
+        Ok(report)
+    }
+}
+
+fn update_permitted_parts(
+    root: &SqliteConnection,
+    old_parts: &[PermittedPart],
+    new_parts: &[NewPermittedPart],
+    policy: RemovalPolicy,
+    report: &mut SystemUpdateReport,
+) -> Result<(), Error> {
+    use super::system::permitted_parts::dsl::*;
+
+    for new in new_parts.iter() {
+        let label = part_label(&new.part_name, new.part_type);
+        match old_parts
+            .iter()
+            .find(|o| o.part_name == new.part_name && o.part_type == new.part_type)
+        {
+            Some(old) if old.obligatory != new.obligatory => {
+                diesel::update(permitted_parts.filter(id.eq(old.id())))
+                    .set(obligatory.eq(new.obligatory))
+                    .execute(root)?;
+                report.changed_parts.push(label);
+            }
+            Some(_) => {}
+            None => {
+                diesel::insert_into(permitted_parts)
+                    .values(new)
+                    .execute(root)?;
+                report.added_parts.push(label);
+            }
+        }
+    }
+    for old in old_parts.iter().filter(|o| {
+        !new_parts
+            .iter()
+            .any(|n| n.part_name == o.part_name && n.part_type == o.part_type)
+    }) {
+        match policy {
+            RemovalPolicy::Keep => {
+                diesel::update(permitted_parts.filter(id.eq(old.id())))
+                    .set(obligatory.eq(false))
+                    .execute(root)?;
+            }
+            RemovalPolicy::Delete if old.part_type == Part::Main => {
+                let m = format!("The main part '{}' can't be deleted.", old.part_name);
+                return Err(Error::validation(m));
+            }
+            RemovalPolicy::Delete => {
+                diesel::delete(permitted_parts.filter(id.eq(old.id()))).execute(root)?;
+            }
+        }
+        report
+            .removed_parts
+            .push(part_label(&old.part_name, old.part_type));
+    }
+    Ok(())
+}
+
+fn update_permitted_attributes(
+    root: &SqliteConnection,
+    old_attrs: &[PermittedAttribute],
+    new_attrs: &[NewPermittedAttribute],
+    policy: RemovalPolicy,
+    report: &mut SystemUpdateReport,
+) -> Result<(), Error> {
+    use super::system::permitted_attributes::dsl::*;
+
+    for new in new_attrs.iter() {
+        match old_attrs.iter().find(|o| same_place(o, new)) {
+            Some(old) if same_attribute(old, new) => {}
+            Some(old) => {
+                delete_permitted_attribute(root, old)?;
+                diesel::insert_into(permitted_attributes)
+                    .values(new)
+                    .execute(root)?;
+                report.changed_attributes.push(new.key.to_owned());
+            }
+            None => {
+                diesel::insert_into(permitted_attributes)
+                    .values(new)
+                    .execute(root)?;
+                report.added_attributes.push(new.key.to_owned());
+            }
+        }
+    }
+    for old in old_attrs
+        .iter()
+        .filter(|o| !new_attrs.iter().any(|n| same_place(o, n)))
+    {
+        delete_permitted_attribute(root, old)?;
+        if policy == RemovalPolicy::Keep {
+            let kept = NewPermittedAttribute {
+                obligatory: false,
+                ..old.into()
+            };
+            diesel::insert_into(permitted_attributes)
+                .values(&kept)
+                .execute(root)?;
+        }
+        report.removed_attributes.push(old.key.to_owned());
+    }
+    Ok(())
+}
+
+/// Part name and part type can both be NULL, and `NULL = NULL` is never true in SQL,
+/// so each combination needs its own filter.
+fn delete_permitted_attribute(
+    root: &SqliteConnection,
+    old: &PermittedAttribute,
+) -> Result<usize, Error> {
+    use super::system::permitted_attributes::dsl::*;
+
+    let target = permitted_attributes.filter(key.eq(&old.key));
+    let deleted = match (&old.part_name, old.part_type) {
+        (Some(n), Some(t)) => {
+            diesel::delete(target.filter(part_name.eq(n)).filter(part_type.eq(t))).execute(root)?
+        }
+        (Some(n), None) => {
+            diesel::delete(target.filter(part_name.eq(n)).filter(part_type.is_null()))
+                .execute(root)?
+        }
+        (None, Some(t)) => {
+            diesel::delete(target.filter(part_name.is_null()).filter(part_type.eq(t)))
+                .execute(root)?
+        }
+        (None, None) => diesel::delete(
+            target
+                .filter(part_name.is_null())
+                .filter(part_type.is_null()),
+        )
+        .execute(root)?,
+    };
+    Ok(deleted)
+}
+
+/// Delete what is no longer allowed from a sheet and add what is obligatory but missing.
+/// Values of changed attributes are checked, but not changed.
+fn update_sheet(
+    conn: &mut BasicConnection,
+    (permitted_parts, permitted_attrs): (&[PermittedPart], &[PermittedAttribute]),
+    (removed_parts, removed_attrs): (&[(String, Part)], &[String]),
+    changed_attrs: &[String],
+    sheet: &mut SheetReport,
+) -> Result<(), Error> {
+    use crate::character::attribute::attributes::dsl as at_dsl;
+    use crate::character::character::characters::dsl as ch_dsl;
+    use crate::character::image::images::dsl as im_dsl;
+    use crate::character::roll_macro::roll_macros::dsl as rm_dsl;
+
+    let conn = conn.connect()?;
+    crate::sheet_transaction(conn, || {
+        let all = ch_dsl::characters.load::<Character>(conn)?;
+        let removed = all
+            .iter()
+            .filter(|c| c.part_type != Part::Main)
+            .filter(|c| {
+                removed_parts
+                    .iter()
+                    .any(|(n, t)| n == &c.character_type && *t == c.part_type)
+            })
+            .map(|c| c.id)
+            .collect();
+        // What belongs to a removed part would belong to nothing.
+        let removed = Character::with_parts_beneath(&all, removed)
+            .into_iter()
+            .collect::<Vec<_>>();
+        for chunk in removed.chunks(999) {
+            sheet.attributes_deleted +=
+                diesel::delete(at_dsl::attributes.filter(at_dsl::of.eq_any(chunk)))
+                    .execute(conn)?;
+            diesel::delete(im_dsl::images.filter(im_dsl::of.eq_any(chunk))).execute(conn)?;
+            diesel::delete(rm_dsl::roll_macros.filter(rm_dsl::of.eq_any(chunk))).execute(conn)?;
+            sheet.parts_deleted +=
+                diesel::delete(ch_dsl::characters.filter(ch_dsl::id.eq_any(chunk)))
+                    .execute(conn)?;
+        }
+        // A key can still be permitted for some parts after it is removed from others.
+        let parts = ch_dsl::characters.load::<Character>(conn)?;
+        let mut orphaned = Vec::new();
+        for chunk in removed_attrs.chunks(999) {
+            let found = at_dsl::attributes
+                .select((at_dsl::id, at_dsl::key, at_dsl::of))
+                .filter(at_dsl::key.eq_any(chunk))
+                .load::<(i64, String, i64)>(conn)?;
+            for (a_id, a_key, a_of) in found {
+                let still_permitted = parts.iter().find(|p| p.id == a_of).map(|p| {
+                    permitted_attrs.iter().any(|a| {
+                        a.key == a_key && a.permitted_for_part(p.part_type, &p.character_type)
+                    })
+                });
+                if still_permitted != Some(true) {
+                    orphaned.push(a_id);
+                }
+            }
+        }
+        for chunk in orphaned.chunks(999) {
+            sheet.attributes_deleted +=
+                diesel::delete(at_dsl::attributes.filter(at_dsl::id.eq_any(chunk)))
+                    .execute(conn)?;
+        }
+
+        let parts = ch_dsl::characters.load::<Character>(conn)?;
+        let main_id = match parts.iter().find(|c| c.part_type == Part::Main) {
+            Some(main) => main.id,
+            // An empty sheet has nothing to bring up to date.
+            None => return Ok(()),
+        };
+        let new_parts = permitted_parts
+            .iter()
+            .filter(|p| p.obligatory && p.part_type != Part::Main)
+            .filter(|p| {
+                !parts
+                    .iter()
+                    .any(|c| c.character_type == p.part_name && c.part_type == p.part_type)
+            })
+            .map(|p| {
+                let mut p: NewCharacter = p.into();
+                p.belongs_to = Some(main_id);
+                p
+            })
+            .collect::<Vec<_>>();
+        for chunk in new_parts.chunks(999) {
+            sheet.parts_added += diesel::insert_into(ch_dsl::characters)
+                .values(chunk)
+                .execute(conn)?;
+        }
+
+        let parts = ch_dsl::characters.load::<Character>(conn)?;
+        let existing = at_dsl::attributes
+            .select((at_dsl::key, at_dsl::of))
+            .load::<(String, i64)>(conn)?
+            .into_iter()
+            .collect::<FnvHashSet<_>>();
+        let mut new_attributes = Vec::new();
+        for p in parts.iter() {
+            let attr_iter = permitted_attrs
+                .iter()
+                .filter(|a| a.obligatory_for_part(p.part_type, &p.character_type))
+                .filter(|a| !existing.contains(&(a.key.to_owned(), p.id)))
+                .map(|a| NewAttribute::from_permitted(p.id, a));
+            new_attributes.extend(attr_iter);
+        }
+        for chunk in new_attributes.chunks(999) {
+            sheet.attributes_added += diesel::insert_into(at_dsl::attributes)
+                .values(chunk)
+                .execute(conn)?;
+        }
+
+        for chunk in changed_attrs.chunks(999) {
+            let found = at_dsl::attributes
+                .filter(at_dsl::key.eq_any(chunk))
+                .load::<Attribute>(conn)?;
+            for a in found {
+                let part = match parts.iter().find(|p| p.id == a.of) {
+                    Some(p) => p,
+                    None => continue,
+                };
+                let perm = PermittedAttribute::find_for_part(
+                    permitted_attrs,
+                    &a.key,
+                    part.part_type,
+                    &part.character_type,
+                );
+                // Derived attributes are worked out again whenever they are loaded.
+                let perm = match perm {
+                    Some(perm) if perm.formula.is_none() => perm,
+                    _ => continue,
+                };
+                if let Err(e) = check_value(conn, perm, a.value_num, &a.value_text) {
+                    sheet.invalid_values.push(format!("{}: {}", part.name(), e));
+                }
+            }
+        }
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RemovalPolicy;
+    use crate::root_db::system_config::SystemConfig;
+    use crate::root_db::tests::{setup, TestSystem, MEMORY_SPHERE};
+    use azchar_error::Error;
+
+    const ALIGNMENT: &str = "{ key = \"character_alignment\", obligatory = true, attribute_type = 0, attribute_description = \"The character's alignment.\", part_name = \"main\", part_type = \"Main\" },";
+    const SPELL: &str = "{ part_name = \"spell\", part_type = \"Ability\", obligatory = false },";
+
+    /// The memory sphere system, with a familiar and luck instead of alignment.
+    fn evolved_memory_sphere() -> SystemConfig {
+        let toml = MEMORY_SPHERE
+            .replace(
+                ALIGNMENT,
+                "{ key = \"luck\", obligatory = true, attribute_type = 0, attribute_description = \"How lucky.\", part_name = \"main\", part_type = \"Main\" },\
+                { key = \"loyalty\", obligatory = true, attribute_type = 0, attribute_description = \"How loyal.\", part_name = \"Familiar\", part_type = \"Summon\" },",
+            )
+            .replace(
+                SPELL,
+                &format!(
+                    "{}{{ part_name = \"Familiar\", part_type = \"Summon\", obligatory = true }},",
+                    SPELL
+                ),
+            );
+        toml::from_str(&toml).expect("Could not toml")
+    }
+
+    #[test]
+    fn update_system_back_fills_sheets() {
+        let mut setup = setup(TestSystem::MemorySphere);
+        let key = setup.loaded_dbs.create_sheet("Euridice").expect("Sheet.");
+        setup.loaded_dbs.create_sheet("Saloth").expect("Sheet.");
+
+        let report = setup
+            .loaded_dbs
+            .update_system(evolved_memory_sphere(), RemovalPolicy::Keep)
+            .expect("Could not update.");
+        assert_eq!(report.added_parts, vec!["Familiar (Summon)"]);
+        assert_eq!(report.added_attributes, vec!["luck", "loyalty"]);
+        assert_eq!(report.removed_attributes, vec!["character_alignment"]);
+        assert!(report.changed_parts.is_empty());
+        assert_eq!(report.sheets.len(), 2);
+        for sheet in report.sheets.iter() {
+            assert_eq!(sheet.error, None);
+            assert_eq!((sheet.parts_added, sheet.attributes_added), (1, 2));
+            assert_eq!((sheet.parts_deleted, sheet.attributes_deleted), (0, 0));
+        }
+
+        // The sheet can be saved again, and keeps its alignment.
+        let euridice = setup.loaded_dbs.load_character(key).expect("Load.");
+        assert!(euridice
+            .attributes
+            .iter()
+            .any(|(k, _)| k.key() == "character_alignment"));
+        assert!(euridice
+            .parts
+            .iter()
+            .any(|p| p.character_type == "Familiar"));
+        setup
+            .loaded_dbs
+            .create_or_update_character(euridice)
+            .expect("Could not save after the update.");
+
+        // Doing it again changes nothing.
+        let report = setup
+            .loaded_dbs
+            .update_system(evolved_memory_sphere(), RemovalPolicy::Keep)
+            .expect("Could not update.");
+        assert!(report.added_parts.is_empty() && report.added_attributes.is_empty());
+        for sheet in report.sheets.iter() {
+            assert_eq!((sheet.parts_added, sheet.attributes_added), (0, 0));
+        }
+    }
+
+    #[test]
+    fn update_system_deletes_removed_attributes() {
+        let mut setup = setup(TestSystem::MemorySphere);
+        let key = setup.loaded_dbs.create_sheet("Euridice").expect("Sheet.");
+
+        let report = setup
+            .loaded_dbs
+            .update_system(evolved_memory_sphere(), RemovalPolicy::Delete)
+            .expect("Could not update.");
+        assert_eq!(report.removed_attributes, vec!["character_alignment"]);
+        assert_eq!(report.sheets[0].attributes_deleted, 1);
+
+        let euridice = setup.loaded_dbs.load_character(key).expect("Load.");
+        assert!(!euridice
+            .attributes
+            .iter()
+            .any(|(k, _)| k.key() == "character_alignment"));
+        setup
+            .loaded_dbs
+            .create_or_update_character(euridice)
+            .expect("Could not save after the update.");
+    }
+
+    #[test]
+    fn update_system_deletes_what_is_beneath_a_removed_part() {
+        use crate::character::character::InputCharacter;
+        use crate::character::image::InputImage;
+        use crate::shared::Part;
+
+        let mut setup = setup(TestSystem::MemorySphere);
+        let key = setup.loaded_dbs.create_sheet("Euridice").expect("Sheet.");
+        let dbs = &mut setup.loaded_dbs;
+        let euridice = dbs.load_character(key.clone()).expect("Load.");
+        let sphere = euridice.parts[0].id().expect("The sphere is obligatory.");
+        let recollection = InputCharacter {
+            name: "Recollection".to_string(),
+            character_type: "spell".to_string(),
+            speed: 0,
+            weight: None,
+            size: None,
+            hp_total: None,
+            hp_current: None,
+            belongs_to: Some(sphere),
+            part_type: Part::Ability,
+        };
+        let euridice = dbs.create_part(recollection, key.clone()).expect("Part.");
+        let spell = euridice.parts.iter().find(|p| p.name == "Recollection");
+        let spell = spell.and_then(|p| p.id()).expect("Saved.");
+        let image = InputImage {
+            of: spell,
+            link: "../examples/c-euri-2021b.png".to_string(),
+        };
+        dbs.create_update_image(key.0.to_owned(), key.1.to_owned(), image)
+            .expect("Image.");
+
+        let no_sphere = MEMORY_SPHERE
+            .split("},")
+            .filter(|entry| !entry.contains("Memory Sphere"))
+            .collect::<Vec<_>>()
+            .join("},");
+        let no_sphere: SystemConfig = toml::from_str(&no_sphere).expect("Could not toml");
+        let report = dbs
+            .update_system(no_sphere, RemovalPolicy::Delete)
+            .expect("Could not update.");
+        assert_eq!(report.removed_parts, vec!["Memory Sphere (InventoryItem)"]);
+        assert_eq!(report.sheets[0].error, None);
+        assert_eq!(report.sheets[0].parts_deleted, 2);
+
+        let euridice = dbs.load_character(key).expect("Load.");
+        assert!(euridice.parts.is_empty());
+        assert_eq!(dbs.check_integrity(false).expect("Check."), Vec::new());
+    }
+
+    #[test]
+    fn update_system_deletes_a_key_only_where_it_was_removed() {
+        let mut setup = setup(TestSystem::MemorySphere);
+        let sphere_alignment = ALIGNMENT
+            .replace("\"main\"", "\"Memory Sphere\"")
+            .replace("\"Main\"", "\"InventoryItem\"");
+        let both = MEMORY_SPHERE.replace(ALIGNMENT, &format!("{}{}", ALIGNMENT, sphere_alignment));
+        let both: SystemConfig = toml::from_str(&both).expect("Could not toml");
+        let report = setup
+            .loaded_dbs
+            .update_system(both, RemovalPolicy::Keep)
+            .expect("Could not update.");
+        assert_eq!(report.added_attributes, vec!["character_alignment"]);
+        assert!(report.changed_attributes.is_empty());
+        let key = setup.loaded_dbs.create_sheet("Euridice").expect("Sheet.");
+
+        let sphere_only = MEMORY_SPHERE.replace(ALIGNMENT, &sphere_alignment);
+        let sphere_only: SystemConfig = toml::from_str(&sphere_only).expect("Could not toml");
+        let report = setup
+            .loaded_dbs
+            .update_system(sphere_only, RemovalPolicy::Delete)
+            .expect("Could not update.");
+        assert_eq!(report.removed_attributes, vec!["character_alignment"]);
+        assert_eq!(report.sheets[0].attributes_deleted, 1);
+
+        let euridice = setup.loaded_dbs.load_character(key).expect("Load.");
+        assert!(!euridice
+            .attributes
+            .iter()
+            .any(|(k, _)| k.key() == "character_alignment"));
+        assert!(euridice.parts.iter().any(|p| p
+            .attributes
+            .iter()
+            .any(|(k, _)| k.key() == "character_alignment")));
+    }
+
+    #[test]
+    fn update_system_back_fills_defaults() {
+        let mut setup = setup(TestSystem::MemorySphere);
+        let old = setup.loaded_dbs.create_sheet("Euridice").expect("Sheet.");
+        let luck = "{ key = \"luck\", obligatory = true, attribute_type = \"Integer\", attribute_description = \"How lucky.\", part_name = \"main\", part_type = \"Main\", default = 3, min = 0, max = 5 },";
+        let toml = MEMORY_SPHERE.replace(ALIGNMENT, &format!("{}{}", ALIGNMENT, luck));
+        let config: SystemConfig = toml::from_str(&toml).expect("Could not toml");
+        let report = setup
+            .loaded_dbs
+            .update_system(config, RemovalPolicy::Keep)
+            .expect("Could not update.");
+        assert_eq!(report.added_attributes, vec!["luck"]);
+        let new = setup.loaded_dbs.create_sheet("Saloth").expect("Sheet.");
+
+        for key in [old.clone(), new] {
+            let character = setup.loaded_dbs.load_character(key).expect("Load.");
+            let (_, luck) = character
+                .attributes
+                .iter()
+                .find(|(k, _)| k.key() == "luck")
+                .expect("Luck was added.");
+            assert_eq!(luck.value_num(), Some(3));
+        }
+
+        let euridice = setup.loaded_dbs.load_character(old.clone()).expect("Load.");
+        let (k, v) = euridice
+            .attributes
+            .iter()
+            .find(|(k, _)| k.key() == "luck")
+            .cloned()
+            .expect("Luck was added.");
+        let too_lucky = v.update_value_num(Some(9));
+        let res = setup.loaded_dbs.create_update_attribute(k, too_lucky, old);
+        assert!(matches!(res, Err(Error::ValidationFailed { .. })));
+    }
+
+    #[test]
+    fn update_system_reports_values_a_changed_attribute_no_longer_allows() {
+        let mut setup = setup(TestSystem::MemorySphere);
+        let luck = "{ key = \"luck\", obligatory = true, attribute_type = \"Integer\", attribute_description = \"How lucky.\", part_name = \"main\", part_type = \"Main\", default = 3, max = 5 },";
+        let toml = MEMORY_SPHERE.replace(ALIGNMENT, &format!("{}{}", ALIGNMENT, luck));
+        let config: SystemConfig = toml::from_str(&toml).expect("Could not toml");
+        setup
+            .loaded_dbs
+            .update_system(config, RemovalPolicy::Keep)
+            .expect("Could not update.");
+        let key = setup.loaded_dbs.create_sheet("Euridice").expect("Sheet.");
+
+        let toml = toml.replace("default = 3, max = 5", "default = 1, max = 2");
+        let config: SystemConfig = toml::from_str(&toml).expect("Could not toml");
+        let report = setup
+            .loaded_dbs
+            .update_system(config, RemovalPolicy::Keep)
+            .expect("Could not update.");
+        assert_eq!(report.changed_attributes, vec!["luck"]);
+        assert_eq!(report.sheets[0].error, None);
+        assert_eq!(
+            report.sheets[0].invalid_values,
+            vec!["Euridice: Attribute 'luck' can't be more than 2, not 3."]
+        );
+
+        // The value is left alone, for the user to fix.
+        let euridice = setup.loaded_dbs.load_character(key).expect("Load.");
+        let (_, luck) = euridice
+            .attributes
+            .iter()
+            .find(|(k, _)| k.key() == "luck")
+            .expect("Luck was added.");
+        assert_eq!(luck.value_num(), Some(3));
+    }
+
+    #[test]
+    fn update_system_adds_derived_attributes() {
+        let mut setup = setup(TestSystem::MemorySphere);
+        let key = setup.loaded_dbs.create_sheet("Euridice").expect("Sheet.");
+        let derived = "\
+            { key = \"luck\", obligatory = true, attribute_type = \"Integer\", attribute_description = \"How lucky.\", part_name = \"main\", part_type = \"Main\", default = 3 },\
+            { key = \"luck_bonus\", obligatory = true, attribute_type = \"Integer\", attribute_description = \"Luck doubled.\", part_name = \"main\", part_type = \"Main\", formula = \"@luck * 2\" },\
+            { key = \"half_luck\", obligatory = false, attribute_type = \"Decimal\", attribute_description = \"Luck halved.\", part_name = \"main\", part_type = \"Main\", formula = \"@luck / 2\" },\
+            { key = \"swiftness\", obligatory = false, attribute_type = \"Integer\", attribute_description = \"Lucky feet.\", part_name = \"main\", part_type = \"Main\", formula = \"@speed + @luck_bonus\" },";
+        let toml = MEMORY_SPHERE.replace(ALIGNMENT, &format!("{}{}", ALIGNMENT, derived));
+        let config: SystemConfig = toml::from_str(&toml).expect("Could not toml");
+        setup
+            .loaded_dbs
+            .update_system(config, RemovalPolicy::Keep)
+            .expect("Could not update.");
+
+        let euridice = setup.loaded_dbs.load_character(key.clone()).expect("Load.");
+        let find = |key: &str| {
+            euridice
+                .attributes
+                .iter()
+                .find(|(k, _)| k.key() == key)
+                .cloned()
+                .expect("Derived attributes are always there.")
+        };
+        let (_, luck_bonus) = find("luck_bonus");
+        assert_eq!(luck_bonus.value_num(), Some(6));
+        assert!(luck_bonus.read_only());
+        assert!(!find("luck").1.read_only());
+        assert_eq!(find("half_luck").1.value_text(), &Some("1.5".to_owned()));
+        assert_eq!(
+            find("swiftness").1.value_num(),
+            Some(euridice.speed as i64 + 6)
+        );
+
+        // Derived attributes are not stored, so saving what was loaded changes nothing.
+        setup
+            .loaded_dbs
+            .create_or_update_character(euridice.clone())
+            .expect("Could not save.");
+        let (k, v) = find("luck_bonus");
+        let res =
+            setup
+                .loaded_dbs
+                .create_update_attribute(k, v.update_value_num(Some(100)), key.clone());
+        assert!(matches!(res, Err(Error::NotPermitted { .. })));
+        let reloaded = setup.loaded_dbs.load_character(key).expect("Load.");
+        assert_eq!(reloaded, euridice);
+    }
+
+    #[test]
+    fn update_system_refuses_formulas_which_depend_on_themselves() {
+        let mut setup = setup(TestSystem::MemorySphere);
+        let cycle = "\
+            { key = \"a\", obligatory = false, attribute_type = \"Integer\", attribute_description = \"\", part_name = \"main\", part_type = \"Main\", formula = \"@b + 1\" },\
+            { key = \"b\", obligatory = false, attribute_type = \"Integer\", attribute_description = \"\", part_name = \"main\", part_type = \"Main\", formula = \"@c * 2\" },\
+            { key = \"c\", obligatory = false, attribute_type = \"Integer\", attribute_description = \"\", part_name = \"main\", part_type = \"Main\", formula = \"@a\" },";
+        let toml = MEMORY_SPHERE.replace(ALIGNMENT, &format!("{}{}", ALIGNMENT, cycle));
+        let config: SystemConfig = toml::from_str(&toml).expect("Could not toml");
+        match setup.loaded_dbs.update_system(config, RemovalPolicy::Keep) {
+            Err(Error::ValidationFailed { reason }) => {
+                assert!(
+                    reason.contains("depends on itself: a -> b -> c -> a"),
+                    "{}",
+                    reason
+                )
+            }
+            other => panic!("A cycle got through: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn update_system_keeps_the_main_part() {
+        let mut setup = setup(TestSystem::MemorySphere);
+        let toml = MEMORY_SPHERE.replace(
+            "{ part_name = \"main\", part_type = \"Main\", obligatory = true },",
+            "",
+        );
+        let config: SystemConfig = toml::from_str(&toml).expect("Could not toml");
+        assert!(setup
+            .loaded_dbs
+            .update_system(config, RemovalPolicy::Delete)
+            .is_err());
+    }
+}
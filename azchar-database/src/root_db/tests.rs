@@ -38,14 +38,14 @@ pub(crate) fn setup(ts: TestSystem) -> TestSetup {
         .rand_bytes(10)
         .tempdir()
         .expect("Failed to create a tempfile.");
-    let root_path = root_dir.path().to_string_lossy();
+    let root_path = root_dir.path();
     let a = match ts {
         TestSystem::MemorySphere => MEMORY_SPHERE.to_string(),
         TestSystem::DnD5 => std::fs::read_to_string("../examples/dnd5e0.toml").expect("Yes."),
     };
     let sys_config: SystemConfig = toml::from_str(&a).expect("Could not toml");
     let system = sys_config
-        .into_system(&root_path, system_name)
+        .into_system(root_path, system_name)
         .expect("Could not create system.");
     TestSetup {
         root_dir,
@@ -1,4 +1,5 @@
 //! Contains shared elements.
+use azchar_error::Error;
 use diesel::backend::Backend;
 use diesel::serialize::{Output, Result as SrlResult, ToSql};
 use diesel::types::Integer;
@@ -78,3 +79,170 @@ impl ToString for Part {
         }
     }
 }
+
+#[repr(i32)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+/// The meaning of a permitted attribute's `attribute_type`, which decides
+/// which of `value_num` and `value_text` an attribute uses and what they may hold.
+/// Either value may be left empty, since obligatory attributes are created without one.
+pub enum AttributeType {
+    /// Anything goes. Systems written before types existed use this.
+    #[default]
+    Untyped = 0,
+    /// A whole number in `value_num`.
+    Integer = 1,
+    /// A number such as "1.5" in `value_text`.
+    Decimal = 2,
+    /// Free text in `value_text`.
+    Text = 3,
+    /// 0 or 1 in `value_num`.
+    Boolean = 4,
+    /// A dice expression such as "2d6+3" in `value_text`.
+    Dice = 5,
+    /// One of a set of options in `value_text`.
+    Enumeration = 6,
+    /// The id of another part of the same sheet in `value_num`.
+    PartReference = 7,
+}
+
+impl From<i32> for AttributeType {
+    fn from(n: i32) -> Self {
+        match n {
+            1 => Self::Integer,
+            2 => Self::Decimal,
+            3 => Self::Text,
+            4 => Self::Boolean,
+            5 => Self::Dice,
+            6 => Self::Enumeration,
+            7 => Self::PartReference,
+            _ => Self::Untyped,
+        }
+    }
+}
+
+impl AttributeType {
+    /// Whether a number is one of the defined types.
+    pub fn is_defined(n: i32) -> bool {
+        (0..=7).contains(&n)
+    }
+
+    /// Whether values of this type are kept in `value_text`.
+    pub(crate) fn uses_text(self) -> bool {
+        matches!(
+            self,
+            Self::Decimal | Self::Text | Self::Dice | Self::Enumeration
+        )
+    }
+
+    /// Check that a value fits this type.
+    /// Whether a part reference points at an existing part is left to the caller.
+    pub(crate) fn check_value(
+        self,
+        key: &str,
+        value_num: Option<i64>,
+        value_text: &Option<String>,
+    ) -> Result<(), Error> {
+        let wrong = |expected: &str| {
+            let m = format!("Attribute '{}' should be {}.", key, expected);
+            Err(Error::validation(m))
+        };
+        match self {
+            Self::Untyped => Ok(()),
+            Self::Integer | Self::PartReference if value_text.is_some() => {
+                wrong("a number, without text")
+            }
+            Self::Integer | Self::PartReference => Ok(()),
+            Self::Boolean if value_text.is_some() => wrong("0 or 1, without text"),
+            Self::Boolean => match value_num {
+                None | Some(0) | Some(1) => Ok(()),
+                Some(_) => wrong("0 or 1"),
+            },
+            _ if value_num.is_some() => wrong("text, without a number"),
+            Self::Text => Ok(()),
+            Self::Decimal => match value_text.as_ref().map(|t| t.trim().parse::<f64>()) {
+                None => Ok(()),
+                Some(Ok(n)) if n.is_finite() => Ok(()),
+                Some(_) => wrong("a decimal number"),
+            },
+            Self::Dice => match value_text {
+                Some(t) if !is_dice_expression(t) => wrong("a dice expression such as 2d6+3"),
+                _ => Ok(()),
+            },
+            Self::Enumeration => match value_text {
+                Some(t) if t.trim().is_empty() => wrong("one of its options"),
+                _ => Ok(()),
+            },
+        }
+    }
+}
+
+/// Whether text looks like a dice expression: dice such as "2d6" or "d20" and
+/// whole numbers, joined by "+" or "-". Dice may have modifiers such as "4d6kh3".
+pub fn is_dice_expression(text: &str) -> bool {
+    let text = text
+        .chars()
+        .filter(|c| !c.is_whitespace())
+        .collect::<String>();
+    let text = text.strip_prefix('-').unwrap_or(&text);
+    !text.is_empty()
+        && text
+            .split(['+', '-'])
+            .all(|term| match term.find(['d', 'D']) {
+                None => !term.is_empty() && term.chars().all(|c| c.is_ascii_digit()),
+                Some(i) => {
+                    let count = &term[..i];
+                    let rest = &term[i + 1..];
+                    let sides = rest
+                        .find(|c: char| !c.is_ascii_digit())
+                        .map(|j| &rest[..j])
+                        .unwrap_or(rest);
+                    let modifiers = &rest[sides.len()..];
+                    count.chars().all(|c| c.is_ascii_digit())
+                        && !sides.is_empty()
+                        && modifiers.chars().all(|c| c.is_ascii_alphanumeric())
+                }
+            })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{is_dice_expression, AttributeType};
+
+    #[test]
+    fn dice_expressions() {
+        for ok in ["2d6+3", "d20", "1d8 + 1d6 - 2", "4d6kh3", "-1+d4", "12"].iter() {
+            assert!(is_dice_expression(ok), "{}", ok);
+        }
+        for bad in ["", "banana", "2d", "2d6++3", "d6+", "2x6"].iter() {
+            assert!(!is_dice_expression(bad), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn attribute_values_fit_their_type() {
+        let text = |t: &str| Some(t.to_owned());
+        let checks = [
+            (AttributeType::Untyped, Some(3), text("banana"), true),
+            (AttributeType::Integer, Some(18), None, true),
+            (AttributeType::Integer, None, text("banana"), false),
+            (AttributeType::Decimal, None, text("1.5"), true),
+            (AttributeType::Decimal, None, text("one and a half"), false),
+            (AttributeType::Decimal, Some(1), None, false),
+            (AttributeType::Text, None, text("banana"), true),
+            (AttributeType::Text, Some(3), text("banana"), false),
+            (AttributeType::Boolean, Some(1), None, true),
+            (AttributeType::Boolean, Some(2), None, false),
+            (AttributeType::Dice, None, text("2d6+3"), true),
+            (AttributeType::Dice, None, text("banana"), false),
+            (AttributeType::Enumeration, None, text("Lawful Good"), true),
+            (AttributeType::Enumeration, None, text(" "), false),
+            (AttributeType::PartReference, Some(2), None, true),
+            (AttributeType::Integer, None, None, true),
+            (AttributeType::Dice, None, None, true),
+        ];
+        for (t, num, txt, ok) in checks.iter() {
+            let res = t.check_value("x", *num, txt);
+            assert_eq!(res.is_ok(), *ok, "{:?} {:?} {:?}", t, num, txt);
+        }
+    }
+}
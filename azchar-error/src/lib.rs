@@ -1,4 +1,187 @@
-// This module exists to very simply convert errors.
+//! This module describes what can go wrong, so that clients can tell errors apart.
+#[macro_use]
+extern crate serde_derive;
+extern crate diesel;
+extern crate diesel_migrations;
+extern crate serde;
+extern crate serde_json;
+extern crate toml;
+
+use diesel::result::{DatabaseErrorKind, Error as DsError};
+use std::fmt;
+
+/// An error, serialized with a machine-readable `code` and some context.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "code")]
+pub enum Error {
+    /// Something that was asked for does not exist, eg. a character.
+    NotFound { what: String, key: String },
+    /// The system does not allow this, eg. an attribute it does not know.
+    NotPermitted { what: String, reason: String },
+    /// The input is not acceptable.
+    ValidationFailed { reason: String },
+    /// This clashes with something that already exists.
+    Conflict { reason: String },
+    /// A request needs a system but none is loaded.
+    NoSystem,
+    /// Reading or writing files or sockets failed.
+    Io { reason: String },
+    /// The database failed, eg. because it is locked.
+    Database { reason: String },
+    /// The input could not be parsed.
+    Parse { reason: String },
+}
+
+impl Error {
+    pub fn not_found<W: fmt::Display, K: fmt::Display>(what: W, key: K) -> Self {
+        Error::NotFound {
+            what: what.to_string(),
+            key: key.to_string(),
+        }
+    }
+
+    pub fn not_permitted<W: fmt::Display, R: fmt::Display>(what: W, reason: R) -> Self {
+        Error::NotPermitted {
+            what: what.to_string(),
+            reason: reason.to_string(),
+        }
+    }
+
+    pub fn validation<R: fmt::Display>(reason: R) -> Self {
+        Error::ValidationFailed {
+            reason: reason.to_string(),
+        }
+    }
+
+    pub fn conflict<R: fmt::Display>(reason: R) -> Self {
+        Error::Conflict {
+            reason: reason.to_string(),
+        }
+    }
+
+    pub fn io<R: fmt::Debug>(reason: R) -> Self {
+        Error::Io { reason: ma(reason) }
+    }
+
+    pub fn database<R: fmt::Debug>(reason: R) -> Self {
+        Error::Database { reason: ma(reason) }
+    }
+
+    pub fn parse<R: fmt::Display>(reason: R) -> Self {
+        Error::Parse {
+            reason: reason.to_string(),
+        }
+    }
+
+    /// The machine-readable code of the error.
+    pub fn code(&self) -> &'static str {
+        match self {
+            Error::NotFound { .. } => "NotFound",
+            Error::NotPermitted { .. } => "NotPermitted",
+            Error::ValidationFailed { .. } => "ValidationFailed",
+            Error::Conflict { .. } => "Conflict",
+            Error::NoSystem => "NoSystem",
+            Error::Io { .. } => "Io",
+            Error::Database { .. } => "Database",
+            Error::Parse { .. } => "Parse",
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::NotFound { what, key } => write!(f, "{} {} not found.", what, key),
+            Error::NotPermitted { what, reason } => write!(f, "{} not permitted: {}", what, reason),
+            Error::NoSystem => write!(f, "Load system first."),
+            Error::ValidationFailed { reason }
+            | Error::Conflict { reason }
+            | Error::Io { reason }
+            | Error::Database { reason }
+            | Error::Parse { reason } => write!(f, "{}", reason),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<DsError> for Error {
+    fn from(e: DsError) -> Self {
+        match e {
+            DsError::NotFound => Error::not_found("Row", "requested"),
+            DsError::DatabaseError(DatabaseErrorKind::UniqueViolation, info) => {
+                Error::conflict(info.message())
+            }
+            DsError::DatabaseError(DatabaseErrorKind::ForeignKeyViolation, info) => {
+                Error::validation(info.message())
+            }
+            e => Error::database(e),
+        }
+    }
+}
+
+impl From<diesel::ConnectionError> for Error {
+    fn from(e: diesel::ConnectionError) -> Self {
+        Error::database(e)
+    }
+}
+
+impl From<diesel_migrations::RunMigrationsError> for Error {
+    fn from(e: diesel_migrations::RunMigrationsError) -> Self {
+        Error::database(e)
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Error::io(e)
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Self {
+        Error::parse(e)
+    }
+}
+
+impl From<toml::de::Error> for Error {
+    fn from(e: toml::de::Error) -> Self {
+        Error::parse(e)
+    }
+}
+
+impl From<toml::ser::Error> for Error {
+    fn from(e: toml::ser::Error) -> Self {
+        Error::parse(e)
+    }
+}
+
+/// Debug-format anything. Only for errors which are logged rather than returned.
 pub fn ma<D: std::fmt::Debug>(input: D) -> String {
     format!("{:?}", input)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn serialize_with_code() {
+        let e = Error::not_found("Character", "Euridice");
+        assert_eq!(
+            "{\"code\":\"NotFound\",\"what\":\"Character\",\"key\":\"Euridice\"}",
+            serde_json::to_string(&e).unwrap()
+        );
+        assert_eq!(
+            "{\"code\":\"NoSystem\"}",
+            serde_json::to_string(&Error::NoSystem).unwrap()
+        );
+        assert_eq!(e.code(), "NotFound");
+    }
+
+    #[test]
+    fn diesel_errors() {
+        assert_eq!(Error::from(DsError::NotFound).code(), "NotFound");
+        assert_eq!(Error::from(DsError::RollbackTransaction).code(), "Database");
+    }
+}
@@ -1,31 +1,56 @@
-use std::io::{Read, Write};
+use std::io::{self, Read, Write};
 use std::net::TcpStream;
 
+/// Must match the server.
+const MAX_FRAME: usize = 64 * 1024 * 1024;
+
 fn main() {
-    let input = if let Some(input) = std::env::args().nth(1) {
-        input
-    } else {
-        "{\"Roll\":\"2d10+1d4+6\"}".to_string()
-    };
+    // Every argument is a request. They all go over the same connection.
+    let mut inputs = std::env::args().skip(1).collect::<Vec<_>>();
+    if inputs.is_empty() {
+        inputs.push("{\"Roll\":\"2d10+1d4+6\"}".to_string());
+    }
     let mut stream = TcpStream::connect("127.0.0.1:55555").expect("Unparseable address");
-    send_message(input, &mut stream);
+    for input in inputs {
+        send_message(input, &mut stream);
+    }
 }
 // {\"Roll\":\"2d10dl1mx10+1d4+6\"}
 
+/// Messages are a 4-byte big-endian length followed by the message.
 pub fn send_message(message: String, stream: &mut TcpStream) -> String {
-    stream.write_all(message.as_bytes()).expect("Can't send");
+    let mut frame = Vec::with_capacity(4 + message.len());
+    frame.extend_from_slice(&(message.len() as u32).to_be_bytes());
+    frame.extend_from_slice(message.as_bytes());
+    stream.write_all(&frame).expect("Can't send");
     stream.flush().expect("Can't flush");
 
     // Receive.
-    let mut input = vec![0; 10 * 1024 * 1024];
-    input = match stream.read(&mut input) {
+    let out = match receive(stream) {
         Err(e) => {
             println!("Bad stream from {:?} because {:?}.", stream.peer_addr(), e);
             return String::new();
         }
-        Ok(n) => input[0..n].to_vec(),
+        Ok(input) => String::from_utf8_lossy(&input).to_string(),
     };
-    let out = String::from_utf8_lossy(&input);
     println!("{}", out);
-    out.to_owned().to_string()
+    out
+}
+
+fn receive(stream: &mut TcpStream) -> io::Result<Vec<u8>> {
+    let mut len = [0; 4];
+    stream.read_exact(&mut len)?;
+    let len = u32::from_be_bytes(len) as usize;
+    if len > MAX_FRAME {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Frame too large.",
+        ));
+    }
+    let mut input = Vec::new();
+    stream.take(len as u64).read_to_end(&mut input)?;
+    if input.len() < len {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(input)
 }
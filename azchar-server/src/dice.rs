@@ -0,0 +1,310 @@
+//! This deals with dice expressions which refer to a character's attributes,
+//! eg. `1d20+@dex_mod+@proficiency_bonus`.
+//! A reference is `@key`, or `@part.key` for the attribute of a particular part,
+//! where the part is its name or its id. Names with spaces go in braces: `@{+1 Scimitar}.weapon_damage1`.
+use azchar_database::character::attribute::{AttributeKey, AttributeValue};
+use azchar_database::character::character::CompleteCharacter;
+use azchar_error::Error;
+
+/// An attribute value which was put into a dice expression.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct Substitution {
+    /// The reference as written, eg. "@dex_mod".
+    pub(crate) reference: String,
+    /// The name of the part (or character) the attribute belongs to.
+    pub(crate) part: String,
+    pub(crate) key: String,
+    pub(crate) value: String,
+}
+
+/// One of the rolls of a roll macro, made the way `RollFor` makes them.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct MacroRoll {
+    /// "attack" or "damage".
+    pub(crate) label: String,
+    /// The roll as it was rolled.
+    pub(crate) dice: String,
+    pub(crate) substitutions: Vec<Substitution>,
+    /// The roll for each dice group.
+    pub(crate) totals: Vec<i64>,
+    pub(crate) bonus: i64,
+    /// The seed it was rolled with, if the session's rolls are seeded.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) seed: Option<u64>,
+}
+
+/// Replace the attribute references in a dice expression with their values.
+/// Without a part, a key is looked for on `own_part` (the id of the part rolling, if any),
+/// then on the character itself, then on its parts, as long as only one part has it.
+pub(crate) fn resolve(
+    expression: &str,
+    character: &CompleteCharacter,
+    own_part: Option<i64>,
+) -> Result<(String, Vec<Substitution>), Error> {
+    let chars = expression.chars().collect::<Vec<_>>();
+    let mut resolved = String::with_capacity(expression.len());
+    let mut substitutions = Vec::new();
+    let mut pos = 0;
+    while pos < chars.len() {
+        if chars[pos] != '@' {
+            resolved.push(chars[pos]);
+            pos += 1;
+            continue;
+        }
+        let start = pos;
+        pos += 1;
+        let first = read_name(&chars, &mut pos, expression)?;
+        let (part, key) = if chars.get(pos) == Some(&'.') {
+            pos += 1;
+            (Some(first), read_name(&chars, &mut pos, expression)?)
+        } else {
+            (None, first)
+        };
+        let reference = chars[start..pos].iter().collect::<String>();
+        let (part_name, value) = find_value(character, own_part, part.as_deref(), &key)?;
+        push_value(&mut resolved, &value, &reference)?;
+        substitutions.push(Substitution {
+            reference,
+            part: part_name.to_owned(),
+            key,
+            value,
+        });
+    }
+    Ok((resolved, substitutions))
+}
+
+/// Read a key or part name after `@` or `.`.
+fn read_name(chars: &[char], pos: &mut usize, expression: &str) -> Result<String, Error> {
+    let name = if chars.get(*pos) == Some(&'{') {
+        let start = *pos + 1;
+        match chars[start..].iter().position(|c| *c == '}') {
+            Some(len) => {
+                *pos = start + len + 1;
+                chars[start..start + len].iter().collect::<String>()
+            }
+            None => {
+                let m = format!("\"{}\": missing '}}' at position {}.", expression, start);
+                return Err(Error::parse(m));
+            }
+        }
+    } else {
+        let start = *pos;
+        while chars
+            .get(*pos)
+            .map(|c| c.is_alphanumeric() || *c == '_')
+            .unwrap_or(false)
+        {
+            *pos += 1;
+        }
+        chars[start..*pos].iter().collect::<String>()
+    };
+    if name.trim().is_empty() {
+        let m = format!("\"{}\": missing name at position {}.", expression, *pos);
+        return Err(Error::parse(m));
+    }
+    Ok(name)
+}
+
+/// Add a value, folding its sign into the operator before it, since `1d20+-1` is not a roll.
+/// After `-` only a single term can be added, since `1d20-@x` with `x = 1d4+2` is not `1d20-1d4+2`.
+fn push_value(resolved: &mut String, value: &str, reference: &str) -> Result<(), Error> {
+    let trimmed = resolved.trim_end().len();
+    let after_minus = resolved[..trimmed].ends_with('-');
+    let unsigned = value.strip_prefix('-').unwrap_or(value);
+    if after_minus && unsigned.contains(['+', '-']) {
+        let m = format!(
+            "'{}' is \"{}\", which can't be subtracted since it has several terms.",
+            reference, value
+        );
+        return Err(Error::parse(m));
+    }
+    match (value.strip_prefix('-'), resolved[..trimmed].chars().last()) {
+        (Some(positive), Some('+')) => {
+            resolved.truncate(trimmed - 1);
+            resolved.push('-');
+            resolved.push_str(positive);
+        }
+        (Some(positive), Some('-')) => {
+            resolved.truncate(trimmed - 1);
+            resolved.push('+');
+            resolved.push_str(positive);
+        }
+        _ => resolved.push_str(value),
+    }
+    Ok(())
+}
+
+/// Find the attribute a reference is to, and return where it was found and its value.
+fn find_value<'a>(
+    character: &'a CompleteCharacter,
+    own_part: Option<i64>,
+    part: Option<&str>,
+    key: &str,
+) -> Result<(&'a str, String), Error> {
+    let has_key = |attrs| find_key(attrs, key);
+    let reference = match part {
+        Some(p) => format!("@{{{}}}.{}", p, key),
+        None => format!("@{}", key),
+    };
+    let (part_name, value) = match part {
+        Some(p) if p == character.name() => match has_key(character.attributes()) {
+            Some(v) => (character.name(), v),
+            None => return Err(Error::not_found("Attribute", reference)),
+        },
+        Some(p) => {
+            let id = p.parse::<i64>().ok();
+            let parts = character
+                .parts()
+                .iter()
+                .filter(|c| c.name() == p || (id.is_some() && c.id() == id))
+                .collect::<Vec<_>>();
+            let found = match parts.as_slice() {
+                [] => return Err(Error::not_found("Part", p)),
+                [one] => one,
+                _ => {
+                    let m = format!("Several parts are called '{}', use its id instead.", p);
+                    return Err(Error::validation(m));
+                }
+            };
+            match has_key(&found.attributes) {
+                Some(v) => (found.name(), v),
+                None => return Err(Error::not_found("Attribute", reference)),
+            }
+        }
+        None => match own_part
+            .and_then(|id| character.parts().iter().find(|p| p.id() == Some(id)))
+            .and_then(|p| has_key(&p.attributes).map(|v| (p.name(), v)))
+            .or_else(|| has_key(character.attributes()).map(|v| (character.name(), v)))
+        {
+            Some(found) => found,
+            None => {
+                let parts = character
+                    .parts()
+                    .iter()
+                    .filter_map(|p| has_key(&p.attributes).map(|v| (p.name(), v)))
+                    .collect::<Vec<_>>();
+                match parts.as_slice() {
+                    [] => return Err(Error::not_found("Attribute", reference)),
+                    [one] => *one,
+                    _ => {
+                        let names = parts.iter().map(|p| p.0).collect::<Vec<_>>().join(", ");
+                        let m = format!(
+                            "'{}' is on several parts ({}), say which, eg. @{{{}}}.{}",
+                            reference, names, parts[0].0, key
+                        );
+                        return Err(Error::validation(m));
+                    }
+                }
+            }
+        },
+    };
+    match (value.value_num(), value.value_text()) {
+        (Some(n), _) => Ok((part_name, n.to_string())),
+        (None, Some(t)) if !t.trim().is_empty() => Ok((part_name, t.trim().to_owned())),
+        _ => Err(Error::validation(format!("'{}' has no value.", reference))),
+    }
+}
+
+fn find_key<'a>(
+    attrs: &'a [(AttributeKey, AttributeValue)],
+    key: &str,
+) -> Option<&'a AttributeValue> {
+    attrs.iter().find(|(k, _)| k.key() == key).map(|(_, v)| v)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::resolve;
+    use azchar_database::character::character::CompleteCharacter;
+    use azchar_error::Error;
+
+    fn euridice() -> CompleteCharacter {
+        let json = "{\"id\":1,\"name\":\"Euridice\",\"uuid\":\"e\",\"character_type\":\"dnd5e\",\
+            \"speed\":30,\"weight\":null,\"size\":null,\"hp_total\":null,\"hp_current\":null,\
+            \"parts\":[\
+              {\"id\":2,\"name\":\"+1 Scimitar\",\"uuid\":\"s\",\"character_type\":\"weapon\",\"speed\":0,\
+               \"weight\":3,\"size\":null,\"hp_total\":null,\"hp_current\":null,\"part_type\":\"InventoryItem\",\
+               \"belongs_to\":1,\"image\":null,\"attributes\":[\
+                 [{\"key\":\"weapon_damage1\",\"of\":2},{\"id\":5,\"value_num\":null,\"value_text\":\"1d6\",\"description\":null}],\
+                 [{\"key\":\"weapon_attack_bonus\",\"of\":2},{\"id\":6,\"value_num\":1,\"value_text\":null,\"description\":null}]]},\
+              {\"id\":3,\"name\":\"Dagger\",\"uuid\":\"d\",\"character_type\":\"weapon\",\"speed\":0,\
+               \"weight\":1,\"size\":null,\"hp_total\":null,\"hp_current\":null,\"part_type\":\"InventoryItem\",\
+               \"belongs_to\":1,\"image\":null,\"attributes\":[\
+                 [{\"key\":\"weapon_damage1\",\"of\":3},{\"id\":7,\"value_num\":null,\"value_text\":\"1d4\",\"description\":null}]]}],\
+            \"attributes\":[\
+              [{\"key\":\"dex_mod\",\"of\":1},{\"id\":1,\"value_num\":-1,\"value_text\":null,\"description\":null}],\
+              [{\"key\":\"proficiency_bonus\",\"of\":1},{\"id\":2,\"value_num\":2,\"value_text\":null,\"description\":null}],\
+              [{\"key\":\"luck\",\"of\":1},{\"id\":3,\"value_num\":null,\"value_text\":null,\"description\":null}],\
+              [{\"key\":\"sneak_attack\",\"of\":1},{\"id\":4,\"value_num\":null,\"value_text\":\"2d6+1\",\"description\":null}]],\
+            \"image\":null,\"notes\":[]}";
+        serde_json::from_str(json).expect("Not a character.")
+    }
+
+    #[test]
+    fn resolve_main_attributes() {
+        let (dice, subs) =
+            resolve("1d20+@dex_mod + @proficiency_bonus", &euridice(), None).unwrap();
+        assert_eq!(dice, "1d20-1 + 2");
+        assert_eq!(subs.len(), 2);
+        assert_eq!(subs[0].reference, "@dex_mod");
+        assert_eq!(subs[0].part, "Euridice");
+        assert_eq!(subs[0].value, "-1");
+        assert_eq!(subs[1].key, "proficiency_bonus");
+    }
+
+    #[test]
+    fn resolve_part_attributes() {
+        let e = euridice();
+        let (dice, subs) = resolve("@{+1 Scimitar}.weapon_damage1+@dex_mod", &e, None).unwrap();
+        assert_eq!(dice, "1d6-1");
+        assert_eq!(subs[0].part, "+1 Scimitar");
+        let (dice, _) = resolve("@3.weapon_damage1", &e, None).unwrap();
+        assert_eq!(dice, "1d4");
+        // Only one part has it, so it needs no part.
+        let (dice, subs) = resolve("1d20+@weapon_attack_bonus", &e, None).unwrap();
+        assert_eq!(dice, "1d20+1");
+        assert_eq!(subs[0].part, "+1 Scimitar");
+        // The part rolling goes first.
+        let (dice, subs) = resolve("@weapon_damage1+@dex_mod", &e, Some(3)).unwrap();
+        assert_eq!(dice, "1d4-1");
+        assert_eq!(subs[0].part, "Dagger");
+        assert_eq!(subs[1].part, "Euridice");
+    }
+
+    #[test]
+    fn resolve_mistakes() {
+        let e = euridice();
+        let res = resolve("@weapon_damage1", &e, None);
+        assert!(
+            matches!(res, Err(Error::ValidationFailed { reason }) if reason.contains("several parts"))
+        );
+        assert!(matches!(
+            resolve("1d20+@wis_mod", &e, None),
+            Err(Error::NotFound { .. })
+        ));
+        assert!(matches!(
+            resolve("@Sling.weapon_damage1", &e, None),
+            Err(Error::NotFound { .. })
+        ));
+        assert!(matches!(
+            resolve("1d4+@luck", &e, None),
+            Err(Error::ValidationFailed { .. })
+        ));
+        assert!(matches!(
+            resolve("1d4+@", &e, None),
+            Err(Error::Parse { .. })
+        ));
+        assert!(matches!(
+            resolve("1d4+@{luck", &e, None),
+            Err(Error::Parse { .. })
+        ));
+        // Subtracting a value with several terms would only subtract the first.
+        assert_eq!(
+            resolve("1d20+@sneak_attack", &e, None).unwrap().0,
+            "1d20+2d6+1"
+        );
+        let res = resolve("1d20 - @sneak_attack", &e, None);
+        assert!(matches!(res, Err(Error::Parse { reason }) if reason.contains("'@sneak_attack'")));
+        assert_eq!(resolve("1d20-@dex_mod", &e, None).unwrap().0, "1d20+1");
+    }
+}
@@ -0,0 +1,85 @@
+//! This deals with framing messages in client mode.
+//! Every message is a 4-byte big-endian length followed by that many bytes of UTF-8.
+use std::io::{self, Read, Write};
+
+/// Nobody has a character sheet this big, so a bigger frame is probably garbage.
+/// It is the same as the largest HTTP body.
+pub(crate) const MAX_FRAME: usize = crate::http::MAX_BODY;
+
+/// Read one message. Returns `None` if the other side closed the connection between messages.
+pub(crate) fn read_frame<R: Read>(r: &mut R) -> io::Result<Option<String>> {
+    let mut len = [0; 4];
+    // Tell a clean close from one in the middle of the length.
+    let mut read = 0;
+    while read < len.len() {
+        match r.read(&mut len[read..]) {
+            Ok(0) if read == 0 => return Ok(None),
+            Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(n) => read += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    let len = u32::from_be_bytes(len) as usize;
+    if len > MAX_FRAME {
+        let m = format!("Frame of {} bytes is larger than {} bytes.", len, MAX_FRAME);
+        return Err(io::Error::new(io::ErrorKind::InvalidData, m));
+    }
+    // The message grows as it arrives, rather than trusting the length up front.
+    let mut message = Vec::new();
+    r.by_ref().take(len as u64).read_to_end(&mut message)?;
+    if message.len() < len {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    String::from_utf8(message)
+        .map(Some)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// Write one message and flush it.
+pub(crate) fn write_frame<W: Write>(w: &mut W, message: &str) -> io::Result<()> {
+    if message.len() > MAX_FRAME {
+        let m = format!("Frame of {} bytes is too large.", message.len());
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, m));
+    }
+    // One write, or Nagle holds the message back until the length is acknowledged.
+    let mut frame = Vec::with_capacity(4 + message.len());
+    frame.extend_from_slice(&(message.len() as u32).to_be_bytes());
+    frame.extend_from_slice(message.as_bytes());
+    w.write_all(&frame)?;
+    w.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip_several_frames() {
+        let big = "x".repeat(11 * 1024 * 1024);
+        let mut buf = Vec::new();
+        write_frame(&mut buf, "\"ListCharacters\"").unwrap();
+        write_frame(&mut buf, &big).unwrap();
+        write_frame(&mut buf, "").unwrap();
+
+        let mut r = buf.as_slice();
+        assert_eq!(read_frame(&mut r).unwrap().unwrap(), "\"ListCharacters\"");
+        assert_eq!(read_frame(&mut r).unwrap().unwrap().len(), big.len());
+        assert_eq!(read_frame(&mut r).unwrap().unwrap(), "");
+        assert!(read_frame(&mut r).unwrap().is_none());
+    }
+
+    #[test]
+    fn truncated_frames_are_errors() {
+        let mut r: &[u8] = &[0, 0];
+        assert!(read_frame(&mut r).is_err());
+        let mut r: &[u8] = &[0, 0, 0, 5, b'a'];
+        assert!(read_frame(&mut r).is_err());
+        let mut r: &[u8] = &[0xff, 0xff, 0xff, 0xff];
+        assert!(read_frame(&mut r).is_err());
+        let too_big = (MAX_FRAME as u32 + 1).to_be_bytes();
+        let mut r: &[u8] = &too_big;
+        let e = read_frame(&mut r).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    }
+}
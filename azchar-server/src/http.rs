@@ -0,0 +1,719 @@
+//! This deals with the HTTP/1.1 REST interface.
+//! The routes are:
+//! - `POST /` with a raw `Request` as the body.
+//! - `POST /systems` with `[name, path, config]` as the body.
+//! - `PUT /systems` with the path to the root database as the body.
+//! - `GET /systems`, and `PUT|DELETE /systems/{name}` to use or unload a system.
+//! - `GET /characters`, `POST /characters` (name), `PUT /characters` (sheet).
+//! - `GET|PUT|DELETE /characters/{name}/{uuid}`.
+//! - `POST /characters/{name}/{uuid}/parts` and `PUT|DELETE .../parts/{id}`.
+//! - `GET|PUT .../parts/{id}/macros`, `DELETE .../macros/{macro}` and `POST .../macros/{macro}/roll`.
+//! - `POST|PUT /characters/{name}/{uuid}/attributes`.
+//! - `PUT /characters/{name}/{uuid}/images`.
+//! - `POST /characters/{name}/{uuid}/notes` and `PUT .../notes/{id}`.
+//! - `POST /roll` with the dice expression as the body, and `POST /roll/stats` for its odds.
+//! - `PUT /roll/seed` with a seed (or null) as the body, to seed the session's rolls.
+//! - `POST /shutdown`.
+//!
+//! Requests go to the last system loaded or used, unless an `X-System` header names another.
+//! A roll is seeded with the number in an `X-Seed` header, if there is one,
+//! and judged by the `RollMode` in an `X-Roll-Mode` header, eg. `{"Successes":8}`.
+use crate::requests::{Request, Response};
+use crate::roll::RollMode;
+use azchar_database::character::character::{CharacterPart, CompleteCharacter};
+use azchar_database::character::note::Note;
+use azchar_database::character::roll_macro::RollMacro;
+use azchar_error::Error;
+
+use serde::de::DeserializeOwned;
+use std::io::{BufRead, Write};
+
+const ALLOWED_HEADERS: &str = "Content-Type, X-System, X-Seed, X-Roll-Mode";
+/// A body bigger than this is refused, since it would all be read into memory.
+pub(crate) const MAX_BODY: usize = 64 * 1024 * 1024;
+
+/// An incoming HTTP request.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct HttpRequest {
+    pub(crate) method: String,
+    pub(crate) path: String,
+    pub(crate) headers: Vec<(String, String)>,
+    pub(crate) body: Vec<u8>,
+}
+
+/// A failure to turn an HTTP request into a `Request`.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct HttpError {
+    pub(crate) status: u16,
+    pub(crate) message: String,
+}
+
+impl HttpError {
+    fn new(status: u16, message: String) -> Self {
+        Self { status, message }
+    }
+}
+
+impl HttpRequest {
+    /// Read a single request from a stream.
+    /// Returns `None` if the stream closed before a request line arrived.
+    pub(crate) fn read_from<R: BufRead>(reader: &mut R) -> Result<Option<Self>, HttpError> {
+        let mut line = String::new();
+        if read_line(reader, &mut line)? == 0 {
+            return Ok(None);
+        }
+        let mut request_line = line.split_whitespace();
+        let (method, target) = match (request_line.next(), request_line.next()) {
+            (Some(m), Some(t)) => (m.to_uppercase(), t.to_owned()),
+            _ => return Err(HttpError::new(400, format!("Bad request line: {:?}", line))),
+        };
+
+        let mut headers = Vec::new();
+        loop {
+            line.clear();
+            if read_line(reader, &mut line)? == 0 {
+                return Err(HttpError::new(400, "Unexpected end of headers.".to_owned()));
+            }
+            let header = line.trim_end();
+            if header.is_empty() {
+                break;
+            }
+            match header.split_once(':') {
+                Some((k, v)) => headers.push((k.trim().to_lowercase(), v.trim().to_owned())),
+                None => return Err(HttpError::new(400, format!("Bad header: {:?}", header))),
+            }
+        }
+
+        let mut request = HttpRequest {
+            method,
+            path: target.split('?').next().unwrap_or("/").to_owned(),
+            headers,
+            body: Vec::new(),
+        };
+        let chunked = request
+            .header("transfer-encoding")
+            .map(|te| te.to_lowercase().contains("chunked"))
+            .unwrap_or(false);
+        if chunked {
+            request.body = read_chunked(reader)?;
+        } else if let Some(len) = request.header("content-length") {
+            let len = len
+                .parse::<usize>()
+                .map_err(|e| HttpError::new(400, format!("Bad Content-Length: {:?}", e)))?;
+            check_body_size(len)?;
+            request.body = vec![0; len];
+            reader
+                .read_exact(&mut request.body)
+                .map_err(|e| HttpError::new(400, format!("Body too short: {:?}", e)))?;
+        }
+        Ok(Some(request))
+    }
+
+    /// Get a header by its (case insensitive) name.
+    pub(crate) fn header(&self, name: &str) -> Option<&str> {
+        let name = name.to_lowercase();
+        self.headers
+            .iter()
+            .find(|(k, _)| k == &name)
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// The percent-decoded segments of the path.
+    pub(crate) fn segments(&self) -> Result<Vec<String>, HttpError> {
+        self.path
+            .split('/')
+            .filter(|s| !s.is_empty())
+            .map(percent_decode)
+            .collect()
+    }
+
+    /// Work out which `Request` this HTTP request represents.
+    pub(crate) fn route(&self) -> Result<Request, HttpError> {
+        let segments = self.segments()?;
+        let segs = segments.iter().map(String::as_str).collect::<Vec<_>>();
+        let b = &self.body;
+        let request = match (self.method.as_str(), segs.as_slice()) {
+            ("POST", []) => body_json(b)?,
+            ("POST", ["systems"]) => {
+                let (name, path, config): (String, String, String) = body_json(b)?;
+                Request::CreateSystem(name, path, config)
+            }
+            ("PUT", ["systems"]) => Request::InitialiseFromPath(body_text(b)?),
+            ("GET", ["systems"]) => Request::ListSystems,
+            ("PUT", ["systems", name]) => Request::UseSystem(name.to_string()),
+            ("DELETE", ["systems", name]) => Request::UnloadSystem(name.to_string()),
+            ("POST", ["backup"]) => Request::Backup(body_text(b)?),
+            ("POST", ["restore"]) => {
+                let (archive, dir) = body_json(b)?;
+                Request::Restore(archive, dir)
+            }
+            ("GET", ["integrity"]) => Request::CheckIntegrity,
+            ("POST", ["integrity", "repair"]) => Request::Repair,
+            ("GET", ["characters"]) => Request::ListCharacters,
+            ("POST", ["characters"]) => Request::CreateCharacterSheet(body_text(b)?),
+            ("PUT", ["characters"]) => Request::CreateUpdateCharacter(body_json(b)?),
+            ("GET", ["characters", name, uuid]) => {
+                Request::LoadCharacter(name.to_string(), uuid.to_string())
+            }
+            ("PUT", ["characters", name, uuid]) => {
+                let sheet: CompleteCharacter = body_json(b)?;
+                if (sheet.name(), sheet.uuid()) != (*name, *uuid) {
+                    let m = format!("Sheet is not ({}, {}).", name, uuid);
+                    return Err(HttpError::new(400, m));
+                }
+                Request::CreateUpdateCharacter(sheet)
+            }
+            ("DELETE", ["characters", name, uuid]) => {
+                Request::DeleteCharacter(name.to_string(), uuid.to_string())
+            }
+            ("POST", ["characters", "import"]) => {
+                let (path, policy) = body_json(b)?;
+                Request::ImportCharacter(path, policy)
+            }
+            ("POST", ["characters", name, uuid, "export"]) => {
+                let (path, format) = body_json(b)?;
+                Request::ExportCharacter(name.to_string(), uuid.to_string(), path, format)
+            }
+            ("POST", ["characters", name, uuid, "parts"]) => {
+                Request::CreatePart(name.to_string(), uuid.to_string(), body_json(b)?)
+            }
+            ("PUT", ["characters", name, uuid, "parts", id]) => {
+                let part: CharacterPart = body_json(b)?;
+                if part.id() != Some(parse_id(id)?) {
+                    let m = format!("Part in body is not part {}.", id);
+                    return Err(HttpError::new(400, m));
+                }
+                Request::UpdatePart(name.to_string(), uuid.to_string(), part)
+            }
+            ("DELETE", ["characters", name, uuid, "parts", id]) => {
+                Request::DeletePart(name.to_string(), uuid.to_string(), parse_id(id)?)
+            }
+            ("GET", ["characters", name, uuid, "parts", id, "macros"]) => {
+                Request::GetRollMacros(name.to_string(), uuid.to_string(), parse_id(id)?)
+            }
+            ("PUT", ["characters", name, uuid, "parts", id, "macros"]) => {
+                let roll_macro: RollMacro = body_json(b)?;
+                if roll_macro.of != parse_id(id)? {
+                    let m = format!("Roll macro in body is not for part {}.", id);
+                    return Err(HttpError::new(400, m));
+                }
+                Request::SetRollMacro(name.to_string(), uuid.to_string(), roll_macro)
+            }
+            ("DELETE", ["characters", name, uuid, "parts", id, "macros", m]) => {
+                let (id, m) = (parse_id(id)?, m.to_string());
+                Request::DeleteRollMacro(name.to_string(), uuid.to_string(), id, m)
+            }
+            ("POST", ["characters", name, uuid, "parts", id, "macros", m, "roll"]) => {
+                let (id, m) = (parse_id(id)?, m.to_string());
+                Request::RollMacro(name.to_string(), uuid.to_string(), id, m)
+            }
+            ("POST", ["characters", name, uuid, "attributes"]) => {
+                Request::CreateAttribute(name.to_string(), uuid.to_string(), body_json(b)?)
+            }
+            ("PUT", ["characters", name, uuid, "attributes"]) => {
+                let (k, v) = body_json(b)?;
+                Request::UpdateAttribute(name.to_string(), uuid.to_string(), k, v)
+            }
+            ("PUT", ["characters", name, uuid, "images"]) => {
+                Request::InsertUpdateImage(name.to_string(), uuid.to_string(), body_json(b)?)
+            }
+            ("POST", ["characters", name, uuid, "notes"]) => {
+                Request::InsertNote(name.to_string(), uuid.to_string(), body_json(b)?)
+            }
+            ("PUT", ["characters", name, uuid, "notes", id]) => {
+                let note: Note = body_json(b)?;
+                if note.id != parse_id(id)? {
+                    let m = format!("Note in body is not note {}.", id);
+                    return Err(HttpError::new(400, m));
+                }
+                Request::UpdateNote(name.to_string(), uuid.to_string(), note)
+            }
+            ("POST", ["characters", name, uuid, "roll"]) => {
+                let (dice, seed, mode) = (body_text(b)?, self.seed()?, self.roll_mode()?);
+                Request::RollFor {
+                    name: name.to_string(),
+                    uuid: uuid.to_string(),
+                    dice,
+                    seed,
+                    mode,
+                }
+            }
+            ("GET", ["characters", name, uuid, "rolls"]) => {
+                Request::GetRollLog(name.to_string(), uuid.to_string(), None, None)
+            }
+            ("GET", ["rolls"]) => Request::GetSessionRollLog(None, None),
+            ("GET", ["hello"]) => Request::Hello,
+            ("POST", ["batch"]) => Request::Batch(body_json(b)?),
+            ("POST", ["roll"]) => match (self.seed()?, self.roll_mode()?) {
+                (None, None) => Request::Roll(body_text(b)?),
+                (seed, mode) => Request::RollWith {
+                    dice: body_text(b)?,
+                    seed,
+                    mode,
+                },
+            },
+            ("POST", ["roll", "detailed"]) => {
+                let (dice, label) = body_json(b)?;
+                Request::RollDetailed {
+                    dice,
+                    label,
+                    seed: self.seed()?,
+                    mode: self.roll_mode()?,
+                }
+            }
+            ("POST", ["roll", "stats"]) => Request::RollStats(body_text(b)?),
+            ("PUT", ["roll", "seed"]) => Request::SeedRolls(body_json(b)?),
+            ("POST", ["shutdown"]) => Request::Shutdown,
+            (m, path) => {
+                let allowed = allowed_methods(path);
+                let e = if allowed.is_empty() {
+                    HttpError::new(404, format!("No such resource: {}", self.path))
+                } else {
+                    HttpError::new(405, format!("{} not allowed on {}", m, self.path))
+                };
+                return Err(e);
+            }
+        };
+        Ok(request)
+    }
+
+    /// The seed from the `X-Seed` header, if there is one.
+    fn seed(&self) -> Result<Option<u64>, HttpError> {
+        match self.header("x-seed") {
+            Some(s) => s
+                .parse()
+                .map(Some)
+                .map_err(|_| HttpError::new(400, format!("'{}' is not a valid seed.", s))),
+            None => Ok(None),
+        }
+    }
+
+    /// The way to judge a roll from the `X-Roll-Mode` header, if there is one.
+    fn roll_mode(&self) -> Result<Option<RollMode>, HttpError> {
+        match self.header("x-roll-mode") {
+            Some(m) => serde_json::from_str(m)
+                .map(Some)
+                .map_err(|_| HttpError::new(400, format!("'{}' is not a valid roll mode.", m))),
+            None => Ok(None),
+        }
+    }
+}
+
+/// The methods which can be used on a path.
+pub(crate) fn allowed_methods(segs: &[&str]) -> &'static [&'static str] {
+    match segs {
+        [] => &["POST", "OPTIONS"],
+        ["systems"] => &["GET", "POST", "PUT", "OPTIONS"],
+        ["systems", _] => &["PUT", "DELETE", "OPTIONS"],
+        ["characters"] => &["GET", "POST", "PUT", "OPTIONS"],
+        ["characters", "import"] => &["POST", "OPTIONS"],
+        ["characters", _, _] => &["GET", "PUT", "DELETE", "OPTIONS"],
+        ["characters", _, _, "export"] => &["POST", "OPTIONS"],
+        ["characters", _, _, "parts"] => &["POST", "OPTIONS"],
+        ["characters", _, _, "parts", _] => &["PUT", "DELETE", "OPTIONS"],
+        ["characters", _, _, "parts", _, "macros"] => &["GET", "PUT", "OPTIONS"],
+        ["characters", _, _, "parts", _, "macros", _] => &["DELETE", "OPTIONS"],
+        ["characters", _, _, "parts", _, "macros", _, "roll"] => &["POST", "OPTIONS"],
+        ["characters", _, _, "attributes"] => &["POST", "PUT", "OPTIONS"],
+        ["characters", _, _, "images"] => &["PUT", "OPTIONS"],
+        ["characters", _, _, "notes"] => &["POST", "OPTIONS"],
+        ["characters", _, _, "notes", _] => &["PUT", "OPTIONS"],
+        ["characters", _, _, "roll"] => &["POST", "OPTIONS"],
+        ["characters", _, _, "rolls"] => &["GET", "OPTIONS"],
+        ["rolls"] => &["GET", "OPTIONS"],
+        ["hello"] => &["GET", "OPTIONS"],
+        ["roll", "seed"] => &["PUT", "OPTIONS"],
+        ["backup"] | ["restore"] | ["integrity", "repair"] => &["POST", "OPTIONS"],
+        ["integrity"] => &["GET", "OPTIONS"],
+        ["batch"] | ["roll"] | ["roll", "detailed"] | ["roll", "stats"] | ["shutdown"] => {
+            &["POST", "OPTIONS"]
+        }
+        _ => &[],
+    }
+}
+
+/// The HTTP status code which best describes an error.
+fn status_of_error(error: &Error) -> u16 {
+    match error {
+        Error::NotFound { .. } => 404,
+        Error::NotPermitted { .. } => 403,
+        Error::ValidationFailed { .. } | Error::Parse { .. } => 400,
+        Error::Conflict { .. } | Error::NoSystem => 409,
+        Error::Io { .. } | Error::Database { .. } => 500,
+    }
+}
+
+/// The HTTP status code to return with a response.
+pub(crate) fn status_of(response: &Response) -> u16 {
+    match response {
+        Response::Err(_, e) | Response::BatchFailed(_, e) => status_of_error(e),
+        Response::Invalid(_) => 400,
+        _ => 200,
+    }
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        204 => "No Content",
+        400 => "Bad Request",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        409 => "Conflict",
+        413 => "Payload Too Large",
+        500 => "Internal Server Error",
+        _ => "Unknown Status",
+    }
+}
+
+/// Write a JSON reply with CORS headers. The connection is closed afterwards.
+pub(crate) fn write_response<W: Write>(
+    w: &mut W,
+    status: u16,
+    body: &str,
+    allow: &[&str],
+) -> std::io::Result<()> {
+    let allow = if allow.is_empty() {
+        "GET, POST, PUT, DELETE, OPTIONS".to_owned()
+    } else {
+        allow.join(", ")
+    };
+    let head = format!(
+        "HTTP/1.1 {} {}\r\n\
+        Content-Type: application/json; charset=utf-8\r\n\
+        Content-Length: {}\r\n\
+        Connection: close\r\n\
+        Allow: {}\r\n\
+        Access-Control-Allow-Origin: *\r\n\
+        Access-Control-Allow-Methods: {}\r\n\
+        Access-Control-Allow-Headers: {}\r\n\r\n",
+        status,
+        reason(status),
+        body.len(),
+        allow,
+        allow,
+        ALLOWED_HEADERS,
+    );
+    w.write_all(head.as_bytes())?;
+    w.write_all(body.as_bytes())?;
+    w.flush()
+}
+
+fn read_line<R: BufRead>(reader: &mut R, line: &mut String) -> Result<usize, HttpError> {
+    reader
+        .read_line(line)
+        .map_err(|e| HttpError::new(400, format!("Could not read request: {:?}", e)))
+}
+
+fn check_body_size(len: usize) -> Result<(), HttpError> {
+    if len > MAX_BODY {
+        let m = format!("Body of {} bytes is larger than {} bytes.", len, MAX_BODY);
+        return Err(HttpError::new(413, m));
+    }
+    Ok(())
+}
+
+fn read_chunked<R: BufRead>(reader: &mut R) -> Result<Vec<u8>, HttpError> {
+    let mut body = Vec::new();
+    let mut line = String::new();
+    loop {
+        line.clear();
+        read_line(reader, &mut line)?;
+        let size = line.split(';').next().unwrap_or("").trim();
+        let size = usize::from_str_radix(size, 16)
+            .map_err(|e| HttpError::new(400, format!("Bad chunk size {:?}: {:?}", size, e)))?;
+        if size == 0 {
+            // Skip any trailers.
+            loop {
+                line.clear();
+                if read_line(reader, &mut line)? == 0 || line.trim_end().is_empty() {
+                    return Ok(body);
+                }
+            }
+        }
+        let start = body.len();
+        check_body_size(start.saturating_add(size))?;
+        body.resize(start + size, 0);
+        reader
+            .read_exact(&mut body[start..])
+            .map_err(|e| HttpError::new(400, format!("Chunk too short: {:?}", e)))?;
+        line.clear();
+        read_line(reader, &mut line)?;
+    }
+}
+
+fn percent_decode(input: &str) -> Result<String, HttpError> {
+    let bytes = input.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' && i + 2 < bytes.len() {
+            let hex = std::str::from_utf8(&bytes[i + 1..i + 3]).unwrap_or("");
+            if let Ok(b) = u8::from_str_radix(hex, 16) {
+                out.push(b);
+                i += 3;
+                continue;
+            }
+        }
+        out.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8(out).map_err(|e| HttpError::new(400, format!("Bad path: {:?}", e)))
+}
+
+fn parse_id(id: &str) -> Result<i64, HttpError> {
+    id.parse()
+        .map_err(|_| HttpError::new(400, format!("'{}' is not a valid id.", id)))
+}
+
+fn body_json<T: DeserializeOwned>(body: &[u8]) -> Result<T, HttpError> {
+    serde_json::from_slice(body).map_err(|e| HttpError::new(400, format!("Bad body: {}", e)))
+}
+
+/// Plain text bodies may also be sent as a JSON string.
+fn body_text(body: &[u8]) -> Result<String, HttpError> {
+    if let Ok(s) = serde_json::from_slice::<String>(body) {
+        return Ok(s);
+    }
+    String::from_utf8(body.to_vec())
+        .map(|s| s.trim().to_owned())
+        .map_err(|e| HttpError::new(400, format!("Bad body: {:?}", e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use azchar_database::root_db::character_file::{CharacterFileFormat, UuidClashPolicy};
+    use std::io::BufReader;
+
+    fn parse(raw: &str) -> HttpRequest {
+        let mut reader = BufReader::new(raw.as_bytes());
+        HttpRequest::read_from(&mut reader)
+            .expect("Could not parse.")
+            .expect("No request.")
+    }
+
+    #[test]
+    fn parse_content_length_body() {
+        let req = parse("POST /roll HTTP/1.1\r\nHost: x\r\nContent-Length: 6\r\n\r\n\"1d20\"");
+        assert_eq!(req.method, "POST");
+        assert_eq!(req.path, "/roll");
+        assert_eq!(req.header("HOST"), Some("x"));
+        assert!(matches!(req.route(), Ok(Request::Roll(r)) if r == "1d20"));
+    }
+
+    #[test]
+    fn parse_chunked_body() {
+        let req = parse(
+            "POST /characters HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n\
+            4\r\nEuri\r\n4;x=y\r\ndice\r\n0\r\n\r\n",
+        );
+        assert_eq!(req.body, b"Euridice");
+        assert!(matches!(
+            req.route(),
+            Ok(Request::CreateCharacterSheet(n)) if n == "Euridice"
+        ));
+    }
+
+    #[test]
+    fn refuse_too_large_body() {
+        let raw = format!(
+            "POST /characters HTTP/1.1\r\nContent-Length: {}\r\n\r\n",
+            MAX_BODY + 1
+        );
+        let mut reader = BufReader::new(raw.as_bytes());
+        let e = HttpRequest::read_from(&mut reader).unwrap_err();
+        assert_eq!(e.status, 413);
+        let raw = format!(
+            "POST /characters HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n\
+            4\r\nEuri\r\n{:x}\r\n",
+            MAX_BODY
+        );
+        let mut reader = BufReader::new(raw.as_bytes());
+        let e = HttpRequest::read_from(&mut reader).unwrap_err();
+        assert_eq!(e.status, 413);
+    }
+
+    #[test]
+    fn parse_large_body() {
+        let content = "a".repeat(3 * 1024 * 1024);
+        let raw = format!(
+            "POST /characters HTTP/1.1\r\nContent-Length: {}\r\n\r\n{}",
+            content.len(),
+            content
+        );
+        assert_eq!(parse(&raw).body.len(), content.len());
+    }
+
+    #[test]
+    fn route_load_character_with_escapes() {
+        let req = parse("GET /characters/Lalth%20Isintantin/1234?x=1 HTTP/1.1\r\n\r\n");
+        assert!(matches!(
+            req.route(),
+            Ok(Request::LoadCharacter(n, u)) if n == "Lalth Isintantin" && u == "1234"
+        ));
+    }
+
+    #[test]
+    fn route_roll_for() {
+        let req = parse("POST /characters/Euridice/1234/roll HTTP/1.1\r\nContent-Length: 15\r\n\r\n\"1d20+@dex_mod\"");
+        assert!(matches!(
+            req.route(),
+            Ok(Request::RollFor { name, dice, seed: None, mode: None, .. })
+                if name == "Euridice" && dice == "1d20+@dex_mod"
+        ));
+    }
+
+    #[test]
+    fn route_seeded_rolls() {
+        let req = parse("POST /roll HTTP/1.1\r\nX-Seed: 42\r\nContent-Length: 4\r\n\r\n1d20");
+        assert!(
+            matches!(req.route(), Ok(Request::RollWith { dice, seed: Some(42), mode: None }) if dice == "1d20")
+        );
+        let req = parse("POST /roll HTTP/1.1\r\nX-Seed: -1\r\nContent-Length: 4\r\n\r\n1d20");
+        assert_eq!(req.route().unwrap_err().status, 400);
+        let req = parse("PUT /roll/seed HTTP/1.1\r\nContent-Length: 4\r\n\r\nnull");
+        assert!(matches!(req.route(), Ok(Request::SeedRolls(None))));
+        let req = parse("PUT /roll/seed HTTP/1.1\r\nContent-Length: 3\r\n\r\n123");
+        assert!(matches!(req.route(), Ok(Request::SeedRolls(Some(123)))));
+    }
+
+    #[test]
+    fn route_roll_macros() {
+        let req =
+            parse("POST /characters/Euridice/1234/parts/42/macros/strike/roll HTTP/1.1\r\n\r\n");
+        assert!(matches!(
+            req.route(),
+            Ok(Request::RollMacro(n, _, 42, m)) if n == "Euridice" && m == "strike"
+        ));
+        let body = "{\"of\":41,\"name\":\"strike\",\"attack\":\"1d20\",\"damage\":null}";
+        let req = parse(&format!(
+            "PUT /characters/Euridice/1234/parts/42/macros HTTP/1.1\r\nContent-Length: {}\r\n\r\n{}",
+            body.len(),
+            body
+        ));
+        assert_eq!(req.route().unwrap_err().status, 400);
+        let req = parse("DELETE /characters/Euridice/1234/parts/42/macros HTTP/1.1\r\n\r\n");
+        assert_eq!(req.route().unwrap_err().status, 405);
+    }
+
+    #[test]
+    fn route_export_import_character() {
+        let body = "[\"euridice.json\",\"Json\"]";
+        let req = parse(&format!(
+            "POST /characters/Euridice/1234/export HTTP/1.1\r\nContent-Length: {}\r\n\r\n{}",
+            body.len(),
+            body
+        ));
+        assert!(matches!(
+            req.route(),
+            Ok(Request::ExportCharacter(n, _, p, CharacterFileFormat::Json))
+                if n == "Euridice" && p == "euridice.json"
+        ));
+        let body = "[\"euridice.json\",\"Overwrite\"]";
+        let req = parse(&format!(
+            "POST /characters/import HTTP/1.1\r\nContent-Length: {}\r\n\r\n{}",
+            body.len(),
+            body
+        ));
+        assert!(matches!(
+            req.route(),
+            Ok(Request::ImportCharacter(p, UuidClashPolicy::Overwrite)) if p == "euridice.json"
+        ));
+        let req = parse("GET /characters/import HTTP/1.1\r\n\r\n");
+        assert_eq!(req.route().unwrap_err().status, 405);
+    }
+
+    #[test]
+    fn route_roll_modes() {
+        let req = parse("POST /roll HTTP/1.1\r\nX-Roll-Mode: {\"Successes\":8}\r\nContent-Length: 4\r\n\r\n5d10");
+        assert!(matches!(
+            req.route(),
+            Ok(Request::RollWith {
+                seed: None,
+                mode: Some(RollMode::Successes(8)),
+                ..
+            })
+        ));
+        let req =
+            parse("POST /roll HTTP/1.1\r\nX-Roll-Mode: Target\r\nContent-Length: 4\r\n\r\n1d20");
+        assert_eq!(req.route().unwrap_err().status, 400);
+    }
+
+    #[test]
+    fn route_delete_part() {
+        let req = parse("DELETE /characters/Euridice/1234/parts/42 HTTP/1.1\r\n\r\n");
+        assert!(matches!(req.route(), Ok(Request::DeletePart(_, _, 42))));
+    }
+
+    #[test]
+    fn route_systems() {
+        let req = parse("GET /systems HTTP/1.1\r\nX-System: dnd5e\r\n\r\n");
+        assert!(matches!(req.route(), Ok(Request::ListSystems)));
+        assert_eq!(req.header("x-system"), Some("dnd5e"));
+        let req = parse("DELETE /systems/dnd5e HTTP/1.1\r\n\r\n");
+        assert!(matches!(req.route(), Ok(Request::UnloadSystem(n)) if n == "dnd5e"));
+        let req = parse("POST /systems/dnd5e HTTP/1.1\r\n\r\n");
+        assert_eq!(req.route().unwrap_err().status, 405);
+    }
+
+    #[test]
+    fn route_integrity() {
+        let req = parse("GET /integrity HTTP/1.1\r\n\r\n");
+        assert!(matches!(req.route(), Ok(Request::CheckIntegrity)));
+        let req = parse("POST /integrity/repair HTTP/1.1\r\n\r\n");
+        assert!(matches!(req.route(), Ok(Request::Repair)));
+        let req = parse("GET /integrity/repair HTTP/1.1\r\n\r\n");
+        assert_eq!(req.route().unwrap_err().status, 405);
+    }
+
+    #[test]
+    fn route_errors() {
+        let req = parse("GET /nowhere HTTP/1.1\r\n\r\n");
+        assert_eq!(req.route().unwrap_err().status, 404);
+        let req = parse("DELETE /roll HTTP/1.1\r\n\r\n");
+        assert_eq!(req.route().unwrap_err().status, 405);
+        let req = parse("DELETE /characters/a/b/parts/x HTTP/1.1\r\n\r\n");
+        assert_eq!(req.route().unwrap_err().status, 400);
+    }
+
+    #[test]
+    fn status_from_error_code() {
+        let err = |e| Response::Err(String::new(), e);
+        assert_eq!(status_of(&err(Error::NoSystem)), 409);
+        assert_eq!(status_of(&err(Error::not_found("Character", "x"))), 404);
+        assert_eq!(status_of(&err(Error::validation("x"))), 400);
+        assert_eq!(
+            status_of(&Response::BatchFailed(1, Error::database("x"))),
+            500
+        );
+        assert_eq!(status_of(&Response::UpdatePart), 200);
+    }
+
+    #[test]
+    fn write_response_headers() {
+        let mut out = Vec::new();
+        write_response(&mut out, 409, "{}", &[]).expect("Can write.");
+        let out = String::from_utf8(out).expect("utf8");
+        assert!(out.starts_with("HTTP/1.1 409 Conflict\r\n"));
+        assert!(out.contains("Content-Type: application/json; charset=utf-8\r\n"));
+        assert!(out.contains("Content-Length: 2\r\n"));
+        assert!(out.contains("Access-Control-Allow-Origin: *\r\n"));
+        assert!(out.ends_with("\r\n\r\n{}"));
+    }
+
+    #[test]
+    fn write_forbidden_response() {
+        let forbidden = Error::not_permitted("Attribute 'str_mod'", "it is derived from a formula");
+        let status = status_of(&Response::Err(String::new(), forbidden));
+        let mut out = Vec::new();
+        write_response(&mut out, status, "{}", &[]).expect("Can write.");
+        let out = String::from_utf8(out).expect("utf8");
+        assert!(out.starts_with("HTTP/1.1 403 Forbidden\r\n"));
+        let mut out = Vec::new();
+        write_response(&mut out, 500, "{}", &[]).expect("Can write.");
+        let out = String::from_utf8(out).expect("utf8");
+        assert!(out.starts_with("HTTP/1.1 500 Internal Server Error\r\n"));
+    }
+}
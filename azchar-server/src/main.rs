@@ -14,8 +14,14 @@ extern crate azchar_config;
 extern crate azchar_database;
 extern crate azchar_error;
 
+mod dice;
+mod framing;
+mod http;
 mod main_loop;
 mod requests;
+mod roll;
+mod stats;
+mod systems;
 mod websocket_loop;
 
 #[cfg(test)]
@@ -25,10 +31,14 @@ extern crate tempfile;
 
 use crate::main_loop::MainLoop;
 use crate::websocket_loop::WsMainLoop;
+use azchar_database::root_db::system_config::SystemConfig;
 
 fn main() {
     // Get settings.
     let args: Vec<String> = std::env::args().map(String::from).collect();
+    if let (Some("validate"), Some(path)) = (args.get(1).map(String::as_str), args.get(2)) {
+        std::process::exit(validate(path));
+    }
     let address: String = match args.get(1) {
         Some(s) => String::from(s),
         None => String::from("127.0.0.1:55555"),
@@ -48,6 +58,34 @@ fn main() {
     }
 }
 
+/// Check a system config and print what is wrong with it.
+/// The exit code is 1 if it has errors, or 2 if it can't be read at all.
+fn validate(path: &str) -> i32 {
+    match SystemConfig::lint_file(std::path::Path::new(path)) {
+        Ok(diagnostics) => {
+            for d in diagnostics.iter() {
+                println!("{}", d);
+            }
+            let errors = diagnostics.iter().filter(|d| d.is_error()).count();
+            println!(
+                "{}: {} error(s), {} warning(s).",
+                path,
+                errors,
+                diagnostics.len() - errors
+            );
+            if errors > 0 {
+                1
+            } else {
+                0
+            }
+        }
+        Err(e) => {
+            println!("{}: {}", path, e);
+            2
+        }
+    }
+}
+
 #[derive(Clone, Copy, Debug)]
 pub(crate) enum Mode {
     Http,
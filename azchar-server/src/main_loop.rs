@@ -1,148 +1,193 @@
 //! Here we deal with the main loop.
 use super::Mode;
+use crate::framing;
+use crate::http::{self, HttpRequest};
 use crate::requests::{Request, Response};
-use azchar_database::root_db::LoadedDbs;
+use crate::systems::{Session, Systems};
+use crate::websocket_loop::{lock, SharedDbs};
 use azchar_error::ma;
 
-use std::io::{Read, Write};
+use std::io::BufReader;
 use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
 
-const START: &str = "AZCHARREQUEST__";
-const END: &str = "__ENDAZCHARREQUEST";
-
-const ESC: [&str; 26] = [
-    "%3A", "%3C", "%3E", "%5B", "%5D", "%7B", "%7D", "%2B", "%2F", "%2C", "%3B", "%3D", "%3F",
-    "%5C", "%5E", "%7C", "%7E", "%23", "%20", "%24", "%25", "%26", "%40", "%60", "%22", "%27",
-];
-const ESC2: [&str; 26] = [
-    ":", "<", ">", "[", "]", "{", "}", "+", "/", ",", ";", "=", "?", "\\", "^", "|", "~", "#", " ",
-    "$", "%", "&", "@", "`", "\"", "'",
-];
+/// How long an HTTP client may take to send its request.
+const HTTP_READ_TIMEOUT: Duration = Duration::from_secs(30);
 
 pub struct MainLoop {
-    /// This represents the local connection to the system.
-    pub(super) dbs: Option<LoadedDbs>,
+    /// This represents the loaded systems, shared by the client connections.
+    pub(super) systems: SharedDbs,
+    /// What HTTP requests work on. Client mode has a session per connection.
+    pub(super) session: Arc<Mutex<Session>>,
     /// This represents the TCP stream.
     pub(super) stream: TcpListener,
+    /// Set to false once a client asks for a shutdown.
+    running: Arc<AtomicBool>,
 }
 
 impl MainLoop {
     pub(crate) fn create_with_connection(address: &str) -> Result<Self, String> {
         let stream = TcpListener::bind(address).map_err(ma)?;
-        Ok(Self { dbs: None, stream })
+        Ok(Self {
+            systems: Arc::new(Mutex::new(Systems::default())),
+            session: Arc::new(Mutex::new(Session::default())),
+            stream,
+            running: Arc::new(AtomicBool::new(true)),
+        })
     }
 
+    /// Every connection is served on its own thread, so that a slow or idle client
+    /// can't hold up the others. The systems are only locked while a request runs.
     pub(crate) fn run(&mut self, mode: Mode) {
-        let stream = &mut self.stream;
-        for stream in stream.incoming() {
-            let res = match (stream, mode) {
-                (Ok(s), Mode::Client) => Self::handle_stream_as_client(s, &mut self.dbs),
-                (Ok(s), _) => Self::handle_stream_as_http(s, &mut self.dbs),
-                (Err(e), _) => Err(format!("Incoming error: {:?}", e)),
-            };
-            match res {
-                Ok(true) => {}
-                Err(e) => println!("Error: {:?}", e),
-                Ok(false) => break,
+        for stream in self.stream.incoming() {
+            if !self.running.load(Ordering::SeqCst) {
+                break;
+            }
+            match (stream, mode) {
+                (Ok(s), Mode::Client) => self
+                    .serve_on_thread(move |systems, _| Self::handle_stream_as_client(s, systems)),
+                (Ok(s), _) => self.serve_on_thread(move |systems, session| {
+                    Self::handle_stream_as_http(s, systems, session)
+                }),
+                (Err(e), _) => println!("Error: Incoming error: {:?}", e),
             }
         }
     }
 
-    fn handle_stream_as_client(
-        mut s: TcpStream,
-        dbs: &mut Option<LoadedDbs>,
-    ) -> Result<bool, String> {
+    /// Serve a connection on a thread of its own. Once it asks for a shutdown,
+    /// no more connections are taken.
+    fn serve_on_thread<F>(&self, serve: F)
+    where
+        F: FnOnce(&SharedDbs, &Mutex<Session>) -> Result<bool, String> + Send + 'static,
+    {
+        let address = self.stream.local_addr().ok();
+        let systems = Arc::clone(&self.systems);
+        let session = Arc::clone(&self.session);
+        let running = Arc::clone(&self.running);
+        thread::spawn(move || match serve(&systems, &session) {
+            Ok(true) => {}
+            Ok(false) => {
+                running.store(false, Ordering::SeqCst);
+                // Wake the listener so that it notices.
+                if let Some(address) = address {
+                    let _ = TcpStream::connect(address);
+                }
+            }
+            Err(e) => println!("Error: {:?}", e),
+        });
+    }
+
+    /// Serve one client connection until it closes it.
+    /// Requests and responses are framed, so many can go over the same connection.
+    fn handle_stream_as_client(s: TcpStream, systems: &SharedDbs) -> Result<bool, String> {
         let peer = match s.peer_addr() {
             Ok(addr) => format!("{}", addr),
             _ => "Unknown Sender".to_owned(),
         };
-        let mut input = vec![0; 1024 * 1024];
-        input = match s.read(&mut input) {
-            Err(e) => {
-                return Err(format!("Bad stream from {:?} because {:?}.", peer, e));
-            }
-            Ok(n) => input[0..n].to_vec(),
-        };
-        // println!("About to handle input: {}", String::from_utf8_lossy(&input));
-        let mut echo = match String::from_utf8(input) {
-            Ok(s) => s,
-            Err(e) => format!("Client receiving error: {:?}", e),
-        };
-        let ln = echo.chars().count();
-
-        let res = if ln == 0 {
-            "Invalid input mofo!!!".to_string()
-        } else {
-            for (a, b) in ESC.iter().zip(ESC2.iter()) {
-                echo = echo.replace(a, b);
-            }
-            let req = Request::convert(&echo);
-            // println!("{:?}", req);
-            match req.execute(dbs) {
-                Ok(Response::Shutdown) => return Ok(false),
-                Ok(r) => serde_json::to_string(&r),
-                Err(e) => serde_json::to_string(&Response::Err(echo, ma(e))),
+        let mut writer = s.try_clone().map_err(ma)?;
+        let mut reader = BufReader::new(s);
+        let mut session = Session::default();
+        loop {
+            let echo = match framing::read_frame(&mut reader) {
+                Ok(Some(m)) => m,
+                Ok(None) => return Ok(true),
+                Err(e) => return Err(format!("Bad stream from {:?} because {:?}.", peer, e)),
+            };
+            let mut keep_running = true;
+            let res = if echo.is_empty() {
+                "Invalid input mofo!!!".to_string()
+            } else {
+                let (req, reply_to) = Request::convert_enveloped(&echo);
+                // println!("{:?}", req);
+                let res = req.execute(&mut lock(systems), &mut session);
+                match res {
+                    Ok(Response::Shutdown) => {
+                        keep_running = false;
+                        reply_to.serialize(Response::Shutdown)
+                    }
+                    Ok(r) => reply_to.serialize(r),
+                    Err(e) => reply_to.serialize(Response::Err(echo, e)),
+                }?
+            };
+            framing::write_frame(&mut writer, &res)
+                .map_err(|e| format!("Can't reply to {:?} because {:?}.", peer, e))?;
+            if !keep_running {
+                return Ok(false);
             }
-            .unwrap()
-        };
-        send_and_flush(&mut s, &res, &peer)?;
-        Ok(true)
+        }
     }
 
+    /// Serve one HTTP request. It is read before anything is locked,
+    /// and a client which is too slow to send it is given up on.
     fn handle_stream_as_http(
-        mut s: TcpStream,
-        dbs: &mut Option<LoadedDbs>,
+        s: TcpStream,
+        systems: &SharedDbs,
+        session: &Mutex<Session>,
     ) -> Result<bool, String> {
         let peer = match s.peer_addr() {
             Ok(addr) => format!("{}", addr),
             _ => "Unknown Sender".to_owned(),
         };
-        let mut input = vec![0; 1024 * 1024];
-        input = match s.read(&mut input) {
+        s.set_read_timeout(Some(HTTP_READ_TIMEOUT)).map_err(ma)?;
+        let mut writer = s.try_clone().map_err(ma)?;
+        let mut reader = BufReader::new(s);
+        let http_request = match HttpRequest::read_from(&mut reader) {
+            Ok(Some(r)) => r,
+            Ok(None) => return Ok(true),
             Err(e) => {
-                return Err(format!("Bad stream from {:?} because {:?}.", peer, e));
+                let res = serde_json::to_string(&Response::Invalid(e.message)).map_err(ma)?;
+                http::write_response(&mut writer, e.status, &res, &[])
+                    .map_err(|e| format!("Can't reply to {:?} because {:?}.", peer, e))?;
+                return Ok(true);
             }
-            Ok(n) => input[0..n].to_vec(),
         };
-        let mut echo = String::from_utf8(input).unwrap();
-        // println!("{}", echo);
-        let ln = echo.chars().count();
 
-        echo = echo.split_once(END).unwrap_or(("", "")).0.to_owned();
-        echo = echo.split_once(START).unwrap_or(("", "")).1.to_owned();
-        let ln2 = echo.chars().count();
-
-        let res = if ln == ln2 || ln2 == 0 {
-            "Invalid input mofo!!!".to_string()
+        let segments = http_request.segments().unwrap_or_default();
+        let segs = segments.iter().map(String::as_str).collect::<Vec<_>>();
+        let allowed = http::allowed_methods(&segs);
+        let mut keep_running = true;
+        let (status, res) = if http_request.method == "OPTIONS" {
+            (204, String::new())
         } else {
-            for (a, b) in ESC.iter().zip(ESC2.iter()) {
-                echo = echo.replace(a, b);
-            }
-            let req = Request::convert(&echo);
-            // println!("{:?}", req);
-            match req.execute(dbs) {
-                Ok(Response::Shutdown) => return Ok(false),
-                Ok(r) => serde_json::to_string(&r),
-                Err(e) => serde_json::to_string(&Response::Err(echo, ma(e))),
+            match http_request.route() {
+                Ok(req) => {
+                    let echo = serde_json::to_string(&req).map_err(ma)?;
+                    let mut systems = lock(systems);
+                    let mut session = lock(session);
+                    // A header can pick a system for this request only.
+                    let r = match http_request.header("x-system") {
+                        Some(name) => {
+                            let mut one_off = Session {
+                                system: Some(name.to_owned()),
+                                rng: session.rng.take(),
+                            };
+                            let r = req.execute(&mut systems, &mut one_off);
+                            session.rng = one_off.rng;
+                            r
+                        }
+                        None => req.execute(&mut systems, &mut session),
+                    };
+                    let r = match r {
+                        Ok(Response::Shutdown) => {
+                            keep_running = false;
+                            Response::Shutdown
+                        }
+                        Ok(r) => r,
+                        Err(e) => Response::Err(echo, e),
+                    };
+                    (http::status_of(&r), serde_json::to_string(&r).map_err(ma)?)
+                }
+                Err(e) => (
+                    e.status,
+                    serde_json::to_string(&Response::Invalid(e.message)).map_err(ma)?,
+                ),
             }
-            .unwrap()
         };
-        let ret = format!(
-            "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nContent-Type: text/html\r\n\r\nContent: {}",
-            res.len() + 8,
-            res
-        );
-        send_and_flush(&mut s, &ret, &peer)?;
-        Ok(true)
-    }
-}
-
-fn send_and_flush(s: &mut TcpStream, msg: &str, peer: &str) -> Result<(), String> {
-    if let Err(e) = s.write(msg.as_bytes()) {
-        return Err(format!("POST Can't reply to {:?} because {:?}.", peer, e));
-    }
-    if let Err(e) = s.flush() {
-        return Err(format!("Can't flush {:?} because {:?}.", peer, e));
+        http::write_response(&mut writer, status, &res, allowed)
+            .map_err(|e| format!("Can't reply to {:?} because {:?}.", peer, e))?;
+        Ok(keep_running)
     }
-    Ok(())
 }
@@ -4,21 +4,153 @@ use azchar_database::character::character::InputCharacter;
 use azchar_database::character::character::{CharacterPart, CompleteCharacter};
 use azchar_database::character::image::{Image, InputImage};
 use azchar_database::character::note::{InputNote, Note};
+use azchar_database::character::roll_log::LoggedRoll;
+use azchar_database::character::roll_macro::RollMacro;
+use azchar_database::root_db::backup::BackupManifest;
+use azchar_database::root_db::character_file::{CharacterFileFormat, UuidClashPolicy};
+use azchar_database::root_db::integrity::IntegrityProblem;
 use azchar_database::root_db::system_config::SystemConfig;
+use azchar_database::root_db::system_lint::Diagnostic;
+use azchar_database::root_db::system_update::{RemovalPolicy, SystemUpdateReport};
 use azchar_database::CharacterDbRef;
 use azchar_database::LoadedDbs;
-use azchar_error::ma;
+use azchar_error::{ma, Error};
+
+use crate::dice::{self, MacroRoll, Substitution};
+use crate::roll::{self, DetailedRoll, Rng, RollLogEntry, RollMode, RollOutcome};
+use crate::stats::RollStats;
+use crate::systems::{system_name, Session, Systems};
 
 use std::path::PathBuf;
 
+/// The version of the protocol spoken by this server.
+/// This goes up whenever an existing request or response changes shape.
+pub(crate) const PROTOCOL_VERSION: u32 = 3;
+
+/// The kinds of request this server understands, as reported by `Hello`.
+pub(crate) const REQUEST_KINDS: &[&str] = &[
+    "Hello",
+    "CreateSystem",
+    "InitialiseFromPath",
+    "UseSystem",
+    "ListSystems",
+    "UnloadSystem",
+    "ExportSystem",
+    "UpdateSystem",
+    "ValidateSystem",
+    "Backup",
+    "Restore",
+    "CheckIntegrity",
+    "Repair",
+    "CreateCharacterSheet",
+    "CreateUpdateCharacter",
+    "UpdateAttribute",
+    "CreateAttribute",
+    "UpdatePart",
+    "CreatePart",
+    "DeletePart",
+    "InsertUpdateImage",
+    "InsertNote",
+    "UpdateNote",
+    "DeleteCharacter",
+    "ExportCharacter",
+    "ImportCharacter",
+    "ListCharacters",
+    "LoadCharacter",
+    "Roll",
+    "RollWith",
+    "RollFor",
+    "RollDetailed",
+    "RollStats",
+    "SeedRolls",
+    "GetRollLog",
+    "GetSessionRollLog",
+    "GetRollMacros",
+    "SetRollMacro",
+    "DeleteRollMacro",
+    "RollMacro",
+    "Subscribe",
+    "Unsubscribe",
+    "Batch",
+    "Shutdown",
+];
+
+/// A request wrapped so that its reply can be matched up with it.
+/// A bare `Request` is still accepted and gets a bare `Response`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct Envelope {
+    /// Anything the client likes. It is returned untouched with the reply.
+    #[serde(default)]
+    pub(crate) id: Option<serde_json::Value>,
+    /// The protocol version the client speaks, if it cares.
+    #[serde(default)]
+    pub(crate) version: Option<u32>,
+    pub(crate) request: Request,
+}
+
+/// The reply to an `Envelope`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct ResponseEnvelope {
+    pub(crate) id: Option<serde_json::Value>,
+    pub(crate) version: u32,
+    pub(crate) response: Response,
+}
+
+/// How the reply to a request must be sent, which depends on how the request came in.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum ReplyTo {
+    Bare,
+    Envelope(Option<serde_json::Value>),
+}
+
+impl ReplyTo {
+    /// Serialize the response, wrapping it if the request was wrapped.
+    pub(crate) fn serialize(self, response: Response) -> Result<String, String> {
+        match self {
+            Self::Bare => serde_json::to_string(&response),
+            Self::Envelope(id) => serde_json::to_string(&ResponseEnvelope {
+                id,
+                version: PROTOCOL_VERSION,
+                response,
+            }),
+        }
+        .map_err(ma)
+    }
+}
+
 /// A request.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub(crate) enum Request {
+    /// Ask for the protocol version and the supported requests.
+    Hello,
     /// String is a config file as a TOML.
     // The strings are name && uuid
     CreateSystem(String, String, String),
     /// This is the path of the system to load.
+    /// The system is named after the file, and a loaded system is reused.
     InitialiseFromPath(String),
+    /// Work on a loaded system, by name.
+    UseSystem(String),
+    /// List the loaded systems.
+    ListSystems,
+    /// Unload a system, by name.
+    UnloadSystem(String),
+    /// Write the system in use to a config TOML at this path.
+    ExportSystem(String),
+    /// Change the system in use and bring its sheets up to date.
+    /// The string is a config TOML, or the path of one.
+    UpdateSystem(String, RemovalPolicy),
+    /// Check the system config at this path for mistakes. No system needs to be loaded.
+    ValidateSystem(String),
+    /// Write the system in use and all its sheets to a single archive at this path.
+    Backup(String),
+    /// Unpack a backup into a directory and load the system in it.
+    // The strings are the path of the archive and the directory.
+    Restore(String, String),
+    /// Look for sheets and rows of the system in use which no longer agree with each other.
+    CheckIntegrity,
+    /// Fix everything `CheckIntegrity` finds which can be fixed.
+    Repair,
     /// This represents the character name.
     CreateCharacterSheet(String),
     /// The string is a CompleteCharacter JSON/TOML.
@@ -48,12 +180,78 @@ pub(crate) enum Request {
     /// Delete a character.
     // The strings are name && uuid
     DeleteCharacter(String, String),
+    /// Write a character, with its images, notes and roll macros, to a file of its own.
+    // The strings are name && uuid, then the path of the file.
+    ExportCharacter(String, String, String, CharacterFileFormat),
+    /// Put the character in a file written by `ExportCharacter` on a new sheet,
+    /// and say what to do if its uuid is already in use.
+    ImportCharacter(String, UuidClashPolicy),
     /// This needs no arguments and uses the current root.
     ListCharacters,
     /// The string a name and UUID.
     LoadCharacter(String, String),
     /// Represents a request to parse and run a roll.
     Roll(String),
+    /// Roll with a seed, so that the roll can be replayed, or with a way to judge it,
+    /// eg. counting successes. Either may be left out.
+    RollWith {
+        dice: String,
+        #[serde(default)]
+        seed: Option<u64>,
+        #[serde(default)]
+        mode: Option<RollMode>,
+    },
+    /// Roll for a character, with attributes in the roll, eg. `1d20+@dex_mod`.
+    /// The seed and mode are as for `RollWith`, and may be left out.
+    RollFor {
+        name: String,
+        uuid: String,
+        dice: String,
+        #[serde(default)]
+        seed: Option<u64>,
+        #[serde(default)]
+        mode: Option<RollMode>,
+    },
+    /// Roll, and show every die, with an optional label.
+    /// The seed and mode are as for `RollWith`, and may be left out.
+    RollDetailed {
+        dice: String,
+        #[serde(default)]
+        label: Option<String>,
+        #[serde(default)]
+        seed: Option<u64>,
+        #[serde(default)]
+        mode: Option<RollMode>,
+    },
+    /// The odds of a roll: its mean, variance, and the chance of each total.
+    RollStats(String),
+    /// Seed the rolls of this session which have no seed of their own, or stop seeding them.
+    SeedRolls(Option<u64>),
+    /// The latest rolls for a character: at most this many, made no earlier than this date.
+    // The strings are name && uuid.
+    GetRollLog(String, String, Option<i64>, Option<String>),
+    /// The same for the rolls made in the system which are not for a character.
+    GetSessionRollLog(Option<i64>, Option<String>),
+    /// The roll macros of a part: its own, and those the system gives its kind of part.
+    // The strings are name && uuid, the id is the part id.
+    GetRollMacros(String, String, i64),
+    /// Give a part its own roll macro, or replace its macro of the same name.
+    // The strings are name && uuid.
+    SetRollMacro(String, String, RollMacro),
+    /// Delete a part's own roll macro.
+    // The strings are name && uuid, then the part id and the name of the macro.
+    DeleteRollMacro(String, String, i64, String),
+    /// Roll the attack and damage of a part's roll macro.
+    // The strings are name && uuid, then the part id and the name of the macro.
+    RollMacro(String, String, i64, String),
+    /// Receive a `CharacterChanged` whenever the character is changed by anyone.
+    // The strings are name && uuid
+    Subscribe(String, String),
+    /// Stop receiving changes to the character.
+    // The strings are name && uuid
+    Unsubscribe(String, String),
+    /// Several requests for the same character, run in a single transaction.
+    Batch(Vec<Request>),
     /// Shut down the server.
     Shutdown,
     /// Represents an invalid request.
@@ -63,10 +261,33 @@ pub(crate) enum Request {
 /// A request.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub(crate) enum Response {
+    /// The protocol version and the kinds of request the server understands.
+    Hello(u32, Vec<String>),
     /// Returns a useless message.
     CreateSystem(String),
     /// Returns a list of characters, because what else?
-    InitialiseFromPath(Vec<CharacterDbRef>),
+    /// Also the characters whose sheets are missing, with where they should be.
+    InitialiseFromPath(Vec<CharacterDbRef>, Vec<((String, String), String)>),
+    /// The characters of the system now in use, and those whose sheets are missing.
+    UseSystem(Vec<CharacterDbRef>, Vec<((String, String), String)>),
+    /// The names of the loaded systems.
+    ListSystems(Vec<String>),
+    /// The names of the systems still loaded.
+    UnloadSystem(Vec<String>),
+    /// Where the config was written.
+    ExportSystem(String),
+    /// What was changed in the system and its sheets.
+    UpdateSystem(SystemUpdateReport),
+    /// Everything that looks wrong with a config.
+    ValidateSystem(Vec<Diagnostic>),
+    /// What was written to the archive.
+    Backup(BackupManifest),
+    /// The characters of the restored system, which is now in use.
+    Restore(Vec<CharacterDbRef>),
+    /// Everything found to be wrong with the system.
+    CheckIntegrity(Vec<IntegrityProblem>),
+    /// Everything found to be wrong with the system, and whether it was fixed.
+    Repair(Vec<IntegrityProblem>),
     /// Returns an updated list of characters
     CreateCharacterSheet(Vec<CharacterDbRef>),
     /// Returns updated list of characters.
@@ -86,26 +307,60 @@ pub(crate) enum Response {
     InsertNote(Note),
     /// Delete Character, return the list.
     DeleteCharacter(Vec<CharacterDbRef>),
+    /// Where the character was written.
+    ExportCharacter(String),
+    /// The name and uuid the imported character has in the system.
+    ImportCharacter(String, String),
     /// Returns a list of characters.
     ListCharacters(Vec<CharacterDbRef>),
     /// The Complete Character.
     LoadCharacter(CompleteCharacter),
-    /// The roll for each dice group and the total.
-    Roll(Vec<i64>, i64),
+    /// The roll for each dice group, the bonus, the seed if the roll was seeded,
+    /// and how it did if it was judged.
+    Roll(Vec<i64>, i64, Option<u64>, Option<RollOutcome>),
+    /// The roll as it was rolled, the attributes put into it,
+    /// then the roll for each dice group, the bonus, the seed and the outcome, as for `Roll`.
+    RollFor(
+        String,
+        Vec<Substitution>,
+        Vec<i64>,
+        i64,
+        Option<u64>,
+        Option<RollOutcome>,
+    ),
+    /// Every die of every group.
+    RollDetailed(DetailedRoll),
+    /// The distribution of the total.
+    RollStats(RollStats),
+    /// The seed the session's rolls are now seeded with, if any.
+    SeedRolls(Option<u64>),
+    /// The latest rolls first.
+    GetRollLog(Vec<RollLogEntry>),
+    /// All the roll macros the part has.
+    GetRollMacros(Vec<RollMacro>),
+    /// The name of the macro, and its attack and damage as they were rolled.
+    RollMacro(String, Vec<MacroRoll>),
+    /// The character is now being watched.
+    Subscribe(String, String),
+    /// The character is no longer being watched.
+    Unsubscribe(String, String),
+    /// Pushed to subscribers when a character has been changed.
+    CharacterChanged(CompleteCharacter),
+    /// The responses to each request in a batch.
+    Batch(Vec<Response>),
+    /// The index of the request that failed and why. Nothing in the batch was done.
+    BatchFailed(usize, Error),
     /// Represents an invalid request.
     Invalid(String),
     /// Shut down the server.
     Shutdown,
-    /// Represents an error.
-    Err(String, String),
+    /// Represents an error: the request which failed and why.
+    Err(String, Error),
 }
 
 impl Response {
     fn load_db_error(r: Request) -> Self {
-        Response::Err(
-            String::from("Load system first."),
-            serde_json::to_string(&r).unwrap(),
-        )
+        Response::Err(serde_json::to_string(&r).unwrap(), Error::NoSystem)
     }
 }
 
@@ -124,34 +379,214 @@ impl Request {
         }
     }
 
+    /// Converts incoming text which may or may not be wrapped in an `Envelope`.
+    /// An envelope asking for a newer protocol than ours gives an invalid request.
+    pub(crate) fn convert_enveloped(input: &str) -> (Self, ReplyTo) {
+        let envelope = match toml::from_str::<Envelope>(input) {
+            Ok(e) => e,
+            Err(_) => match serde_json::from_str::<Envelope>(input) {
+                Ok(e) => e,
+                Err(_) => return (Self::convert(input), ReplyTo::Bare),
+            },
+        };
+        let request = match envelope.version {
+            Some(v) if v > PROTOCOL_VERSION => Self::Invalid(format!(
+                "Protocol version {} is not supported, this server speaks {}",
+                v, PROTOCOL_VERSION
+            )),
+            _ => envelope.request,
+        };
+        (request, ReplyTo::Envelope(envelope.id))
+    }
+
+    /// The character (name, uuid) which this request changes if it succeeds.
+    pub(crate) fn changed_character(&self) -> Option<(String, String)> {
+        match self {
+            Self::CreateUpdateCharacter(sheet) => {
+                Some((sheet.name().to_owned(), sheet.uuid().to_owned()))
+            }
+            Self::UpdateAttribute(name, uuid, _, _)
+            | Self::CreateAttribute(name, uuid, _)
+            | Self::UpdatePart(name, uuid, _)
+            | Self::CreatePart(name, uuid, _)
+            | Self::DeletePart(name, uuid, _)
+            | Self::InsertUpdateImage(name, uuid, _)
+            | Self::InsertNote(name, uuid, _)
+            | Self::UpdateNote(name, uuid, _) => Some((name.to_owned(), uuid.to_owned())),
+            Self::Batch(requests) => requests.iter().find_map(|r| r.changed_character()),
+            _ => None,
+        }
+    }
+
+    /// The one character which every request in a batch must be about.
+    /// Only requests which read or change an existing character can be batched.
+    fn batch_character(requests: &[Request]) -> Result<(String, String), Error> {
+        let mut key = None;
+        for (i, r) in requests.iter().enumerate() {
+            let this = match r {
+                Self::LoadCharacter(name, uuid)
+                | Self::RollFor { name, uuid, .. }
+                | Self::GetRollLog(name, uuid, _, _)
+                | Self::GetRollMacros(name, uuid, _)
+                | Self::SetRollMacro(name, uuid, _)
+                | Self::DeleteRollMacro(name, uuid, _, _)
+                | Self::RollMacro(name, uuid, _, _) => (name.to_owned(), uuid.to_owned()),
+                r => match r.changed_character() {
+                    Some(k) if !matches!(r, Self::Batch(_)) => k,
+                    _ => {
+                        let m = format!("Request {} can not be part of a batch.", i);
+                        return Err(Error::validation(m));
+                    }
+                },
+            };
+            match key {
+                Some(ref k) if k != &this => {
+                    let m = format!("Request {} is for a different character.", i);
+                    return Err(Error::validation(m));
+                }
+                Some(_) => {}
+                None => key = Some(this),
+            }
+        }
+        key.ok_or_else(|| Error::validation("The batch is empty."))
+    }
+
     /// Run the request and give a response.
+    /// Requests about systems are handled here, the rest go to the session's system.
     /// NB: An error case should be unwrapped
-    pub(crate) fn execute(self, main_loop: &mut Option<LoadedDbs>) -> Result<Response, String> {
+    pub(crate) fn execute(
+        self,
+        systems: &mut Systems,
+        session: &mut Session,
+    ) -> Result<Response, Error> {
         let a = std::time::Instant::now();
+        systems.bind_default(session);
         let res = match self {
+            Self::Hello => Response::Hello(
+                PROTOCOL_VERSION,
+                REQUEST_KINDS.iter().map(|k| k.to_string()).collect(),
+            ),
             Self::CreateSystem(name, path, system) => {
+                if systems.contains(&name) {
+                    let m = format!("A system called {} is already loaded.", name);
+                    return Err(Error::conflict(m));
+                }
                 let cfg_path = PathBuf::from(&system);
                 let sys = if cfg_path.exists() {
                     SystemConfig::from_config(&cfg_path)?
                 } else {
                     let path = format!("{:?}", path);
-                    toml::from_str(&path).map_err(ma)?
+                    toml::from_str(&path)?
                 };
                 let dbs = sys.into_system(&PathBuf::from(&path), &name);
                 if let Err(ref e) = dbs {
                     println!("Error in creation: {:?}", e);
                 }
-                *main_loop = Some(dbs?);
+                systems.insert(name.to_owned(), dbs?)?;
+                session.system = Some(name.to_owned());
                 Response::CreateSystem(format!("Created \"{}\" in \"{}\"", name, path))
             }
             Self::InitialiseFromPath(path) => {
-                let mut dbs = LoadedDbs::custom(&path)?;
-                let chars = dbs.list_characters()?;
-                *main_loop = Some(dbs);
-                Response::InitialiseFromPath(chars)
+                // Another client may have loaded it already.
+                let name = system_name(&path)?;
+                if !systems.contains(&name) {
+                    systems.insert(name.to_owned(), LoadedDbs::custom(&path)?)?;
+                } else if !systems.is_at(&name, &path) {
+                    let m = format!("A different system called {} is already loaded.", name);
+                    return Err(Error::conflict(m));
+                }
+                session.system = Some(name);
+                let dbs = systems.get_mut(session).expect("Just loaded.");
+                Response::InitialiseFromPath(dbs.list_characters()?, dbs.missing_sheets())
+            }
+            Self::Restore(archive, dir) => {
+                // The system is named after its root database, and may already be loaded.
+                let mut file = std::fs::File::open(&archive)?;
+                let name = BackupManifest::read(&mut file)?.system_name();
+                if systems.contains(&name) {
+                    let m = format!("A system called {} is already loaded.", name);
+                    return Err(Error::conflict(m));
+                }
+                let dbs = LoadedDbs::restore(&PathBuf::from(&archive), &PathBuf::from(&dir))?;
+                systems.insert(name.to_owned(), dbs)?;
+                session.system = Some(name);
+                let dbs = systems.get_mut(session).expect("Just loaded.");
+                Response::Restore(dbs.list_characters()?)
+            }
+            Self::UseSystem(name) => {
+                if !systems.contains(&name) {
+                    return Err(Error::not_found("System", name));
+                }
+                session.system = Some(name);
+                let dbs = systems.get_mut(session).expect("Just checked.");
+                Response::UseSystem(dbs.list_characters()?, dbs.missing_sheets())
+            }
+            Self::ListSystems => Response::ListSystems(systems.names()),
+            Self::UnloadSystem(name) => {
+                systems.remove(&name)?;
+                if session.system.as_ref() == Some(&name) {
+                    session.system = None;
+                }
+                Response::UnloadSystem(systems.names())
             }
+            Self::Batch(requests) => {
+                let key = Self::batch_character(&requests)?;
+                match systems.get_mut(session) {
+                    Some(dbs) if !dbs.character_connections().contains_key(&key) => {
+                        return Err(Error::not_found(
+                            "Character",
+                            format!("{} (uuid = {})", key.0, key.1),
+                        ));
+                    }
+                    Some(dbs) => dbs.begin_sheet_transaction(&key)?,
+                    None => return Ok(Response::load_db_error(Self::Batch(requests))),
+                }
+                let mut responses = Vec::with_capacity(requests.len());
+                let mut failure = None;
+                for (i, r) in requests.into_iter().enumerate() {
+                    match r.execute(systems, session) {
+                        Ok(Response::Err(_, e)) | Err(e) => {
+                            failure = Some((i, e));
+                            break;
+                        }
+                        Ok(r) => responses.push(r),
+                    }
+                }
+                let dbs = systems
+                    .get_mut(session)
+                    .expect("Batches can't unload the system.");
+                match failure {
+                    None => {
+                        dbs.commit_sheet_transaction(&key)?;
+                        Response::Batch(responses)
+                    }
+                    Some((i, e)) => {
+                        dbs.rollback_sheet_transaction(&key)?;
+                        Response::BatchFailed(i, e)
+                    }
+                }
+            }
+            Self::SeedRolls(seed) => {
+                session.rng = seed.map(Rng::seeded);
+                Response::SeedRolls(seed)
+            }
+            r => r.execute_on_system(systems.get_mut(session), session.rng.as_mut())?,
+        };
+        let b = a.elapsed().as_micros();
+        println!("inner exec: {}us", b);
+        Ok(res)
+    }
+
+    /// Run a request which needs nothing but the session's system,
+    /// and its generator for rolls if they are seeded.
+    fn execute_on_system(
+        self,
+        main_loop: Option<&mut LoadedDbs>,
+        mut session_rng: Option<&mut Rng>,
+    ) -> Result<Response, Error> {
+        let res = match self {
             Self::CreateCharacterSheet(name) => match main_loop {
-                Some(ref mut dbs) => {
+                Some(dbs) => {
                     dbs.create_sheet(&name)?;
                     let chars = dbs.list_characters()?;
                     Response::CreateCharacterSheet(chars)
@@ -159,7 +594,7 @@ impl Request {
                 None => Response::load_db_error(Self::CreateCharacterSheet(name)),
             },
             Self::CreateUpdateCharacter(sheet) => match main_loop {
-                Some(ref mut dbs) => {
+                Some(dbs) => {
                     dbs.create_or_update_character(sheet)?;
                     let chars = dbs.list_characters();
                     Response::CreateUpdateCharacter(chars?)
@@ -167,38 +602,38 @@ impl Request {
                 None => Response::load_db_error(Self::CreateUpdateCharacter(sheet)),
             },
             Self::UpdateAttribute(name, uuid, attr_k, attr_v) => match main_loop {
-                Some(ref mut dbs) => {
+                Some(dbs) => {
                     dbs.create_update_attribute(attr_k, attr_v, (name, uuid))?;
                     Response::UpdateAttribute
                 }
                 None => Response::load_db_error(Self::UpdateAttribute(name, uuid, attr_k, attr_v)),
             },
             Self::UpdatePart(name, uuid, character) => match main_loop {
-                Some(ref mut dbs) => {
+                Some(dbs) => {
                     dbs.create_update_part(character, (name, uuid))?;
                     Response::UpdatePart
                 }
                 None => Response::load_db_error(Self::UpdatePart(name, uuid, character)),
             },
             Self::DeletePart(name, uuid, part_id) => match main_loop {
-                Some(ref mut dbs) => {
+                Some(dbs) => {
                     let updated = dbs.delete_part(part_id, (name, uuid))?;
                     Response::CreateDeleteAttributePart(updated)
                 }
                 None => Response::load_db_error(Self::DeletePart(name, uuid, part_id)),
             },
             Self::InsertUpdateImage(name, uuid, input_image) => match main_loop {
-                Some(ref mut dbs) => {
+                Some(dbs) => {
                     Response::InsertUpdateImage(dbs.create_update_image(name, uuid, input_image)?)
                 }
                 None => Response::load_db_error(Self::InsertUpdateImage(name, uuid, input_image)),
             },
             Self::InsertNote(name, uuid, new_note) => match main_loop {
-                Some(ref mut dbs) => Response::InsertNote(dbs.add_note(name, uuid, new_note)?),
+                Some(dbs) => Response::InsertNote(dbs.add_note(name, uuid, new_note)?),
                 None => Response::load_db_error(Self::InsertNote(name, uuid, new_note)),
             },
             Self::UpdateNote(name, uuid, mut note) => match main_loop {
-                Some(ref mut dbs) => {
+                Some(dbs) => {
                     if let Some(ref mut c) = note.content {
                         *c = c.replace("[[enter]]", "\n");
                     }
@@ -208,62 +643,279 @@ impl Request {
                 None => Response::load_db_error(Self::UpdateNote(name, uuid, note)),
             },
             Self::CreatePart(name, uuid, part) => match main_loop {
-                Some(ref mut dbs) => {
+                Some(dbs) => {
                     Response::CreateDeleteAttributePart(dbs.create_part(part, (name, uuid))?)
                 }
                 None => Response::load_db_error(Self::CreatePart(name, uuid, part)),
             },
             Self::CreateAttribute(name, uuid, attr) => match main_loop {
-                Some(ref mut dbs) => {
+                Some(dbs) => {
                     let res = dbs.create_attribute(attr, (name, uuid))?;
                     Response::CreateDeleteAttributePart(res)
                 }
                 None => Response::load_db_error(Self::CreateAttribute(name, uuid, attr)),
             },
             Self::DeleteCharacter(name, uuid) => match main_loop {
-                Some(ref mut dbs) => {
+                Some(dbs) => {
                     dbs.delete_character(name, uuid)?;
                     Response::DeleteCharacter(dbs.list_characters()?)
                 }
                 None => Response::load_db_error(Self::LoadCharacter(name, uuid)),
             },
+            Self::ExportCharacter(name, uuid, path, format) => match main_loop {
+                Some(dbs) => {
+                    dbs.export_character((name, uuid), &PathBuf::from(&path), format)?;
+                    Response::ExportCharacter(path)
+                }
+                None => Response::load_db_error(Self::ExportCharacter(name, uuid, path, format)),
+            },
+            Self::ImportCharacter(path, policy) => match main_loop {
+                Some(dbs) => {
+                    let (name, uuid) = dbs.import_character(&PathBuf::from(&path), policy)?;
+                    Response::ImportCharacter(name, uuid)
+                }
+                None => Response::load_db_error(Self::ImportCharacter(path, policy)),
+            },
+            Self::ExportSystem(path) => match main_loop {
+                Some(dbs) => {
+                    let cfg = SystemConfig::from_root_db(dbs.get_inner_root()?)?;
+                    cfg.to_config(&PathBuf::from(&path))?;
+                    Response::ExportSystem(path)
+                }
+                None => Response::load_db_error(Self::ExportSystem(path)),
+            },
+            Self::UpdateSystem(system, policy) => match main_loop {
+                Some(dbs) => {
+                    let cfg_path = PathBuf::from(&system);
+                    let cfg = if cfg_path.exists() {
+                        SystemConfig::from_config(&cfg_path)?
+                    } else {
+                        toml::from_str(&system)?
+                    };
+                    Response::UpdateSystem(dbs.update_system(cfg, policy)?)
+                }
+                None => Response::load_db_error(Self::UpdateSystem(system, policy)),
+            },
+            Self::Backup(path) => match main_loop {
+                Some(dbs) => Response::Backup(dbs.backup(&PathBuf::from(&path))?),
+                None => Response::load_db_error(Self::Backup(path)),
+            },
+            Self::CheckIntegrity => match main_loop {
+                Some(dbs) => Response::CheckIntegrity(dbs.check_integrity(false)?),
+                None => Response::load_db_error(Self::CheckIntegrity),
+            },
+            Self::Repair => match main_loop {
+                Some(dbs) => Response::Repair(dbs.check_integrity(true)?),
+                None => Response::load_db_error(Self::Repair),
+            },
+            Self::ValidateSystem(path) => {
+                Response::ValidateSystem(SystemConfig::lint_file(&PathBuf::from(path))?)
+            }
             Self::ListCharacters => match main_loop {
-                Some(ref mut dbs) => {
+                Some(dbs) => {
                     let chars = dbs.list_characters()?;
                     Response::ListCharacters(chars)
                 }
                 None => Response::load_db_error(Self::ListCharacters),
             },
             Self::LoadCharacter(name, uuid) => match main_loop {
-                Some(ref mut dbs) => {
+                Some(dbs) => {
                     let char = dbs.load_character((name, uuid));
                     Response::LoadCharacter(char?)
                 }
                 None => Response::load_db_error(Self::LoadCharacter(name, uuid)),
             },
             Self::Roll(dice) => {
-                let roll = libazdice::parse::parse(dice)?.roll();
-                let totals = roll
-                    .get_dice_groups()
-                    .iter()
-                    .map(|r| r.total())
-                    .collect::<Vec<_>>();
-                let bonus = roll.get_bonus().total();
-                Response::Roll(totals, bonus)
+                let roll = roll(&dice, None, seed_for(None, session_rng), None)?;
+                if let Some(dbs) = main_loop {
+                    dbs.log_roll(None, roll.to_log()?)?;
+                }
+                Response::Roll(roll.totals(), roll.bonus, roll.seed, roll.outcome)
+            }
+            Self::RollWith { dice, seed, mode } => {
+                let roll = roll(&dice, None, seed_for(seed, session_rng), mode)?;
+                if let Some(dbs) = main_loop {
+                    dbs.log_roll(None, roll.to_log()?)?;
+                }
+                Response::Roll(roll.totals(), roll.bonus, roll.seed, roll.outcome)
             }
+            Self::RollDetailed {
+                dice,
+                label,
+                seed,
+                mode,
+            } => {
+                let roll = roll(&dice, label, seed_for(seed, session_rng), mode)?;
+                if let Some(dbs) = main_loop {
+                    dbs.log_roll(None, roll.to_log()?)?;
+                }
+                Response::RollDetailed(roll)
+            }
+            Self::RollStats(dice) => {
+                let expression = roll::parse(&dice)?;
+                Response::RollStats(RollStats::of(&expression, &mut Rng::from_entropy()))
+            }
+            Self::RollFor {
+                name,
+                uuid,
+                dice: expression,
+                seed,
+                mode,
+            } => match main_loop {
+                Some(dbs) => {
+                    let key = (name, uuid);
+                    let character = dbs.load_character(key.to_owned())?;
+                    let (dice, substitutions) = dice::resolve(&expression, &character, None)?;
+                    let roll = roll(&dice, None, seed_for(seed, session_rng), mode)?;
+                    dbs.log_roll(Some(key), roll.to_log()?)?;
+                    let (totals, bonus) = (roll.totals(), roll.bonus);
+                    Response::RollFor(dice, substitutions, totals, bonus, roll.seed, roll.outcome)
+                }
+                None => Response::load_db_error(Self::RollFor {
+                    name,
+                    uuid,
+                    dice: expression,
+                    seed,
+                    mode,
+                }),
+            },
+            Self::GetRollLog(name, uuid, limit, since) => match main_loop {
+                Some(dbs) => {
+                    let key = Some((name, uuid));
+                    let log = dbs.get_roll_log(key, limit, since.as_deref())?;
+                    Response::GetRollLog(roll_log(log)?)
+                }
+                None => Response::load_db_error(Self::GetRollLog(name, uuid, limit, since)),
+            },
+            Self::GetSessionRollLog(limit, since) => match main_loop {
+                Some(dbs) => {
+                    let log = dbs.get_roll_log(None, limit, since.as_deref())?;
+                    Response::GetRollLog(roll_log(log)?)
+                }
+                None => Response::load_db_error(Self::GetSessionRollLog(limit, since)),
+            },
+            Self::GetRollMacros(name, uuid, part_id) => match main_loop {
+                Some(dbs) => Response::GetRollMacros(dbs.get_roll_macros((name, uuid), part_id)?),
+                None => Response::load_db_error(Self::GetRollMacros(name, uuid, part_id)),
+            },
+            Self::SetRollMacro(name, uuid, roll_macro) => match main_loop {
+                Some(dbs) => Response::GetRollMacros(dbs.set_roll_macro((name, uuid), roll_macro)?),
+                None => Response::load_db_error(Self::SetRollMacro(name, uuid, roll_macro)),
+            },
+            Self::DeleteRollMacro(name, uuid, part_id, macro_name) => match main_loop {
+                Some(dbs) => {
+                    let key = (name, uuid);
+                    let macros = dbs.delete_roll_macro(key, part_id, &macro_name)?;
+                    Response::GetRollMacros(macros)
+                }
+                None => {
+                    Response::load_db_error(Self::DeleteRollMacro(name, uuid, part_id, macro_name))
+                }
+            },
+            Self::RollMacro(name, uuid, part_id, macro_name) => match main_loop {
+                Some(dbs) => {
+                    let key = (name, uuid);
+                    let roll_macro = dbs
+                        .get_roll_macros(key.to_owned(), part_id)?
+                        .into_iter()
+                        .find(|m| m.name == macro_name)
+                        .ok_or_else(|| Error::not_found("Roll macro", &macro_name))?;
+                    let character = dbs.load_character(key.to_owned())?;
+                    let part_name = character
+                        .parts()
+                        .iter()
+                        .find(|p| p.id() == Some(part_id))
+                        .map(|p| p.name().to_owned())
+                        .unwrap_or_default();
+                    let mut rolls = Vec::new();
+                    let templates = [("attack", roll_macro.attack), ("damage", roll_macro.damage)];
+                    // Resolve both first, so that nothing is logged if either is broken.
+                    let mut resolved = Vec::new();
+                    for (label, expression) in templates.iter() {
+                        if let Some(e) = expression {
+                            resolved.push((*label, dice::resolve(e, &character, Some(part_id))?));
+                        }
+                    }
+                    for (label, (dice, substitutions)) in resolved {
+                        let log_label = format!("{}: {} ({})", part_name, macro_name, label);
+                        let seed = seed_for(None, session_rng.as_deref_mut());
+                        let roll = roll(&dice, Some(log_label), seed, None)?;
+                        dbs.log_roll(Some(key.to_owned()), roll.to_log()?)?;
+                        rolls.push(MacroRoll {
+                            label: label.to_string(),
+                            dice,
+                            substitutions,
+                            totals: roll.totals(),
+                            bonus: roll.bonus,
+                            seed: roll.seed,
+                        });
+                    }
+                    Response::RollMacro(macro_name, rolls)
+                }
+                None => Response::load_db_error(Self::RollMacro(name, uuid, part_id, macro_name)),
+            },
+            Self::Subscribe(name, uuid) => match main_loop {
+                Some(dbs) => {
+                    let key = (name, uuid);
+                    if !dbs.character_connections().contains_key(&key) {
+                        return Err(Error::not_found(
+                            "Character",
+                            format!("{} (uuid = {})", key.0, key.1),
+                        ));
+                    }
+                    Response::Subscribe(key.0, key.1)
+                }
+                None => Response::load_db_error(Self::Subscribe(name, uuid)),
+            },
+            Self::Unsubscribe(name, uuid) => Response::Unsubscribe(name, uuid),
             Self::Shutdown => Response::Shutdown,
             Self::Invalid(x) => Response::Invalid(format!("Invalid request received:({})", x)),
+            // These need more than one system.
+            Self::Hello
+            | Self::CreateSystem(_, _, _)
+            | Self::InitialiseFromPath(_)
+            | Self::Restore(_, _)
+            | Self::UseSystem(_)
+            | Self::ListSystems
+            | Self::UnloadSystem(_)
+            | Self::SeedRolls(_)
+            | Self::Batch(_) => unreachable!("Handled by `execute`."),
         };
-        let b = a.elapsed().as_micros();
-        println!("inner exec: {}us", b);
         Ok(res)
     }
 }
 
+/// The seed for a roll: its own, or else the next one from the session, if its rolls are seeded.
+fn seed_for(seed: Option<u64>, session_rng: Option<&mut Rng>) -> Option<u64> {
+    seed.or_else(|| session_rng.map(Rng::next_u64))
+}
+
+/// Roll some dice, and judge them by `mode` if there is one.
+/// A seeded roll can be replayed by rolling it with the same seed.
+fn roll(
+    dice: &str,
+    label: Option<String>,
+    seed: Option<u64>,
+    mode: Option<RollMode>,
+) -> Result<DetailedRoll, Error> {
+    let expression = roll::parse(dice)?;
+    let roll = match seed {
+        Some(s) => expression.roll_seeded(s, label),
+        None => expression.roll(&mut Rng::from_entropy(), label),
+    };
+    Ok(roll.judge(mode))
+}
+
+fn roll_log(log: Vec<LoggedRoll>) -> Result<Vec<RollLogEntry>, Error> {
+    log.into_iter().map(RollLogEntry::from_logged).collect()
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::requests::Request;
+    use crate::requests::{ReplyTo, Request, Response, PROTOCOL_VERSION, REQUEST_KINDS};
+    use crate::roll::RollMode;
     use azchar_database::character::character::CompleteCharacter;
+    use azchar_database::root_db::character_file::{CharacterFileFormat, UuidClashPolicy};
     use std::io::Read;
 
     #[test]
@@ -298,6 +950,27 @@ mod tests {
         );
     }
 
+    #[test]
+    fn make_backup_restore() {
+        let exp = "{\"Backup\":\"campaign.azbak\"}";
+        let request = Request::Backup("campaign.azbak".to_owned());
+        assert_eq!(exp, serde_json::to_string(&request).unwrap());
+        let exp = "{\"Restore\":[\"campaign.azbak\",\"/campaigns/dnd\"]}";
+        let request = Request::Restore("campaign.azbak".to_owned(), "/campaigns/dnd".to_owned());
+        assert_eq!(exp, serde_json::to_string(&request).unwrap());
+    }
+
+    #[test]
+    fn make_check_integrity_repair() {
+        let exp = "\"CheckIntegrity\"";
+        assert_eq!(
+            exp,
+            serde_json::to_string(&Request::CheckIntegrity).unwrap()
+        );
+        let exp = "\"Repair\"";
+        assert_eq!(exp, serde_json::to_string(&Request::Repair).unwrap());
+    }
+
     #[test]
     fn make_create_character_sheet() {
         let exp = "{\"CreateCharacterSheet\":\"Euridice\"}";
@@ -336,6 +1009,127 @@ mod tests {
         );
     }
 
+    #[test]
+    fn make_roll_with() {
+        let request = Request::RollWith {
+            dice: "5d10".to_owned(),
+            seed: Some(42),
+            mode: Some(RollMode::Successes(8)),
+        };
+        let exp = "{\"RollWith\":{\"dice\":\"5d10\",\"seed\":42,\"mode\":{\"Successes\":8}}}";
+        assert_eq!(exp, serde_json::to_string(&request).unwrap());
+        // Whatever is left out is not used.
+        let input = "{\"RollWith\":{\"dice\":\"1d20\",\"seed\":7}}";
+        match serde_json::from_str(input).unwrap() {
+            Request::RollWith {
+                dice,
+                seed: Some(7),
+                mode: None,
+            } => assert_eq!(dice, "1d20"),
+            r => panic!("Expected `RollWith`, got {:?}", r),
+        }
+    }
+
+    #[test]
+    fn make_roll_detailed() {
+        let exp = "{\"RollDetailed\":{\"dice\":\"2d10dl1\",\"label\":\"Initiative\",\"seed\":42,\"mode\":{\"Target\":15}}}";
+        let request = Request::RollDetailed {
+            dice: "2d10dl1".to_owned(),
+            label: Some("Initiative".to_owned()),
+            seed: Some(42),
+            mode: Some(RollMode::Target(15)),
+        };
+        assert_eq!(exp, serde_json::to_string(&request).unwrap());
+        // Whatever is left out is not used.
+        let input =
+            "{\"RollFor\":{\"name\":\"Euridice\",\"uuid\":\"1234\",\"dice\":\"1d20+@dex_mod\"}}";
+        match serde_json::from_str(input).unwrap() {
+            Request::RollFor {
+                seed: None,
+                mode: None,
+                ..
+            } => {}
+            r => panic!("Expected `RollFor` without a seed or mode, got {:?}", r),
+        }
+    }
+
+    #[test]
+    fn make_roll_stats() {
+        let exp = "{\"RollStats\":\"2d6\"}";
+        assert_eq!(
+            exp,
+            serde_json::to_string(&Request::RollStats("2d6".to_owned())).unwrap()
+        );
+    }
+
+    #[test]
+    fn make_subscribe() {
+        let exp = "{\"Subscribe\":[\"Euridice\",\"5936ce00-2275-463c-106a-0f2edde38175\"]}";
+        let eur = String::from("Euridice");
+        let uuid = String::from("5936ce00-2275-463c-106a-0f2edde38175");
+        assert_eq!(
+            exp,
+            serde_json::to_string(&Request::Subscribe(eur, uuid)).unwrap(),
+        );
+    }
+
+    #[test]
+    fn make_hello() {
+        assert_eq!("\"Hello\"", serde_json::to_string(&Request::Hello).unwrap());
+    }
+
+    #[test]
+    fn request_kinds_are_real() {
+        // A known kind without its arguments is a type error, not an unknown variant.
+        for kind in REQUEST_KINDS {
+            if let Err(e) = serde_json::from_str::<Request>(&format!("\"{}\"", kind)) {
+                assert!(!e.to_string().contains("unknown variant"), "{}", e);
+            }
+        }
+    }
+
+    #[test]
+    fn convert_bare_request() {
+        match Request::convert_enveloped("\"ListCharacters\"") {
+            (Request::ListCharacters, ReplyTo::Bare) => {}
+            r => panic!("Expected a bare `ListCharacters`, got {:?}", r),
+        }
+    }
+
+    #[test]
+    fn convert_enveloped_request() {
+        let input = "{\"id\":7,\"version\":3,\"request\":{\"Roll\":\"1d4\"}}";
+        let (request, reply_to) = Request::convert_enveloped(input);
+        match request {
+            Request::Roll(d) => assert_eq!(d, "1d4"),
+            r => panic!("Expected `Roll`, got {:?}", r),
+        }
+        assert_eq!(reply_to, ReplyTo::Envelope(Some(serde_json::json!(7))));
+        assert_eq!(
+            "{\"id\":7,\"version\":3,\"response\":\"UpdatePart\"}",
+            reply_to.serialize(Response::UpdatePart).unwrap(),
+        );
+    }
+
+    #[test]
+    fn convert_enveloped_request_from_the_future() {
+        let input = format!(
+            "{{\"id\":\"a\",\"version\":{},\"request\":\"ListCharacters\"}}",
+            PROTOCOL_VERSION + 1
+        );
+        match Request::convert_enveloped(&input) {
+            (Request::Invalid(_), ReplyTo::Envelope(Some(_))) => {}
+            r => panic!("Expected an enveloped `Invalid`, got {:?}", r),
+        }
+    }
+
+    #[test]
+    fn make_batch() {
+        let exp = "{\"Batch\":[\"ListCharacters\",{\"Roll\":\"1d4\"}]}";
+        let batch = vec![Request::ListCharacters, Request::Roll(String::from("1d4"))];
+        assert_eq!(exp, serde_json::to_string(&Request::Batch(batch)).unwrap());
+    }
+
     #[test]
     fn make_create_invalid() {
         let exp = "{\"Invalid\":\"PoopaScooottta!!!\"}";
@@ -485,6 +1279,23 @@ mod tests {
         );
     }
 
+    #[test]
+    fn make_export_import_character() {
+        let exp = "{\"ExportCharacter\":[\"Euridice\",\"5936ce00-2275-463c-106a-0f2edde38175\",\
+            \"euridice.toml\",\"Toml\"]}";
+        let request = Request::ExportCharacter(
+            "Euridice".to_owned(),
+            "5936ce00-2275-463c-106a-0f2edde38175".to_owned(),
+            "euridice.toml".to_owned(),
+            CharacterFileFormat::Toml,
+        );
+        assert_eq!(exp, serde_json::to_string(&request).unwrap());
+        let exp = "{\"ImportCharacter\":[\"euridice.toml\",\"Regenerate\"]}";
+        let request =
+            Request::ImportCharacter("euridice.toml".to_owned(), UuidClashPolicy::Regenerate);
+        assert_eq!(exp, serde_json::to_string(&request).unwrap());
+    }
+
     #[test]
     fn make_delete_character_part() {
         let eur = "Euridice".to_string();
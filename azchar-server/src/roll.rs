@@ -0,0 +1,610 @@
+//! This deals with rolling dice, so that every die can be shown.
+//! Expressions are written eg. `2d10dl1mx10+1d4+6`:
+//! `dlN` drops the N lowest dice, `dhN` the N highest,
+//! and `mxN` rolls a die again and adds it whenever it shows N or more.
+//! A roll can also be judged by a `RollMode`, eg. counting successes in a dice pool.
+use azchar_database::character::roll_log::{InputRoll, LoggedRoll};
+use azchar_error::Error;
+
+use std::collections::hash_map::RandomState;
+use std::fmt;
+use std::hash::{BuildHasher, Hasher};
+
+/// More dice than this in one group is probably a mistake.
+const MAX_DICE: u32 = 1000;
+/// A die explodes at most this many times.
+const MAX_EXPLOSIONS: usize = 100;
+
+/// A group of identical dice, eg. `2d10dl1`.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct DiceGroup {
+    pub(crate) count: u32,
+    pub(crate) sides: u32,
+    pub(crate) drop_lowest: u32,
+    pub(crate) drop_highest: u32,
+    pub(crate) explode_at: Option<u32>,
+    /// The group is taken away from the total.
+    pub(crate) negative: bool,
+}
+
+/// A parsed dice expression.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct DiceExpression {
+    pub(crate) groups: Vec<DiceGroup>,
+    pub(crate) bonus: i64,
+}
+
+impl fmt::Display for DiceGroup {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}d{}", self.count, self.sides)?;
+        if self.drop_lowest > 0 {
+            write!(f, "dl{}", self.drop_lowest)?;
+        }
+        if self.drop_highest > 0 {
+            write!(f, "dh{}", self.drop_highest)?;
+        }
+        if let Some(n) = self.explode_at {
+            write!(f, "mx{}", n)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for DiceExpression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, g) in self.groups.iter().enumerate() {
+            match (i, g.negative) {
+                (_, true) => write!(f, "-{}", g)?,
+                (0, false) => write!(f, "{}", g)?,
+                (_, false) => write!(f, "+{}", g)?,
+            }
+        }
+        match (self.groups.is_empty(), self.bonus) {
+            (true, b) => write!(f, "{}", b),
+            (false, 0) => Ok(()),
+            (false, b) if b < 0 => write!(f, "{}", b),
+            (false, b) => write!(f, "+{}", b),
+        }
+    }
+}
+
+/// One die, and what became of it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct Die {
+    /// What the die counts for: the sum of its rolls.
+    pub(crate) value: i64,
+    /// Every roll of the die. There is more than one if it exploded.
+    pub(crate) rolls: Vec<i64>,
+    pub(crate) exploded: bool,
+    /// Dropped dice are not counted.
+    pub(crate) dropped: bool,
+}
+
+/// The roll of a dice group.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct GroupRoll {
+    /// The group as it was parsed, eg. "2d10dl1".
+    pub(crate) dice: String,
+    pub(crate) negative: bool,
+    /// Empty when only the total of the group is known.
+    pub(crate) dice_rolled: Vec<Die>,
+    /// The sum of the dice which were not dropped, with the sign of the group.
+    pub(crate) total: i64,
+}
+
+/// A roll with every die in it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct DetailedRoll {
+    /// What the roll is for, eg. "Attack with +1 Scimitar".
+    pub(crate) label: Option<String>,
+    /// The expression as it was understood.
+    pub(crate) expression: String,
+    pub(crate) groups: Vec<GroupRoll>,
+    pub(crate) bonus: i64,
+    pub(crate) total: i64,
+    /// The seed the roll was made with, if any. `Rng::seeded` with it rolls the same again.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) seed: Option<u64>,
+    /// How the roll did, if it was judged.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) outcome: Option<RollOutcome>,
+}
+
+/// How a roll is judged, besides by its total.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub(crate) enum RollMode {
+    /// Count the dice which show at least this, eg. for a pool of d10s.
+    /// Every roll of an exploded die counts, and dropped dice don't.
+    Successes(i64),
+    /// The same, but every die which shows 1 takes a success away.
+    /// A roll with ones and no successes is a botch.
+    SuccessesAndBotches(i64),
+    /// Compare the total with a target number or DC.
+    Target(i64),
+}
+
+/// How a roll did.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) enum RollOutcome {
+    /// The dice which met the threshold, the dice which showed 1,
+    /// and the successes left once the ones have been taken away, if they are.
+    Successes {
+        successes: i64,
+        ones: i64,
+        net: i64,
+        botched: bool,
+    },
+    /// Whether the total met the target, and by how much it beat it (or missed, if negative).
+    Target { succeeded: bool, margin: i64 },
+}
+
+impl DetailedRoll {
+    /// Judge the roll, and keep the outcome with it.
+    pub(crate) fn judge(mut self, mode: Option<RollMode>) -> Self {
+        let kept_rolls = || {
+            self.groups
+                .iter()
+                .filter(|g| !g.negative)
+                .flat_map(|g| g.dice_rolled.iter())
+                .filter(|d| !d.dropped)
+                .flat_map(|d| d.rolls.iter().copied())
+        };
+        self.outcome = match mode {
+            Some(RollMode::Successes(t)) | Some(RollMode::SuccessesAndBotches(t)) => {
+                let botches = matches!(mode, Some(RollMode::SuccessesAndBotches(_)));
+                let successes = kept_rolls().filter(|r| *r >= t).count() as i64;
+                let ones = kept_rolls().filter(|r| *r == 1).count() as i64;
+                Some(RollOutcome::Successes {
+                    successes,
+                    ones,
+                    net: if botches { successes - ones } else { successes },
+                    botched: botches && successes == 0 && ones > 0,
+                })
+            }
+            Some(RollMode::Target(t)) => Some(RollOutcome::Target {
+                succeeded: self.total >= t,
+                margin: self.total - t,
+            }),
+            None => None,
+        };
+        self
+    }
+
+    /// The total of each dice group.
+    pub(crate) fn totals(&self) -> Vec<i64> {
+        self.groups.iter().map(|g| g.total).collect()
+    }
+
+    /// The roll as it goes into the roll log.
+    pub(crate) fn to_log(&self) -> Result<InputRoll, Error> {
+        Ok(InputRoll {
+            label: self.label.to_owned(),
+            expression: self.expression.to_owned(),
+            details: serde_json::to_string(self)?,
+            total: self.total,
+        })
+    }
+}
+
+/// A roll from the roll log.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct RollLogEntry {
+    pub(crate) id: i64,
+    /// When it was rolled, eg. "2021-03-04 18:15:00" (UTC).
+    pub(crate) date: String,
+    pub(crate) roll: DetailedRoll,
+}
+
+impl RollLogEntry {
+    pub(crate) fn from_logged(logged: LoggedRoll) -> Result<Self, Error> {
+        Ok(Self {
+            id: logged.id,
+            date: logged.date,
+            roll: serde_json::from_str(&logged.details)?,
+        })
+    }
+}
+
+/// A small, fast random number generator (SplitMix64).
+/// NB: It is good enough for dice, but not for secrets.
+#[derive(Debug, Clone)]
+pub(crate) struct Rng(u64);
+
+impl Rng {
+    /// A generator seeded from the clock and the hasher keys of std.
+    pub(crate) fn from_entropy() -> Self {
+        let mut hasher = RandomState::new().build_hasher();
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_nanos())
+            .unwrap_or_default();
+        hasher.write_u128(now);
+        Self(hasher.finish())
+    }
+
+    /// A generator which always gives the same rolls for the same seed.
+    pub(crate) fn seeded(seed: u64) -> Self {
+        Self(seed)
+    }
+
+    pub(crate) fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// A number from 1 to `sides`.
+    pub(crate) fn roll_die(&mut self, sides: u32) -> i64 {
+        (((self.next_u64() as u128 * sides as u128) >> 64) + 1) as i64
+    }
+}
+
+impl DiceGroup {
+    fn roll(&self, rng: &mut Rng) -> GroupRoll {
+        let mut dice = (0..self.count)
+            .map(|_| {
+                let mut rolls = vec![rng.roll_die(self.sides)];
+                if let Some(at) = self.explode_at {
+                    while rolls.len() <= MAX_EXPLOSIONS && rolls[rolls.len() - 1] >= at as i64 {
+                        rolls.push(rng.roll_die(self.sides));
+                    }
+                }
+                Die {
+                    value: rolls.iter().sum(),
+                    exploded: rolls.len() > 1,
+                    rolls,
+                    dropped: false,
+                }
+            })
+            .collect::<Vec<_>>();
+        // Drop by value, but keep the dice in the order they were rolled.
+        let mut order = (0..dice.len()).collect::<Vec<_>>();
+        order.sort_by_key(|i| dice[*i].value);
+        let low = self.drop_lowest as usize;
+        let high = (self.drop_highest as usize).min(order.len().saturating_sub(low));
+        for i in order[..low]
+            .iter()
+            .chain(order[order.len() - high..].iter())
+        {
+            dice[*i].dropped = true;
+        }
+        let total = dice
+            .iter()
+            .filter(|d| !d.dropped)
+            .map(|d| d.value)
+            .sum::<i64>();
+        GroupRoll {
+            dice: self.to_string(),
+            negative: self.negative,
+            dice_rolled: dice,
+            total: if self.negative { -total } else { total },
+        }
+    }
+}
+
+impl DiceExpression {
+    pub(crate) fn roll(&self, rng: &mut Rng, label: Option<String>) -> DetailedRoll {
+        let groups = self.groups.iter().map(|g| g.roll(rng)).collect::<Vec<_>>();
+        DetailedRoll {
+            label,
+            expression: self.to_string(),
+            total: groups.iter().map(|g| g.total).sum::<i64>() + self.bonus,
+            groups,
+            bonus: self.bonus,
+            seed: None,
+            outcome: None,
+        }
+    }
+
+    /// Roll with a generator seeded with `seed`, and record the seed with the roll.
+    pub(crate) fn roll_seeded(&self, seed: u64, label: Option<String>) -> DetailedRoll {
+        DetailedRoll {
+            seed: Some(seed),
+            ..self.roll(&mut Rng::seeded(seed), label)
+        }
+    }
+}
+
+/// Parse a dice expression.
+pub(crate) fn parse(text: &str) -> Result<DiceExpression, Error> {
+    let mut parser = Parser {
+        chars: text
+            .chars()
+            .filter(|c| !c.is_whitespace())
+            .flat_map(char::to_lowercase)
+            .collect(),
+        pos: 0,
+    };
+    let mut expression = DiceExpression {
+        groups: Vec::new(),
+        bonus: 0,
+    };
+    let mut negative = parser.next_is('-');
+    if !negative {
+        parser.next_is('+');
+    }
+    loop {
+        parser.term(negative, &mut expression)?;
+        if parser.next_is('+') {
+            negative = false;
+        } else if parser.next_is('-') {
+            negative = true;
+        } else {
+            break;
+        }
+    }
+    match parser.peek() {
+        None => Ok(expression),
+        Some(c) => Err(parser.error(&format!("unexpected '{}'", c))),
+    }
+}
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+}
+
+impl Parser {
+    fn error(&self, what: &str) -> Error {
+        let text = self.chars.iter().collect::<String>();
+        Error::parse(format!(
+            "Dice \"{}\": {} at position {}.",
+            text, what, self.pos
+        ))
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn next_is(&mut self, c: char) -> bool {
+        if self.peek() == Some(c) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn next_is_str(&mut self, s: &str) -> bool {
+        let found = s
+            .chars()
+            .enumerate()
+            .all(|(i, c)| self.chars.get(self.pos + i) == Some(&c));
+        if found {
+            self.pos += s.len();
+        }
+        found
+    }
+
+    fn number(&mut self) -> Result<Option<u32>, Error> {
+        let start = self.pos;
+        while self.peek().map(|c| c.is_ascii_digit()).unwrap_or(false) {
+            self.pos += 1;
+        }
+        if start == self.pos {
+            return Ok(None);
+        }
+        let digits = self.chars[start..self.pos].iter().collect::<String>();
+        digits
+            .parse()
+            .map(Some)
+            .map_err(|_| self.error(&format!("{} is too big", digits)))
+    }
+
+    fn term(&mut self, negative: bool, expression: &mut DiceExpression) -> Result<(), Error> {
+        let count = self.number()?;
+        if !self.next_is('d') {
+            let n = count.ok_or_else(|| self.error("expected a number or dice"))?;
+            expression.bonus += if negative { -(n as i64) } else { n as i64 };
+            return Ok(());
+        }
+        let count = count.unwrap_or(1);
+        let sides = match self.number()? {
+            Some(s) if s > 0 => s,
+            _ => return Err(self.error("dice need a number of sides")),
+        };
+        if count > MAX_DICE {
+            return Err(self.error(&format!("more than {} dice", MAX_DICE)));
+        }
+        let mut group = DiceGroup {
+            count,
+            sides,
+            drop_lowest: 0,
+            drop_highest: 0,
+            explode_at: None,
+            negative,
+        };
+        loop {
+            let modifier = if self.next_is_str("dl") {
+                &mut group.drop_lowest
+            } else if self.next_is_str("dh") {
+                &mut group.drop_highest
+            } else if self.next_is_str("mx") {
+                group.explode_at = Some(0);
+                group.explode_at.as_mut().expect("Just set.")
+            } else {
+                break;
+            };
+            *modifier = self
+                .number()?
+                .ok_or_else(|| self.error("expected a number"))?;
+        }
+        if group.drop_lowest + group.drop_highest > count {
+            return Err(self.error(&format!("can't drop more than {} dice", count)));
+        }
+        if group.explode_at.map(|n| n < 2).unwrap_or(false) {
+            return Err(self.error("dice can only explode on 2 or more"));
+        }
+        expression.groups.push(group);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse, DetailedRoll, Die, GroupRoll, Rng, RollMode, RollOutcome};
+    use azchar_error::Error;
+
+    #[test]
+    fn parse_and_print() {
+        for (text, printed) in [
+            ("2d10dl1mx10+1d4+6", "2d10dl1mx10+1d4+6"),
+            ("d20 - 2", "1d20-2"),
+            ("-1d4+3D6dh1", "-1d4+3d6dh1"),
+            ("4", "4"),
+        ]
+        .iter()
+        {
+            assert_eq!(&parse(text).expect("Parse.").to_string(), printed);
+        }
+    }
+
+    #[test]
+    fn dice_which_do_not_parse() {
+        for bad in [
+            "", "1d", "2d6dl3", "1d6mx1", "1d6+", "2x6", "5000d6", "1d6dl",
+        ]
+        .iter()
+        {
+            assert!(matches!(parse(bad), Err(Error::Parse { .. })), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn roll_every_die() {
+        let mut rng = Rng::seeded(7);
+        let roll = parse("4d6dl1dh1+2")
+            .unwrap()
+            .roll(&mut rng, Some("Stats".to_owned()));
+        assert_eq!(roll.label.as_deref(), Some("Stats"));
+        let group = &roll.groups[0];
+        assert_eq!(group.dice_rolled.len(), 4);
+        assert_eq!(group.dice_rolled.iter().filter(|d| d.dropped).count(), 2);
+        let kept = group.dice_rolled.iter().filter(|d| !d.dropped);
+        assert_eq!(group.total, kept.map(|d| d.value).sum::<i64>());
+        assert_eq!(roll.total, group.total + 2);
+        for d in group.dice_rolled.iter() {
+            assert!((1..=6).contains(&d.value));
+        }
+    }
+
+    #[test]
+    fn roll_exploding_dice() {
+        let mut rng = Rng::seeded(11);
+        let roll = parse("200d4mx4").unwrap().roll(&mut rng, None);
+        let dice = &roll.groups[0].dice_rolled;
+        assert!(dice.iter().any(|d| d.exploded));
+        for d in dice.iter() {
+            assert_eq!(d.value, d.rolls.iter().sum::<i64>());
+            let (last, exploding) = d.rolls.split_last().unwrap();
+            assert!(*last < 4 && exploding.iter().all(|r| *r == 4));
+        }
+    }
+
+    #[test]
+    fn the_same_seed_rolls_the_same() {
+        let dice = parse("4d6dl1+2d10mx10").unwrap();
+        let first = dice.roll(&mut Rng::seeded(42), None);
+        assert_eq!(first, dice.roll(&mut Rng::seeded(42), None));
+        let others = (0..10)
+            .map(|s| dice.roll(&mut Rng::seeded(s), None))
+            .collect::<Vec<_>>();
+        assert!(others.iter().any(|r| r.groups != first.groups));
+    }
+
+    fn pool(rolls: &[&[i64]]) -> DetailedRoll {
+        let dice_rolled = rolls
+            .iter()
+            .map(|r| Die {
+                value: r.iter().sum(),
+                rolls: r.to_vec(),
+                exploded: r.len() > 1,
+                dropped: false,
+            })
+            .collect::<Vec<_>>();
+        let total = dice_rolled.iter().map(|d| d.value).sum();
+        DetailedRoll {
+            label: None,
+            expression: format!("{}d10mx10", rolls.len()),
+            groups: vec![GroupRoll {
+                dice: format!("{}d10mx10", rolls.len()),
+                negative: false,
+                dice_rolled,
+                total,
+            }],
+            bonus: 0,
+            total,
+            seed: None,
+            outcome: None,
+        }
+    }
+
+    #[test]
+    fn count_successes_and_botches() {
+        let roll = pool(&[&[10, 8], &[1], &[7], &[3]]).judge(Some(RollMode::Successes(8)));
+        let expected = RollOutcome::Successes {
+            successes: 2,
+            ones: 1,
+            net: 2,
+            botched: false,
+        };
+        assert_eq!(roll.outcome, Some(expected));
+        let roll = roll.judge(Some(RollMode::SuccessesAndBotches(8)));
+        assert!(matches!(
+            roll.outcome,
+            Some(RollOutcome::Successes {
+                net: 1,
+                botched: false,
+                ..
+            })
+        ));
+        let roll = pool(&[&[1], &[1], &[5]]).judge(Some(RollMode::SuccessesAndBotches(6)));
+        assert!(matches!(
+            roll.outcome,
+            Some(RollOutcome::Successes {
+                net: -2,
+                botched: true,
+                ..
+            })
+        ));
+        // Without taking ones away, a roll can't botch.
+        let roll = roll.judge(Some(RollMode::Successes(6)));
+        assert!(matches!(
+            roll.outcome,
+            Some(RollOutcome::Successes {
+                net: 0,
+                botched: false,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn compare_with_a_target() {
+        let roll = parse("1d20+5").unwrap().roll_seeded(7, None);
+        let beat = roll.clone().judge(Some(RollMode::Target(roll.total - 2)));
+        let expected = RollOutcome::Target {
+            succeeded: true,
+            margin: 2,
+        };
+        assert_eq!(beat.outcome, Some(expected));
+        let missed = roll.clone().judge(Some(RollMode::Target(roll.total + 1)));
+        assert!(matches!(
+            missed.outcome,
+            Some(RollOutcome::Target {
+                succeeded: false,
+                margin: -1
+            })
+        ));
+    }
+
+    #[test]
+    fn negative_groups_take_away() {
+        let mut rng = Rng::seeded(3);
+        let roll = parse("-2d6").unwrap().roll(&mut rng, None);
+        assert!(roll.total <= -2 && roll.total >= -12);
+    }
+}
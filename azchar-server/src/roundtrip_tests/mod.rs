@@ -1,15 +1,17 @@
 //! This module exists to run a servant and client.
 //! This will use the ExpClient, which is somewhat limited.
-//! Currently the messagesize is limited to 10MB.
+//! Messages are framed, so a frame keeps one connection for all its requests.
 
 use std::net::{Shutdown, TcpStream};
 use std::path::PathBuf;
 use std::thread::{self, JoinHandle};
 
+use crate::framing;
 use crate::requests::{Request, Response};
 use crate::{MainLoop, Mode};
 
 mod test_library;
+mod websocket_tests;
 
 /// A structure which deletes all traces of the directory it writes.
 /// It's a little rudimentary.
@@ -82,6 +84,7 @@ impl Frame {
         // We need to sleep because the server needs a moment to start up.
         // before we can begin accepting signals.
         std::thread::sleep(std::time::Duration::from_millis(10));
+        frame.client = TcpStream::connect(&frame.address).ok();
         frame
     }
 
@@ -106,20 +109,15 @@ impl Frame {
 
 /// TODO: Figure out how to import `expclient` properly, so we don't have to use this.
 pub fn send_message(message: String, stream: &mut TcpStream) -> String {
-    use std::io::{Read, Write};
-
-    stream.write_all(message.as_bytes()).expect("Can't send");
-    stream.flush().expect("Can't flush");
+    framing::write_frame(stream, &message).expect("Can't send");
 
     // Receive.
-    let mut input = vec![0; 10 * 1024 * 1024];
-    input = match stream.read(&mut input) {
+    match framing::read_frame(stream) {
+        Ok(Some(reply)) => reply,
+        Ok(None) => String::new(),
         Err(e) => {
             println!("Bad stream from {:?} because {:?}.", stream.peer_addr(), e);
-            return String::new();
+            String::new()
         }
-        Ok(n) => input[0..n].to_vec(),
-    };
-    let out = String::from_utf8_lossy(&input);
-    out.to_owned().to_string()
+    }
 }
@@ -1,11 +1,14 @@
 //! I am dumping all the tests here for now.
 use super::*;
 use crate::requests::{Request, Response};
+use crate::roll::{RollMode, RollOutcome};
 use azchar_database::character::attribute::InputAttribute;
 use azchar_database::character::character::{CompleteCharacter, InputCharacter};
-use azchar_database::shared::Part;
+use azchar_database::character::roll_macro::RollMacro;
+use azchar_database::shared::{AttributeType, Part};
 
 use std::path::PathBuf;
+use std::sync::atomic::{AtomicU16, Ordering};
 use tempfile::TempDir;
 
 use azchar_error::ma;
@@ -13,12 +16,16 @@ use azchar_error::ma;
 const DND_TOML: &str = "examples/dnd5e0.toml";
 const DND_TOML2: &str = "../examples/dnd5e0.toml";
 
+// Tests run in parallel, so each frame gets its own port.
+static NEXT_PORT: AtomicU16 = AtomicU16::new(55600);
+
 pub(super) fn create_dnd() -> Result<(Frame, TempDir), String> {
     let new_dir = TempDir::new().map_err(ma)?;
     let storage_path = new_dir.path();
     let sp_s = storage_path.to_string_lossy().to_owned();
 
-    let mut fram = Frame::create(storage_path.to_path_buf(), "127.0.0.1:55555");
+    let address = format!("127.0.0.1:{}", NEXT_PORT.fetch_add(1, Ordering::SeqCst));
+    let mut fram = Frame::create(storage_path.to_path_buf(), &address);
     println!("Frame::create done.");
 
     let toml = if PathBuf::from(DND_TOML).exists() {
@@ -384,6 +391,492 @@ fn create_euridice_and_give_her_some_experience() {
     assert_eq!(exp_value.value_num(), Some(-999));
 }
 
+#[test]
+fn create_euridice_and_give_her_a_banana_for_strength() {
+    let (mut frame, _dir, euridice) = create_euridice_and_load_inner();
+
+    let uuid = euridice.uuid().to_owned();
+    let name = euridice.name().to_owned();
+    let (sk, sv) = euridice
+        .attributes()
+        .iter()
+        .find(|(k, _)| k.key() == "str")
+        .cloned()
+        .unwrap();
+    assert_eq!(sv.attribute_type(), Some(AttributeType::Integer));
+
+    let banana = sv.update_value_text(Some("banana".to_owned()));
+    let banana_req = Request::UpdateAttribute(name, uuid, sk, banana);
+    match frame.send_and_receive(banana_req) {
+        FrameReply::Success(Response::Err(_, azchar_error::Error::ValidationFailed { reason })) => {
+            assert!(reason.contains("'str'"), "Unexpected reason: {}", reason)
+        }
+        FrameReply::Fail(e) => panic!("Failed to send and receive: {}", e),
+        FrameReply::Success(r) => panic!("Strength can't be a banana, got {:?}", r),
+    };
+}
+
+#[test]
+fn create_euridice_and_choose_her_alignment() {
+    let (mut frame, _dir, euridice) = create_euridice_and_load_inner();
+
+    let uuid = euridice.uuid().to_owned();
+    let name = euridice.name().to_owned();
+    let (ak, av) = euridice
+        .attributes()
+        .iter()
+        .find(|(k, _)| k.key() == "alignment")
+        .cloned()
+        .unwrap();
+    assert_eq!(av.attribute_type(), Some(AttributeType::Enumeration));
+
+    let stupid = av
+        .clone()
+        .update_value_text(Some("Chaotic Stupid".to_owned()));
+    let stupid_req = Request::UpdateAttribute(name.to_owned(), uuid.to_owned(), ak.clone(), stupid);
+    match frame.send_and_receive(stupid_req) {
+        FrameReply::Success(Response::Err(_, azchar_error::Error::ValidationFailed { reason })) => {
+            assert!(
+                reason.contains("Chaotic Stupid"),
+                "Unexpected reason: {}",
+                reason
+            )
+        }
+        FrameReply::Fail(e) => panic!("Failed to send and receive: {}", e),
+        FrameReply::Success(r) => panic!("Not an alignment, got {:?}", r),
+    };
+
+    let good = av.update_value_text(Some("Chaotic Good".to_owned()));
+    let good_req = Request::UpdateAttribute(name, uuid, ak, good);
+    match frame.send_and_receive(good_req) {
+        FrameReply::Success(Response::UpdateAttribute) => {}
+        FrameReply::Fail(e) => panic!("Failed to send and receive: {}", e),
+        FrameReply::Success(r) => panic!("Expect `Response::UpdateAttribute`, got {:?}", r),
+    };
+}
+
+#[test]
+fn create_euridice_and_roll_for_her() {
+    let (mut frame, _dir, euridice) = create_euridice_and_load_inner();
+
+    let uuid = euridice.uuid().to_owned();
+    let name = euridice.name().to_owned();
+    let (sk, sv) = euridice
+        .attributes()
+        .iter()
+        .find(|(k, _)| k.key() == "str")
+        .cloned()
+        .unwrap();
+    let no_str_req = Request::RollFor {
+        name: name.to_owned(),
+        uuid: uuid.to_owned(),
+        dice: "1d20+@str".to_owned(),
+        seed: None,
+        mode: None,
+    };
+    match frame.send_and_receive(no_str_req) {
+        FrameReply::Success(Response::Err(_, azchar_error::Error::ValidationFailed { reason })) => {
+            assert!(reason.contains("'@str' has no value"), "{}", reason)
+        }
+        FrameReply::Fail(e) => panic!("Failed to send and receive: {}", e),
+        FrameReply::Success(r) => panic!("Strength is empty, got {:?}", r),
+    };
+
+    let str_req = Request::UpdateAttribute(
+        name.to_owned(),
+        uuid.to_owned(),
+        sk,
+        sv.update_value_num(Some(14)),
+    );
+    assert!(matches!(
+        frame.send_and_receive(str_req),
+        FrameReply::Success(Response::UpdateAttribute)
+    ));
+
+    let roll_req = Request::RollFor {
+        name: name.to_owned(),
+        uuid: uuid.to_owned(),
+        dice: "1d20+@str".to_owned(),
+        seed: Some(20),
+        mode: Some(RollMode::Target(15)),
+    };
+    match frame.send_and_receive(roll_req) {
+        FrameReply::Success(Response::RollFor(dice, subs, totals, _, seed, outcome)) => {
+            assert_eq!(dice, "1d20+14");
+            assert_eq!(seed, Some(20));
+            match outcome {
+                Some(RollOutcome::Target { succeeded, margin }) => {
+                    assert_eq!(margin, totals[0] + 14 - 15);
+                    assert_eq!(succeeded, margin >= 0);
+                }
+                o => panic!("Expect a target outcome, got {:?}", o),
+            }
+            assert_eq!(subs.len(), 1);
+            assert_eq!(
+                (subs[0].part.as_str(), subs[0].key.as_str()),
+                ("Euridice", "str")
+            );
+            assert_eq!(totals.len(), 1);
+        }
+        FrameReply::Fail(e) => panic!("Failed to send and receive: {}", e),
+        FrameReply::Success(r) => panic!("Expect `Response::RollFor`, got {:?}", r),
+    };
+
+    let luck_req = Request::RollFor {
+        name: name.to_owned(),
+        uuid: uuid.to_owned(),
+        dice: "1d20+@luck".to_owned(),
+        seed: None,
+        mode: None,
+    };
+    assert!(matches!(
+        frame.send_and_receive(luck_req),
+        FrameReply::Success(Response::Err(_, azchar_error::Error::NotFound { .. }))
+    ));
+
+    // Only the roll which worked is in her log.
+    let log_req = Request::GetRollLog(name, uuid, Some(10), None);
+    match frame.send_and_receive(log_req) {
+        FrameReply::Success(Response::GetRollLog(log)) => {
+            assert_eq!(log.len(), 1);
+            assert_eq!(log[0].roll.expression, "1d20+14");
+            assert_eq!(log[0].roll.groups[0].dice, "1d20");
+            assert_eq!(log[0].roll.seed, Some(20));
+        }
+        FrameReply::Fail(e) => panic!("Failed to send and receive: {}", e),
+        FrameReply::Success(r) => panic!("Expect `Response::GetRollLog`, got {:?}", r),
+    };
+}
+
+#[test]
+fn create_euridice_and_strike_with_her_sword() {
+    let (mut frame, _dir, euridice) = create_euridice_and_load_inner();
+
+    let uuid = euridice.uuid().to_owned();
+    let name = euridice.name().to_owned();
+    let scimitar = InputCharacter {
+        name: "+1 Scimitar".to_string(),
+        character_type: "weapon".to_string(),
+        speed: 0,
+        weight: Some(3),
+        size: None,
+        hp_total: None,
+        hp_current: None,
+        belongs_to: euridice.id(),
+        part_type: Part::InventoryItem,
+    };
+    let sword_request = Request::CreatePart(name.to_owned(), uuid.to_owned(), scimitar);
+    let sword = match frame.send_and_receive(sword_request) {
+        FrameReply::Success(Response::CreateDeleteAttributePart(c)) => c
+            .parts()
+            .iter()
+            .find(|p| p.name() == "+1 Scimitar")
+            .cloned()
+            .expect("She has a sword."),
+        FrameReply::Fail(e) => panic!("Failed to send and receive: {}", e),
+        FrameReply::Success(r) => panic!("Expect `CreateAttributePart`, got {:?}", r),
+    };
+    let sword_id = sword.id().expect("Saved.");
+    let strike =
+        |m: &str| Request::RollMacro(name.to_owned(), uuid.to_owned(), sword_id, m.to_owned());
+
+    // The sword has no bonus yet, nor any damage.
+    assert!(matches!(
+        frame.send_and_receive(strike("strike")),
+        FrameReply::Success(Response::Err(_, azchar_error::Error::NotFound { .. }))
+    ));
+    let bonus = InputAttribute {
+        key: "weapon_attack_bonus".to_owned(),
+        value_num: Some(1),
+        value_text: None,
+        description: None,
+        of: sword_id,
+    };
+    let request = Request::CreateAttribute(name.to_owned(), uuid.to_owned(), bonus);
+    assert!(matches!(
+        frame.send_and_receive(request),
+        FrameReply::Success(Response::CreateDeleteAttributePart(_))
+    ));
+    assert!(matches!(
+        frame.send_and_receive(strike("strike")),
+        FrameReply::Success(Response::Err(
+            _,
+            azchar_error::Error::ValidationFailed { .. }
+        ))
+    ));
+    let (k, v) = sword
+        .attributes
+        .iter()
+        .find(|(k, _)| k.key() == "weapon_damage1")
+        .cloned()
+        .expect("Every weapon does damage.");
+    let v = v.update_value_text(Some("1d6".to_owned()));
+    let request = Request::UpdateAttribute(name.to_owned(), uuid.to_owned(), k, v);
+    assert!(matches!(
+        frame.send_and_receive(request),
+        FrameReply::Success(Response::UpdateAttribute)
+    ));
+
+    match frame.send_and_receive(strike("strike")) {
+        FrameReply::Success(Response::RollMacro(m, rolls)) => {
+            assert_eq!(m, "strike");
+            let rolled = rolls
+                .iter()
+                .map(|r| (r.label.as_str(), r.dice.as_str()))
+                .collect::<Vec<_>>();
+            assert_eq!(rolled, vec![("attack", "1d20+1"), ("damage", "1d6")]);
+            assert_eq!(rolls[0].substitutions[0].part, "+1 Scimitar");
+        }
+        FrameReply::Fail(e) => panic!("Failed to send and receive: {}", e),
+        FrameReply::Success(r) => panic!("Expect `Response::RollMacro`, got {:?}", r),
+    };
+
+    // Her own strike goes before the one every weapon has.
+    let own = RollMacro {
+        of: sword_id,
+        name: "strike".to_owned(),
+        attack: Some("1d20+@weapon_attack_bonus+2".to_owned()),
+        damage: None,
+    };
+    let request = Request::SetRollMacro(name.to_owned(), uuid.to_owned(), own.clone());
+    match frame.send_and_receive(request) {
+        FrameReply::Success(Response::GetRollMacros(macros)) => assert_eq!(macros, vec![own]),
+        FrameReply::Fail(e) => panic!("Failed to send and receive: {}", e),
+        FrameReply::Success(r) => panic!("Expect `Response::GetRollMacros`, got {:?}", r),
+    };
+    match frame.send_and_receive(strike("strike")) {
+        FrameReply::Success(Response::RollMacro(_, rolls)) => {
+            assert_eq!(rolls.len(), 1);
+            assert_eq!(rolls[0].dice, "1d20+1+2");
+        }
+        FrameReply::Fail(e) => panic!("Failed to send and receive: {}", e),
+        FrameReply::Success(r) => panic!("Expect `Response::RollMacro`, got {:?}", r),
+    };
+    assert!(matches!(
+        frame.send_and_receive(strike("flourish")),
+        FrameReply::Success(Response::Err(_, azchar_error::Error::NotFound { .. }))
+    ));
+
+    let log_req = Request::GetRollLog(name, uuid, None, None);
+    match frame.send_and_receive(log_req) {
+        FrameReply::Success(Response::GetRollLog(log)) => {
+            assert_eq!(log.len(), 3);
+            assert_eq!(
+                log[0].roll.label.as_deref(),
+                Some("+1 Scimitar: strike (attack)")
+            );
+        }
+        FrameReply::Fail(e) => panic!("Failed to send and receive: {}", e),
+        FrameReply::Success(r) => panic!("Expect `Response::GetRollLog`, got {:?}", r),
+    };
+}
+
+fn roll_detailed(
+    dice: &str,
+    label: Option<String>,
+    seed: Option<u64>,
+    mode: Option<RollMode>,
+) -> Request {
+    Request::RollDetailed {
+        dice: dice.to_owned(),
+        label,
+        seed,
+        mode,
+    }
+}
+
+#[test]
+fn roll_some_dice_in_detail() {
+    let (mut frame, _dir) = create_dnd().expect("Couldn't create.");
+    let label = Some("Strength".to_owned());
+    let request = roll_detailed("4d6dl1+1", label, None, None);
+    match frame.send_and_receive(request) {
+        FrameReply::Success(Response::RollDetailed(roll)) => {
+            assert_eq!(roll.label.as_deref(), Some("Strength"));
+            assert_eq!(roll.expression, "4d6dl1+1");
+            assert_eq!(roll.groups[0].dice_rolled.len(), 4);
+            assert_eq!(roll.total, roll.groups[0].total + 1);
+        }
+        FrameReply::Fail(e) => panic!("Failed to send and receive: {}", e),
+        FrameReply::Success(r) => panic!("Expect `Response::RollDetailed`, got {:?}", r),
+    };
+    let request = roll_detailed("4d6dl5", None, None, None);
+    assert!(matches!(
+        frame.send_and_receive(request),
+        FrameReply::Success(Response::Err(_, azchar_error::Error::Parse { .. }))
+    ));
+
+    frame.send_and_receive(roll_detailed("1d8", None, None, None));
+    match frame.send_and_receive(Request::GetSessionRollLog(None, None)) {
+        FrameReply::Success(Response::GetRollLog(log)) => {
+            let labels = log
+                .iter()
+                .map(|e| e.roll.label.as_deref())
+                .collect::<Vec<_>>();
+            assert_eq!(labels, vec![None, Some("Strength")]);
+            assert_eq!(log[1].roll.groups[0].dice_rolled.len(), 4);
+        }
+        FrameReply::Fail(e) => panic!("Failed to send and receive: {}", e),
+        FrameReply::Success(r) => panic!("Expect `Response::GetRollLog`, got {:?}", r),
+    };
+}
+
+/// A plain roll without a seed of its own is seeded by the session, if its rolls are seeded.
+fn roll_with_seed(frame: &mut Frame, seed: Option<u64>) -> (Vec<i64>, Option<u64>) {
+    let dice = "3d1000".to_owned();
+    let request = match seed {
+        Some(_) => Request::RollWith {
+            dice,
+            seed,
+            mode: None,
+        },
+        None => Request::Roll(dice),
+    };
+    match frame.send_and_receive(request) {
+        FrameReply::Success(Response::Roll(totals, _, seed, _)) => (totals, seed),
+        FrameReply::Fail(e) => panic!("Failed to send and receive: {}", e),
+        FrameReply::Success(r) => panic!("Expect `Response::Roll`, got {:?}", r),
+    }
+}
+
+#[test]
+fn seeded_rolls_can_be_replayed() {
+    let (mut frame, _dir) = create_dnd().expect("Couldn't create.");
+    let detailed = || roll_detailed("4d6dl1", None, Some(42), None);
+    let first = match frame.send_and_receive(detailed()) {
+        FrameReply::Success(Response::RollDetailed(roll)) => roll,
+        FrameReply::Fail(e) => panic!("Failed to send and receive: {}", e),
+        FrameReply::Success(r) => panic!("Expect `Response::RollDetailed`, got {:?}", r),
+    };
+    assert_eq!(first.seed, Some(42));
+    match frame.send_and_receive(detailed()) {
+        FrameReply::Success(Response::RollDetailed(again)) => assert_eq!(again, first),
+        FrameReply::Fail(e) => panic!("Failed to send and receive: {}", e),
+        FrameReply::Success(r) => panic!("Expect `Response::RollDetailed`, got {:?}", r),
+    }
+    let (totals, seed) = roll_with_seed(&mut frame, Some(42));
+    assert_eq!(seed, Some(42));
+    assert_eq!(totals, roll_with_seed(&mut frame, Some(42)).0);
+
+    // A seeded session rolls the same again when it is seeded the same.
+    assert!(matches!(
+        frame.send_and_receive(Request::SeedRolls(Some(7))),
+        FrameReply::Success(Response::SeedRolls(Some(7)))
+    ));
+    let rolls = (0..3)
+        .map(|_| roll_with_seed(&mut frame, None))
+        .collect::<Vec<_>>();
+    assert!(rolls.iter().all(|(_, seed)| seed.is_some()));
+    frame.send_and_receive(Request::SeedRolls(Some(7)));
+    for roll in rolls.iter() {
+        assert_eq!(&roll_with_seed(&mut frame, None), roll);
+    }
+    // Each roll can be replayed on its own by its seed.
+    let (totals, seed) = rolls[1].clone();
+    assert_eq!(roll_with_seed(&mut frame, seed).0, totals);
+
+    frame.send_and_receive(Request::SeedRolls(None));
+    assert_eq!(roll_with_seed(&mut frame, None).1, None);
+}
+
+#[test]
+fn count_successes_in_a_dice_pool() {
+    let (mut frame, _dir) = create_dnd().expect("Couldn't create.");
+    let mode = Some(RollMode::SuccessesAndBotches(8));
+    let request = Request::RollWith {
+        dice: "6d10mx10".to_owned(),
+        seed: Some(5),
+        mode,
+    };
+    let (totals, outcome) = match frame.send_and_receive(request) {
+        FrameReply::Success(Response::Roll(totals, _, _, Some(outcome))) => (totals, outcome),
+        FrameReply::Fail(e) => panic!("Failed to send and receive: {}", e),
+        FrameReply::Success(r) => panic!("Expect `Response::Roll` with an outcome, got {:?}", r),
+    };
+    // The same seed shows every die, which have to agree with the count.
+    let request = roll_detailed("6d10mx10", None, Some(5), mode);
+    match frame.send_and_receive(request) {
+        FrameReply::Success(Response::RollDetailed(roll)) => {
+            assert_eq!(roll.totals(), totals);
+            assert_eq!(roll.outcome.as_ref(), Some(&outcome));
+            let rolls = roll.groups[0]
+                .dice_rolled
+                .iter()
+                .flat_map(|d| d.rolls.iter())
+                .collect::<Vec<_>>();
+            let successes = rolls.iter().filter(|r| ***r >= 8).count() as i64;
+            let ones = rolls.iter().filter(|r| ***r == 1).count() as i64;
+            let expected = RollOutcome::Successes {
+                successes,
+                ones,
+                net: successes - ones,
+                botched: successes == 0 && ones > 0,
+            };
+            assert_eq!(outcome, expected);
+        }
+        FrameReply::Fail(e) => panic!("Failed to send and receive: {}", e),
+        FrameReply::Success(r) => panic!("Expect `Response::RollDetailed`, got {:?}", r),
+    };
+}
+
+#[test]
+fn an_idle_client_does_not_hold_up_the_others() {
+    let (frame, _dir) = create_dnd().expect("Couldn't create.");
+    // This client says nothing, and keeps its connection open.
+    let _idle = TcpStream::connect(&frame.address).expect("Can't connect.");
+    let mut other = TcpStream::connect(&frame.address).expect("Can't connect.");
+    other
+        .set_read_timeout(Some(std::time::Duration::from_secs(5)))
+        .expect("Can't set a timeout.");
+    // Text which looks percent-encoded is left alone.
+    let request = Request::RollStats("1d20%20".to_owned());
+    let reply = send_message(
+        serde_json::to_string(&request).expect("invalid"),
+        &mut other,
+    );
+    match serde_json::from_str(&reply) {
+        Ok(Response::Err(echo, _)) => assert!(echo.contains("1d20%20")),
+        r => panic!("Expected `Response::Err`, got {:?}", r),
+    }
+    let reply = send_message("\"ListSystems\"".to_owned(), &mut other);
+    match serde_json::from_str(&reply) {
+        Ok(Response::ListSystems(names)) => assert_eq!(names, vec!["dnd5e_test"]),
+        r => panic!("Expected `Response::ListSystems`, got {:?}", r),
+    }
+}
+
+#[test]
+fn an_idle_http_client_does_not_hold_up_the_others() {
+    use std::io::{Read, Write};
+
+    let address = format!("127.0.0.1:{}", NEXT_PORT.fetch_add(1, Ordering::SeqCst));
+    let addr = address.to_owned();
+    std::thread::spawn(move || match MainLoop::create_with_connection(&addr) {
+        Ok(mut ml) => ml.run(Mode::Http),
+        Err(e) => println!("Error in main loop: {}", e),
+    });
+    std::thread::sleep(std::time::Duration::from_millis(10));
+    let request = |text: &str| {
+        let mut stream = TcpStream::connect(&address).expect("Can't connect.");
+        stream
+            .set_read_timeout(Some(std::time::Duration::from_secs(5)))
+            .expect("Can't set a timeout.");
+        stream.write_all(text.as_bytes()).expect("Can't send.");
+        let mut reply = String::new();
+        stream.read_to_string(&mut reply).expect("No reply.");
+        reply
+    };
+
+    // This client sends half a request, and keeps its connection open.
+    let mut idle = TcpStream::connect(&address).expect("Can't connect.");
+    idle.write_all(b"POST /roll HTTP/1.1\r\nContent-Length: 100\r\n\r\n1d")
+        .expect("Can't send.");
+    let reply = request("GET /hello HTTP/1.1\r\n\r\n");
+    assert!(reply.starts_with("HTTP/1.1 200 OK"), "{}", reply);
+    let reply = request("POST /shutdown HTTP/1.1\r\n\r\n");
+    assert!(reply.starts_with("HTTP/1.1 200 OK"), "{}", reply);
+}
+
 #[test]
 fn create_euridice_and_delete_euridice() {
     let (mut frame, _dir, euridice) = create_euridice_and_load_inner();
@@ -485,3 +978,166 @@ fn create_euridice_and_fiddle_with_euridice() {
         );
     }
 }
+
+#[test]
+fn export_dnd_and_create_a_system_from_it() {
+    let (mut frame, dir) = create_dnd().expect("Couldn't create.");
+    let exported = dir
+        .path()
+        .join("exported.toml")
+        .to_string_lossy()
+        .to_string();
+    match frame.send_and_receive(Request::ExportSystem(exported.to_owned())) {
+        FrameReply::Success(Response::ExportSystem(p)) => assert_eq!(p, exported),
+        FrameReply::Fail(e) => panic!("Failed to send and receive: {}", e),
+        FrameReply::Success(r) => panic!("Expect `Response::ExportSystem`, got {:?}", r),
+    }
+
+    let path = dir.path().to_string_lossy().to_string();
+    let create = Request::CreateSystem("dnd5e_again".to_owned(), path, exported);
+    match frame.send_and_receive(create) {
+        FrameReply::Success(Response::CreateSystem(_)) => {}
+        FrameReply::Fail(e) => panic!("Failed to send and receive: {}", e),
+        FrameReply::Success(r) => panic!("Expect `Response::CreateSystem`, got {:?}", r),
+    }
+    match frame.send_and_receive(Request::CreateCharacterSheet("Euridice".to_owned())) {
+        FrameReply::Success(Response::CreateCharacterSheet(list)) => assert_eq!(list.len(), 1),
+        FrameReply::Fail(e) => panic!("Failed to send and receive: {}", e),
+        FrameReply::Success(r) => panic!("Expect `Response::CreateCharacterSheet`, got {:?}", r),
+    }
+}
+
+#[test]
+fn back_up_euridice_and_restore_her_elsewhere() {
+    let (mut frame, dir, euridice) = create_euridice_and_load_inner();
+    let archive = dir.path().join("dnd.azbak").to_string_lossy().to_string();
+    match frame.send_and_receive(Request::Backup(archive.to_owned())) {
+        FrameReply::Success(Response::Backup(m)) => assert_eq!(m.sheets.len(), 1),
+        FrameReply::Fail(e) => panic!("Failed to send and receive: {}", e),
+        FrameReply::Success(r) => panic!("Expect `Response::Backup`, got {:?}", r),
+    }
+
+    // The system is still loaded, so it can't be restored next to itself.
+    let target = dir.path().join("restored").to_string_lossy().to_string();
+    let restore = Request::Restore(archive, target);
+    match frame.send_and_receive(restore.clone()) {
+        FrameReply::Success(Response::Err(_, azchar_error::Error::Conflict { .. })) => {}
+        FrameReply::Fail(e) => panic!("Failed to send and receive: {}", e),
+        FrameReply::Success(r) => panic!("Expect a conflict, got {:?}", r),
+    }
+    match frame.send_and_receive(Request::UnloadSystem("dnd5e_test".to_owned())) {
+        FrameReply::Success(Response::UnloadSystem(_)) => {}
+        FrameReply::Fail(e) => panic!("Failed to send and receive: {}", e),
+        FrameReply::Success(r) => panic!("Expect `Response::UnloadSystem`, got {:?}", r),
+    }
+    match frame.send_and_receive(restore) {
+        FrameReply::Success(Response::Restore(list)) => assert_eq!(list.len(), 1),
+        FrameReply::Fail(e) => panic!("Failed to send and receive: {}", e),
+        FrameReply::Success(r) => panic!("Expect `Response::Restore`, got {:?}", r),
+    }
+    let load = Request::LoadCharacter(euridice.name().to_owned(), euridice.uuid().to_owned());
+    match frame.send_and_receive(load) {
+        FrameReply::Success(Response::LoadCharacter(c)) => assert_eq!(c, euridice),
+        FrameReply::Fail(e) => panic!("Failed to send and receive: {}", e),
+        FrameReply::Success(r) => panic!("Expect `Response::LoadCharacter`, got {:?}", r),
+    }
+}
+
+#[test]
+fn a_system_with_a_missing_sheet_says_so() {
+    let (mut frame, dir, euridice) = create_euridice_and_load_inner();
+    match frame.send_and_receive(Request::UnloadSystem("dnd5e_test".to_owned())) {
+        FrameReply::Success(Response::UnloadSystem(_)) => {}
+        FrameReply::Fail(e) => panic!("Failed to send and receive: {}", e),
+        FrameReply::Success(r) => panic!("Expect `Response::UnloadSystem`, got {:?}", r),
+    }
+    let sheet = format!("{}_{}.db", euridice.name(), euridice.uuid());
+    std::fs::remove_file(dir.path().join(&sheet)).expect("The sheet is there.");
+
+    let root = dir
+        .path()
+        .join("dnd5e_test.db")
+        .to_string_lossy()
+        .to_string();
+    let key = (euridice.name().to_owned(), euridice.uuid().to_owned());
+    match frame.send_and_receive(Request::InitialiseFromPath(root)) {
+        FrameReply::Success(Response::InitialiseFromPath(list, missing)) => {
+            assert_eq!(list.len(), 1);
+            assert_eq!(missing.len(), 1);
+            assert_eq!(missing[0].0, key);
+            assert!(missing[0].1.ends_with(&sheet), "{}", missing[0].1);
+        }
+        FrameReply::Fail(e) => panic!("Failed to send and receive: {}", e),
+        FrameReply::Success(r) => panic!("Expect `Response::InitialiseFromPath`, got {:?}", r),
+    }
+    match frame.send_and_receive(Request::UseSystem("dnd5e_test".to_owned())) {
+        FrameReply::Success(Response::UseSystem(_, missing)) => assert_eq!(missing[0].0, key),
+        FrameReply::Fail(e) => panic!("Failed to send and receive: {}", e),
+        FrameReply::Success(r) => panic!("Expect `Response::UseSystem`, got {:?}", r),
+    }
+}
+
+#[test]
+fn create_euridice_and_update_the_system_under_her() {
+    use azchar_database::root_db::system_update::RemovalPolicy;
+
+    let (mut frame, _dir, _) = create_euridice_and_load_inner();
+    let toml = if PathBuf::from(DND_TOML).exists() {
+        DND_TOML
+    } else {
+        DND_TOML2
+    };
+    let toml = std::fs::read_to_string(toml).expect("Can't read the config.");
+    let toml = toml.replacen(
+        "permitted_attributes = [",
+        "permitted_attributes = [\n  { key = \"inspiration\", attribute_type = 0, attribute_description = \"Inspiration.\", part_name = \"main\", part_type = \"Main\", obligatory = true },",
+        1,
+    );
+    let report = match frame.send_and_receive(Request::UpdateSystem(toml, RemovalPolicy::Keep)) {
+        FrameReply::Success(Response::UpdateSystem(r)) => r,
+        FrameReply::Fail(e) => panic!("Failed to send and receive: {}", e),
+        FrameReply::Success(r) => panic!("Expect `Response::UpdateSystem`, got {:?}", r),
+    };
+    assert_eq!(report.added_attributes, vec!["inspiration"]);
+    assert_eq!(report.sheets.len(), 1);
+    assert_eq!(report.sheets[0].attributes_added, 1);
+
+    // The updated sheet can still be saved.
+    let (name, uuid) = (
+        report.sheets[0].name.to_owned(),
+        report.sheets[0].uuid.to_owned(),
+    );
+    let euridice = match frame.send_and_receive(Request::LoadCharacter(name, uuid)) {
+        FrameReply::Success(Response::LoadCharacter(c)) => c,
+        FrameReply::Fail(e) => panic!("Failed to send and receive: {}", e),
+        FrameReply::Success(r) => panic!("Expect `Response::LoadCharacter`, got {:?}", r),
+    };
+    match frame.send_and_receive(Request::CreateUpdateCharacter(euridice)) {
+        FrameReply::Success(Response::CreateUpdateCharacter(_)) => {}
+        FrameReply::Fail(e) => panic!("Failed to send and receive: {}", e),
+        FrameReply::Success(r) => panic!("Expect `Response::CreateUpdateCharacter`, got {:?}", r),
+    }
+}
+
+#[test]
+fn validate_a_system_with_mistakes() {
+    let (mut frame, _dir) = create_dnd().expect("Couldn't create.");
+    let toml = if PathBuf::from("examples/dnd5e.toml").exists() {
+        "examples/dnd5e.toml"
+    } else {
+        "../examples/dnd5e.toml"
+    };
+    let found = match frame.send_and_receive(Request::ValidateSystem(toml.to_owned())) {
+        FrameReply::Success(Response::ValidateSystem(d)) => d,
+        FrameReply::Fail(e) => panic!("Failed to send and receive: {}", e),
+        FrameReply::Success(r) => panic!("Expect `Response::ValidateSystem`, got {:?}", r),
+    };
+    assert!(found.iter().all(|d| !d.is_error()));
+    assert!(found.iter().any(|d| d.message.contains("'amour_ac_bonus'")));
+
+    match frame.send_and_receive(Request::ValidateSystem("no/such/file.toml".to_owned())) {
+        FrameReply::Success(Response::Err(_, azchar_error::Error::Io { .. })) => {}
+        FrameReply::Fail(e) => panic!("Failed to send and receive: {}", e),
+        FrameReply::Success(r) => panic!("Expect `Response::Err`, got {:?}", r),
+    }
+}
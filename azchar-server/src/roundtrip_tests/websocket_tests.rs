@@ -0,0 +1,399 @@
+//! Tests for the websocket server, which can serve several clients at once.
+use crate::requests::{Request, Response, ResponseEnvelope, PROTOCOL_VERSION};
+use crate::websocket_loop::WsMainLoop;
+use azchar_error::Error;
+
+use std::net::TcpStream;
+use std::path::PathBuf;
+use std::thread::JoinHandle;
+use tempfile::TempDir;
+use websocket::sync::Client;
+use websocket::{ClientBuilder, Message, OwnedMessage};
+
+const WS_ADDRESS: &str = "127.0.0.1:55557";
+const WS_ADDRESS_SUBSCRIBE: &str = "127.0.0.1:55558";
+const WS_ADDRESS_ENVELOPE: &str = "127.0.0.1:55559";
+const WS_ADDRESS_BATCH: &str = "127.0.0.1:55560";
+const WS_ADDRESS_SYSTEMS: &str = "127.0.0.1:55561";
+
+fn connect(address: &str) -> Client<TcpStream> {
+    ClientBuilder::new(&format!("ws://{}", address))
+        .expect("Bad address?")
+        .connect_insecure()
+        .expect("Could not connect.")
+}
+
+fn receive(client: &mut Client<TcpStream>) -> Response {
+    match client.recv_message().expect("Can't receive") {
+        OwnedMessage::Text(t) => serde_json::from_str(&t).expect("Not a response."),
+        m => panic!("Expected a text message, got {:?}", m),
+    }
+}
+
+fn send_and_receive(client: &mut Client<TcpStream>, request: Request) -> Response {
+    let letter = serde_json::to_string(&request).expect("invalid");
+    client
+        .send_message(&Message::text(letter))
+        .expect("Can't send");
+    receive(client)
+}
+
+/// Start a server and create a system on it, returning the server thread and the creating client.
+fn start_with_system(
+    address: &'static str,
+    name: &str,
+    dir: &TempDir,
+) -> (JoinHandle<()>, Client<TcpStream>) {
+    let handle = std::thread::spawn(move || WsMainLoop::create(address).run());
+    std::thread::sleep(std::time::Duration::from_millis(10));
+
+    let mut client = connect(address);
+    create_system(&mut client, name, "dnd5e0.toml", dir);
+    (handle, client)
+}
+
+/// Create a system from one of the example configs.
+fn create_system(client: &mut Client<TcpStream>, name: &str, example: &str, dir: &TempDir) {
+    let toml = if PathBuf::from("examples").join(example).exists() {
+        PathBuf::from("examples").join(example)
+    } else {
+        PathBuf::from("../examples").join(example)
+    };
+    let path = dir.path().to_string_lossy().to_string();
+    let toml = toml.to_string_lossy().to_string();
+
+    let create = Request::CreateSystem(name.to_owned(), path, toml);
+    match send_and_receive(client, create) {
+        Response::CreateSystem(_) => {}
+        r => panic!("Expected `Response::CreateSystem`, got {:?}", r),
+    }
+}
+
+#[test]
+fn two_clients_share_a_system() {
+    let dir = TempDir::new().expect("No tempdir.");
+    let (handle, mut gm) = start_with_system(WS_ADDRESS, "dnd5e_ws", &dir);
+    let mut player = connect(WS_ADDRESS);
+
+    // The player sees the system the GM just created.
+    match send_and_receive(
+        &mut player,
+        Request::CreateCharacterSheet("Saloth".to_owned()),
+    ) {
+        Response::CreateCharacterSheet(list) => assert_eq!(list.len(), 1),
+        r => panic!("Expected `Response::CreateCharacterSheet`, got {:?}", r),
+    }
+    // And the GM sees the player's character.
+    match send_and_receive(&mut gm, Request::ListCharacters) {
+        Response::ListCharacters(list) => assert_eq!(list[0].name(), "Saloth"),
+        r => panic!("Expected `Response::ListCharacters`, got {:?}", r),
+    }
+
+    match send_and_receive(&mut player, Request::Shutdown) {
+        Response::Shutdown => {}
+        r => panic!("Expected `Response::Shutdown`, got {:?}", r),
+    }
+    handle.join().expect("Server thread panicked.");
+}
+
+#[test]
+fn subscriber_is_told_about_changes() {
+    let dir = TempDir::new().expect("No tempdir.");
+    let (handle, mut gm) = start_with_system(WS_ADDRESS_SUBSCRIBE, "dnd5e_sub", &dir);
+    let mut player = connect(WS_ADDRESS_SUBSCRIBE);
+
+    let (name, uuid) = match send_and_receive(
+        &mut player,
+        Request::CreateCharacterSheet("Saloth".to_owned()),
+    ) {
+        Response::CreateCharacterSheet(list) => {
+            (list[0].name().to_owned(), list[0].uuid().to_owned())
+        }
+        r => panic!("Expected `Response::CreateCharacterSheet`, got {:?}", r),
+    };
+    // Nobody can watch a character that does not exist.
+    let missing = Request::Subscribe(name.to_owned(), "no-such-uuid".to_owned());
+    match send_and_receive(&mut player, missing) {
+        Response::Err(_, _) => {}
+        r => panic!("Expected `Response::Err`, got {:?}", r),
+    }
+    let subscribe = Request::Subscribe(name.to_owned(), uuid.to_owned());
+    match send_and_receive(&mut player, subscribe) {
+        Response::Subscribe(n, u) => assert_eq!((n, u), (name.to_owned(), uuid.to_owned())),
+        r => panic!("Expected `Response::Subscribe`, got {:?}", r),
+    }
+
+    // The GM changes the character and the player hears of it without asking.
+    let sheet = match send_and_receive(
+        &mut gm,
+        Request::LoadCharacter(name.to_owned(), uuid.to_owned()),
+    ) {
+        Response::LoadCharacter(c) => c,
+        r => panic!("Expected `Response::LoadCharacter`, got {:?}", r),
+    };
+    match send_and_receive(&mut gm, Request::CreateUpdateCharacter(sheet)) {
+        Response::CreateUpdateCharacter(_) => {}
+        r => panic!("Expected `Response::CreateUpdateCharacter`, got {:?}", r),
+    }
+    match receive(&mut player) {
+        Response::CharacterChanged(c) => {
+            assert_eq!(c.name(), name);
+            assert_eq!(c.uuid(), uuid);
+        }
+        r => panic!("Expected `Response::CharacterChanged`, got {:?}", r),
+    }
+
+    // A batch which is rolled back changes nothing, so nobody hears of it.
+    let orphan = serde_json::from_str(
+        "{\"name\":\"Orphan\",\"character_type\":\"spell\",\"speed\":0,\"weight\":null,\
+        \"size\":null,\"hp_total\":null,\"hp_current\":null,\"part_type\":\"Ability\",\
+        \"belongs_to\":999}",
+    )
+    .expect("Not an input character.");
+    let failing = vec![
+        Request::LoadCharacter(name.to_owned(), uuid.to_owned()),
+        Request::CreatePart(name.to_owned(), uuid.to_owned(), orphan),
+    ];
+    match send_and_receive(&mut gm, Request::Batch(failing)) {
+        Response::BatchFailed(i, _) => assert_eq!(i, 1),
+        r => panic!("Expected `Response::BatchFailed`, got {:?}", r),
+    }
+    match send_and_receive(&mut player, Request::ListCharacters) {
+        Response::ListCharacters(list) => assert_eq!(list.len(), 1),
+        r => panic!("Expected `Response::ListCharacters`, got {:?}", r),
+    }
+
+    // After unsubscribing the next reply the player gets is to its own request.
+    let unsubscribe = Request::Unsubscribe(name.to_owned(), uuid.to_owned());
+    match send_and_receive(&mut player, unsubscribe) {
+        Response::Unsubscribe(_, _) => {}
+        r => panic!("Expected `Response::Unsubscribe`, got {:?}", r),
+    }
+    let sheet = match send_and_receive(&mut gm, Request::LoadCharacter(name, uuid)) {
+        Response::LoadCharacter(c) => c,
+        r => panic!("Expected `Response::LoadCharacter`, got {:?}", r),
+    };
+    match send_and_receive(&mut gm, Request::CreateUpdateCharacter(sheet)) {
+        Response::CreateUpdateCharacter(_) => {}
+        r => panic!("Expected `Response::CreateUpdateCharacter`, got {:?}", r),
+    }
+    match send_and_receive(&mut player, Request::ListCharacters) {
+        Response::ListCharacters(list) => assert_eq!(list.len(), 1),
+        r => panic!("Expected `Response::ListCharacters`, got {:?}", r),
+    }
+
+    match send_and_receive(&mut gm, Request::Shutdown) {
+        Response::Shutdown => {}
+        r => panic!("Expected `Response::Shutdown`, got {:?}", r),
+    }
+    handle.join().expect("Server thread panicked.");
+}
+
+#[test]
+fn pipelined_replies_carry_their_ids() {
+    let handle = std::thread::spawn(|| WsMainLoop::create(WS_ADDRESS_ENVELOPE).run());
+    std::thread::sleep(std::time::Duration::from_millis(10));
+    let mut client = connect(WS_ADDRESS_ENVELOPE);
+
+    let hello = "{\"id\":\"first\",\"version\":1,\"request\":\"Hello\"}";
+    let list = "{\"id\":2,\"request\":\"ListCharacters\"}";
+    for letter in &[hello, list] {
+        client
+            .send_message(&Message::text(*letter))
+            .expect("Can't send");
+    }
+    let mut replies = (0..2).map(|_| match client.recv_message().expect("Can't receive") {
+        OwnedMessage::Text(t) => {
+            serde_json::from_str::<ResponseEnvelope>(&t).expect("Not enveloped.")
+        }
+        m => panic!("Expected a text message, got {:?}", m),
+    });
+
+    let first = replies.next().unwrap();
+    assert_eq!(first.id, Some(serde_json::json!("first")));
+    match first.response {
+        Response::Hello(v, kinds) => {
+            assert_eq!(v, PROTOCOL_VERSION);
+            assert!(kinds.iter().any(|k| k == "Subscribe"));
+        }
+        r => panic!("Expected `Response::Hello`, got {:?}", r),
+    }
+    let second = replies.next().unwrap();
+    assert_eq!(second.id, Some(serde_json::json!(2)));
+    match second.response {
+        Response::Err(_, _) => {}
+        r => panic!("Expected `Response::Err`, got {:?}", r),
+    }
+    drop(replies);
+
+    // Bare requests still get bare replies.
+    match send_and_receive(&mut client, Request::Shutdown) {
+        Response::Shutdown => {}
+        r => panic!("Expected `Response::Shutdown`, got {:?}", r),
+    }
+    handle.join().expect("Server thread panicked.");
+}
+
+#[test]
+fn batch_is_all_or_nothing() {
+    use azchar_database::character::note::InputNote;
+
+    let dir = TempDir::new().expect("No tempdir.");
+    let (handle, mut client) = start_with_system(WS_ADDRESS_BATCH, "dnd5e_batch", &dir);
+    let list = match send_and_receive(
+        &mut client,
+        Request::CreateCharacterSheet("Saloth".to_owned()),
+    ) {
+        Response::CreateCharacterSheet(list) => list,
+        r => panic!("Expected `Response::CreateCharacterSheet`, got {:?}", r),
+    };
+    let (name, uuid) = (list[0].name().to_owned(), list[0].uuid().to_owned());
+    let note = |t: &str| {
+        let n = InputNote::new_note(t.to_owned(), None);
+        Request::InsertNote(name.to_owned(), uuid.to_owned(), n)
+    };
+    // This spell belongs to a part that does not exist.
+    let orphan = serde_json::from_str(
+        "{\"name\":\"Orphan\",\"character_type\":\"spell\",\"speed\":0,\"weight\":null,\
+        \"size\":null,\"hp_total\":null,\"hp_current\":null,\"part_type\":\"Ability\",\
+        \"belongs_to\":999}",
+    )
+    .expect("Not an input character.");
+    let load = || Request::LoadCharacter(name.to_owned(), uuid.to_owned());
+
+    let failing = vec![
+        note("First"),
+        Request::CreatePart(name.to_owned(), uuid.to_owned(), orphan),
+        note("Never"),
+    ];
+    match send_and_receive(&mut client, Request::Batch(failing)) {
+        Response::BatchFailed(i, _) => assert_eq!(i, 1),
+        r => panic!("Expected `Response::BatchFailed`, got {:?}", r),
+    }
+    match send_and_receive(&mut client, load()) {
+        Response::LoadCharacter(c) => assert!(c.notes().is_empty()),
+        r => panic!("Expected `Response::LoadCharacter`, got {:?}", r),
+    }
+
+    let mixed = vec![
+        note("First"),
+        Request::LoadCharacter(name.to_owned(), "other".to_owned()),
+    ];
+    match send_and_receive(&mut client, Request::Batch(mixed)) {
+        Response::Err(_, _) => {}
+        r => panic!("Expected `Response::Err`, got {:?}", r),
+    }
+
+    let good = vec![note("First"), note("Second"), load()];
+    match send_and_receive(&mut client, Request::Batch(good)) {
+        Response::Batch(responses) => {
+            assert_eq!(responses.len(), 3);
+            match &responses[2] {
+                Response::LoadCharacter(c) => assert_eq!(c.notes().len(), 2),
+                r => panic!("Expected `Response::LoadCharacter`, got {:?}", r),
+            }
+        }
+        r => panic!("Expected `Response::Batch`, got {:?}", r),
+    }
+    match send_and_receive(&mut client, load()) {
+        Response::LoadCharacter(c) => assert_eq!(c.notes().len(), 2),
+        r => panic!("Expected `Response::LoadCharacter`, got {:?}", r),
+    }
+
+    // Saving the whole character and deleting a part open transactions of their own.
+    let spell = serde_json::from_str(
+        "{\"name\":\"Fireball\",\"character_type\":\"spell\",\"speed\":0,\"weight\":null,\
+        \"size\":null,\"hp_total\":null,\"hp_current\":null,\"part_type\":\"Ability\",\
+        \"belongs_to\":1}",
+    )
+    .expect("Not an input character.");
+    let sheet = match send_and_receive(
+        &mut client,
+        Request::CreatePart(name.to_owned(), uuid.to_owned(), spell),
+    ) {
+        Response::CreateDeleteAttributePart(c) => c,
+        r => panic!(
+            "Expected `Response::CreateDeleteAttributePart`, got {:?}",
+            r
+        ),
+    };
+    let spell_id = sheet
+        .parts()
+        .iter()
+        .find(|p| p.name() == "Fireball")
+        .and_then(|p| p.id())
+        .expect("The spell was saved.");
+    let mut heavier = serde_json::to_value(&sheet).expect("Can't serialize.");
+    heavier["weight"] = serde_json::json!(77);
+    let heavier = serde_json::from_value(heavier).expect("Not a character.");
+    let save_and_delete = vec![
+        Request::CreateUpdateCharacter(heavier),
+        Request::DeletePart(name.to_owned(), uuid.to_owned(), spell_id),
+    ];
+    match send_and_receive(&mut client, Request::Batch(save_and_delete)) {
+        Response::Batch(responses) => assert_eq!(responses.len(), 2),
+        r => panic!("Expected `Response::Batch`, got {:?}", r),
+    }
+    match send_and_receive(&mut client, load()) {
+        Response::LoadCharacter(c) => {
+            assert_eq!(c.weight(), Some(77));
+            assert!(c.parts().iter().all(|p| p.name() != "Fireball"));
+        }
+        r => panic!("Expected `Response::LoadCharacter`, got {:?}", r),
+    }
+
+    match send_and_receive(&mut client, Request::Shutdown) {
+        Response::Shutdown => {}
+        r => panic!("Expected `Response::Shutdown`, got {:?}", r),
+    }
+    handle.join().expect("Server thread panicked.");
+}
+
+#[test]
+fn two_systems_side_by_side() {
+    let dir = TempDir::new().expect("No tempdir.");
+    let (handle, mut dnd) = start_with_system(WS_ADDRESS_SYSTEMS, "dnd5e_side", &dir);
+    let mut fusion = connect(WS_ADDRESS_SYSTEMS);
+    create_system(&mut fusion, "fusion_side", "cjfusion.toml", &dir);
+
+    match send_and_receive(&mut dnd, Request::ListSystems) {
+        Response::ListSystems(names) => assert_eq!(names, vec!["dnd5e_side", "fusion_side"]),
+        r => panic!("Expected `Response::ListSystems`, got {:?}", r),
+    }
+    // Each client's characters stay in its own system.
+    for (client, name) in [(&mut dnd, "Saloth"), (&mut fusion, "Euridice")] {
+        match send_and_receive(client, Request::CreateCharacterSheet(name.to_owned())) {
+            Response::CreateCharacterSheet(list) => {
+                assert_eq!(list.len(), 1);
+                assert_eq!(list[0].name(), name);
+            }
+            r => panic!("Expected `Response::CreateCharacterSheet`, got {:?}", r),
+        }
+    }
+    // A client can switch to the other system.
+    match send_and_receive(&mut dnd, Request::UseSystem("fusion_side".to_owned())) {
+        Response::UseSystem(list, missing) => {
+            assert_eq!(list[0].name(), "Euridice");
+            assert!(missing.is_empty());
+        }
+        r => panic!("Expected `Response::UseSystem`, got {:?}", r),
+    }
+    match send_and_receive(&mut dnd, Request::UnloadSystem("dnd5e_side".to_owned())) {
+        Response::UnloadSystem(names) => assert_eq!(names, vec!["fusion_side"]),
+        r => panic!("Expected `Response::UnloadSystem`, got {:?}", r),
+    }
+    match send_and_receive(&mut dnd, Request::UseSystem("dnd5e_side".to_owned())) {
+        Response::Err(_, Error::NotFound { .. }) => {}
+        r => panic!("Expected `Response::Err`, got {:?}", r),
+    }
+    match send_and_receive(&mut fusion, Request::ListCharacters) {
+        Response::ListCharacters(list) => assert_eq!(list[0].name(), "Euridice"),
+        r => panic!("Expected `Response::ListCharacters`, got {:?}", r),
+    }
+
+    match send_and_receive(&mut fusion, Request::Shutdown) {
+        Response::Shutdown => {}
+        r => panic!("Expected `Response::Shutdown`, got {:?}", r),
+    }
+    handle.join().expect("Server thread panicked.");
+}
@@ -0,0 +1,300 @@
+//! This deals with the odds of a dice expression, eg. whether 2d6 beats 1d12.
+//! The distribution is worked out exactly where that can be done quickly,
+//! and otherwise (eg. for exploding dice) estimated by rolling many times.
+use crate::roll::{DiceExpression, DiceGroup, Rng};
+
+use std::collections::BTreeMap;
+
+/// How many rolls an estimate is made from.
+const SAMPLES: u32 = 20_000;
+/// A distribution is worked out exactly only if it takes fewer steps than this.
+const EXACT_WORK_LIMIT: f64 = 5.0e7;
+
+/// The distribution of the total of a dice expression.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct RollStats {
+    /// The expression as it was understood.
+    pub(crate) expression: String,
+    /// How many rolls the numbers were estimated from, or none if they are exact.
+    pub(crate) samples: Option<u32>,
+    pub(crate) mean: f64,
+    pub(crate) variance: f64,
+    pub(crate) min: i64,
+    /// For an estimate, the highest total which was rolled.
+    pub(crate) max: i64,
+    /// The chance of each total, from `min` to `max`.
+    pub(crate) chances: Vec<f64>,
+    /// The chance of a total of at least each number, from `min` to `max`.
+    pub(crate) at_least: Vec<f64>,
+}
+
+/// The chance of each total, from `min` up.
+#[derive(Debug, Clone, PartialEq)]
+struct Distribution {
+    min: i64,
+    chances: Vec<f64>,
+}
+
+impl Distribution {
+    fn constant(n: i64) -> Self {
+        Self {
+            min: n,
+            chances: vec![1.0],
+        }
+    }
+
+    /// The distribution of the sum of a roll of each, unless that takes too long.
+    fn add(&self, other: &Self) -> Option<Self> {
+        if self.chances.len() as f64 * other.chances.len() as f64 > EXACT_WORK_LIMIT {
+            return None;
+        }
+        let mut chances = vec![0.0; self.chances.len() + other.chances.len() - 1];
+        for (i, a) in self.chances.iter().enumerate().filter(|(_, a)| **a > 0.0) {
+            for (j, b) in other.chances.iter().enumerate() {
+                chances[i + j] += a * b;
+            }
+        }
+        Some(Self {
+            min: self.min + other.min,
+            chances,
+        })
+    }
+
+    fn negate(mut self) -> Self {
+        self.chances.reverse();
+        self.min = -(self.min + self.chances.len() as i64 - 1);
+        self
+    }
+
+    /// Leave out the totals which can't be rolled at either end.
+    fn trim(mut self) -> Self {
+        let first = self.chances.iter().position(|c| *c > 0.0).unwrap_or(0);
+        let last = self.chances.iter().rposition(|c| *c > 0.0).unwrap_or(0);
+        self.chances = self.chances[first..=last].to_vec();
+        self.min += first as i64;
+        self
+    }
+}
+
+impl RollStats {
+    /// The odds of an expression, exact if possible, or estimated with `rng`.
+    pub(crate) fn of(expression: &DiceExpression, rng: &mut Rng) -> Self {
+        let exact = expression
+            .groups
+            .iter()
+            .try_fold(Distribution::constant(expression.bonus), |t, g| {
+                t.add(&exact_distribution(g)?)
+            });
+        match exact {
+            Some(total) => Self::from_distribution(expression, total.trim(), None),
+            None => {
+                let mut counts = BTreeMap::new();
+                for _ in 0..SAMPLES {
+                    *counts
+                        .entry(expression.roll(rng, None).total)
+                        .or_insert(0u32) += 1;
+                }
+                let min = *counts.keys().next().expect("Rolled at least once.");
+                let max = *counts.keys().last().expect("Rolled at least once.");
+                let mut chances = vec![0.0; (max - min + 1) as usize];
+                for (total, count) in counts {
+                    chances[(total - min) as usize] = count as f64 / SAMPLES as f64;
+                }
+                let total = Distribution { min, chances };
+                Self::from_distribution(expression, total, Some(SAMPLES))
+            }
+        }
+    }
+
+    fn from_distribution(
+        expression: &DiceExpression,
+        total: Distribution,
+        samples: Option<u32>,
+    ) -> Self {
+        let totals = || {
+            total
+                .chances
+                .iter()
+                .enumerate()
+                .map(|(i, c)| ((total.min + i as i64) as f64, *c))
+        };
+        let mean = totals().map(|(t, c)| t * c).sum::<f64>();
+        let variance = totals()
+            .map(|(t, c)| (t - mean) * (t - mean) * c)
+            .sum::<f64>();
+        let mut at_least = total
+            .chances
+            .iter()
+            .rev()
+            .scan(0.0, |sum, c| {
+                *sum += c;
+                Some(f64::min(*sum, 1.0))
+            })
+            .collect::<Vec<_>>();
+        at_least.reverse();
+        Self {
+            expression: expression.to_string(),
+            samples,
+            mean,
+            variance,
+            min: total.min,
+            max: total.min + total.chances.len() as i64 - 1,
+            chances: total.chances,
+            at_least,
+        }
+    }
+
+    /// The chance of rolling at least `n`.
+    #[cfg(test)]
+    fn chance_of_at_least(&self, n: i64) -> f64 {
+        match n {
+            n if n <= self.min => 1.0,
+            n if n > self.max => 0.0,
+            n => self.at_least[(n - self.min) as usize],
+        }
+    }
+}
+
+/// The distribution of a dice group, if it can be worked out quickly.
+fn exact_distribution(group: &DiceGroup) -> Option<Distribution> {
+    if group.explode_at.is_some() {
+        return None;
+    }
+    let distribution = if group.drop_lowest + group.drop_highest == 0 {
+        let (n, sides) = (group.count as f64, group.sides as f64);
+        if n * n * sides * sides / 2.0 > EXACT_WORK_LIMIT {
+            return None;
+        }
+        let die = Distribution {
+            min: 1,
+            chances: vec![1.0 / sides; group.sides as usize],
+        };
+        (0..group.count).try_fold(Distribution::constant(0), |t, _| t.add(&die))?
+    } else {
+        kept_distribution(group)?
+    };
+    Some(if group.negative {
+        distribution.negate()
+    } else {
+        distribution
+    })
+}
+
+/// The distribution of the dice which are kept after dropping some.
+/// The dice are put in order, and each face from lowest to highest takes its share of them.
+/// A state is how many dice have a face so far, and the sum of those of them which are kept.
+fn kept_distribution(group: &DiceGroup) -> Option<Distribution> {
+    let (n, sides) = (group.count as usize, group.sides as usize);
+    let kept = (group.drop_lowest as usize)..(n - group.drop_highest as usize);
+    let max_sum = kept.len() * sides;
+    let work = sides as f64 * (n as f64 + 1.0).powi(2) * (max_sum as f64 + 1.0) / 2.0;
+    if work > EXACT_WORK_LIMIT {
+        return None;
+    }
+    // The log of i!, so that big binomials don't overflow.
+    let ln_fact = (0..=n)
+        .scan(0.0, |s, i| {
+            if i > 0 {
+                *s += (i as f64).ln();
+            }
+            Some(*s)
+        })
+        .collect::<Vec<f64>>();
+    let ln_side = (sides as f64).ln();
+    let mut states = vec![vec![0.0; max_sum + 1]; n + 1];
+    states[0][0] = 1.0;
+    for face in 1..=sides {
+        let mut next = vec![vec![0.0; max_sum + 1]; n + 1];
+        for (placed, sums) in states.iter().enumerate() {
+            let left = n - placed;
+            for (sum, chance) in sums.iter().enumerate().filter(|(_, c)| **c > 0.0) {
+                // The dice left all have to show this face, if it is the last.
+                let counts = if face == sides { left..=left } else { 0..=left };
+                for k in counts {
+                    let in_kept = (placed.max(kept.start)..(placed + k).min(kept.end)).len();
+                    let ways = ln_fact[left] - ln_fact[k] - ln_fact[left - k];
+                    let ways = (ways - k as f64 * ln_side).exp();
+                    next[placed + k][sum + in_kept * face] += chance * ways;
+                }
+            }
+        }
+        states = next;
+    }
+    Some(Distribution {
+        min: 0,
+        chances: states.pop().expect("There are n + 1 states."),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RollStats;
+    use crate::roll::{parse, Rng};
+
+    fn stats(dice: &str) -> RollStats {
+        RollStats::of(&parse(dice).expect("Parse."), &mut Rng::from_entropy())
+    }
+
+    fn close(a: f64, b: f64) -> bool {
+        (a - b).abs() < 1e-9
+    }
+
+    #[test]
+    fn greatsword_or_greataxe() {
+        let sword = stats("2d6");
+        assert_eq!(sword.samples, None);
+        assert_eq!((sword.min, sword.max), (2, 12));
+        assert!(close(sword.mean, 7.0));
+        assert!(close(sword.variance, 35.0 / 6.0));
+        assert!(close(sword.chances[5], 6.0 / 36.0));
+        assert!(close(sword.chance_of_at_least(10), 6.0 / 36.0));
+
+        let axe = stats("1d12");
+        assert!(close(axe.mean, 6.5));
+        assert!(close(axe.chance_of_at_least(10), 3.0 / 12.0));
+        assert!(close(axe.at_least[0], 1.0));
+        assert!(close(axe.chance_of_at_least(13), 0.0));
+    }
+
+    #[test]
+    fn bonuses_and_negative_groups() {
+        let s = stats("1d4-1d4+3");
+        assert_eq!((s.min, s.max), (0, 6));
+        assert!(close(s.mean, 3.0));
+        assert!(close(s.chances[3], 4.0 / 16.0));
+        let s = stats("5");
+        assert_eq!((s.min, s.max, s.chances.len()), (5, 5, 1));
+        assert!(close(s.variance, 0.0));
+    }
+
+    #[test]
+    fn dropped_dice() {
+        // The best 3 of 4d6, the usual way to roll for stats.
+        let s = stats("4d6dl1");
+        assert_eq!((s.min, s.max), (3, 18));
+        assert!(close(s.chances.iter().sum::<f64>(), 1.0));
+        assert!(close(s.chances[15], 21.0 / 1296.0));
+        assert!((s.mean - 12.2446).abs() < 1e-4);
+        // Advantage.
+        let s = stats("2d20dl1");
+        assert!(close(s.chance_of_at_least(20), 39.0 / 400.0));
+        // Disadvantage.
+        let s = stats("2d20dh1");
+        assert!(close(s.chance_of_at_least(20), 1.0 / 400.0));
+    }
+
+    #[test]
+    fn exploding_dice_are_estimated() {
+        let s = stats("1d6mx6");
+        assert_eq!(s.samples, Some(super::SAMPLES));
+        assert_eq!(s.min, 1);
+        assert!(s.max > 12);
+        // The mean is 3.5 * 6 / 5 = 4.2.
+        assert!((s.mean - 4.2).abs() < 0.1, "{}", s.mean);
+        assert!((s.chance_of_at_least(7) - 1.0 / 6.0).abs() < 0.02);
+        // Too many dice to work out exactly.
+        let s = stats("40d1000");
+        assert_eq!(s.samples, Some(super::SAMPLES));
+        assert!((s.mean - 20_020.0).abs() < 100.0, "{}", s.mean);
+    }
+}
@@ -0,0 +1,116 @@
+//! This deals with the game systems the server has loaded.
+//! Several systems can be loaded at once, and every client works on one of them.
+use crate::roll::Rng;
+use azchar_database::LoadedDbs;
+use azchar_error::Error;
+
+use std::collections::BTreeMap;
+use std::path::Path;
+
+/// All loaded systems, keyed by name.
+#[derive(Default)]
+pub(crate) struct Systems {
+    loaded: BTreeMap<String, LoadedDbs>,
+}
+
+/// What a single client is working on.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Session {
+    /// The name of the system which requests go to.
+    pub(crate) system: Option<String>,
+    /// Set by `SeedRolls`. Rolls without a seed of their own take one from this,
+    /// so that a session started with the same seed rolls the same.
+    pub(crate) rng: Option<Rng>,
+}
+
+impl Systems {
+    /// The names of the loaded systems, in order.
+    pub(crate) fn names(&self) -> Vec<String> {
+        self.loaded.keys().cloned().collect()
+    }
+
+    pub(crate) fn contains(&self, name: &str) -> bool {
+        self.loaded.contains_key(name)
+    }
+
+    /// The system a session is working on, if it is still loaded.
+    pub(crate) fn get_mut(&mut self, session: &Session) -> Option<&mut LoadedDbs> {
+        match session.system {
+            Some(ref name) => self.loaded.get_mut(name),
+            None => None,
+        }
+    }
+
+    /// A session which has not picked a system gets the only one loaded, if there is one.
+    /// This keeps things simple for servers with a single system.
+    pub(crate) fn bind_default(&self, session: &mut Session) {
+        if session.system.is_none() && self.loaded.len() == 1 {
+            session.system = self.loaded.keys().next().cloned();
+        }
+    }
+
+    /// Add a newly loaded system. Loading over another system is not allowed.
+    pub(crate) fn insert(&mut self, name: String, dbs: LoadedDbs) -> Result<(), Error> {
+        if self.loaded.contains_key(&name) {
+            let m = format!("A system called {} is already loaded.", name);
+            return Err(Error::conflict(m));
+        }
+        self.loaded.insert(name, dbs);
+        Ok(())
+    }
+
+    /// Remove a system. Sessions working on it will have to load it or another one.
+    pub(crate) fn remove(&mut self, name: &str) -> Result<LoadedDbs, Error> {
+        self.loaded
+            .remove(name)
+            .ok_or_else(|| Error::not_found("System", name))
+    }
+
+    /// Whether the loaded system of this name is the one at `path`.
+    pub(crate) fn is_at(&self, name: &str, path: &str) -> bool {
+        match self.loaded.get(name) {
+            Some(dbs) => same_file(dbs.root_path(), path),
+            None => false,
+        }
+    }
+}
+
+/// A system is named after its root database, eg. "dnd5e.db" is "dnd5e".
+pub(crate) fn system_name(path: &str) -> Result<String, Error> {
+    Path::new(path)
+        .file_stem()
+        .map(|s| s.to_string_lossy().to_string())
+        .ok_or_else(|| Error::validation(format!("{} is not a system file.", path)))
+}
+
+fn same_file(a: &str, b: &str) -> bool {
+    match (Path::new(a).canonicalize(), Path::new(b).canonicalize()) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => a == b,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn name_from_path() {
+        assert_eq!(system_name("/tmp/games/dnd5e.db").unwrap(), "dnd5e");
+        assert_eq!(system_name("fusion.db").unwrap(), "fusion");
+        assert!(system_name("/").is_err());
+    }
+
+    #[test]
+    fn session_without_system() {
+        let mut systems = Systems::default();
+        assert!(systems.get_mut(&Session::default()).is_none());
+        let session = Session {
+            system: Some("dnd5e".to_owned()),
+            rng: None,
+        };
+        assert!(systems.get_mut(&session).is_none());
+        assert!(systems.remove("dnd5e").is_err());
+        assert!(systems.names().is_empty());
+    }
+}
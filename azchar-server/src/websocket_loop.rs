@@ -1,44 +1,157 @@
 //! This deals with a websocket type system.
+//! Every client is served on its own thread and all of them share the loaded systems.
 use crate::requests::{Request, Response};
-use azchar_database::root_db::LoadedDbs;
-use azchar_error::ma;
+use crate::systems::{Session, Systems};
+use azchar_database::character::character::CompleteCharacter;
+use azchar_error::{ma, Error};
 
 use websocket::server::NoTlsAcceptor;
-use websocket::sync::Server;
+use websocket::sync::server::upgrade::Upgrade;
+use websocket::sync::{Server, Writer};
 use websocket::{Message, OwnedMessage};
 
+use std::collections::{HashMap, HashSet};
+use std::net::TcpStream;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+
+/// The systems shared between all clients.
+pub(crate) type SharedDbs = Arc<Mutex<Systems>>;
+
+/// The sending half of a client, shared so that other clients can push to it.
+type SharedSender = Arc<Mutex<Writer<TcpStream>>>;
+
+/// A character in a system, as (system, (name, uuid)).
+type Watched = (String, (String, String));
+
+/// Keeps track of which client is watching which character.
+#[derive(Default)]
+pub(crate) struct Subscriptions {
+    senders: HashMap<usize, SharedSender>,
+    characters: HashMap<Watched, HashSet<usize>>,
+}
+
+impl Subscriptions {
+    fn add_client(&mut self, client: usize, sender: SharedSender) {
+        self.senders.insert(client, sender);
+    }
+
+    fn remove_client(&mut self, client: usize) {
+        self.senders.remove(&client);
+        for subscribers in self.characters.values_mut() {
+            subscribers.remove(&client);
+        }
+        self.characters.retain(|_, s| !s.is_empty());
+    }
+
+    fn subscribe(&mut self, client: usize, key: Watched) {
+        self.characters.entry(key).or_default().insert(client);
+    }
+
+    fn unsubscribe(&mut self, client: usize, key: &Watched) {
+        if let Some(subscribers) = self.characters.get_mut(key) {
+            subscribers.remove(&client);
+            if subscribers.is_empty() {
+                self.characters.remove(key);
+            }
+        }
+    }
+
+    fn subscribers(&self, key: &Watched) -> Vec<SharedSender> {
+        match self.characters.get(key) {
+            Some(subscribers) => subscribers
+                .iter()
+                .filter_map(|c| self.senders.get(c))
+                .cloned()
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+}
+
+/// Everything the client threads share.
+#[derive(Clone)]
+struct Shared {
+    dbs: SharedDbs,
+    subscriptions: Arc<Mutex<Subscriptions>>,
+    running: Arc<AtomicBool>,
+    address: String,
+}
+
 pub struct WsMainLoop {
-    /// This represents the local connection to the system.
-    pub(super) dbs: Option<LoadedDbs>,
+    /// This represents the loaded systems.
+    pub(super) dbs: SharedDbs,
     /// This represents the Websocket stream.
     pub(super) stream_addr: String,
+    /// Who is watching which character.
+    subscriptions: Arc<Mutex<Subscriptions>>,
+    /// Set to false once a client asks for a shutdown.
+    running: Arc<AtomicBool>,
 }
 
 impl WsMainLoop {
     pub(crate) fn create(address: &str) -> Self {
         Self {
-            dbs: None,
+            dbs: Arc::new(Mutex::new(Systems::default())),
             stream_addr: address.to_string(),
+            subscriptions: Arc::new(Mutex::new(Subscriptions::default())),
+            running: Arc::new(AtomicBool::new(true)),
         }
     }
 
     // Can only be run in WS mode.
-    pub(crate) fn run(mut self) {
-        loop {
-            match self.run_inner() {
-                Ok(_) => println!("Stream  processed successfully."),
-                Err(e) => println!("Stream process failed: {:?}", e),
+    pub(crate) fn run(self) {
+        let mut server = match Server::<NoTlsAcceptor>::bind(&self.stream_addr) {
+            Ok(s) => s,
+            Err(e) => {
+                println!("Could not bind to {}: {:?}", self.stream_addr, e);
+                return;
+            }
+        };
+        let shared = Shared {
+            dbs: Arc::clone(&self.dbs),
+            subscriptions: Arc::clone(&self.subscriptions),
+            running: Arc::clone(&self.running),
+            address: self.stream_addr.to_owned(),
+        };
+        let next_client = AtomicUsize::new(0);
+        while self.running.load(Ordering::SeqCst) {
+            let upgrade = match server.accept() {
+                Ok(u) => u,
+                Err(e) => {
+                    println!("Connection failed: {:?}", e.error);
+                    continue;
+                }
+            };
+            if !self.running.load(Ordering::SeqCst) {
+                break;
             }
+            let shared = shared.clone();
+            let client = next_client.fetch_add(1, Ordering::SeqCst);
+            thread::spawn(move || {
+                let res = Self::serve_client(upgrade, &shared, client);
+                lock(&shared.subscriptions).remove_client(client);
+                match res {
+                    Ok(_) => println!("Stream  processed successfully."),
+                    Err(e) => println!("Stream process failed: {:?}", e),
+                }
+            });
         }
     }
 
-    fn run_inner(&mut self) -> Result<(), String> {
-        let dbs = &mut self.dbs;
-        let mut stream = Server::<NoTlsAcceptor>::bind(&self.stream_addr).map_err(ma)?;
-        let u = stream.accept().map_err(ma)?;
-        let cli = u.accept().map_err(ma)?;
+    /// Serve a single client until it disconnects.
+    fn serve_client(
+        upgrade: Upgrade<TcpStream>,
+        shared: &Shared,
+        client: usize,
+    ) -> Result<(), String> {
+        let cli = upgrade.accept().map_err(|(_, e)| ma(e))?;
         println!("Accepting connection: {:?}", cli.peer_addr());
-        let (mut receiver, mut sender) = cli.split().unwrap();
+        let (mut receiver, sender) = cli.split().map_err(ma)?;
+        let sender = Arc::new(Mutex::new(sender));
+        lock(&shared.subscriptions).add_client(client, Arc::clone(&sender));
+        let mut session = Session::default();
 
         for m in receiver.incoming_messages() {
             let then = std::time::Instant::now();
@@ -46,19 +159,45 @@ impl WsMainLoop {
             match m {
                 Ok(OwnedMessage::Close(_d)) => {
                     println!("Close.");
-                    continue;
+                    break;
+                }
+                Ok(OwnedMessage::Ping(p)) => {
+                    lock(&sender)
+                        .send_message(&OwnedMessage::Pong(p))
+                        .map_err(ma)?;
                 }
                 Ok(OwnedMessage::Text(t)) => {
                     let then = std::time::Instant::now();
-                    let res = match Request::convert(&t).execute(dbs) {
-                        Ok(Response::Shutdown) => return Ok(()),
-                        Ok(r) => serde_json::to_string(&r),
-                        Err(e) => serde_json::to_string(&Response::Err(t, ma(e))),
+                    let (request, reply_to) = Request::convert_enveloped(&t);
+                    let (res, changed) = Self::execute(request, shared, &mut session);
+                    match (&res, &session.system) {
+                        (Ok(Response::Subscribe(name, uuid)), Some(system)) => {
+                            let key = (system.to_owned(), (name.to_owned(), uuid.to_owned()));
+                            lock(&shared.subscriptions).subscribe(client, key);
+                        }
+                        (Ok(Response::Unsubscribe(name, uuid)), Some(system)) => {
+                            let key = (system.to_owned(), (name.to_owned(), uuid.to_owned()));
+                            lock(&shared.subscriptions).unsubscribe(client, &key);
+                        }
+                        _ => {}
                     }
-                    .map_err(ma)?;
+                    let shutdown = matches!(res, Ok(Response::Shutdown));
+                    let res = match res {
+                        Ok(r) => reply_to.serialize(r),
+                        Err(e) => reply_to.serialize(Response::Err(t, e)),
+                    }?;
                     elapsed_a = then.elapsed().as_micros();
                     let m = Message::text(&res);
-                    sender.send_message(&m).map_err(ma)?;
+                    lock(&sender).send_message(&m).map_err(ma)?;
+                    if let Some((character, subscribers)) = changed {
+                        push(Response::CharacterChanged(character), &subscribers)?;
+                    }
+                    if shutdown {
+                        shared.running.store(false, Ordering::SeqCst);
+                        // Wake the listener so that it notices.
+                        let _ = TcpStream::connect(&shared.address);
+                        return Ok(());
+                    }
                 }
                 Err(e) => return Err(ma(e)),
                 _ => {}
@@ -68,4 +207,54 @@ impl WsMainLoop {
         }
         Ok(())
     }
+
+    /// Execute a request for a client's session while holding the systems.
+    /// If it changed a character that someone is watching, the updated character
+    /// is loaded before anyone else gets a chance to change it again.
+    #[allow(clippy::type_complexity)]
+    fn execute(
+        request: Request,
+        shared: &Shared,
+        session: &mut Session,
+    ) -> (
+        Result<Response, Error>,
+        Option<(CompleteCharacter, Vec<SharedSender>)>,
+    ) {
+        let changed = request.changed_character();
+        let mut systems = lock(&shared.dbs);
+        let res = request.execute(&mut systems, session);
+        let system = session.system.to_owned();
+        let changed = match (&res, changed, system, systems.get_mut(session)) {
+            // A batch which failed was rolled back, so nothing changed.
+            (Ok(Response::Err(_, _)), _, _, _) | (Ok(Response::BatchFailed(_, _)), _, _, _) => None,
+            (Ok(_), Some(key), Some(system), Some(dbs)) => {
+                let watched = (system, key.to_owned());
+                let subscribers = lock(&shared.subscriptions).subscribers(&watched);
+                if subscribers.is_empty() {
+                    None
+                } else {
+                    dbs.load_character(key).ok().map(|c| (c, subscribers))
+                }
+            }
+            _ => None,
+        };
+        (res, changed)
+    }
+}
+
+/// Send an unrequested message to some clients.
+/// A client which has gone away is skipped, it is removed when its thread ends.
+fn push(response: Response, subscribers: &[SharedSender]) -> Result<(), String> {
+    let m = Message::text(serde_json::to_string(&response).map_err(ma)?);
+    for s in subscribers {
+        if let Err(e) = lock(s).send_message(&m) {
+            println!("Could not push to subscriber: {:?}", e);
+        }
+    }
+    Ok(())
+}
+
+/// A client which panicked while holding a lock should not take the others down with it.
+pub(crate) fn lock<T>(m: &Mutex<T>) -> MutexGuard<'_, T> {
+    m.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
 }